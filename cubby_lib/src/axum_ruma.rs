@@ -3,19 +3,35 @@
 //! The main features of this module are the `RumaExtractor`, which is a
 //! request body extractor that provides a given request, and `RumaResponder`,
 //! which converts Ruma responses into ones Axum is happer about.
+//!
+//! Endpoints that need to know who is making the request use
+//! `AuthRumaExtractor` instead, which also checks the access token the
+//! endpoint's metadata asks for. This library doesn't know where access tokens
+//! are stored, so the server installs a `TokenValidator` as a request
+//! extension for it to ask. Nor does it know which application services are
+//! registered, so requests to endpoints that appservices authenticate to just
+//! have their token passed along for the server to check.
 
-use std::ops::Deref;
+use std::{collections::HashMap, error::Error, ops::Deref, sync::Arc};
 
 use axum::{
     async_trait,
     body::{Body, Bytes},
-    extract::{FromRequest, Path, Request},
-    http::StatusCode,
+    extract::{FromRequest, Path, Query, Request},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
     response::{IntoResponse, Response},
     RequestPartsExt,
 };
 use bytes::BytesMut;
-use ruma::api::{error::MatrixError, IncomingRequest, OutgoingResponse};
+use ruma::{
+    api::{
+        error::{MatrixError, MatrixErrorBody},
+        AuthScheme, IncomingRequest, OutgoingResponse,
+    },
+    OwnedDeviceId, OwnedUserId,
+};
+use serde_json::json;
+use tracing::error;
 
 /// Extractor for pulling Ruma request structs from the Axum request body
 pub struct RumaExtractor<T>(pub T);
@@ -37,23 +53,205 @@ where
 {
     type Rejection = Response;
 
+    async fn from_request(
+        req: Request<Body>,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let (parts, body) = req.into_parts();
+        parse_request(parts, body).await.map(Self)
+    }
+}
+
+/// Parse a Ruma request from the parts of an Axum request
+async fn parse_request<T: IncomingRequest>(
+    mut parts: Parts,
+    body: Body,
+) -> Result<T, Response> {
+    let path_arguments: Path<Vec<String>> = parts
+        .extract()
+        .await
+        .map_err(|_e| StatusCode::BAD_REQUEST.into_response())?;
+    let body_bytes = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_e| StatusCode::BAD_REQUEST.into_response())?;
+    let new_request: Request<Bytes> = Request::from_parts(parts, body_bytes);
+    T::try_from_http_request(new_request, &path_arguments)
+        .map_err(|_e| StatusCode::BAD_REQUEST.into_response())
+}
+
+/// The user and device an access token was issued to
+#[derive(Debug, Clone)]
+pub struct Sender {
+    /// The user making the request
+    pub user_id: OwnedUserId,
+    /// The device the user is making the request from
+    pub device_id: OwnedDeviceId,
+}
+
+/// Looks up who access tokens were issued to.
+///
+/// The server installs one with `axum::Extension`, as a `SharedTokenValidator`,
+/// for `AuthRumaExtractor` to use.
+#[async_trait]
+pub trait TokenValidator: Send + Sync {
+    /// Find the sender `token` was issued to, or `None` if it isn't a valid
+    /// access token
+    async fn validate(
+        &self,
+        token: &str,
+    ) -> Result<Option<Sender>, Box<dyn Error + Send + Sync>>;
+}
+
+/// The form `TokenValidator`s are installed as a request extension in
+pub type SharedTokenValidator = Arc<dyn TokenValidator>;
+
+/// Extractor for Ruma requests to endpoints that may need an access token.
+///
+/// Whether a token is required is read from the authentication scheme in the
+/// metadata of `T`. Requests to endpoints requiring one are rejected with
+/// `M_MISSING_TOKEN` if they don't carry one, and with `M_UNKNOWN_TOKEN` if it
+/// isn't valid.
+pub struct AuthRumaExtractor<T> {
+    /// Who made the request. This is always set for endpoints that require an
+    /// access token, and set for endpoints where one is optional if the
+    /// request carried one.
+    pub sender: Option<Sender>,
+    /// The token an application service sent the request with, if the
+    /// endpoint is one appservices authenticate to and the request carried
+    /// one. It hasn't been checked.
+    pub appservice_token: Option<String>,
+    /// The request
+    pub request: T,
+}
+
+/// A response holding a Matrix error
+fn matrix_error(status: StatusCode, body: serde_json::Value) -> Response {
+    let error = MatrixError {
+        status_code: status,
+        body: MatrixErrorBody::Json(body),
+    };
+    match error.try_into_http_response::<BytesMut>() {
+        Ok(response) => {
+            response.map(BytesMut::freeze).map(Body::from).into_response()
+        }
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// The response to a request that needed an access token but didn't carry
+/// one
+fn missing_token() -> Response {
+    matrix_error(
+        StatusCode::UNAUTHORIZED,
+        json!({
+            "errcode": "M_MISSING_TOKEN",
+            "error": "Missing access token",
+        }),
+    )
+}
+
+/// The access token a request carries, either as a bearer token or in the
+/// deprecated `access_token` query parameter
+async fn access_token(parts: &mut Parts) -> Option<String> {
+    let bearer = parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_owned);
+    if bearer.is_some() {
+        return bearer;
+    }
+    let Query(mut query) =
+        parts.extract::<Query<HashMap<String, String>>>().await.ok()?;
+    query.remove("access_token")
+}
+
+/// Find the sender of a request to an endpoint with the authentication
+/// scheme `scheme`, rejecting the request if it doesn't carry a valid access
+/// token when it has to
+async fn authenticate(
+    parts: &mut Parts,
+    scheme: AuthScheme,
+) -> Result<Option<Sender>, Response> {
+    let required = match scheme {
+        AuthScheme::AccessToken => true,
+        AuthScheme::AccessTokenOptional => false,
+        // Other servers authenticate in their own way
+        _ => return Ok(None),
+    };
+    let Some(token) = access_token(parts).await else {
+        if required {
+            return Err(missing_token());
+        }
+        return Ok(None);
+    };
+    let Some(validator) = parts.extensions.get::<SharedTokenValidator>() else {
+        error!(
+            "No token validator is installed, so access tokens can't be \
+             checked"
+        );
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    };
+    match validator.validate(&token).await {
+        Ok(Some(sender)) => Ok(Some(sender)),
+        // A token that was given has to be valid, even where none is needed
+        Ok(None) => Err(matrix_error(
+            StatusCode::UNAUTHORIZED,
+            json!({
+                "errcode": "M_UNKNOWN_TOKEN",
+                "error": "Unrecognised access token",
+                "soft_logout": false,
+            }),
+        )),
+        Err(e) => {
+            error!("Failed to validate an access token: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// Find who made a request to an endpoint with the authentication scheme
+/// `scheme`, along with the token an application service made it with,
+/// rejecting the request if it doesn't carry a token it needs
+async fn credentials(
+    parts: &mut Parts,
+    scheme: AuthScheme,
+) -> Result<(Option<Sender>, Option<String>), Response> {
+    match scheme {
+        AuthScheme::AppserviceToken => match access_token(parts).await {
+            Some(token) => Ok((None, Some(token))),
+            None => Err(missing_token()),
+        },
+        AuthScheme::AppserviceTokenOptional => {
+            Ok((None, access_token(parts).await))
+        }
+        scheme => Ok((authenticate(parts, scheme).await?, None)),
+    }
+}
+
+#[async_trait]
+impl<S, T> FromRequest<S> for AuthRumaExtractor<T>
+where
+    T: IncomingRequest,
+    S: Send + Sync,
+    Bytes: FromRequest<S>,
+{
+    type Rejection = Response;
+
     async fn from_request(
         req: Request<Body>,
         _state: &S,
     ) -> Result<Self, Self::Rejection> {
         let (mut parts, body) = req.into_parts();
-        let path_arguments: Path<Vec<String>> = parts
-            .extract()
-            .await
-            .map_err(|_e| StatusCode::BAD_REQUEST.into_response())?;
-        let body_bytes = axum::body::to_bytes(body, usize::MAX)
-            .await
-            .map_err(|_e| StatusCode::BAD_REQUEST.into_response())?;
-        let new_request: Request<Bytes> =
-            Request::from_parts(parts, body_bytes);
-        let new_t = T::try_from_http_request(new_request, &path_arguments)
-            .map_err(|_e| StatusCode::BAD_REQUEST.into_response())?;
-        Ok(Self(new_t))
+        let (sender, appservice_token) =
+            credentials(&mut parts, T::METADATA.authentication).await?;
+        let request = parse_request(parts, body).await?;
+        Ok(Self {
+            sender,
+            appservice_token,
+            request,
+        })
     }
 }
 
@@ -106,3 +304,150 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use axum::{
+        async_trait,
+        body::Body,
+        extract::Request,
+        http::{header::AUTHORIZATION, request::Parts, StatusCode},
+        response::Response,
+    };
+    use ruma::{api::AuthScheme, OwnedDeviceId, UserId};
+
+    use super::{credentials, Sender, SharedTokenValidator, TokenValidator};
+
+    /// Accepts `valid` as the access token of alice's phone, and nothing else
+    struct Validator;
+
+    #[async_trait]
+    impl TokenValidator for Validator {
+        async fn validate(
+            &self,
+            token: &str,
+        ) -> Result<Option<Sender>, Box<dyn Error + Send + Sync>> {
+            if token != "valid" {
+                return Ok(None);
+            }
+            Ok(Some(Sender {
+                user_id: UserId::parse("@alice:example.org")?,
+                device_id: OwnedDeviceId::from("PHONE"),
+            }))
+        }
+    }
+
+    /// The parts of a request to `uri`, carrying `bearer` as a bearer token
+    /// if given, with the `Validator` installed if `validator` is set
+    fn parts(uri: &str, bearer: Option<&str>, validator: bool) -> Parts {
+        let mut request = Request::builder().uri(uri);
+        if let Some(token) = bearer {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let (mut parts, _body) =
+            request.body(Body::empty()).expect("Invalid request").into_parts();
+        if validator {
+            parts
+                .extensions
+                .insert::<SharedTokenValidator>(Arc::new(Validator));
+        }
+        parts
+    }
+
+    /// The status and Matrix error code of a rejection
+    async fn rejection(response: Response) -> (StatusCode, String) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let errcode = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| body["errcode"].as_str().map(str::to_owned))
+            .unwrap_or_default();
+        (status, errcode)
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_required_token_are_rejected() {
+        for scheme in [AuthScheme::AccessToken, AuthScheme::AppserviceToken] {
+            let Err(response) =
+                credentials(&mut parts("/", None, true), scheme).await
+            else {
+                panic!("Request without a token was accepted");
+            };
+            assert_eq!(
+                rejection(response).await,
+                (StatusCode::UNAUTHORIZED, "M_MISSING_TOKEN".to_owned())
+            );
+        }
+        let (sender, appservice_token) = credentials(
+            &mut parts("/", Some("as_token"), true),
+            AuthScheme::AppserviceToken,
+        )
+        .await
+        .expect("Appservice token was refused");
+        assert!(sender.is_none());
+        assert_eq!(appservice_token.as_deref(), Some("as_token"));
+    }
+
+    #[tokio::test]
+    async fn optional_tokens_still_have_to_be_valid() {
+        let (sender, _) = credentials(
+            &mut parts("/", None, true),
+            AuthScheme::AccessTokenOptional,
+        )
+        .await
+        .expect("Request without a token was refused");
+        assert!(sender.is_none());
+
+        let Err(response) = credentials(
+            &mut parts("/", Some("invalid"), true),
+            AuthScheme::AccessTokenOptional,
+        )
+        .await
+        else {
+            panic!("Invalid token was accepted");
+        };
+        assert_eq!(
+            rejection(response).await,
+            (StatusCode::UNAUTHORIZED, "M_UNKNOWN_TOKEN".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_can_be_given_in_the_query_string() {
+        let (sender, _) = credentials(
+            &mut parts("/?access_token=valid", None, true),
+            AuthScheme::AccessToken,
+        )
+        .await
+        .expect("Token in the query string was refused");
+        assert_eq!(
+            sender.map(|sender| sender.user_id.to_string()).as_deref(),
+            Some("@alice:example.org")
+        );
+
+        // The header wins if both are given
+        assert!(credentials(
+            &mut parts("/?access_token=valid", Some("invalid"), true),
+            AuthScheme::AccessToken,
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn tokens_are_not_trusted_without_a_validator() {
+        let Err(response) = credentials(
+            &mut parts("/", Some("valid"), false),
+            AuthScheme::AccessToken,
+        )
+        .await
+        else {
+            panic!("Token was accepted without being checked");
+        };
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
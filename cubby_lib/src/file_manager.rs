@@ -3,16 +3,81 @@
 //! This module provides tools for mitigating race conditions when accessing
 //! resources on disk.
 //!
-//! TODO: Write more docs about how this works.
+//! Locks are issued by a scheduler running on a dedicated thread. Requests
+//! for a lock are sent to the scheduler over a channel along with a oneshot
+//! sender the lock is returned through. When a `FileLock` is dropped it sends
+//! its path back to the scheduler, which hands the lock to the next waiting
+//! requester for that path. Requesters waiting on the same path are served in
+//! the order their requests arrived.
+//!
+//! Locks are either shared or exclusive. Any number of shared locks may be held
+//! on a file at once, but an exclusive lock can only be held while no other
+//! lock is. Since waiters are served in order, a shared request that arrives
+//! while an exclusive request is waiting queues up behind it, so a steady
+//! stream of readers can never starve a writer.
+//!
+//! Several files can be locked as one unit with `lock_many`, which always
+//! acquires them in the same order so two requesters can't each end up holding
+//! a file the other is waiting for. A ``FileManager`` can also be given a
+//! timeout, after which a request gives up and returns an error instead of
+//! waiting forever.
+//!
+//! Calling `shutdown` stops the scheduler from issuing new locks and waits
+//! until every issued lock has been released.
+//!
+//! A ``FileManager`` can also cache values derived from the files it manages,
+//! such as the parsed contents of small tables that are read often. Cached
+//! values are keyed by path and can only be read or written while holding a
+//! lock on that path, so a writer can replace a value in the same critical
+//! section as the file it was derived from. The cache is bounded by a memory
+//! budget, which is zero unless set with `with_cache_budget`.
+//!
+//! Anything else the users of a ``FileManager`` need to share, such as where
+//! the files it locks are stored, can be attached to it with
+//! `with_extension`. Every clone shares the same extensions, so they reach
+//! anything the ``FileManager`` is handed to.
+//!
+//! The scheduler is supervised. If it panics it is restarted with every held
+//! and queued lock intact, and if it keeps panicking it is stopped and every
+//! request fails with an error instead of taking the program down with it.
+//!
+//! The scheduler blocks while waiting for messages, so it uses no CPU while
+//! the server is idle. In debug builds it additionally wakes up periodically
+//! to log any requests that have been waiting for a long time, along with the
+//! chain of requesters they are waiting on.
+
+mod cache;
 
 use std::{
-    collections::{HashMap, VecDeque},
+    any::{Any, TypeId},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::{self, Display},
+    future::Future,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use crossbeam_channel::{select, Receiver, Sender};
 use tokio::sync::oneshot;
+use tracing::{error, trace, warn};
+
+use self::cache::Cache;
+
+/// How many times the scheduler is restarted after panicking before it is
+/// stopped for good
+const MAX_RESTARTS: u32 = 3;
+
+/// How often the scheduler checks for long waits in debug builds
+const WAIT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a request has to wait before it is logged in debug builds
+const WAIT_REPORT_THRESHOLD: Duration = Duration::from_secs(5);
 
 /// A message to be sent to and handled by the ``FileManager``.
 pub trait Message {
@@ -30,6 +95,50 @@ where
     async fn handle(&self, message: M) -> M::Response;
 }
 
+/// The kind of access a ``FileLock`` grants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Read-only access, which may be shared with other shared locks
+    Shared,
+    /// Read-write access, which excludes every other lock
+    Exclusive,
+}
+
+/// Errors that can occur while acquiring locks from a ``FileManager``
+#[derive(Debug)]
+pub enum FileManagerError {
+    /// The lock could not be acquired before the ``FileManager``'s timeout
+    /// elapsed
+    Timeout,
+    /// The scheduler has stopped, so no more locks can be issued
+    Stopped,
+}
+
+impl Display for FileManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileManagerError::Timeout => {
+                write!(f, "Timed out while waiting for a file lock")
+            }
+            FileManagerError::Stopped => {
+                write!(f, "The file manager is no longer issuing locks")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FileManagerError {}
+
+/// Who holds a lock and how
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Holder {
+    /// Identifies the request the lock was issued for. Every lock acquired by
+    /// a single `lock_many` call shares an owner.
+    owner: u64,
+    /// The kind of access the lock grants
+    mode: LockMode,
+}
+
 /// Represents a lock on an individual file.
 ///
 /// This lock implements custom drop logic, phoning home to the ``FileManager``
@@ -38,8 +147,10 @@ where
 pub struct FileLock {
     /// The path this lock represents
     path: PathBuf,
+    /// Who holds this lock and how
+    holder: Holder,
     /// The internal sender for phoning home
-    tx: Sender<PathBuf>,
+    tx: Sender<(PathBuf, Holder)>,
 }
 
 impl FileLock {
@@ -54,48 +165,283 @@ impl FileLock {
     pub fn get_path_owned(&self) -> PathBuf {
         self.path.clone()
     }
+
+    /// Gets the kind of access this lock grants
+    #[must_use]
+    pub fn get_mode(&self) -> LockMode {
+        self.holder.mode
+    }
 }
 
 impl Drop for FileLock {
     fn drop(&mut self) {
-        self.tx.send(self.path.clone()).expect("TODO: panic message");
+        // If the scheduler has stopped there is nobody left to tell
+        if self.tx.send((self.path.clone(), self.holder)).is_err() {
+            trace!(
+                "File manager stopped before {} was unlocked",
+                self.path.display()
+            );
+        }
     }
 }
 
+/// A message sent from a ``FileManager`` to the scheduler thread
+#[derive(Debug)]
+enum Command {
+    /// Issue a lock
+    Lock(LockRequest),
+    /// Stop issuing locks and report back once every issued lock has been
+    /// released
+    Drain(oneshot::Sender<()>),
+    /// Make the scheduler panic, to test how it is supervised
+    #[cfg(test)]
+    Panic,
+}
+
+/// A request for a lock sent to the scheduler thread
+#[derive(Debug)]
+struct LockRequest {
+    /// The file to lock
+    path: PathBuf,
+    /// Who the lock is for and how
+    holder: Holder,
+    /// Where to send the lock once it is issued
+    tx: oneshot::Sender<FileLock>,
+}
+
 #[derive(Debug, Clone)]
 /// A ``FileManager``. See module-level docs for more details.
 pub struct FileManager {
     /// The channel transmitter for communication with the management thread.
-    pub tx: Sender<(PathBuf, oneshot::Sender<FileLock>)>,
+    tx: Sender<Command>,
+    /// The source of owner ids for lock requests
+    next_owner: Arc<AtomicU64>,
+    /// How long a request may wait for its locks, if limited
+    timeout: Option<Duration>,
+    /// Values derived from the contents of managed files
+    cache: Arc<Cache>,
+    /// Values attached with `with_extension`, by type
+    extensions: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
 }
 
 impl FileManager {
     /// Create a new ``FileManager``
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the operating system refuses to spawn the
+    /// scheduler thread.
     #[must_use]
     pub fn new() -> Self {
         let (manager_tx, thread_rx) = crossbeam_channel::unbounded();
-        tokio::spawn(async move {
-            file_manager_thread(&thread_rx);
-        });
+        thread::Builder::new()
+            .name("file-manager".to_owned())
+            .spawn(move || file_manager_thread(thread_rx))
+            .expect("Failed to spawn the file manager thread");
         Self {
             tx: manager_tx,
+            next_owner: Arc::new(AtomicU64::new(0)),
+            timeout: None,
+            cache: Arc::default(),
+            extensions: Arc::default(),
         }
     }
 
-    /// Request a lock on a specific file.
+    /// Limit how long any request made through this ``FileManager`` may wait
+    /// for its locks.
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Allow up to `budget` bytes of values to be cached.
     ///
-    /// This function will block until the lock is achieved.
+    /// This replaces the cache, so it should be called before this
+    /// ``FileManager`` is cloned.
+    #[must_use]
+    pub fn with_cache_budget(mut self, budget: usize) -> Self {
+        self.cache = Arc::new(Cache::new(budget));
+        self
+    }
+
+    /// Attach a value to this ``FileManager``, replacing any value of the
+    /// same type attached before.
     ///
-    /// # Panics
+    /// Only clones made after this is called share the value.
+    #[must_use]
+    pub fn with_extension<T: Any + Send + Sync>(mut self, value: T) -> Self {
+        Arc::make_mut(&mut self.extensions)
+            .insert(TypeId::of::<T>(), Arc::new(value));
+        self
+    }
+
+    /// Get the value of type `T` attached with `with_extension`, if any
+    #[must_use]
+    pub fn extension<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.extensions.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    /// Get the value cached for the file `lock` is held on, if there is one
+    /// and it is of type `T`
+    #[must_use]
+    pub fn cached<T: Any + Send + Sync>(
+        &self,
+        lock: &FileLock,
+    ) -> Option<Arc<T>> {
+        self.cache.get(lock.get_path())
+    }
+
+    /// Cache a value derived from the file `lock` is held on, replacing any
+    /// value already cached for it.
+    ///
+    /// `size` is the approximate size of the value in bytes and is counted
+    /// against the cache budget. Callers holding a shared lock must only cache
+    /// values derived from the file as it currently is on disk.
+    pub fn cache<T: Any + Send + Sync>(
+        &self,
+        lock: &FileLock,
+        value: Arc<T>,
+        size: usize,
+    ) {
+        self.cache.insert(lock.get_path_owned(), value, size);
+    }
+
+    /// Forget the value cached for the file `lock` is held on, if any
+    pub fn uncache(&self, lock: &FileLock) {
+        self.cache.remove(lock.get_path());
+    }
+
+    /// Request a shared lock on a specific file, for reading it.
+    ///
+    /// This function will wait until the lock is achieved.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the lock could not be acquired
+    /// before the timeout elapsed, or if the scheduler has stopped.
+    pub async fn lock_shared(
+        &self,
+        path: PathBuf,
+    ) -> Result<FileLock, FileManagerError> {
+        let owner = self.new_owner();
+        self.limit(self.request(path, owner, LockMode::Shared)).await
+    }
+
+    /// Request an exclusive lock on a specific file, for modifying it.
+    ///
+    /// This function will wait until the lock is achieved.
+    ///
+    /// # Errors
     ///
-    /// This function will panic if any channels it uses become disconnected
-    /// while the program is still running
-    pub async fn lock(&self, path: PathBuf) -> FileLock {
+    /// This function will return an error if the lock could not be acquired
+    /// before the timeout elapsed, or if the scheduler has stopped.
+    pub async fn lock_exclusive(
+        &self,
+        path: PathBuf,
+    ) -> Result<FileLock, FileManagerError> {
+        let owner = self.new_owner();
+        self.limit(self.request(path, owner, LockMode::Exclusive)).await
+    }
+
+    /// Request locks on several files at once.
+    ///
+    /// The files are always locked in the same order regardless of the order
+    /// they are given in, which rules out deadlocks between requesters that
+    /// only ever lock several files through this function. If a file is
+    /// requested more than once, a single lock of the strongest requested
+    /// mode is acquired for it.
+    ///
+    /// Either every lock is returned, or none are held when this returns.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the locks could not all be
+    /// acquired before the timeout elapsed, or if the scheduler has stopped.
+    pub async fn lock_many<I>(
+        &self,
+        requests: I,
+    ) -> Result<Vec<FileLock>, FileManagerError>
+    where
+        I: IntoIterator<Item = (PathBuf, LockMode)>,
+    {
+        let mut ordered: BTreeMap<PathBuf, LockMode> = BTreeMap::new();
+        for (path, mode) in requests {
+            let entry = ordered.entry(path).or_insert(mode);
+            if mode == LockMode::Exclusive {
+                *entry = LockMode::Exclusive;
+            }
+        }
+        let owner = self.new_owner();
+        self.limit(async {
+            let mut locks = Vec::with_capacity(ordered.len());
+            for (path, mode) in ordered {
+                locks.push(self.request(path, owner, mode).await?);
+            }
+            Ok(locks)
+        })
+        .await
+    }
+
+    /// Stop issuing locks and wait until every lock that has already been
+    /// issued is released.
+    ///
+    /// Requests that are already waiting for a lock are still served, but any
+    /// request made after this is called fails with
+    /// `FileManagerError::Stopped`. This affects every clone of this
+    /// ``FileManager``. The timeout set with `with_timeout` does not apply.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the scheduler stopped before
+    /// every lock was released.
+    pub async fn shutdown(&self) -> Result<(), FileManagerError> {
         let (tx, rx) = oneshot::channel();
         self.tx
-            .send((path, tx))
-            .expect("Channel became disconnected while requesting lock");
-        rx.await.expect("Channel became disconnected while waiting for lock")
+            .send(Command::Drain(tx))
+            .map_err(|_disconnected| FileManagerError::Stopped)?;
+        rx.await.map_err(|_dropped| FileManagerError::Stopped)
+    }
+
+    /// Allocate an id identifying a new lock request
+    fn new_owner(&self) -> u64 {
+        self.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Run a future that acquires locks, giving up once the timeout elapses.
+    ///
+    /// Any locks the future has already acquired are dropped along with it.
+    async fn limit<T, F>(&self, future: F) -> Result<T, FileManagerError>
+    where
+        F: Future<Output = Result<T, FileManagerError>>,
+    {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, future)
+                .await
+                .map_err(|_elapsed| FileManagerError::Timeout)?,
+            None => future.await,
+        }
+    }
+
+    /// Request a lock of the given mode on a specific file
+    async fn request(
+        &self,
+        path: PathBuf,
+        owner: u64,
+        mode: LockMode,
+    ) -> Result<FileLock, FileManagerError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::Lock(LockRequest {
+                path,
+                holder: Holder {
+                    owner,
+                    mode,
+                },
+                tx,
+            }))
+            .map_err(|_disconnected| FileManagerError::Stopped)?;
+        rx.await.map_err(|_dropped| FileManagerError::Stopped)
     }
 }
 
@@ -105,81 +451,503 @@ impl Default for FileManager {
     }
 }
 
-/// The background thread to manage locks requested and freed by the program via
-/// the ``FileManager``.
-fn file_manager_thread(
-    lock_rx: &Receiver<(PathBuf, oneshot::Sender<FileLock>)>,
+/// A requester waiting for a lock
+struct Waiter {
+    /// Who the lock is for and how
+    holder: Holder,
+    /// When the request arrived
+    since: Instant,
+    /// Where to send the lock once it is issued
+    tx: oneshot::Sender<FileLock>,
+}
+
+/// The locking state of a single file
+#[derive(Default)]
+struct LockState {
+    /// Every lock currently held on the file
+    holders: Vec<Holder>,
+    /// The requesters waiting for the file, in the order they asked for it
+    queue: VecDeque<Waiter>,
+}
+
+impl LockState {
+    /// Whether a lock of the given mode could be issued right now
+    fn compatible(&self, mode: LockMode) -> bool {
+        match mode {
+            LockMode::Shared => self
+                .holders
+                .iter()
+                .all(|holder| holder.mode == LockMode::Shared),
+            LockMode::Exclusive => self.holders.is_empty(),
+        }
+    }
+
+    /// Whether nothing holds or is waiting for the file
+    fn is_idle(&self) -> bool {
+        self.holders.is_empty() && self.queue.is_empty()
+    }
+
+    /// Record that a lock has been released
+    fn release(&mut self, holder: Holder) {
+        if let Some(index) = self.holders.iter().position(|h| *h == holder) {
+            self.holders.swap_remove(index);
+        }
+    }
+
+    /// Forget about requesters that stopped waiting, such as ones that timed
+    /// out
+    fn prune_abandoned(&mut self) {
+        self.queue.retain(|waiter| !waiter.tx.is_closed());
+    }
+}
+
+/// Hand a lock on `path` to a requester and record it in `state`
+///
+/// If the requester has stopped waiting, the lock is dropped straight away
+/// and phones home like any other lock, passing it on to the next requester.
+fn grant(
+    path: &Path,
+    state: &mut LockState,
+    holder: Holder,
+    tx: oneshot::Sender<FileLock>,
+    unlock_tx: &Sender<(PathBuf, Holder)>,
 ) {
-    let mut locks: HashMap<PathBuf, AtomicBool> = HashMap::new();
-    let mut queue: HashMap<PathBuf, VecDeque<oneshot::Sender<FileLock>>> =
-        HashMap::new();
-    let (unlock_tx, unlock_rx) = crossbeam_channel::unbounded::<PathBuf>();
-    loop {
-        // Free any finished locks
-        loop {
-            match unlock_rx.try_recv() {
-                Err(TryRecvError::Disconnected) => {
-                    panic!("Thread became disconnected from DataframeManager");
-                }
-                Err(TryRecvError::Empty) => {
+    state.holders.push(holder);
+    let lock = FileLock {
+        path: path.to_path_buf(),
+        holder,
+        tx: unlock_tx.clone(),
+    };
+    if tx.send(lock).is_err() {
+        trace!("Lock requester stopped waiting before the lock was issued");
+    }
+}
+
+/// Issue locks to the requesters at the front of the queue for `path` for as
+/// long as they are compatible with the locks already held
+fn grant_waiting(
+    path: &Path,
+    state: &mut LockState,
+    unlock_tx: &Sender<(PathBuf, Holder)>,
+) {
+    state.prune_abandoned();
+    while state
+        .queue
+        .front()
+        .is_some_and(|waiter| state.compatible(waiter.holder.mode))
+    {
+        let Some(waiter) = state.queue.pop_front() else {
+            break;
+        };
+        grant(path, state, waiter.holder, waiter.tx, unlock_tx);
+    }
+}
+
+/// Log every request that has been waiting for a long time, following the
+/// chain of who holds the file it wants and what they are waiting for in turn
+fn log_wait_chains(files: &HashMap<PathBuf, LockState>) {
+    let now = Instant::now();
+    for (path, state) in files {
+        for waiter in &state.queue {
+            let waited = now.duration_since(waiter.since);
+            if waited < WAIT_REPORT_THRESHOLD {
+                continue;
+            }
+            let mut chain = vec![format!(
+                "request {} has waited {waited:?} for {}",
+                waiter.holder.owner,
+                path.display()
+            )];
+            let mut seen = HashSet::from([waiter.holder.owner]);
+            let mut current = state;
+            while let Some(holder) = current.holders.first() {
+                if !seen.insert(holder.owner) {
+                    chain.push(format!(
+                        "held by request {} (deadlock)",
+                        holder.owner
+                    ));
                     break;
                 }
-                Ok(path) => {
-                    if let Some(lock) = locks.get_mut(&path) {
-                        // We can mark the file as available
-                        lock.store(false, Ordering::Relaxed);
-                    } else {
-                        // I'm not sure how this path would ever be reached, but
-                        // can't hurt to cover it
-                        locks.insert(path, AtomicBool::new(false));
-                        continue;
-                    };
-                    // Restart the loop to free locks as eagerly as possible
-                    continue;
-                }
+                chain.push(format!("held by request {}", holder.owner));
+                let Some((next_path, next)) = files.iter().find(|(_, s)| {
+                    s.queue.iter().any(|w| w.holder.owner == holder.owner)
+                }) else {
+                    break;
+                };
+                chain.push(format!(
+                    "which is waiting for {}",
+                    next_path.display()
+                ));
+                current = next;
             }
+            warn!("Lock wait chain: {}", chain.join(", "));
         }
-        // Add new requests to the queue
-        loop {
-            match lock_rx.try_recv() {
-                Err(TryRecvError::Disconnected) => {
-                    panic!("Thread became disconnected from manager");
-                }
-                Err(TryRecvError::Empty) => {
-                    break;
-                }
-                Ok((path, tx)) => {
-                    if let Some(handle) = queue.get_mut(&path) {
-                        handle.push_back(tx);
-                    } else {
-                        let mut new_queue = VecDeque::new();
-                        new_queue.push_back(tx);
-                        queue.insert(path, new_queue);
+    }
+}
+
+/// The state of the scheduler, kept outside the scheduler loop so it survives
+/// the loop being restarted after a panic
+struct Scheduler {
+    /// Every file that currently has a lock held on it or waited for
+    files: HashMap<PathBuf, LockState>,
+    /// Where lock requests arrive from ``FileManager``s
+    requests: Receiver<Command>,
+    /// Whether any ``FileManager`` may still send requests
+    accepting: bool,
+    /// Whether a shutdown has been requested
+    draining: bool,
+    /// Who to notify once every lock has been released after a shutdown
+    drained_tx: Vec<oneshot::Sender<()>>,
+    /// Handed to every issued ``FileLock`` for phoning home
+    unlock_tx: Sender<(PathBuf, Holder)>,
+    /// Where released locks arrive from
+    unlock_rx: Receiver<(PathBuf, Holder)>,
+    /// Wakes the scheduler up to log long waits in debug builds
+    ticker: Receiver<Instant>,
+}
+
+impl Scheduler {
+    /// Create a scheduler serving the requests sent to `lock_rx`
+    fn new(lock_rx: Receiver<Command>) -> Self {
+        let (unlock_tx, unlock_rx) = crossbeam_channel::unbounded();
+        Self {
+            files: HashMap::new(),
+            requests: lock_rx,
+            accepting: true,
+            draining: false,
+            drained_tx: Vec::new(),
+            unlock_tx,
+            unlock_rx,
+            ticker: if cfg!(debug_assertions) {
+                crossbeam_channel::tick(WAIT_CHECK_INTERVAL)
+            } else {
+                crossbeam_channel::never()
+            },
+        }
+    }
+
+    /// Issue and collect locks until every ``FileManager`` has been dropped
+    /// and every lock issued has been released.
+    ///
+    /// A file has an entry in `files` for as long as any lock on it is held
+    /// or waited for.
+    fn run(&mut self) {
+        while self.accepting || !self.files.is_empty() {
+            select! {
+                recv(self.requests) -> message => match message {
+                    Ok(Command::Lock(request)) => self.request(request),
+                    Ok(Command::Drain(tx)) => {
+                        self.draining = true;
+                        self.drained_tx.push(tx);
+                    }
+                    #[cfg(test)]
+                    Ok(Command::Panic) => panic!("Asked to panic"),
+                    Err(_) => {
+                        // Every FileManager is gone, so no new requests can
+                        // arrive. Stop listening for them and wait for the
+                        // remaining locks to be released.
+                        self.requests = crossbeam_channel::never();
+                        self.accepting = false;
+                    }
+                },
+                recv(self.unlock_rx) -> message => {
+                    // We hold a sender for this channel, so it never
+                    // disconnects
+                    if let Ok((path, holder)) = message {
+                        self.release(&path, holder);
                     }
-                    continue;
                 }
+                recv(self.ticker) -> _ => log_wait_chains(&self.files),
             }
-        }
-        // Issue any new locks once all pending locks are freed
-        for (k, v) in &mut queue {
-            // Skip to the next iteration if the requested file is locked
-            if let Some(lock) = locks.get(k) {
-                if lock.load(Ordering::Relaxed) {
-                    continue;
+            if self.files.is_empty() {
+                for tx in self.drained_tx.drain(..) {
+                    if tx.send(()).is_err() {
+                        trace!("Shutdown requester stopped waiting");
+                    }
                 }
-                lock.store(true, Ordering::Relaxed);
-            } else {
-                // If the requested file is not in the map, add and lock it
-                locks.insert(k.clone(), AtomicBool::new(true));
             }
-            // Issue a new lock
-            if let Some(tx) = v.pop_front() {
-                tx.send(FileLock {
-                    path: k.to_owned(),
-                    tx: unlock_tx.clone(),
-                })
-                .expect("TODO: panic message");
+        }
+    }
+
+    /// Issue a lock for a new request, or queue it if it has to wait
+    fn request(&mut self, request: LockRequest) {
+        if self.draining {
+            // Dropping the sender tells the requester no lock is coming
+            trace!(
+                "Refusing lock on {} during shutdown",
+                request.path.display()
+            );
+            return;
+        }
+        let state = self.files.entry(request.path.clone()).or_default();
+        state.prune_abandoned();
+        // Anyone already waiting goes first, even if this request could be
+        // satisfied right now
+        if state.queue.is_empty() && state.compatible(request.holder.mode) {
+            grant(
+                &request.path,
+                state,
+                request.holder,
+                request.tx,
+                &self.unlock_tx,
+            );
+        } else {
+            state.queue.push_back(Waiter {
+                holder: request.holder,
+                since: Instant::now(),
+                tx: request.tx,
+            });
+        }
+    }
+
+    /// Record that a lock has been released and pass the file on to whoever
+    /// is waiting for it
+    fn release(&mut self, path: &Path, holder: Holder) {
+        let Some(state) = self.files.get_mut(path) else {
+            return;
+        };
+        state.release(holder);
+        grant_waiting(path, state, &self.unlock_tx);
+        if state.is_idle() {
+            self.files.remove(path);
+        }
+    }
+}
+
+/// The background thread to manage locks requested and freed by the program
+/// via the ``FileManager``.
+///
+/// If the scheduler panics it is restarted with its state intact, so locks
+/// that were already issued or waited for are unaffected. After
+/// `MAX_RESTARTS` panics the scheduler is stopped instead. Every waiting and
+/// future request then fails with `FileManagerError::Stopped`, and locks that
+/// are still held are simply forgotten when dropped.
+fn file_manager_thread(lock_rx: Receiver<Command>) {
+    let mut scheduler = Scheduler::new(lock_rx);
+    let mut restarts = 0;
+    while panic::catch_unwind(AssertUnwindSafe(|| scheduler.run())).is_err() {
+        if restarts == MAX_RESTARTS {
+            error!(
+                "File manager scheduler panicked {} times, no further locks \
+                 will be issued",
+                MAX_RESTARTS + 1
+            );
+            return;
+        }
+        restarts += 1;
+        error!(
+            "File manager scheduler panicked, restarting it \
+             ({restarts}/{MAX_RESTARTS})"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::task::JoinHandle;
+
+    use super::{
+        Command, FileLock, FileManager, FileManagerError, LockMode,
+        MAX_RESTARTS,
+    };
+
+    /// How long to give the scheduler to handle a request before checking
+    /// what it did with it
+    const SETTLE: Duration = Duration::from_millis(50);
+
+    /// Request a lock on `path` in the background, giving the scheduler time
+    /// to queue the request before returning
+    async fn spawn_lock(
+        file_manager: &FileManager,
+        path: &str,
+        mode: LockMode,
+    ) -> JoinHandle<Result<FileLock, FileManagerError>> {
+        let file_manager = file_manager.clone();
+        let path = PathBuf::from(path);
+        let handle = tokio::spawn(async move {
+            match mode {
+                LockMode::Shared => file_manager.lock_shared(path).await,
+                LockMode::Exclusive => file_manager.lock_exclusive(path).await,
             }
+        });
+        tokio::time::sleep(SETTLE).await;
+        handle
+    }
+
+    #[tokio::test]
+    async fn waiters_are_served_in_the_order_they_asked() {
+        let file_manager = FileManager::new();
+        let held = file_manager
+            .lock_exclusive(PathBuf::from("table"))
+            .await
+            .expect("Failed to lock");
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut waiters = Vec::new();
+        for id in 0..4 {
+            let file_manager = file_manager.clone();
+            let order = Arc::clone(&order);
+            waiters.push(tokio::spawn(async move {
+                let lock = file_manager
+                    .lock_exclusive(PathBuf::from("table"))
+                    .await
+                    .expect("Failed to lock");
+                order.lock().expect("Poisoned").push(id);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                drop(lock);
+            }));
+            tokio::time::sleep(SETTLE).await;
+        }
+        drop(held);
+        for waiter in waiters {
+            waiter.await.expect("Waiter panicked");
+        }
+        assert_eq!(*order.lock().expect("Poisoned"), [0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn readers_queue_behind_a_waiting_writer() {
+        let file_manager = FileManager::new();
+        let reader = file_manager
+            .lock_shared(PathBuf::from("table"))
+            .await
+            .expect("Failed to lock");
+        let writer =
+            spawn_lock(&file_manager, "table", LockMode::Exclusive).await;
+        let late_reader =
+            spawn_lock(&file_manager, "table", LockMode::Shared).await;
+        assert!(!writer.is_finished());
+        assert!(!late_reader.is_finished());
+
+        drop(reader);
+        let writer =
+            writer.await.expect("Writer panicked").expect("Failed to lock");
+        tokio::time::sleep(SETTLE).await;
+        assert!(!late_reader.is_finished());
+        drop(writer);
+        late_reader.await.expect("Reader panicked").expect("Failed to lock");
+    }
+
+    #[tokio::test]
+    async fn lock_many_locks_each_file_once_in_order() {
+        let file_manager = FileManager::new();
+        let locks = file_manager
+            .lock_many([
+                (PathBuf::from("b"), LockMode::Shared),
+                (PathBuf::from("a"), LockMode::Shared),
+                (PathBuf::from("b"), LockMode::Exclusive),
+            ])
+            .await
+            .expect("Failed to lock");
+        let locked: Vec<_> = locks
+            .iter()
+            .map(|lock| (lock.get_path_owned(), lock.get_mode()))
+            .collect();
+        assert_eq!(
+            locked,
+            [
+                (PathBuf::from("a"), LockMode::Shared),
+                (PathBuf::from("b"), LockMode::Exclusive),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lock_many_holds_nothing_after_timing_out() {
+        let file_manager = FileManager::new().with_timeout(SETTLE);
+        let held = file_manager
+            .lock_exclusive(PathBuf::from("b"))
+            .await
+            .expect("Failed to lock");
+        let result = file_manager
+            .lock_many([
+                (PathBuf::from("a"), LockMode::Exclusive),
+                (PathBuf::from("b"), LockMode::Exclusive),
+            ])
+            .await;
+        assert!(matches!(result, Err(FileManagerError::Timeout)));
+        // `a` was locked before waiting for `b`, and released on timeout
+        file_manager
+            .lock_exclusive(PathBuf::from("a"))
+            .await
+            .expect("a is still locked");
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn requests_that_timed_out_stop_waiting() {
+        let file_manager = FileManager::new().with_timeout(SETTLE);
+        let reader = file_manager
+            .lock_shared(PathBuf::from("table"))
+            .await
+            .expect("Failed to lock");
+        let writer = file_manager.lock_exclusive(PathBuf::from("table")).await;
+        assert!(matches!(writer, Err(FileManagerError::Timeout)));
+        // Nobody is waiting any more, so readers don't have to queue behind
+        // the writer that gave up
+        file_manager
+            .lock_shared(PathBuf::from("table"))
+            .await
+            .expect("Reader queued behind an abandoned writer");
+        drop(reader);
+    }
+
+    #[tokio::test]
+    async fn scheduler_is_restarted_until_it_panics_too_often() {
+        let file_manager = FileManager::new();
+        let held = file_manager
+            .lock_exclusive(PathBuf::from("table"))
+            .await
+            .expect("Failed to lock");
+        let waiter =
+            spawn_lock(&file_manager, "table", LockMode::Exclusive).await;
+
+        // Held and queued locks survive a restart
+        file_manager.tx.send(Command::Panic).expect("Scheduler stopped");
+        drop(held);
+        waiter.await.expect("Waiter panicked").expect("Waiter lost its place");
+
+        for _ in 0..MAX_RESTARTS {
+            file_manager.tx.send(Command::Panic).expect("Scheduler stopped");
         }
+        let result = file_manager.lock_shared(PathBuf::from("table")).await;
+        assert!(matches!(result, Err(FileManagerError::Stopped)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_held_and_waiting_locks() {
+        let file_manager = FileManager::new();
+        let held = file_manager
+            .lock_exclusive(PathBuf::from("table"))
+            .await
+            .expect("Failed to lock");
+        let waiter =
+            spawn_lock(&file_manager, "table", LockMode::Exclusive).await;
+        let shutdown = {
+            let file_manager = file_manager.clone();
+            tokio::spawn(async move { file_manager.shutdown().await })
+        };
+        tokio::time::sleep(SETTLE).await;
+
+        let refused = file_manager.lock_shared(PathBuf::from("other")).await;
+        assert!(matches!(refused, Err(FileManagerError::Stopped)));
+        assert!(!shutdown.is_finished());
+
+        // Requests made before the shutdown are still served
+        drop(held);
+        let waiter =
+            waiter.await.expect("Waiter panicked").expect("Waiter was refused");
+        tokio::time::sleep(SETTLE).await;
+        assert!(!shutdown.is_finished());
+        drop(waiter);
+        shutdown
+            .await
+            .expect("Shutdown panicked")
+            .expect("Failed to shut down");
     }
 }
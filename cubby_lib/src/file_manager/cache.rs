@@ -0,0 +1,187 @@
+//! A memory-bounded cache of values derived from the contents of files
+//!
+//! Entries are keyed by the path of the file they were derived from. When
+//! inserting an entry would exceed the memory budget, the least recently used
+//! entries are evicted until it fits. The cache does not know what it holds,
+//! so callers report the size of each entry themselves.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use tracing::trace;
+
+/// A single cached value
+struct Entry {
+    /// The cached value
+    value: Arc<dyn Any + Send + Sync>,
+    /// The size of the value in bytes, as reported when it was inserted
+    size: usize,
+    /// The value of `CacheState::clock` when the entry was last used
+    last_used: u64,
+}
+
+/// The contents of a ``Cache``
+#[derive(Default)]
+struct CacheState {
+    /// How many bytes the cache may hold
+    budget: usize,
+    /// How many bytes the cache currently holds
+    used: usize,
+    /// Incremented on every access, to order entries by when they were used
+    clock: u64,
+    /// Every cached value
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl CacheState {
+    /// Remove the entry for `path`, if any
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.used -= entry.size;
+        }
+    }
+
+    /// Remove the least recently used entry. Returns `false` if the cache is
+    /// empty.
+    fn evict_oldest(&mut self) -> bool {
+        let Some(path) = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone())
+        else {
+            return false;
+        };
+        trace!("Evicting {} from the cache", path.display());
+        self.remove(&path);
+        true
+    }
+}
+
+/// A memory-bounded cache. See module-level docs for more details.
+#[derive(Default)]
+pub(super) struct Cache {
+    /// The contents of the cache
+    state: Mutex<CacheState>,
+}
+
+impl Cache {
+    /// Create a cache that holds at most `budget` bytes
+    pub(super) fn new(budget: usize) -> Self {
+        Self {
+            state: Mutex::new(CacheState {
+                budget,
+                ..CacheState::default()
+            }),
+        }
+    }
+
+    /// Lock the contents of the cache.
+    ///
+    /// Every update leaves the state consistent before anything that could
+    /// panic, so a poisoned lock is safe to keep using.
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the value cached for `path`, if it is of type `T`
+    pub(super) fn get<T: Any + Send + Sync>(
+        &self,
+        path: &Path,
+    ) -> Option<Arc<T>> {
+        let mut state = self.state();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(path)?;
+        entry.last_used = clock;
+        Arc::clone(&entry.value).downcast().ok()
+    }
+
+    /// Cache `value` for `path`, replacing any previous value.
+    ///
+    /// Values larger than the whole budget are not cached at all.
+    pub(super) fn insert(
+        &self,
+        path: PathBuf,
+        value: Arc<dyn Any + Send + Sync>,
+        size: usize,
+    ) {
+        let mut state = self.state();
+        state.remove(&path);
+        if size > state.budget {
+            return;
+        }
+        while state.used + size > state.budget {
+            if !state.evict_oldest() {
+                break;
+            }
+        }
+        state.clock += 1;
+        let clock = state.clock;
+        state.used += size;
+        state.entries.insert(
+            path,
+            Entry {
+                value,
+                size,
+                last_used: clock,
+            },
+        );
+    }
+
+    /// Forget the value cached for `path`, if any
+    pub(super) fn remove(&self, path: &Path) {
+        self.state().remove(path);
+    }
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state();
+        f.debug_struct("Cache")
+            .field("budget", &state.budget)
+            .field("used", &state.used)
+            .field("entries", &state.entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::Cache;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = Cache::new(10);
+        cache.insert("a".into(), Arc::new(1_u8), 4);
+        cache.insert("b".into(), Arc::new(2_u8), 4);
+        // Reading `a` makes `b` the least recently used
+        assert_eq!(cache.get::<u8>(Path::new("a")).as_deref(), Some(&1));
+        cache.insert("c".into(), Arc::new(3_u8), 4);
+        assert!(cache.get::<u8>(Path::new("a")).is_some());
+        assert!(cache.get::<u8>(Path::new("b")).is_none());
+        assert!(cache.get::<u8>(Path::new("c")).is_some());
+
+        // Replacing an entry frees the space of the old value first
+        cache.insert("c".into(), Arc::new(4_u8), 6);
+        assert!(cache.get::<u8>(Path::new("a")).is_some());
+        assert_eq!(cache.get::<u8>(Path::new("c")).as_deref(), Some(&4));
+    }
+
+    #[test]
+    fn values_larger_than_the_budget_are_not_cached() {
+        let cache = Cache::new(10);
+        cache.insert("a".into(), Arc::new(1_u8), 4);
+        cache.insert("b".into(), Arc::new(2_u8), 4);
+        cache.insert("b".into(), Arc::new(3_u8), 11);
+        assert!(cache.get::<u8>(Path::new("b")).is_none());
+        assert!(cache.get::<u8>(Path::new("a")).is_some());
+    }
+}
@@ -2,5 +2,7 @@
 
 mod axum_ruma;
 pub mod file_manager;
+mod parquet_row;
 pub use axum_ruma::*;
 pub use file_manager::FileManager;
+pub use parquet_row::{ParquetRow, ParquetValue};
@@ -0,0 +1,103 @@
+//! Typed rows of tables
+//!
+//! Structs deriving `ParquetRow` from `cubby_macros` describe the rows of a
+//! table in Rust types, so handlers can name columns through constants and
+//! move data in and out of polars without matching on column names and
+//! datatypes by hand. The columns of a table with a row struct are taken from
+//! `ParquetRow::COLUMNS`, so the two can't disagree.
+
+use polars::prelude::*;
+
+/// A struct describing a row of a table.
+///
+/// This should be derived with `#[derive(ParquetRow)]` rather than
+/// implemented by hand.
+pub trait ParquetRow: Sized {
+    /// The columns the fields are stored in and their datatypes, in the order
+    /// the fields are declared
+    const COLUMNS: &'static [(&'static str, DataType)];
+
+    /// The polars `Schema` of the columns the fields are stored in
+    #[must_use]
+    fn schema() -> Schema {
+        Self::COLUMNS
+            .iter()
+            .map(|(name, dtype)| Field::new(name, dtype.clone()))
+            .collect()
+    }
+
+    /// Convert every row of `df` into a struct.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a column is missing from `df`
+    /// or can't be converted to the type of its field, or if a field that
+    /// isn't an `Option` would be null.
+    fn from_dataframe(df: &DataFrame) -> PolarsResult<Vec<Self>>;
+
+    /// Convert structs into the rows of a `DataFrame`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a column can't be built.
+    fn to_dataframe(rows: &[Self]) -> PolarsResult<DataFrame>;
+}
+
+/// A Rust type that can be stored in a column
+pub trait ParquetValue: Clone + Sized {
+    /// The datatype of columns holding this type
+    const DTYPE: DataType;
+
+    /// Build a column holding `values`
+    fn series(name: &str, values: Vec<Option<Self>>) -> Series;
+
+    /// Read every value of a column, converting it to `DTYPE` first
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the column can't be converted
+    /// to `DTYPE`.
+    fn values(column: &Series) -> PolarsResult<Vec<Option<Self>>>;
+}
+
+impl ParquetValue for String {
+    const DTYPE: DataType = DataType::String;
+
+    fn series(name: &str, values: Vec<Option<Self>>) -> Series {
+        Series::new(name, values)
+    }
+
+    fn values(column: &Series) -> PolarsResult<Vec<Option<Self>>> {
+        let column = column.cast(&Self::DTYPE)?;
+        let values =
+            column.str()?.into_iter().map(|v| v.map(str::to_owned)).collect();
+        Ok(values)
+    }
+}
+
+/// Implement `ParquetValue` for a primitive type, given its datatype and the
+/// `Series` method returning its `ChunkedArray`
+macro_rules! primitive_value {
+    ($type:ty, $dtype:ident, $accessor:ident) => {
+        impl ParquetValue for $type {
+            const DTYPE: DataType = DataType::$dtype;
+
+            fn series(name: &str, values: Vec<Option<Self>>) -> Series {
+                Series::new(name, values)
+            }
+
+            fn values(column: &Series) -> PolarsResult<Vec<Option<Self>>> {
+                let column = column.cast(&Self::DTYPE)?;
+                let values = column.$accessor()?.into_iter().collect();
+                Ok(values)
+            }
+        }
+    };
+}
+
+primitive_value!(bool, Boolean, bool);
+primitive_value!(u32, UInt32, u32);
+primitive_value!(u64, UInt64, u64);
+primitive_value!(i32, Int32, i32);
+primitive_value!(i64, Int64, i64);
+primitive_value!(f64, Float64, f64);
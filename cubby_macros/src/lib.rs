@@ -7,8 +7,8 @@ use syn::{
     parse::{Parse, ParseStream, Result},
     parse_macro_input,
     punctuated::Punctuated,
-    token, Attribute, Error, Generics, Ident, LitStr, Token, Variant,
-    Visibility,
+    token, Attribute, Data, DeriveInput, Error, Fields, GenericArgument,
+    Generics, Ident, LitStr, PathArguments, Token, Type, Variant, Visibility,
 };
 
 #[derive(Debug)]
@@ -117,3 +117,370 @@ pub fn derive_into_matrix_error(input: TokenStream) -> TokenStream {
     }
     .into()
 }
+
+/// A single field of a struct deriving `ParquetRow`
+struct RowField {
+    /// The name of the field
+    ident: Ident,
+    /// The name of the column the field is stored in
+    column: String,
+    /// The type of the field, without the `Option` if it has one
+    ty: Type,
+    /// Whether the field is an `Option`, and so allows nulls
+    nullable: bool,
+    /// Whether the column is stored as a categorical
+    categorical: bool,
+    /// Whether the column may be missing from a `DataFrame` entirely
+    optional: bool,
+}
+
+/// Returns `T` if `ty` is `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    match arguments.args.first() {
+        Some(GenericArgument::Type(inner)) if arguments.args.len() == 1 => {
+            Some(inner)
+        }
+        _ => None,
+    }
+}
+
+impl RowField {
+    /// Read a field and its `#[parquet(...)]` attributes
+    fn parse(field: &syn::Field) -> Result<Self> {
+        let Some(ident) = field.ident.clone() else {
+            return Err(Error::new_spanned(
+                field,
+                "ParquetRow fields must be named",
+            ));
+        };
+        let mut column = ident.to_string().trim_start_matches("r#").to_owned();
+        let mut categorical = false;
+        let mut optional = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("parquet"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("categorical") {
+                    categorical = true;
+                } else if meta.path.is_ident("optional") {
+                    optional = true;
+                } else if meta.path.is_ident("rename") {
+                    column = meta.value()?.parse::<LitStr>()?.value();
+                } else {
+                    return Err(meta.error(
+                        "expected `categorical`, `optional` or `rename`",
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+        let (ty, nullable) = match option_inner(&field.ty) {
+            Some(inner) => (inner.clone(), true),
+            None => (field.ty.clone(), false),
+        };
+        if optional && !nullable {
+            return Err(Error::new_spanned(
+                &field.ty,
+                "`optional` columns must be an `Option`",
+            ));
+        }
+        Ok(Self {
+            ident,
+            column,
+            ty,
+            nullable,
+            categorical,
+            optional,
+        })
+    }
+
+    /// The polars datatype of the column
+    fn dtype(&self) -> proc_macro2::TokenStream {
+        let ty = &self.ty;
+        if self.categorical {
+            quote! {
+                polars::prelude::DataType::Categorical(
+                    None,
+                    polars::prelude::CategoricalOrdering::Physical,
+                )
+            }
+        } else {
+            quote! { <#ty as cubby_lib::ParquetValue>::DTYPE }
+        }
+    }
+
+    /// The name of the constant holding the column name
+    fn constant(&self) -> Ident {
+        Ident::new(&self.column.to_uppercase(), self.ident.span())
+    }
+
+    /// Code reading the column into an iterator named after the field, for
+    /// `from_dataframe`
+    fn reader(&self) -> proc_macro2::TokenStream {
+        let ident = &self.ident;
+        let ty = &self.ty;
+        let column = &self.column;
+        if self.optional {
+            quote! {
+                let mut #ident = match df.column(#column) {
+                    Ok(column) => {
+                        <#ty as cubby_lib::ParquetValue>::values(column)?
+                    }
+                    Err(_) => vec![None; df.height()],
+                }
+                .into_iter();
+            }
+        } else {
+            quote! {
+                let mut #ident =
+                    <#ty as cubby_lib::ParquetValue>::values(
+                        df.column(#column)?,
+                    )?
+                    .into_iter();
+            }
+        }
+    }
+
+    /// Code setting the field from the next value of its reader
+    fn assignment(&self) -> proc_macro2::TokenStream {
+        let ident = &self.ident;
+        if self.nullable {
+            quote! { #ident: #ident.next().flatten() }
+        } else {
+            let message = format!("Column {} holds a null", self.column);
+            quote! {
+                #ident: #ident.next().flatten().ok_or_else(|| {
+                    polars::prelude::PolarsError::ComputeError(#message.into())
+                })?
+            }
+        }
+    }
+
+    /// Code building the column from every row, for `to_dataframe`
+    fn writer(&self) -> proc_macro2::TokenStream {
+        let ident = &self.ident;
+        let ty = &self.ty;
+        let column = &self.column;
+        let dtype = self.dtype();
+        let value = if self.nullable {
+            quote! { row.#ident.clone() }
+        } else {
+            quote! { Some(row.#ident.clone()) }
+        };
+        quote! {
+            <#ty as cubby_lib::ParquetValue>::series(
+                #column,
+                rows.iter().map(|row| #value).collect(),
+            )
+            .cast(&#dtype)?
+        }
+    }
+}
+
+/// Generate the implementation of `ParquetRow` for a struct
+fn gen_parquet_row(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            input,
+            "ParquetRow can only be derived for structs",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(Error::new_spanned(
+            input,
+            "ParquetRow can only be derived for structs with named fields",
+        ));
+    };
+    let fields =
+        named.named.iter().map(RowField::parse).collect::<Result<Vec<_>>>()?;
+    let struct_name = &input.ident;
+    let vis = &input.vis;
+    let (ig, tyg, where_clause) = input.generics.split_for_impl();
+
+    let constants = fields.iter().map(|field| {
+        let constant = field.constant();
+        let column = &field.column;
+        let doc = format!("The name of the `{column}` column");
+        quote! {
+            #[doc = #doc]
+            #vis const #constant: &'static str = #column;
+        }
+    });
+    let columns = fields.iter().map(|field| {
+        let column = &field.column;
+        let dtype = field.dtype();
+        quote! { (#column, #dtype) }
+    });
+    let readers = fields.iter().map(RowField::reader);
+    let assignments = fields.iter().map(RowField::assignment);
+    let writers = fields.iter().map(RowField::writer);
+
+    Ok(quote! {
+        impl #ig #struct_name #tyg #where_clause {
+            #(#constants)*
+        }
+
+        impl #ig cubby_lib::ParquetRow for #struct_name #tyg #where_clause {
+            const COLUMNS: &'static [(
+                &'static str,
+                polars::prelude::DataType,
+            )] = &[#(#columns),*];
+
+            fn from_dataframe(
+                df: &polars::prelude::DataFrame,
+            ) -> polars::prelude::PolarsResult<Vec<Self>> {
+                #(#readers)*
+                let mut rows = Vec::with_capacity(df.height());
+                for _ in 0..df.height() {
+                    rows.push(Self {
+                        #(#assignments),*
+                    });
+                }
+                Ok(rows)
+            }
+
+            fn to_dataframe(
+                rows: &[Self],
+            ) -> polars::prelude::PolarsResult<polars::prelude::DataFrame> {
+                polars::prelude::DataFrame::new(vec![#(#writers),*])
+            }
+        }
+    })
+}
+
+/// Derive macro for the `ParquetRow` trait in `cubby_lib`.
+///
+/// Every field is stored in a column of the same name, in the order the fields
+/// are declared, and an associated constant holding the column name is
+/// generated for it, named after the column in upper case. Fields of type
+/// `Option<T>` allow nulls. Fields can be annotated with `#[parquet(...)]`:
+///
+/// - `categorical` stores a `String` field as a categorical column
+/// - `optional` allows the column to be missing from a `DataFrame`, in which
+///   case the field is `None`. The field must be an `Option`.
+/// - `rename = "name"` stores the field in a column with a different name
+#[proc_macro_derive(ParquetRow, attributes(parquet))]
+pub fn derive_parquet_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    gen_parquet_row(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::ToTokens;
+    use syn::{parse_quote, DeriveInput, FieldsNamed};
+
+    use super::{gen_parquet_row, RowField};
+
+    /// Parse the only field of `fields`
+    fn field(fields: &FieldsNamed) -> syn::Result<RowField> {
+        RowField::parse(fields.named.first().expect("No field"))
+    }
+
+    #[test]
+    fn option_fields_are_nullable() {
+        let nullable = field(&parse_quote!({ display_name: Option<String> }))
+            .expect("Failed to parse field");
+        assert!(nullable.nullable);
+        assert_eq!(nullable.ty.to_token_stream().to_string(), "String");
+        assert_eq!(nullable.column, "display_name");
+
+        let required = field(&parse_quote!({ user_id: u64 }))
+            .expect("Failed to parse field");
+        assert!(!required.nullable);
+        assert_eq!(required.ty.to_token_stream().to_string(), "u64");
+    }
+
+    #[test]
+    fn attributes_are_parsed() {
+        let categorical = field(&parse_quote!({
+            #[parquet(categorical)]
+            username: String
+        }))
+        .expect("Failed to parse field");
+        assert!(categorical.categorical && !categorical.optional);
+        assert!(categorical.dtype().to_string().contains("Categorical"));
+
+        let optional = field(&parse_quote!({
+            #[parquet(optional)]
+            last_seen: Option<u64>
+        }))
+        .expect("Failed to parse field");
+        assert!(optional.optional && optional.nullable);
+        assert!(!optional.categorical);
+
+        let renamed = field(&parse_quote!({
+            #[parquet(rename = "type")]
+            r#type: String
+        }))
+        .expect("Failed to parse field");
+        assert_eq!(renamed.column, "type");
+        assert_eq!(renamed.constant().to_string(), "TYPE");
+
+        let raw = field(&parse_quote!({ r#type: String }))
+            .expect("Failed to parse field");
+        assert_eq!(raw.column, "type");
+    }
+
+    #[test]
+    fn invalid_attributes_are_refused() {
+        let e = field(&parse_quote!({
+            #[parquet(optional)]
+            user_id: u64
+        }))
+        .err()
+        .expect("Accepted an optional field that isn't an Option");
+        assert!(e.to_string().contains("must be an `Option`"), "{e}");
+
+        let e = field(&parse_quote!({
+            #[parquet(indexed)]
+            user_id: u64
+        }))
+        .err()
+        .expect("Accepted an unknown attribute");
+        assert!(e.to_string().contains("expected `categorical`"), "{e}");
+    }
+
+    #[test]
+    fn nulls_are_only_errors_in_fields_that_arent_options() {
+        let required = field(&parse_quote!({ user_id: u64 }))
+            .expect("Failed to parse field")
+            .assignment()
+            .to_string();
+        assert!(required.contains("Column user_id holds a null"), "{required}");
+
+        let nullable = field(&parse_quote!({ user_id: Option<u64> }))
+            .expect("Failed to parse field")
+            .assignment()
+            .to_string();
+        assert!(!nullable.contains("holds a null"), "{nullable}");
+    }
+
+    #[test]
+    fn only_structs_with_named_fields_can_derive() {
+        let input: DeriveInput = parse_quote! {
+            struct Row {
+                #[parquet(rename = "id")]
+                user_id: u64,
+            }
+        };
+        let generated = gen_parquet_row(&input)
+            .expect("Failed to derive ParquetRow")
+            .to_string();
+        assert!(generated.contains("const ID"), "{generated}");
+
+        let tuple: DeriveInput = parse_quote! { struct Row(u64); };
+        assert!(gen_parquet_row(&tuple).is_err());
+        let enumeration: DeriveInput = parse_quote! { enum Row { A } };
+        assert!(gen_parquet_row(&enumeration).is_err());
+    }
+}
@@ -0,0 +1,409 @@
+//! Read-only SQL queries over the homeserver's tables
+//!
+//! Admins can ask questions of the data with polars SQL, either through the
+//! `/admin/v1/sql` endpoint or with `cubby sql` while the server is stopped.
+//! Every table in `schema::TABLES` is registered under its own name, except
+//! for the tables identifiers are spread across, which are registered together
+//! as a single `identifiers` table. Columns hold short IDs rather than Matrix
+//! identifiers (see `managers::interner`), which can be resolved by joining
+//! against it.
+//! Timeline events live in the event store rather than a table, so they can't
+//! be queried.
+//!
+//! Queries are kept in check in a few ways:
+//!
+//! - Columns listed in `Table::redacted`, such as password hashes and access
+//!   tokens, are replaced with nulls before the query sees them, so they can't
+//!   be selected, filtered on or joined against.
+//! - Only the registered tables may be read. Queries are parsed before they are
+//!   run, and anything but a single `SELECT` is refused, as is any query
+//!   reading from a table function like `read_parquet`, which could otherwise
+//!   read any file the server can, under any name.
+//! - At most `PROGRAM_CONFIG.analytics_row_limit` rows are returned.
+//! - Queries are cancelled once they have run for
+//!   `PROGRAM_CONFIG.analytics_timeout` seconds. Every table holds a shared
+//!   lock while a query runs, so this also bounds how long writes can be held
+//!   up.
+//!
+//! Results can be returned as JSON (an array of objects), CSV or Arrow IPC.
+
+use std::{
+    ops::ControlFlow,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use cubby_lib::FileManager;
+use polars::{prelude::*, sql::SQLContext};
+use serde::Deserialize;
+use sqlparser::{
+    ast::{Statement, TableFactor, Visit, Visitor},
+    dialect::GenericDialect,
+    parser::Parser,
+};
+
+use crate::{
+    config::PROGRAM_CONFIG,
+    managers::dataframes::ParquetManager,
+    schema::{Table, IDENTIFIERS, IDENTIFIER_TABLES, TABLES},
+};
+
+/// How often a running query is checked for having finished
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The format query results are encoded in
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Format {
+    /// A JSON array holding an object for every row
+    #[default]
+    Json,
+    /// CSV with a header row
+    Csv,
+    /// The Arrow IPC file format
+    Ipc,
+}
+
+impl Format {
+    /// The value of the `Content-Type` header for results in this format
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::Ipc => "application/vnd.apache.arrow.file",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "ipc" => Ok(Format::Ipc),
+            _ => Err(format!("Unknown format {s}, expected json, csv or ipc")),
+        }
+    }
+}
+
+/// The result of a query
+#[derive(Debug)]
+pub(crate) struct QueryResult {
+    /// The rows returned, up to the row limit
+    pub(crate) rows: DataFrame,
+    /// Whether the query returned more rows than the limit, and was cut off
+    pub(crate) truncated: bool,
+}
+
+/// Convert a failed query into a `PolarsError`
+fn query_error(reason: &str) -> PolarsError {
+    PolarsError::ComputeError(format!("Query refused: {reason}").into())
+}
+
+/// Finds the first table factor in a query that isn't a plain table name, a
+/// subquery or a parenthesized join
+struct TableFunctions;
+
+impl Visitor for TableFunctions {
+    type Break = String;
+
+    fn pre_visit_table_factor(
+        &mut self,
+        table_factor: &TableFactor,
+    ) -> ControlFlow<Self::Break> {
+        match table_factor {
+            TableFactor::Table {
+                args: None,
+                ..
+            }
+            | TableFactor::Derived {
+                ..
+            }
+            | TableFactor::NestedJoin {
+                ..
+            } => ControlFlow::Continue(()),
+            other => ControlFlow::Break(other.to_string()),
+        }
+    }
+}
+
+/// Make sure `sql` is a single `SELECT` that reads nothing but tables
+///
+/// # Errors
+///
+/// This function will return an error if `sql` can't be parsed, holds
+/// anything but one query, or reads from a table function.
+fn validate(sql: &str) -> PolarsResult<()> {
+    let statements = Parser::parse_sql(&GenericDialect, sql)
+        .map_err(|e| query_error(&e.to_string()))?;
+    let [statement @ Statement::Query(_)] = statements.as_slice() else {
+        return Err(query_error("only a single SELECT query can be run"));
+    };
+    if let ControlFlow::Break(function) = statement.visit(&mut TableFunctions) {
+        return Err(query_error(&format!(
+            "only the tables of the homeserver can be read, not {function}"
+        )));
+    }
+    Ok(())
+}
+
+/// The contents of `table`, with every redacted column replaced by nulls
+fn redact(table: &Table, frame: LazyFrame) -> LazyFrame {
+    if table.redacted.is_empty() {
+        return frame;
+    }
+    frame.with_columns(
+        table
+            .columns
+            .iter()
+            .filter(|(name, _)| table.redacted.contains(name))
+            .map(|(name, dtype)| lit(NULL).cast(dtype.clone()).alias(name))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Collect `frame`, cancelling it if it runs for longer than `timeout`.
+///
+/// A cancelled query is waited on until it has actually stopped, so the
+/// caller can release its locks knowing nothing still reads the tables.
+fn collect_within(
+    frame: LazyFrame,
+    timeout: Duration,
+) -> PolarsResult<DataFrame> {
+    let deadline = Instant::now() + timeout;
+    let query = frame.collect_concurrently()?;
+    loop {
+        if let Some(result) = query.fetch() {
+            return result;
+        }
+        if Instant::now() >= deadline {
+            query.cancel();
+            let _cancelled = query.fetch_blocking();
+            return Err(query_error(&format!(
+                "it did not finish within {} seconds",
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Run the SQL query `sql` against every table, returning at most `limit`
+/// rows.
+///
+/// # Errors
+///
+/// This function will return an error if the tables can't be locked, if the
+/// query is invalid or tries to do anything but read the registered tables,
+/// or if it fails or times out.
+pub(crate) async fn query(
+    file_manager: &FileManager,
+    sql: &str,
+    limit: usize,
+) -> PolarsResult<QueryResult> {
+    validate(sql)?;
+    let frames = file_manager.get_lazyframes(&TABLES).await?;
+    let sql = sql.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut context = SQLContext::new();
+        let mut identifiers = Vec::new();
+        for (table, frame) in TABLES.iter().zip(&frames) {
+            if IDENTIFIER_TABLES.iter().any(|other| other.name == table.name) {
+                identifiers.push(frame.frame());
+            } else {
+                context.register(table.name, redact(table, frame.frame()));
+            }
+        }
+        context.register(
+            IDENTIFIERS.name,
+            concat(identifiers, UnionArgs::default())?,
+        );
+        let frame = context.execute(&sql)?;
+        // One row past the limit is collected to tell whether there are more
+        let wanted =
+            IdxSize::try_from(limit.saturating_add(1)).unwrap_or(IdxSize::MAX);
+        let mut rows = collect_within(
+            frame.limit(wanted),
+            Duration::from_secs(PROGRAM_CONFIG.analytics_timeout),
+        )?;
+        drop(frames);
+        let truncated = rows.height() > limit;
+        if truncated {
+            rows = rows.head(Some(limit));
+        }
+        Ok(QueryResult {
+            rows,
+            truncated,
+        })
+    })
+    .await
+    .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?
+}
+
+/// Encode the rows of a query result in `format`
+///
+/// # Errors
+///
+/// This function will return an error if the rows can't be represented in
+/// `format`.
+pub(crate) fn encode(
+    rows: &mut DataFrame,
+    format: Format,
+) -> PolarsResult<Vec<u8>> {
+    let mut buffer = Vec::new();
+    match format {
+        Format::Json => JsonWriter::new(&mut buffer)
+            .with_json_format(JsonFormat::Json)
+            .finish(rows)?,
+        Format::Csv => CsvWriter::new(&mut buffer).finish(rows)?,
+        Format::Ipc => IpcWriter::new(&mut buffer).finish(rows)?,
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time::Duration};
+
+    use cubby_lib::FileManager;
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::{collect_within, query};
+    use crate::{
+        managers::{
+            dataframes::ParquetManager, storage::memory_file_manager,
+            wal::Mutation,
+        },
+        schema::{ParquetRow, UserRow, USERS},
+    };
+
+    /// Add `count` users, each with a password hash
+    async fn add_users(file_manager: &FileManager, count: u64) {
+        let users = (0..count)
+            .map(|user| UserRow {
+                user_id: user,
+                username: format!("user{user}"),
+                password_hash: Some(format!("hash{user}")),
+                is_guest: false,
+                is_deactivated: false,
+                created_ts: 0,
+            })
+            .collect::<Vec<_>>();
+        file_manager
+            .get_managed_lazyframe(&USERS)
+            .await
+            .expect("Failed to lock users")
+            .apply(&Mutation::Upsert(
+                UserRow::to_dataframe(&users).expect("Invalid rows"),
+            ))
+            .expect("Failed to add users");
+    }
+
+    #[tokio::test]
+    async fn table_functions_are_refused_under_any_name() {
+        let file_manager = memory_file_manager();
+        add_users(&file_manager, 1).await;
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let key = dir.path().join("cubby.key");
+        fs::write(&key, "secret\nhunter2\n").expect("Failed to write key");
+        let key = key.display();
+        for sql in [
+            format!("SELECT * FROM read_csv('{key}') AS users"),
+            format!("SELECT * FROM read_csv('{key}')"),
+            format!(
+                "SELECT * FROM users WHERE username IN (SELECT secret FROM \
+                 read_csv('{key}'))"
+            ),
+            format!(
+                "SELECT * FROM users JOIN read_csv('{key}') AS keys ON true"
+            ),
+        ] {
+            assert!(query(&file_manager, &sql, 10).await.is_err(), "{sql}");
+        }
+    }
+
+    #[tokio::test]
+    async fn only_queries_are_run() {
+        let file_manager = memory_file_manager();
+        add_users(&file_manager, 1).await;
+        for sql in [
+            "CREATE TABLE copied AS SELECT * FROM users",
+            "DROP TABLE users",
+            "SELECT * FROM users; DROP TABLE users",
+            "TRUNCATE users",
+        ] {
+            assert!(query(&file_manager, sql, 10).await.is_err(), "{sql}");
+        }
+        let result = query(&file_manager, "SELECT * FROM users", 10)
+            .await
+            .expect("Failed to query users");
+        assert_eq!(result.rows.height(), 1);
+    }
+
+    #[tokio::test]
+    async fn redacted_columns_are_null() {
+        let file_manager = memory_file_manager();
+        add_users(&file_manager, 2).await;
+        let result = query(
+            &file_manager,
+            "SELECT username, password_hash FROM users",
+            10,
+        )
+        .await
+        .expect("Failed to query users");
+        assert_eq!(result.rows.height(), 2);
+        assert_eq!(
+            result
+                .rows
+                .column(UserRow::PASSWORD_HASH)
+                .expect("No password hashes")
+                .null_count(),
+            2
+        );
+        // Redacted columns can't be filtered on either
+        let result = query(
+            &file_manager,
+            "SELECT username FROM users WHERE password_hash = 'hash0'",
+            10,
+        )
+        .await
+        .expect("Failed to query users");
+        assert_eq!(result.rows.height(), 0);
+    }
+
+    #[tokio::test]
+    async fn results_are_cut_off_at_the_limit() {
+        let file_manager = memory_file_manager();
+        add_users(&file_manager, 3).await;
+        let result = query(&file_manager, "SELECT * FROM users", 2)
+            .await
+            .expect("Failed to query users");
+        assert!(result.truncated);
+        assert_eq!(result.rows.height(), 2);
+        let result = query(&file_manager, "SELECT * FROM users", 3)
+            .await
+            .expect("Failed to query users");
+        assert!(!result.truncated);
+        assert_eq!(result.rows.height(), 3);
+    }
+
+    #[test]
+    fn slow_queries_are_cancelled() {
+        let slow = df!("a" => [1]).expect("Invalid frame").lazy().map(
+            |frame| {
+                thread::sleep(Duration::from_millis(500));
+                Ok(frame)
+            },
+            AllowedOptimizations::default(),
+            None,
+            None,
+        );
+        assert!(collect_within(slow, Duration::from_millis(50)).is_err());
+        let fast = df!("a" => [1]).expect("Invalid frame").lazy();
+        assert!(collect_within(fast, Duration::from_secs(10)).is_ok());
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! This module is where most of the magic happens
 
+pub(crate) mod admin;
 // pub(crate) mod appservice;
 pub(crate) mod client;
 pub(crate) mod federation;
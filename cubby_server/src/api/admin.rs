@@ -0,0 +1,68 @@
+//! Admin endpoints
+//!
+//! These aren't part of the Matrix spec. They are only served when
+//! `PROGRAM_CONFIG.admin_token` is set, and every request must carry it as a
+//! bearer token.
+
+pub(crate) mod sql;
+
+use axum::{
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{auth, config::PROGRAM_CONFIG};
+
+/// A response holding a Matrix-style error
+pub(crate) fn error(
+    status: StatusCode,
+    errcode: &str,
+    message: &str,
+) -> Response {
+    (
+        status,
+        Json(json!({
+            "errcode": errcode,
+            "error": message,
+        })),
+    )
+        .into_response()
+}
+
+/// Check that a request carries the admin token, returning the error response
+/// to send if it doesn't
+pub(crate) fn unauthorized(headers: &HeaderMap) -> Option<Response> {
+    let Some(expected) = &PROGRAM_CONFIG.admin_token else {
+        return Some(error(
+            StatusCode::NOT_FOUND,
+            "M_UNRECOGNIZED",
+            "Admin endpoints are turned off on this homeserver",
+        ));
+    };
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given)
+            if auth::constant_time_eq(
+                given.as_bytes(),
+                expected.as_bytes(),
+            ) =>
+        {
+            None
+        }
+        Some(_) => Some(error(
+            StatusCode::FORBIDDEN,
+            "M_FORBIDDEN",
+            "The admin token is not valid",
+        )),
+        None => Some(error(
+            StatusCode::UNAUTHORIZED,
+            "M_MISSING_TOKEN",
+            "The admin token is missing",
+        )),
+    }
+}
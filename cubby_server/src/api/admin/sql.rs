@@ -0,0 +1,87 @@
+//! Code related to the analytics query endpoint.
+//!
+//! Runs a read-only SQL query against the homeserver's tables (see
+//! `analytics`) and returns the rows in the requested format. The
+//! `X-Cubby-Truncated` header is set to `true` when the rows were cut off at
+//! the row limit.
+
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use cubby_lib::FileManager;
+use serde::Deserialize;
+use tracing::{info, instrument};
+
+use super::{error, unauthorized};
+use crate::{
+    analytics::{self, Format},
+    config::PROGRAM_CONFIG,
+};
+
+/// Set to `true` or `false` depending on whether the rows were cut off
+const TRUNCATED: HeaderName = HeaderName::from_static("x-cubby-truncated");
+
+/// The body of a query request
+#[derive(Debug, Deserialize)]
+pub(crate) struct Request {
+    /// The SQL query to run
+    query: String,
+    /// The format to return the rows in. Defaults to JSON.
+    #[serde(default)]
+    format: Format,
+    /// The most rows to return. This can only lower the configured limit.
+    limit: Option<usize>,
+}
+
+/// Run an analytics query
+#[instrument(level = "trace", skip(headers))]
+pub(crate) async fn endpoint(
+    State(file_manager): State<FileManager>,
+    headers: HeaderMap,
+    Json(req): Json<Request>,
+) -> Response {
+    if let Some(response) = unauthorized(&headers) {
+        return response;
+    }
+    let limit = req.limit.map_or(PROGRAM_CONFIG.analytics_row_limit, |limit| {
+        limit.min(PROGRAM_CONFIG.analytics_row_limit)
+    });
+    info!("Running analytics query: {}", req.query);
+    let mut result =
+        match analytics::query(&file_manager, &req.query, limit).await {
+            Ok(result) => result,
+            // Queries are written by admins, who need to know why theirs failed
+            Err(e) => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "M_INVALID_PARAM",
+                    &e.to_string(),
+                )
+            }
+        };
+    match analytics::encode(&mut result.rows, req.format) {
+        Ok(body) => (
+            [
+                (CONTENT_TYPE, req.format.content_type()),
+                (
+                    TRUNCATED,
+                    if result.truncated {
+                        "true"
+                    } else {
+                        "false"
+                    },
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "M_INTERNAL_SERVER_ERROR",
+            &format!("The results could not be encoded: {e}"),
+        ),
+    }
+}
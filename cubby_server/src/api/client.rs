@@ -1,3 +1,4 @@
 //! Server-Client Endpoints
 
 pub(crate) mod accounts;
+pub(crate) mod session;
@@ -5,13 +5,16 @@
 use axum::extract::State;
 use cubby_lib::{CubbyResponder, FileManager, RumaExtractor};
 use cubby_macros::IntoMatrixError;
-use polars::lazy::dsl::{col, lit};
 use ruma::api::client::account::get_username_availability::v3::{
     Request, Response,
 };
 use tracing::{error, instrument};
 
-use crate::managers::dataframes::ParquetManager;
+use crate::{
+    managers::dataframes::ParquetManager,
+    schema::{UserRow, USERS},
+    username,
+};
 
 /// All possible errors that can be returned from the endpoint
 #[derive(IntoMatrixError)]
@@ -29,14 +32,14 @@ pub(crate) enum EndpointErrors {
         "M_INVALID_USERNAME",
         "The requested username is not allowed by the homeserver"
     )]
-    _InvalidUsername,
+    InvalidUsername,
     /// The request username is in the namespace of an appservice
     #[matrix_error(
         BAD_REQUEST,
         "M_EXCLUSIVE",
         "The requested username is in the exclusive namespace of an appservice"
     )]
-    _Exclusive,
+    Exclusive,
     /// There was an error running the polars query
     #[matrix_error(
         INTERNAL_SERVER_ERROR,
@@ -46,36 +49,102 @@ pub(crate) enum EndpointErrors {
     PolarsError,
 }
 
+/// Check whether a username is free to register
+///
+/// [Spec](https://spec.matrix.org/latest/client-server-api/#get_matrixclientv3registeravailable)
 #[instrument(level = "trace")]
 pub(crate) async fn endpoint(
     State(file_manager): State<FileManager>,
     RumaExtractor(req): RumaExtractor<Request>,
 ) -> CubbyResponder<Response, EndpointErrors> {
-    // Load the list of users into memory. If this fails, something is very
+    // Registration refuses the same usernames
+    if !username::is_allowed(&req.username) {
+        return CubbyResponder::MatrixError(EndpointErrors::InvalidUsername);
+    }
+    if username::is_exclusive(&req.username) {
+        return CubbyResponder::MatrixError(EndpointErrors::Exclusive);
+    }
+    // Only the blocks of the users table that can hold the username are read.
+    // This fails if the table can't be locked in time or if something is very
     // wrong with the server.
-    let Ok(frame) = file_manager.get_lazyframe("users.parquet").await else {
-        error!(
-            "users.parquet could not be loaded! Something is very wrong with \
-             the server!"
-        );
-        return CubbyResponder::MatrixError(EndpointErrors::PolarsError);
-    };
-    let Ok(query) = frame
-        .select(&[col("username")])
-        .filter(col("username").eq(lit(req.username.clone())))
-        .collect()
-    else {
-        tracing::warn!("Error processing request for username availability");
-        return CubbyResponder::MatrixError(EndpointErrors::PolarsError);
+    let users = match file_manager
+        .get_by_key(&USERS, UserRow::USERNAME, req.username.clone())
+        .await
+    {
+        Ok(users) => users,
+        Err(e) => {
+            error!(
+                "users.parquet could not be searched: {e}. Run `cubby check` \
+                 to look for damage in the data directory."
+            );
+            return CubbyResponder::MatrixError(EndpointErrors::PolarsError);
+        }
     };
 
-    if query
-        .column("username")
-        .expect("We already checked that this exists")
-        .is_empty()
-    {
+    if users.height() == 0 {
         CubbyResponder::Ruma(Response::new(true))
     } else {
         CubbyResponder::MatrixError(EndpointErrors::InUse)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::State;
+    use cubby_lib::{CubbyResponder, RumaExtractor};
+    use ruma::api::client::account::get_username_availability::v3::Request;
+
+    use super::{endpoint, EndpointErrors};
+    use crate::{
+        managers::{
+            dataframes::ParquetManager, storage::memory_file_manager,
+            wal::Mutation,
+        },
+        schema::{ParquetRow, UserRow, USERS},
+    };
+
+    #[tokio::test]
+    async fn registered_usernames_are_in_use() {
+        let file_manager = memory_file_manager();
+        let request = || RumaExtractor(Request::new("alice".to_owned()));
+
+        let response = endpoint(State(file_manager.clone()), request()).await;
+        assert!(matches!(
+            response,
+            CubbyResponder::Ruma(response) if response.available
+        ));
+
+        let alice = UserRow {
+            user_id: 1,
+            username: "alice".to_owned(),
+            password_hash: None,
+            is_guest: false,
+            is_deactivated: false,
+            created_ts: 0,
+        };
+        file_manager
+            .get_managed_lazyframe(&USERS)
+            .await
+            .expect("Failed to lock users")
+            .apply(&Mutation::Upsert(
+                UserRow::to_dataframe(&[alice]).expect("Invalid row"),
+            ))
+            .expect("Failed to add alice");
+
+        let response = endpoint(State(file_manager.clone()), request()).await;
+        assert!(matches!(
+            response,
+            CubbyResponder::MatrixError(EndpointErrors::InUse)
+        ));
+
+        let response = endpoint(
+            State(file_manager),
+            RumaExtractor(Request::new("Alice".to_owned())),
+        )
+        .await;
+        assert!(matches!(
+            response,
+            CubbyResponder::MatrixError(EndpointErrors::InvalidUsername)
+        ));
+    }
+}
@@ -1,21 +1,39 @@
-//! Code related to the username availability checking endpoint.
+//! Code related to the account registration endpoint.
+//!
+//! Users and guests can only register while
+//! `PROGRAM_CONFIG.allow_registration` is set, and users have to complete one
+//! of `PROGRAM_CONFIG.registration_flows` first. An application service
+//! sending its `as_token` can always register users in its own namespace, and
+//! doesn't authenticate any further (see `appservices`).
 //!
 //! [Spec](https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3register)
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use axum::extract::State;
-use cubby_lib::{CubbyResponder, FileManager, RumaExtractor};
+use cubby_lib::{AuthRumaExtractor, CubbyResponder, FileManager};
 use cubby_macros::IntoMatrixError;
-use rand::{distributions::Alphanumeric, Rng};
+use polars::prelude::*;
 use ruma::{
     api::client::account::register::{
         v3::{Request, Response},
         RegistrationKind,
     },
-    OwnedDeviceId,
+    OwnedDeviceId, OwnedUserId, UserId,
 };
-use tracing::{error, instrument};
+use tracing::{error, info, instrument};
 
-use crate::{config::PROGRAM_CONFIG, managers::dataframes::ParquetManager};
+use crate::{
+    appservices::{self, Registered},
+    auth,
+    config::PROGRAM_CONFIG,
+    managers::{interner, transaction::Transactions, wal::Mutation},
+    schema::{
+        AccessTokenRow, DeviceRow, ParquetRow, UserRow, ACCESS_TOKENS, DEVICES,
+        USERS,
+    },
+    uiaa, username,
+};
 
 /// All the possible errors that can be returned by the endpoint
 #[derive(IntoMatrixError)]
@@ -26,22 +44,30 @@ pub(crate) enum EndpointErrors {
         "M_USER_IN_USE",
         "The desired user ID is already taken."
     )]
-    _InUse,
+    InUse,
     /// The requested username is invalid
     #[matrix_error(
         BAD_REQUEST,
         "M_INVALID_USERNAME",
         "The desired user ID is not a valid user name."
     )]
-    _InvalidUsername,
-    /// The requested username is in the exclusive namespace of an appservice
+    InvalidUsername,
+    /// The requested username is in the exclusive namespace of an appservice,
+    /// or outside the namespace of the appservice registering it
     #[matrix_error(
         BAD_REQUEST,
         "M_EXCLUSIVE",
         "The desired user ID is in the exclusive namespace claimed by an \
          application service."
     )]
-    _Exclusive,
+    Exclusive,
+    /// The appservice token doesn't belong to any registered appservice
+    #[matrix_error(
+        UNAUTHORIZED,
+        "M_UNKNOWN_TOKEN",
+        "Unrecognised application service token."
+    )]
+    UnknownAppserviceToken,
     /// Registration is currently disabled on the server
     #[matrix_error(
         FORBIDDEN,
@@ -49,6 +75,20 @@ pub(crate) enum EndpointErrors {
         "Registration is disabled on this homeserver."
     )]
     Disabled,
+    /// A user account was requested without a password
+    #[matrix_error(
+        BAD_REQUEST,
+        "M_MISSING_PARAM",
+        "A password is required to register a user account."
+    )]
+    MissingPassword,
+    /// There was an error reading or writing the tables
+    #[matrix_error(
+        INTERNAL_SERVER_ERROR,
+        "M_UNKNOWN",
+        "The account could not be created."
+    )]
+    PolarsError,
     /// The request reached a code branch that was supposed to be unreachable.
     /// For this specific endpoint, at the time of writing the
     /// `RegistrationKind` enum was limited to `User` and `Guest`. This is
@@ -66,33 +106,194 @@ pub(crate) enum EndpointErrors {
     Unreachable,
 }
 
+/// A device to log a new account in on
+struct NewDevice {
+    /// The ID of the device
+    device_id: OwnedDeviceId,
+    /// The name of the device shown to the user
+    display_name: Option<String>,
+    /// The access token issued to the device
+    access_token: String,
+}
+
+/// An account about to be created
+struct NewAccount {
+    /// The full user ID of the account
+    user_id: OwnedUserId,
+    /// The hash of the account's password. Guests don't have one.
+    password_hash: Option<String>,
+    /// Whether the account is a guest account
+    is_guest: bool,
+    /// The device the account is logged in on, unless the client asked for
+    /// login to be inhibited
+    device: Option<NewDevice>,
+}
+
+/// Create `account`, along with its device and access token, in a single
+/// transaction.
+///
+/// Returns `false` without changing anything if the user ID is already taken.
+///
+/// # Errors
+///
+/// This function will return an error if the tables could not be read or the
+/// transaction could not be committed. Nothing is created in that case.
+async fn create_account(
+    file_manager: &FileManager,
+    account: &NewAccount,
+) -> PolarsResult<bool> {
+    let created_ts =
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| {
+            u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+        });
+    let mut tables = vec![interner::table_of(account.user_id.as_str()), &USERS];
+    if account.device.is_some() {
+        tables.extend([&DEVICES, &ACCESS_TOKENS]);
+    }
+    let mut transaction = file_manager.transaction(&tables).await?;
+    // The tables stay locked until the transaction is committed, so nobody
+    // else can take the user ID in the meantime. A user ID that was never
+    // interned can't belong to anyone.
+    if let Some(user_id) =
+        interner::lookup_in(&transaction, account.user_id.as_str())?
+    {
+        let existing = transaction.query(&USERS, |frame| {
+            frame.filter(col(UserRow::USER_ID).eq(lit(user_id)))
+        })?;
+        if existing.height() > 0 {
+            return Ok(false);
+        }
+    }
+    let user_id =
+        interner::intern_in(&mut transaction, account.user_id.as_str())?;
+    transaction.stage(
+        &USERS,
+        Mutation::Upsert(UserRow::to_dataframe(&[UserRow {
+            user_id,
+            username: account.user_id.localpart().to_owned(),
+            password_hash: account.password_hash.clone(),
+            is_guest: account.is_guest,
+            is_deactivated: false,
+            created_ts,
+        }])?),
+    )?;
+    if let Some(device) = &account.device {
+        transaction.stage(
+            &DEVICES,
+            Mutation::Upsert(DeviceRow::to_dataframe(&[DeviceRow {
+                user_id,
+                device_id: device.device_id.to_string(),
+                display_name: device.display_name.clone(),
+                created_ts,
+            }])?),
+        )?;
+        transaction.stage(
+            &ACCESS_TOKENS,
+            Mutation::Upsert(AccessTokenRow::to_dataframe(&[
+                AccessTokenRow {
+                    token: device.access_token.clone(),
+                    user_id,
+                    device_id: device.device_id.to_string(),
+                    created_ts,
+                },
+            ])?),
+        )?;
+    }
+    transaction.commit()?;
+    Ok(true)
+}
+
+/// Authenticate `req`, a request to register a user account.
+///
+/// The session is for the username that was asked for rather than one
+/// generated for this attempt, and never for the password.
+async fn authenticate(
+    file_manager: &FileManager,
+    req: &Request,
+) -> Result<uiaa::Authenticated, uiaa::Rejection> {
+    let inhibit_login = req.inhibit_login.to_string();
+    let request = [
+        req.username.as_deref().unwrap_or_default(),
+        req.device_id.as_ref().map_or("", |id| id.as_str()),
+        inhibit_login.as_str(),
+    ];
+    uiaa::authenticate(
+        file_manager,
+        uiaa::Endpoint::Register,
+        &request,
+        req.auth.as_ref(),
+        None,
+    )
+    .await
+}
+
+/// Whether `localpart` can be registered on this server, by `appservice` if
+/// it is given
+fn check_username(
+    localpart: &str,
+    appservice: Option<&Registered>,
+) -> Result<OwnedUserId, EndpointErrors> {
+    let Ok(user_id) =
+        UserId::parse(format!("@{localpart}:{}", PROGRAM_CONFIG.server_name))
+    else {
+        return Err(EndpointErrors::InvalidUsername);
+    };
+    // The namespace is the appservice's own, so only the grammar applies
+    let (allowed, exclusive) = if let Some(appservice) = appservice {
+        (username::is_valid(localpart), !appservice.manages(&user_id))
+    } else {
+        (username::is_allowed(localpart), username::is_exclusive(localpart))
+    };
+    if !allowed {
+        return Err(EndpointErrors::InvalidUsername);
+    }
+    if exclusive {
+        return Err(EndpointErrors::Exclusive);
+    }
+    Ok(user_id)
+}
+
 /// Register a new account with the homeserver
 ///
 /// [Spec](https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3register)
-#[instrument(level = "trace")]
+// The request holds the password, so it is left out of the span
+#[instrument(level = "trace", skip_all)]
 pub(crate) async fn endpoint(
     State(file_manager): State<FileManager>,
-    RumaExtractor(req): RumaExtractor<Request>,
+    AuthRumaExtractor {
+        request: req,
+        appservice_token,
+        ..
+    }: AuthRumaExtractor<Request>,
+) -> CubbyResponder<Response, EndpointErrors> {
+    let Some(token) = appservice_token else {
+        return register(&file_manager, req, None).await;
+    };
+    let Some(appservice) = appservices::authenticate(&token) else {
+        return CubbyResponder::MatrixError(
+            EndpointErrors::UnknownAppserviceToken,
+        );
+    };
+    register(&file_manager, req, Some(appservice)).await
+}
+
+/// Register the account asked for in `req`, on behalf of `appservice` if it
+/// sent the request
+async fn register(
+    file_manager: &FileManager,
+    req: Request,
+    appservice: Option<&Registered>,
 ) -> CubbyResponder<Response, EndpointErrors> {
-    if !PROGRAM_CONFIG.allow_registration {
+    if appservice.is_none() && !PROGRAM_CONFIG.allow_registration {
         return CubbyResponder::MatrixError(EndpointErrors::Disabled);
     }
 
-    // Get DataFrame access
-    let _frame = file_manager.get_managed_lazyframe("users.parquet").await;
     // Create a device id if the request did not provide one
-    let _device_id = match (&req.kind, &req.device_id) {
+    let device_id = match (&req.kind, &req.device_id) {
         // Generate a new ID regardless of if a guest provided one or if a user
         // did not provide one
         (RegistrationKind::Guest, _) | (RegistrationKind::User, None) => {
-            let mut rng = rand::thread_rng();
-            // SAFETY: THis as conversion is ok because rand will only return
-            // things that can be cast as char
-            #[allow(clippy::as_conversions)]
-            let chars: String = (0..PROGRAM_CONFIG.device_id_length)
-                .map(|_| rng.sample(Alphanumeric) as char)
-                .collect();
-            OwnedDeviceId::from(chars)
+            auth::generate_device_id()
         }
         (RegistrationKind::User, Some(id)) => id.clone(),
         (..) => {
@@ -103,14 +304,261 @@ pub(crate) async fn endpoint(
             return CubbyResponder::MatrixError(EndpointErrors::Unreachable);
         }
     };
-    // Process the registration request
-    match req.kind {
-        RegistrationKind::Guest => {
-            todo!();
+    let is_guest = matches!(req.kind, RegistrationKind::Guest);
+    // Guests never choose their localpart, and users who don't ask for one
+    // are given one
+    let (localpart, password) = match req.kind {
+        RegistrationKind::Guest => (auth::generate_localpart(), None),
+        RegistrationKind::User => (
+            req.username.clone().unwrap_or_else(auth::generate_localpart),
+            req.password.clone(),
+        ),
+        _ => return CubbyResponder::MatrixError(EndpointErrors::Unreachable),
+    };
+    let user_id = match check_username(&localpart, appservice) {
+        Ok(user_id) => user_id,
+        Err(e) => return CubbyResponder::MatrixError(e),
+    };
+    // Guests can't prove anything about themselves, so only users have to
+    // authenticate. Appservices already have with their token.
+    let authenticated = if is_guest || appservice.is_some() {
+        None
+    } else {
+        match authenticate(file_manager, &req).await {
+            Ok(authenticated) => Some(authenticated),
+            Err(rejection) => return rejection.respond(),
+        }
+    };
+    // Clients start by sending an empty request to find out the flows, so
+    // the password is only required once they have authenticated
+    let password_hash = match password {
+        Some(password) => match auth::hash_password(password).await {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                error!("Failed to hash the password of {user_id}: {e}");
+                return CubbyResponder::MatrixError(
+                    EndpointErrors::PolarsError,
+                );
+            }
+        },
+        // Appservice users log in through their appservice instead
+        None if is_guest || appservice.is_some() => None,
+        None => {
+            return CubbyResponder::MatrixError(EndpointErrors::MissingPassword)
+        }
+    };
+    let account = NewAccount {
+        user_id,
+        password_hash,
+        is_guest,
+        device: (!req.inhibit_login).then(|| NewDevice {
+            device_id,
+            display_name: req.initial_device_display_name,
+            access_token: auth::generate_access_token(),
+        }),
+    };
+
+    match create_account(file_manager, &account).await {
+        Ok(true) => {
+            if let Some(authenticated) = authenticated {
+                authenticated.finish(file_manager).await;
+            }
+        }
+        Ok(false) => {
+            return CubbyResponder::MatrixError(EndpointErrors::InUse);
         }
-        RegistrationKind::User => {
-            todo!();
+        Err(e) => {
+            error!(
+                "Failed to create the account {}: {e}. Run `cubby check` to \
+                 look for damage in the data directory.",
+                account.user_id
+            );
+            return CubbyResponder::MatrixError(EndpointErrors::PolarsError);
+        }
+    }
+    if let Some(appservice) = appservice {
+        info!(
+            "Appservice {} registered {}",
+            appservice.config.id, account.user_id
+        );
+    } else {
+        info!("Registered {}", account.user_id);
+    }
+    let mut response = Response::new(account.user_id);
+    if let Some(device) = account.device {
+        response.device_id = Some(device.device_id);
+        response.access_token = Some(device.access_token);
+    }
+    CubbyResponder::Ruma(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::State;
+    use cubby_lib::{AuthRumaExtractor, CubbyResponder, RumaExtractor};
+    use ruma::{
+        api::client::account::{
+            get_username_availability, register::v3::Request,
+        },
+        UserId,
+    };
+
+    use super::{
+        check_username, create_account, endpoint, register, EndpointErrors,
+        NewAccount,
+    };
+    use crate::{
+        api::client::accounts::get_username_availability as available,
+        appservices::Registered,
+        config::{Appservice, PROGRAM_CONFIG},
+        managers::{
+            dataframes::ParquetManager,
+            interner::{self, Interner},
+            storage::memory_file_manager,
+        },
+        schema::USERS,
+    };
+
+    /// A request to register `username`, without a password or any
+    /// authentication
+    fn request(username: &str) -> Request {
+        let mut request = Request::new();
+        request.username = Some(username.to_owned());
+        request
+    }
+
+    #[tokio::test]
+    async fn appservices_register_users_in_their_namespace() {
+        let file_manager = memory_file_manager();
+        let config = Box::leak(Box::new(Appservice {
+            id: "irc".to_owned(),
+            as_token: "token".to_owned(),
+            users: vec![format!(
+                "^@irc_.*:{}$",
+                regex::escape(&PROGRAM_CONFIG.server_name)
+            )],
+        }));
+        let bridge = Registered::new(config).expect("Invalid namespace");
+
+        let response =
+            register(&file_manager, request("irc_alice"), Some(&bridge)).await;
+        assert!(matches!(
+            response,
+            CubbyResponder::Ruma(response)
+                if response.user_id.localpart() == "irc_alice"
+        ));
+        let response =
+            register(&file_manager, request("alice"), Some(&bridge)).await;
+        assert!(matches!(
+            response,
+            CubbyResponder::MatrixError(EndpointErrors::Exclusive)
+        ));
+        // Without the appservice, registration is closed
+        let response = register(&file_manager, request("irc_bob"), None).await;
+        assert!(matches!(
+            response,
+            CubbyResponder::MatrixError(EndpointErrors::Disabled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_appservice_tokens_are_refused() {
+        let response = endpoint(
+            State(memory_file_manager()),
+            AuthRumaExtractor {
+                sender: None,
+                appservice_token: Some("token".to_owned()),
+                request: request("irc_alice"),
+            },
+        )
+        .await;
+        assert!(matches!(
+            response,
+            CubbyResponder::MatrixError(EndpointErrors::UnknownAppserviceToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn user_ids_are_interned_with_their_account() {
+        let file_manager = memory_file_manager();
+        let account = || NewAccount {
+            user_id: UserId::parse("@alice:example.org")
+                .expect("Invalid user ID"),
+            password_hash: None,
+            is_guest: true,
+            device: None,
+        };
+
+        assert!(create_account(&file_manager, &account())
+            .await
+            .expect("Failed to create alice"));
+        assert!(!create_account(&file_manager, &account())
+            .await
+            .expect("Failed to check alice"));
+
+        let identifiers = file_manager
+            .get_lazyframe(interner::table_of("@alice:example.org"))
+            .await
+            .expect("Failed to read identifiers")
+            .query(|frame| frame)
+            .expect("Failed to query identifiers");
+        assert_eq!(identifiers.height(), 1);
+        let short_id = file_manager
+            .lookup("@alice:example.org")
+            .await
+            .expect("Failed to look alice up")
+            .expect("Alice wasn't interned");
+        let users = file_manager
+            .get_lazyframe(&USERS)
+            .await
+            .expect("Failed to read users")
+            .query(|frame| frame)
+            .expect("Failed to query users");
+        assert_eq!(
+            users
+                .column("user_id")
+                .expect("Missing column")
+                .u64()
+                .ok()
+                .and_then(|ids| ids.get(0)),
+            Some(short_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn usernames_reported_available_can_be_registered() {
+        let file_manager = memory_file_manager();
+        let longest = 255 - "@:".len() - PROGRAM_CONFIG.server_name.len();
+        let too_long = "a".repeat(longest + 1);
+        let longest = "a".repeat(longest);
+        for localpart in [
+            "alice", "Alice", "admin", "al ice", "al:ice", "", &longest,
+            &too_long,
+        ] {
+            let availability = available::endpoint(
+                State(file_manager.clone()),
+                RumaExtractor(get_username_availability::v3::Request::new(
+                    localpart.to_owned(),
+                )),
+            )
+            .await;
+            let agree = match (availability, check_username(localpart, None)) {
+                (CubbyResponder::Ruma(response), Ok(_)) => response.available,
+                (
+                    CubbyResponder::MatrixError(
+                        available::EndpointErrors::InvalidUsername,
+                    ),
+                    Err(EndpointErrors::InvalidUsername),
+                )
+                | (
+                    CubbyResponder::MatrixError(
+                        available::EndpointErrors::Exclusive,
+                    ),
+                    Err(EndpointErrors::Exclusive),
+                ) => true,
+                _ => false,
+            };
+            assert!(agree, "{localpart:?}");
         }
-        _ => todo!(),
     }
 }
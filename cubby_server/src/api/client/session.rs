@@ -0,0 +1,5 @@
+//! Login and logout related endpoints
+
+pub(crate) mod get_login_token;
+pub(crate) mod get_login_types;
+pub(crate) mod login;
@@ -0,0 +1,298 @@
+//! Code related to the endpoint issuing tokens for `m.login.token`.
+//!
+//! A user who is logged in on one device can ask for a single-use token to
+//! log in on another, such as by scanning a QR code. Tokens are kept in the
+//! `login_tokens` table until they are used, and stop working after
+//! `PROGRAM_CONFIG.login_token_lifetime` seconds. The user has to complete
+//! one of `PROGRAM_CONFIG.login_token_flows` first, so a stolen access token
+//! isn't enough to log in elsewhere.
+//!
+//! [Spec](https://spec.matrix.org/latest/client-server-api/#post_matrixclientv1loginget_token)
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use cubby_lib::{AuthRumaExtractor, CubbyResponder, FileManager};
+use cubby_macros::IntoMatrixError;
+use polars::prelude::*;
+use ruma::api::client::session::get_login_token::v1::{Request, Response};
+use tracing::{error, info, instrument};
+
+use crate::{
+    auth,
+    config::PROGRAM_CONFIG,
+    managers::{dataframes::ParquetManager, interner::Interner, wal::Mutation},
+    schema::{LoginTokenRow, ParquetRow, UserRow, LOGIN_TOKENS, USERS},
+    uiaa,
+};
+
+/// All the possible errors that can be returned by the endpoint
+#[derive(IntoMatrixError)]
+pub(crate) enum EndpointErrors {
+    /// The request wasn't made with an access token
+    #[matrix_error(UNAUTHORIZED, "M_MISSING_TOKEN", "Missing access token.")]
+    MissingToken,
+    /// Guests can't log in on other devices
+    #[matrix_error(
+        FORBIDDEN,
+        "M_GUEST_ACCESS_FORBIDDEN",
+        "Guests can't be issued login tokens."
+    )]
+    Guest,
+    /// There was an error reading or writing the tables
+    #[matrix_error(
+        INTERNAL_SERVER_ERROR,
+        "M_UNKNOWN",
+        "The login token could not be issued."
+    )]
+    PolarsError,
+}
+
+/// Find the account with the short ID `user`, if it exists
+async fn load_account(
+    file_manager: &FileManager,
+    user: u64,
+) -> PolarsResult<Option<UserRow>> {
+    let rows = file_manager.get_by_key(&USERS, UserRow::USER_ID, user).await?;
+    Ok(UserRow::from_dataframe(&rows)?.into_iter().next())
+}
+
+/// Issue `token` to the user with the short ID `user` at `now`, removing any
+/// tokens that have expired by then while the table is locked
+async fn issue(
+    file_manager: &FileManager,
+    token: &str,
+    user: u64,
+    now: u64,
+) -> PolarsResult<()> {
+    let mut tokens = file_manager.get_managed_lazyframe(&LOGIN_TOKENS).await?;
+    let expired = tokens.query(|frame| {
+        frame
+            .filter(col(LoginTokenRow::EXPIRES_TS).lt(lit(now)))
+            .select([col(LoginTokenRow::TOKEN)])
+    })?;
+    for expired in
+        expired.column(LoginTokenRow::TOKEN)?.str()?.into_iter().flatten()
+    {
+        tokens.apply(&Mutation::Delete {
+            column: LoginTokenRow::TOKEN.to_owned(),
+            value: expired.to_owned(),
+        })?;
+    }
+    let lifetime = PROGRAM_CONFIG.login_token_lifetime.saturating_mul(1000);
+    tokens.apply(&Mutation::Upsert(LoginTokenRow::to_dataframe(&[
+        LoginTokenRow {
+            token: token.to_owned(),
+            user_id: user,
+            expires_ts: now.saturating_add(lifetime),
+        },
+    ])?))
+}
+
+/// Issue a token the user can log in on another device with
+///
+/// [Spec](https://spec.matrix.org/latest/client-server-api/#post_matrixclientv1loginget_token)
+// The request can hold the password, so it is left out of the span
+#[instrument(level = "trace", skip_all)]
+pub(crate) async fn endpoint(
+    State(file_manager): State<FileManager>,
+    AuthRumaExtractor {
+        sender,
+        request: req,
+        ..
+    }: AuthRumaExtractor<Request>,
+) -> CubbyResponder<Response, EndpointErrors> {
+    let Some(sender) = sender else {
+        return CubbyResponder::MatrixError(EndpointErrors::MissingToken);
+    };
+    let account = match file_manager.lookup(sender.user_id.as_str()).await {
+        Ok(Some(user)) => load_account(&file_manager, user).await,
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    let account = match account {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            error!("{} has an access token but no account", sender.user_id);
+            return CubbyResponder::MatrixError(EndpointErrors::PolarsError);
+        }
+        Err(e) => {
+            error!("Failed to find the account of {}: {e}", sender.user_id);
+            return CubbyResponder::MatrixError(EndpointErrors::PolarsError);
+        }
+    };
+    if account.is_guest {
+        return CubbyResponder::MatrixError(EndpointErrors::Guest);
+    }
+    // The request holds nothing but the `auth` dict, so the session only has
+    // to stay with the user who started it
+    let authenticated = match uiaa::authenticate(
+        &file_manager,
+        uiaa::Endpoint::GetLoginToken,
+        &[],
+        req.auth.as_ref(),
+        Some(&sender.user_id),
+    )
+    .await
+    {
+        Ok(authenticated) => authenticated,
+        Err(rejection) => return rejection.respond(),
+    };
+    let now =
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| {
+            u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+        });
+    let token = auth::generate_login_token();
+    if let Err(e) = issue(&file_manager, &token, account.user_id, now).await {
+        error!(
+            "Failed to issue a login token to {}: {e}. Run `cubby check` to \
+             look for damage in the data directory.",
+            sender.user_id
+        );
+        return CubbyResponder::MatrixError(EndpointErrors::PolarsError);
+    }
+    authenticated.finish(&file_manager).await;
+    info!("Issued a login token to {}", sender.user_id);
+    CubbyResponder::Ruma(Response::new(
+        Duration::from_secs(PROGRAM_CONFIG.login_token_lifetime),
+        token,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::State;
+    use cubby_lib::{AuthRumaExtractor, CubbyResponder, FileManager, Sender};
+    use polars::prelude::*;
+    use ruma::{
+        api::client::{
+            session::{get_login_token, login},
+            uiaa::{AuthData, Password, UserIdentifier},
+        },
+        OwnedDeviceId, UserId,
+    };
+
+    use super::endpoint;
+    use crate::{
+        api::client::session::login::{
+            endpoint as login_endpoint, EndpointErrors,
+        },
+        auth,
+        config::PROGRAM_CONFIG,
+        managers::{
+            dataframes::ParquetManager, interner::Interner,
+            storage::memory_file_manager, wal::Mutation,
+        },
+        schema::{ParquetRow, UserRow, USERS},
+    };
+
+    /// Create an account for alice, whose password is `hunter2`
+    async fn add_alice(file_manager: &FileManager) {
+        let interned = file_manager
+            .intern_rows(
+                &USERS,
+                df!(UserRow::USER_ID => [format!(
+                    "@alice:{}",
+                    PROGRAM_CONFIG.server_name
+                )])
+                .expect("Invalid frame"),
+            )
+            .await
+            .expect("Failed to intern alice");
+        let alice = UserRow {
+            user_id: interned
+                .column(UserRow::USER_ID)
+                .and_then(|column| column.u64().cloned())
+                .expect("Alice was not interned")
+                .get(0)
+                .expect("Alice was not interned"),
+            username: "alice".to_owned(),
+            password_hash: Some(
+                auth::hash_password("hunter2".to_owned())
+                    .await
+                    .expect("Failed to hash password"),
+            ),
+            is_guest: false,
+            is_deactivated: false,
+            created_ts: 0,
+        };
+        file_manager
+            .get_managed_lazyframe(&USERS)
+            .await
+            .expect("Failed to lock users")
+            .apply(&Mutation::Upsert(
+                UserRow::to_dataframe(&[alice]).expect("Invalid row"),
+            ))
+            .expect("Failed to add alice");
+    }
+
+    /// Log in with the login token `token`
+    async fn log_in(
+        file_manager: &FileManager,
+        token: &str,
+    ) -> CubbyResponder<login::v3::Response, EndpointErrors> {
+        login_endpoint(
+            State(file_manager.clone()),
+            AuthRumaExtractor {
+                sender: None,
+                appservice_token: None,
+                request: login::v3::Request::new(login::v3::LoginInfo::Token(
+                    login::v3::Token::new(token.to_owned()),
+                )),
+            },
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn issued_tokens_log_in_once() {
+        let file_manager = memory_file_manager();
+        add_alice(&file_manager).await;
+        let alice =
+            UserId::parse(format!("@alice:{}", PROGRAM_CONFIG.server_name))
+                .expect("Invalid user ID");
+        let request = |auth| {
+            let mut request = get_login_token::v1::Request::new();
+            request.auth = auth;
+            AuthRumaExtractor {
+                sender: Some(Sender {
+                    user_id: alice.clone(),
+                    device_id: OwnedDeviceId::from("PHONE"),
+                }),
+                appservice_token: None,
+                request,
+            }
+        };
+
+        // The password is asked for first
+        let response =
+            endpoint(State(file_manager.clone()), request(None)).await;
+        let CubbyResponder::OneOff(_, body) = response else {
+            panic!("The password wasn't asked for");
+        };
+        let mut password = Password::new(
+            UserIdentifier::UserIdOrLocalpart("alice".to_owned()),
+            "hunter2".to_owned(),
+        );
+        password.session = body["session"].as_str().map(str::to_owned);
+        let response = endpoint(
+            State(file_manager.clone()),
+            request(Some(AuthData::Password(password))),
+        )
+        .await;
+        let CubbyResponder::Ruma(response) = response else {
+            panic!("No token was issued");
+        };
+
+        let login = log_in(&file_manager, &response.login_token).await;
+        assert!(matches!(
+            login,
+            CubbyResponder::Ruma(login) if login.user_id == alice
+        ));
+        let login = log_in(&file_manager, &response.login_token).await;
+        assert!(matches!(
+            login,
+            CubbyResponder::MatrixError(EndpointErrors::InvalidToken)
+        ));
+    }
+}
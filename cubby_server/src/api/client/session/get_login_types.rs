@@ -0,0 +1,37 @@
+//! Code related to the endpoint listing the ways to log in.
+//!
+//! [Spec](https://spec.matrix.org/latest/client-server-api/#get_matrixclientv3login)
+
+use cubby_lib::{CubbyResponder, RumaExtractor};
+use ruma::api::client::session::get_login_types::v3::{
+    ApplicationServiceLoginType, LoginType, PasswordLoginType, Request,
+    Response, TokenLoginType,
+};
+use tracing::instrument;
+
+use super::login::EndpointErrors;
+use crate::config::PROGRAM_CONFIG;
+
+/// List the login types `POST /login` accepts
+///
+/// [Spec](https://spec.matrix.org/latest/client-server-api/#get_matrixclientv3login)
+// Nothing can go wrong here, but the responder still needs an error type
+#[instrument(level = "trace", skip_all)]
+pub(crate) async fn endpoint(
+    RumaExtractor(_req): RumaExtractor<Request>,
+) -> CubbyResponder<Response, EndpointErrors> {
+    // Tokens are issued by `POST /login/get_token`
+    let mut token = TokenLoginType::new();
+    token.get_login_token = true;
+    let mut flows = vec![
+        LoginType::Password(PasswordLoginType::new()),
+        LoginType::Token(token),
+    ];
+    // There is no point advertising appservice login without appservices
+    if !PROGRAM_CONFIG.appservices.is_empty() {
+        flows.push(LoginType::ApplicationService(
+            ApplicationServiceLoginType::new(),
+        ));
+    }
+    CubbyResponder::Ruma(Response::new(flows))
+}
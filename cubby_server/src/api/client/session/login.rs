@@ -0,0 +1,652 @@
+//! Code related to the login endpoint.
+//!
+//! Users log in with their password, with a single-use token from the
+//! `login_tokens` table (see `get_login_token`), or through an application
+//! service that manages them (see `appservices`). Logging in creates a
+//! device, unless the client names one of the user's existing devices, in
+//! which case that device's access tokens are replaced.
+//!
+//! Failed password attempts are counted in the `login_failures` table. After
+//! `PROGRAM_CONFIG.login_failure_limit` of them in a row, the account can't be
+//! logged in to with a password for `PROGRAM_CONFIG.login_lockout` seconds,
+//! even if the right one is given. Every attempt is counted as failed before
+//! the password is checked, and the count is cleared once one succeeds. A
+//! locked account is refused the same way as a wrong password or a user who
+//! doesn't exist, so the lockout doesn't give away which users do.
+//!
+//! [Spec](https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3login)
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use cubby_lib::{AuthRumaExtractor, CubbyResponder, FileManager};
+use cubby_macros::IntoMatrixError;
+use polars::prelude::*;
+use ruma::{
+    api::client::{
+        session::login::v3::{
+            ApplicationService, LoginInfo, Password, Request, Response, Token,
+        },
+        uiaa::UserIdentifier,
+    },
+    DeviceId, OwnedUserId, UserId,
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    appservices, auth,
+    config::PROGRAM_CONFIG,
+    managers::{
+        dataframes::ParquetManager, interner::Interner,
+        transaction::Transactions, wal::Mutation,
+    },
+    schema::{
+        AccessTokenRow, DeviceRow, LoginFailureRow, LoginTokenRow, ParquetRow,
+        UserRow, ACCESS_TOKENS, DEVICES, LOGIN_FAILURES, LOGIN_TOKENS, USERS,
+    },
+};
+
+/// All the possible errors that can be returned by the endpoint
+#[derive(IntoMatrixError)]
+pub(crate) enum EndpointErrors {
+    /// The user doesn't exist, the password is wrong or too many attempts to
+    /// log in to the account have failed recently. All of them are reported
+    /// the same way, so clients can't tell them apart.
+    #[matrix_error(FORBIDDEN, "M_FORBIDDEN", "Invalid username or password.")]
+    Forbidden,
+    /// The login token doesn't exist, has already been used or has expired
+    #[matrix_error(
+        FORBIDDEN,
+        "M_FORBIDDEN",
+        "Invalid or expired login token."
+    )]
+    InvalidToken,
+    /// The user was identified by something other than their user ID or
+    /// localpart, such as an email address
+    #[matrix_error(
+        BAD_REQUEST,
+        "M_INVALID_PARAM",
+        "Users can only be identified by their user ID or localpart."
+    )]
+    UnsupportedIdentifier,
+    /// The client asked for a login type the server doesn't support
+    #[matrix_error(BAD_REQUEST, "M_UNKNOWN", "Unsupported login type.")]
+    UnknownLoginType,
+    /// An appservice login was requested without an appservice token
+    #[matrix_error(
+        UNAUTHORIZED,
+        "M_MISSING_TOKEN",
+        "Missing application service token."
+    )]
+    MissingAppserviceToken,
+    /// The appservice token doesn't belong to any registered appservice
+    #[matrix_error(
+        UNAUTHORIZED,
+        "M_UNKNOWN_TOKEN",
+        "Unrecognised application service token."
+    )]
+    UnknownAppserviceToken,
+    /// The user is outside the namespace of the appservice logging them in
+    #[matrix_error(
+        FORBIDDEN,
+        "M_FORBIDDEN",
+        "The user is not in the namespace of the application service."
+    )]
+    NotInNamespace,
+    /// The account has been deactivated
+    #[matrix_error(
+        FORBIDDEN,
+        "M_USER_DEACTIVATED",
+        "This account has been deactivated."
+    )]
+    Deactivated,
+    /// There was an error reading or writing the tables
+    #[matrix_error(INTERNAL_SERVER_ERROR, "M_UNKNOWN", "Could not log in.")]
+    PolarsError,
+}
+
+/// Log a failure to read or write the tables, and turn it into the error sent
+/// to the client
+fn internal_error(e: &PolarsError) -> EndpointErrors {
+    error!("Failed to log a user in: {e}");
+    EndpointErrors::PolarsError
+}
+
+/// The current time, in milliseconds since the unix epoch
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| {
+        u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+    })
+}
+
+/// The local user named by `identifier`, which can hold either their full
+/// user ID or just their localpart
+fn identify(
+    identifier: Option<&UserIdentifier>,
+) -> Result<OwnedUserId, EndpointErrors> {
+    let Some(UserIdentifier::UserIdOrLocalpart(identifier)) = identifier else {
+        return Err(EndpointErrors::UnsupportedIdentifier);
+    };
+    let user_id = if identifier.starts_with('@') {
+        UserId::parse(identifier.as_str())
+    } else {
+        UserId::parse(format!("@{identifier}:{}", PROGRAM_CONFIG.server_name))
+    };
+    match user_id {
+        Ok(user_id)
+            if user_id.server_name().as_str() == PROGRAM_CONFIG.server_name =>
+        {
+            Ok(user_id)
+        }
+        _ => Err(EndpointErrors::Forbidden),
+    }
+}
+
+/// Find the account with the short ID `user`
+async fn load_account(
+    file_manager: &FileManager,
+    user: u64,
+) -> PolarsResult<Option<UserRow>> {
+    let rows = file_manager.get_by_key(&USERS, UserRow::USER_ID, user).await?;
+    Ok(UserRow::from_dataframe(&rows)?.into_iter().next())
+}
+
+/// Find the account of `user_id`, if it exists
+async fn find_account(
+    file_manager: &FileManager,
+    user_id: &UserId,
+) -> PolarsResult<Option<UserRow>> {
+    match file_manager.lookup(user_id.as_str()).await? {
+        Some(user) => load_account(file_manager, user).await,
+        None => Ok(None),
+    }
+}
+
+/// Whether failures recorded as `failures` are too long ago at `now` to be
+/// counted together with a new one
+fn forgotten(failures: &LoginFailureRow, now: u64) -> bool {
+    let lockout = PROGRAM_CONFIG.login_lockout.saturating_mul(1000);
+    failures.last_failure_ts.saturating_add(lockout) < now
+}
+
+/// Count an attempt at `now` to log in to the account with the short ID
+/// `user` as failed, before its password is checked. Returns `false` without
+/// counting it if the account is locked.
+///
+/// Attempts are counted while the table is locked, so any number of them
+/// made at the same time can't check more passwords than the limit allows.
+async fn reserve_attempt(
+    file_manager: &FileManager,
+    user: u64,
+    now: u64,
+) -> PolarsResult<bool> {
+    let mut failures =
+        file_manager.get_managed_lazyframe(&LOGIN_FAILURES).await?;
+    let rows = failures.query(|frame| {
+        frame.filter(col(LoginFailureRow::USER_ID).eq(lit(user)))
+    })?;
+    let limit = PROGRAM_CONFIG.login_failure_limit;
+    let count = match LoginFailureRow::from_dataframe(&rows)?.into_iter().next()
+    {
+        Some(previous) if !forgotten(&previous, now) => {
+            if limit > 0 && previous.failures >= limit {
+                return Ok(false);
+            }
+            previous.failures.saturating_add(1)
+        }
+        _ => 1,
+    };
+    failures.apply(&Mutation::Upsert(LoginFailureRow::to_dataframe(&[
+        LoginFailureRow {
+            user_id: user,
+            failures: count,
+            last_failure_ts: now,
+        },
+    ])?))?;
+    Ok(true)
+}
+
+/// Forget the failed attempts to log in to the account with the short ID
+/// `user`
+async fn clear_failures(
+    file_manager: &FileManager,
+    user: u64,
+) -> PolarsResult<()> {
+    let mut failures =
+        file_manager.get_managed_lazyframe(&LOGIN_FAILURES).await?;
+    failures.apply(&Mutation::Delete {
+        column: LoginFailureRow::USER_ID.to_owned(),
+        value: user.to_string(),
+    })
+}
+
+/// Take the login token `token` out of the table, returning the short ID of
+/// the user it logs in if it hadn't expired by `now`
+async fn redeem_login_token(
+    file_manager: &FileManager,
+    token: &str,
+    now: u64,
+) -> PolarsResult<Option<u64>> {
+    let mut tokens = file_manager.get_managed_lazyframe(&LOGIN_TOKENS).await?;
+    let rows = tokens.query(|frame| {
+        frame.filter(col(LoginTokenRow::TOKEN).eq(lit(token.to_owned())))
+    })?;
+    let Some(row) = LoginTokenRow::from_dataframe(&rows)?.into_iter().next()
+    else {
+        return Ok(None);
+    };
+    // Tokens only work once, whether or not they have expired
+    tokens.apply(&Mutation::Delete {
+        column: LoginTokenRow::TOKEN.to_owned(),
+        value: row.token,
+    })?;
+    Ok((row.expires_ts >= now).then_some(row.user_id))
+}
+
+/// Replace `old_hash`, the hash `password` was just checked against, with a
+/// hash from `auth::hash_password` in the account `account`. Nothing is
+/// changed if the password was changed in the meantime.
+async fn rehash_password(
+    file_manager: &FileManager,
+    account: &UserRow,
+    old_hash: &str,
+    password: &str,
+) -> PolarsResult<()> {
+    let hash = auth::hash_password(password.to_owned()).await?;
+    let mut users = file_manager.get_managed_lazyframe(&USERS).await?;
+    let rows = users.query(|frame| {
+        frame.filter(col(UserRow::USER_ID).eq(lit(account.user_id)))
+    })?;
+    let Some(current) = UserRow::from_dataframe(&rows)?.into_iter().next()
+    else {
+        return Ok(());
+    };
+    if current.password_hash.as_deref() != Some(old_hash) {
+        return Ok(());
+    }
+    users.apply(&Mutation::Upsert(UserRow::to_dataframe(&[UserRow {
+        password_hash: Some(hash),
+        ..current
+    }])?))
+}
+
+/// Refuse `login` the way a wrong password is refused, after as long as it
+/// takes to check one
+async fn refuse(login: &Password) -> EndpointErrors {
+    match auth::verify_unknown_user(login.password.clone()).await {
+        Ok(()) => EndpointErrors::Forbidden,
+        Err(e) => internal_error(&e),
+    }
+}
+
+/// Log in with `m.login.password`, returning the user and their account
+async fn password_login(
+    file_manager: &FileManager,
+    login: &Password,
+    now: u64,
+) -> Result<(OwnedUserId, UserRow), EndpointErrors> {
+    let user_id = identify(login.identifier.as_ref())?;
+    let account = find_account(file_manager, &user_id)
+        .await
+        .map_err(|e| internal_error(&e))?;
+    // Unknown users take as long to refuse as wrong passwords, so how long a
+    // request takes doesn't give away which users exist
+    let Some(account) = account else {
+        return Err(refuse(login).await);
+    };
+    // A locked account refuses even the right password, so guessing doesn't
+    // get any faster by ignoring the errors
+    if !reserve_attempt(file_manager, account.user_id, now)
+        .await
+        .map_err(|e| internal_error(&e))?
+    {
+        warn!("Refused to check a password for {user_id}, who is locked out");
+        return Err(refuse(login).await);
+    }
+    // Guests don't have passwords, so they can't log in with one
+    let Some(hash) = account.password_hash.clone() else {
+        return Err(refuse(login).await);
+    };
+    let valid = auth::verify_password(login.password.clone(), hash.clone())
+        .await
+        .map_err(|e| internal_error(&e))?;
+    if !valid {
+        warn!("Failed attempt to log in as {user_id}");
+        return Err(EndpointErrors::Forbidden);
+    }
+    clear_failures(file_manager, account.user_id)
+        .await
+        .map_err(|e| internal_error(&e))?;
+    if auth::needs_rehash(&hash) {
+        // The login goes ahead with the old hash if this fails, and the
+        // password is hashed again next time
+        if let Err(e) =
+            rehash_password(file_manager, &account, &hash, &login.password)
+                .await
+        {
+            warn!("Failed to rehash the password of {user_id}: {e}");
+        }
+    }
+    Ok((user_id, account))
+}
+
+/// Log in with `m.login.token`, returning the user and their account
+async fn token_login(
+    file_manager: &FileManager,
+    login: &Token,
+    now: u64,
+) -> Result<(OwnedUserId, UserRow), EndpointErrors> {
+    let user = redeem_login_token(file_manager, &login.token, now)
+        .await
+        .map_err(|e| internal_error(&e))?
+        .ok_or(EndpointErrors::InvalidToken)?;
+    let account = load_account(file_manager, user)
+        .await
+        .map_err(|e| internal_error(&e))?
+        .ok_or(EndpointErrors::InvalidToken)?;
+    let user_id = UserId::parse(format!(
+        "@{}:{}",
+        account.username, PROGRAM_CONFIG.server_name
+    ))
+    .map_err(|e| {
+        error!("The account with short ID {user} has an invalid user ID: {e}");
+        EndpointErrors::PolarsError
+    })?;
+    Ok((user_id, account))
+}
+
+/// Log in with `m.login.application_service` on behalf of the appservice
+/// that sent `token`, returning the user and their account
+async fn appservice_login(
+    file_manager: &FileManager,
+    login: &ApplicationService,
+    token: Option<&str>,
+) -> Result<(OwnedUserId, UserRow), EndpointErrors> {
+    let token = token.ok_or(EndpointErrors::MissingAppserviceToken)?;
+    let appservice = appservices::authenticate(token)
+        .ok_or(EndpointErrors::UnknownAppserviceToken)?;
+    let user_id = identify(login.identifier.as_ref())?;
+    if !appservice.manages(&user_id) {
+        return Err(EndpointErrors::NotInNamespace);
+    }
+    let account = find_account(file_manager, &user_id)
+        .await
+        .map_err(|e| internal_error(&e))?
+        .ok_or(EndpointErrors::Forbidden)?;
+    info!("Appservice {} is logging in {user_id}", appservice.config.id);
+    Ok((user_id, account))
+}
+
+/// Log the user with the short ID `user` in on `device_id` at `now`,
+/// returning the access token issued to the device.
+///
+/// The device is created with `display_name` if the user doesn't have it yet.
+/// Otherwise it keeps its name, and the access tokens it had stop working.
+///
+/// # Errors
+///
+/// This function will return an error if the tables could not be read or the
+/// transaction could not be committed. Nothing is changed in that case.
+async fn start_session(
+    file_manager: &FileManager,
+    user: u64,
+    device_id: &DeviceId,
+    display_name: Option<String>,
+    now: u64,
+) -> PolarsResult<String> {
+    let mut transaction =
+        file_manager.transaction(&[&DEVICES, &ACCESS_TOKENS]).await?;
+    let existing = transaction.query(&DEVICES, |frame| {
+        frame.filter(
+            col(DeviceRow::USER_ID)
+                .eq(lit(user))
+                .and(col(DeviceRow::DEVICE_ID).eq(lit(device_id.as_str()))),
+        )
+    })?;
+    if existing.height() == 0 {
+        transaction.stage(
+            &DEVICES,
+            Mutation::Upsert(DeviceRow::to_dataframe(&[DeviceRow {
+                user_id: user,
+                device_id: device_id.to_string(),
+                display_name,
+                created_ts: now,
+            }])?),
+        )?;
+    } else {
+        let revoked = transaction.query(&ACCESS_TOKENS, |frame| {
+            frame
+                .filter(col(AccessTokenRow::USER_ID).eq(lit(user)).and(
+                    col(AccessTokenRow::DEVICE_ID).eq(lit(device_id.as_str())),
+                ))
+                .select([col(AccessTokenRow::TOKEN)])
+        })?;
+        for token in
+            revoked.column(AccessTokenRow::TOKEN)?.str()?.into_iter().flatten()
+        {
+            transaction.stage(
+                &ACCESS_TOKENS,
+                Mutation::Delete {
+                    column: AccessTokenRow::TOKEN.to_owned(),
+                    value: token.to_owned(),
+                },
+            )?;
+        }
+    }
+    let access_token = auth::generate_access_token();
+    transaction.stage(
+        &ACCESS_TOKENS,
+        Mutation::Upsert(AccessTokenRow::to_dataframe(&[AccessTokenRow {
+            token: access_token.clone(),
+            user_id: user,
+            device_id: device_id.to_string(),
+            created_ts: now,
+        }])?),
+    )?;
+    transaction.commit()?;
+    Ok(access_token)
+}
+
+/// Log a user in, giving their device an access token
+///
+/// [Spec](https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3login)
+// The request holds the password, so it is left out of the span
+#[instrument(level = "trace", skip_all)]
+pub(crate) async fn endpoint(
+    State(file_manager): State<FileManager>,
+    AuthRumaExtractor {
+        request: req,
+        appservice_token,
+        ..
+    }: AuthRumaExtractor<Request>,
+) -> CubbyResponder<Response, EndpointErrors> {
+    let now = now_ms();
+    let login = match &req.login_info {
+        LoginInfo::Password(login) => {
+            password_login(&file_manager, login, now).await
+        }
+        LoginInfo::Token(login) => token_login(&file_manager, login, now).await,
+        LoginInfo::ApplicationService(login) => {
+            appservice_login(&file_manager, login, appservice_token.as_deref())
+                .await
+        }
+        _ => Err(EndpointErrors::UnknownLoginType),
+    };
+    let (user_id, account) = match login {
+        Ok(login) => login,
+        Err(e) => return CubbyResponder::MatrixError(e),
+    };
+    // Only checked once the user has proven who they are, so the state of an
+    // account isn't given away to anyone who asks
+    if account.is_deactivated {
+        return CubbyResponder::MatrixError(EndpointErrors::Deactivated);
+    }
+    let device_id = req.device_id.unwrap_or_else(auth::generate_device_id);
+    match start_session(
+        &file_manager,
+        account.user_id,
+        &device_id,
+        req.initial_device_display_name,
+        now,
+    )
+    .await
+    {
+        Ok(access_token) => {
+            info!("Logged {user_id} in on {device_id}");
+            CubbyResponder::Ruma(Response::new(
+                user_id,
+                access_token,
+                device_id,
+            ))
+        }
+        Err(e) => {
+            error!(
+                "Failed to log {user_id} in: {e}. Run `cubby check` to look \
+                 for damage in the data directory."
+            );
+            CubbyResponder::MatrixError(EndpointErrors::PolarsError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cubby_lib::FileManager;
+    use polars::prelude::*;
+    use ruma::api::client::{
+        session::login::v3::Password, uiaa::UserIdentifier,
+    };
+    use tokio::task::JoinSet;
+
+    use super::{now_ms, password_login, EndpointErrors};
+    use crate::{
+        auth,
+        config::PROGRAM_CONFIG,
+        managers::{
+            dataframes::ParquetManager, interner::Interner,
+            storage::memory_file_manager, wal::Mutation,
+        },
+        schema::{LoginFailureRow, ParquetRow, UserRow, LOGIN_FAILURES, USERS},
+    };
+
+    /// Create an account for alice, whose password is `hunter2`
+    async fn add_alice(file_manager: &FileManager) {
+        let user_id = format!("@alice:{}", PROGRAM_CONFIG.server_name);
+        let interned = file_manager
+            .intern_rows(
+                &USERS,
+                df!(UserRow::USER_ID => [user_id]).expect("Invalid frame"),
+            )
+            .await
+            .expect("Failed to intern alice");
+        let alice = UserRow {
+            user_id: interned
+                .column(UserRow::USER_ID)
+                .and_then(|column| column.u64().cloned())
+                .expect("Alice was not interned")
+                .get(0)
+                .expect("Alice was not interned"),
+            username: "alice".to_owned(),
+            password_hash: Some(
+                auth::hash_password("hunter2".to_owned())
+                    .await
+                    .expect("Failed to hash password"),
+            ),
+            is_guest: false,
+            is_deactivated: false,
+            created_ts: 0,
+        };
+        file_manager
+            .get_managed_lazyframe(&USERS)
+            .await
+            .expect("Failed to lock users")
+            .apply(&Mutation::Upsert(
+                UserRow::to_dataframe(&[alice]).expect("Invalid row"),
+            ))
+            .expect("Failed to add alice");
+    }
+
+    /// Log in as `username` with `password`
+    async fn log_in(
+        file_manager: FileManager,
+        username: &str,
+        password: &str,
+    ) -> Result<(), EndpointErrors> {
+        let login = Password::new(
+            UserIdentifier::UserIdOrLocalpart(username.to_owned()),
+            password.to_owned(),
+        );
+        password_login(&file_manager, &login, now_ms()).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn successful_logins_forget_failures() {
+        let file_manager = memory_file_manager();
+        add_alice(&file_manager).await;
+
+        assert!(matches!(
+            log_in(file_manager.clone(), "bob", "hunter2").await,
+            Err(EndpointErrors::Forbidden)
+        ));
+        for _ in 0..2 {
+            assert!(matches!(
+                log_in(file_manager.clone(), "alice", "hunter3").await,
+                Err(EndpointErrors::Forbidden)
+            ));
+        }
+        assert!(log_in(file_manager.clone(), "alice", "hunter2").await.is_ok());
+        let failures = file_manager
+            .get_lazyframe(&LOGIN_FAILURES)
+            .await
+            .expect("Failed to read failures")
+            .query(|frame| frame)
+            .expect("Failed to query failures");
+        assert_eq!(failures.height(), 0);
+    }
+
+    #[tokio::test]
+    async fn simultaneous_guesses_are_limited() {
+        let file_manager = memory_file_manager();
+        add_alice(&file_manager).await;
+        let limit = PROGRAM_CONFIG.login_failure_limit;
+
+        // Every guess is started before any password has been checked
+        let mut guesses = JoinSet::new();
+        for _ in 0..limit * 2 {
+            guesses.spawn(log_in(file_manager.clone(), "alice", "hunter3"));
+        }
+        while let Some(guess) = guesses.join_next().await {
+            assert!(matches!(
+                guess.expect("Guess panicked"),
+                Err(EndpointErrors::Forbidden)
+            ));
+        }
+        // Only as many guesses as the limit allows were counted, and so
+        // checked
+        let failures = file_manager
+            .get_lazyframe(&LOGIN_FAILURES)
+            .await
+            .expect("Failed to read failures")
+            .query(|frame| frame)
+            .expect("Failed to query failures");
+        assert_eq!(
+            failures
+                .column(LoginFailureRow::FAILURES)
+                .expect("Missing column")
+                .u64()
+                .ok()
+                .and_then(|failures| failures.get(0)),
+            Some(limit)
+        );
+        // The right password is refused the same way as a wrong one, or a
+        // user who doesn't exist
+        assert!(matches!(
+            log_in(file_manager.clone(), "alice", "hunter2").await,
+            Err(EndpointErrors::Forbidden)
+        ));
+        assert!(matches!(
+            log_in(file_manager, "bob", "hunter2").await,
+            Err(EndpointErrors::Forbidden)
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+//! Application services registered in the config
+//!
+//! An appservice proves who it is with the `as_token` it was registered with,
+//! and acts on behalf of the users whose IDs match its `users` patterns. For
+//! now the only things appservices can do are register those users and log
+//! them in, with `m.login.application_service`. Since that works for any user
+//! in their namespace, every namespace is exclusive, and nobody else can
+//! register a username in one.
+//!
+//! [Spec](https://spec.matrix.org/latest/application-service-api/#registration)
+
+use once_cell::sync::Lazy;
+use regex::RegexSet;
+use ruma::UserId;
+
+use crate::{
+    auth,
+    config::{Appservice, PROGRAM_CONFIG},
+};
+
+/// An appservice from the config, with its user namespace compiled
+pub(crate) struct Registered {
+    /// The appservice as it was configured
+    pub(crate) config: &'static Appservice,
+    /// The compiled `config.users`
+    users: RegexSet,
+}
+
+impl Registered {
+    /// Compile the namespace of the appservice configured as `config`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if one of the user namespaces isn't
+    /// a valid regular expression.
+    pub(crate) fn new(config: &'static Appservice) -> Result<Self, String> {
+        let users = RegexSet::new(&config.users).map_err(|e| {
+            format!("Invalid user namespace for appservice {}: {e}", config.id)
+        })?;
+        Ok(Self {
+            config,
+            users,
+        })
+    }
+
+    /// Whether `user_id` is in the namespace of the appservice
+    pub(crate) fn manages(&self, user_id: &UserId) -> bool {
+        self.users.is_match(user_id.as_str())
+    }
+}
+
+/// Every appservice in `PROGRAM_CONFIG.appservices`
+static REGISTERED: Lazy<Result<Vec<Registered>, String>> = Lazy::new(|| {
+    PROGRAM_CONFIG.appservices.iter().map(Registered::new).collect()
+});
+
+/// Check that every configured appservice can be registered
+///
+/// # Errors
+///
+/// This function will return an error if one of the user namespaces isn't a
+/// valid regular expression.
+pub(crate) fn check_config() -> Result<(), String> {
+    REGISTERED.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+/// Whether `user_id` is in the namespace of any of `registered`
+fn claimed_by(registered: &[Registered], user_id: &UserId) -> bool {
+    registered.iter().any(|registered| registered.manages(user_id))
+}
+
+/// Whether `user_id` is in the namespace of any appservice
+pub(crate) fn is_claimed(user_id: &UserId) -> bool {
+    // An invalid config claims everything rather than nothing. The server
+    // doesn't start with one, so this can't happen in practice.
+    let Ok(registered) = &*REGISTERED else {
+        return true;
+    };
+    claimed_by(registered, user_id)
+}
+
+/// Find the appservice `token` was issued to, if any
+pub(crate) fn authenticate(token: &str) -> Option<&'static Registered> {
+    REGISTERED.as_ref().ok()?.iter().find(|registered| {
+        auth::constant_time_eq(
+            registered.config.as_token.as_bytes(),
+            token.as_bytes(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::RegexSet;
+    use ruma::UserId;
+
+    use super::{claimed_by, Registered};
+    use crate::config::Appservice;
+
+    #[test]
+    fn namespaces_claim_matching_users() {
+        let config = Box::leak(Box::new(Appservice {
+            id: "irc".to_owned(),
+            as_token: "token".to_owned(),
+            users: vec!["^@irc_.*:example\\.org$".to_owned()],
+        }));
+        let registered = [Registered {
+            users: RegexSet::new(&config.users).expect("Invalid namespace"),
+            config,
+        }];
+        let user =
+            |user_id: &str| UserId::parse(user_id).expect("Invalid user ID");
+
+        assert!(claimed_by(&registered, &user("@irc_alice:example.org")));
+        assert!(!claimed_by(&registered, &user("@alice:example.org")));
+        assert!(!claimed_by(&[], &user("@irc_alice:example.org")));
+    }
+}
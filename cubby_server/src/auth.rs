@@ -0,0 +1,235 @@
+//! Credentials of local users
+//!
+//! Passwords are stored as Argon2id hashes in the PHC string format, which
+//! records the parameters and salt alongside the hash so they can be changed
+//! later without invalidating existing passwords. Hashing is deliberately
+//! slow, so it runs on the blocking thread pool rather than holding up the
+//! async runtime.
+//!
+//! Accounts imported from Synapse (see `synapse`) keep their bcrypt hashes
+//! until the user next logs in, when the password they give is hashed again
+//! with Argon2id.
+//!
+//! Access tokens, login tokens, session IDs, device IDs and generated
+//! localparts are random strings drawn from the thread-local CSPRNG.
+//! `AccessTokens` looks access tokens up for `cubby_lib::AuthRumaExtractor`.
+
+use std::error::Error;
+
+use argon2::{
+    password_hash::{
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use axum::async_trait;
+use cubby_lib::{FileManager, Sender, TokenValidator};
+use polars::prelude::*;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use ruma::{OwnedDeviceId, OwnedUserId};
+use tokio::sync::OnceCell;
+
+use crate::{
+    config::PROGRAM_CONFIG,
+    managers::{dataframes::ParquetManager, interner::Interner},
+    schema::{AccessTokenRow, ACCESS_TOKENS},
+};
+
+/// How many characters an access token is made of
+const ACCESS_TOKEN_LENGTH: usize = 32;
+
+/// How many characters a user-interactive authentication session ID is made
+/// of
+const SESSION_ID_LENGTH: usize = 24;
+
+/// How many characters a generated localpart is made of
+const LOCALPART_LENGTH: usize = 12;
+
+/// The characters generated localparts are made of. These are allowed by every
+/// version of the user ID grammar.
+const LOCALPART_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Hash `password` for storing in the `users` table
+///
+/// # Errors
+///
+/// This function will return an error if the password could not be hashed.
+pub(crate) async fn hash_password(password: String) -> PolarsResult<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| {
+                PolarsError::ComputeError(
+                    format!("Failed to hash password: {e}").into(),
+                )
+            })
+    })
+    .await
+    .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?
+}
+
+/// Whether `hash` is a bcrypt hash, as written by Synapse
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+/// Whether `hash` should be replaced by a hash from `hash_password` once the
+/// password is known
+pub(crate) fn needs_rehash(hash: &str) -> bool {
+    is_bcrypt(hash)
+}
+
+/// Check `password` against `hash`, as stored by `hash_password` or imported
+/// from Synapse
+///
+/// # Errors
+///
+/// This function will return an error if `hash` is neither a valid PHC string
+/// nor a valid bcrypt hash.
+pub(crate) async fn verify_password(
+    password: String,
+    hash: String,
+) -> PolarsResult<bool> {
+    tokio::task::spawn_blocking(move || {
+        if is_bcrypt(&hash) {
+            return bcrypt::verify(password.as_bytes(), &hash).map_err(|e| {
+                PolarsError::ComputeError(
+                    format!("Invalid bcrypt password hash: {e}").into(),
+                )
+            });
+        }
+        let hash = PasswordHash::new(&hash).map_err(|e| {
+            PolarsError::ComputeError(
+                format!("Invalid password hash: {e}").into(),
+            )
+        })?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await
+    .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?
+}
+
+/// The hash passwords given for unknown users are checked against. No
+/// password matches it.
+static UNKNOWN_USER_HASH: OnceCell<String> = OnceCell::const_new();
+
+/// Check `password` against a hash no password matches, made the same way as
+/// real ones so checking it takes as long. Refusing a user who doesn't exist
+/// then takes as long as refusing a wrong password.
+///
+/// # Errors
+///
+/// This function will return an error if the hash could not be made.
+pub(crate) async fn verify_unknown_user(password: String) -> PolarsResult<()> {
+    let hash = UNKNOWN_USER_HASH
+        .get_or_try_init(|| hash_password(generate_access_token()))
+        .await?;
+    verify_password(password, hash.clone()).await.map(|_| ())
+}
+
+/// Compare two byte strings in time that only depends on their lengths, so
+/// secrets like tokens can't be guessed one byte at a time
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A random string of `length` letters and digits
+fn alphanumeric(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Generate a new access token
+pub(crate) fn generate_access_token() -> String {
+    alphanumeric(ACCESS_TOKEN_LENGTH)
+}
+
+/// Generate a new token for logging in with `m.login.token`
+pub(crate) fn generate_login_token() -> String {
+    alphanumeric(ACCESS_TOKEN_LENGTH)
+}
+
+/// Generate a new user-interactive authentication session ID
+pub(crate) fn generate_session_id() -> String {
+    alphanumeric(SESSION_ID_LENGTH)
+}
+
+/// Generate a new device ID, `PROGRAM_CONFIG.device_id_length` characters long
+pub(crate) fn generate_device_id() -> OwnedDeviceId {
+    OwnedDeviceId::from(alphanumeric(usize::from(
+        PROGRAM_CONFIG.device_id_length,
+    )))
+}
+
+/// Generate a localpart for a user who didn't ask for one, such as a guest
+pub(crate) fn generate_localpart() -> String {
+    let mut rng = rand::thread_rng();
+    (0..LOCALPART_LENGTH)
+        .map(|_| {
+            char::from(
+                LOCALPART_CHARSET[rng.gen_range(0..LOCALPART_CHARSET.len())],
+            )
+        })
+        .collect()
+}
+
+/// Validates access tokens against the `access_tokens` table
+pub(crate) struct AccessTokens(pub(crate) FileManager);
+
+#[async_trait]
+impl TokenValidator for AccessTokens {
+    async fn validate(
+        &self,
+        token: &str,
+    ) -> Result<Option<Sender>, Box<dyn Error + Send + Sync>> {
+        let rows = self
+            .0
+            .get_by_key(&ACCESS_TOKENS, AccessTokenRow::TOKEN, token)
+            .await?;
+        let rows = self.0.resolve_ids(rows, &[AccessTokenRow::USER_ID]).await?;
+        let user_id =
+            rows.column(AccessTokenRow::USER_ID)?.str()?.into_iter().next();
+        let device_id =
+            rows.column(AccessTokenRow::DEVICE_ID)?.str()?.into_iter().next();
+        let (Some(Some(user_id)), Some(Some(device_id))) = (user_id, device_id)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(Sender {
+            user_id: OwnedUserId::try_from(user_id)?,
+            device_id: OwnedDeviceId::from(device_id),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_password, needs_rehash, verify_password};
+
+    #[tokio::test]
+    async fn bcrypt_hashes_from_synapse_are_verified() {
+        let hash = bcrypt::hash("hunter2", 4).expect("Failed to hash");
+        assert!(needs_rehash(&hash));
+        assert!(verify_password("hunter2".to_owned(), hash.clone())
+            .await
+            .expect("Failed to verify"));
+        assert!(!verify_password("hunter3".to_owned(), hash)
+            .await
+            .expect("Failed to verify"));
+
+        let hash =
+            hash_password("hunter2".to_owned()).await.expect("Failed to hash");
+        assert!(!needs_rehash(&hash));
+        assert!(verify_password("hunter2".to_owned(), hash)
+            .await
+            .expect("Failed to verify"));
+    }
+}
@@ -0,0 +1,843 @@
+//! Offline consistency checks of the data directory
+//!
+//! `cubby check` opens every table and every event fragment in the data
+//! directory and looks for problems that would otherwise only surface as
+//! errors from whichever endpoint happens to read the damaged file:
+//!
+//! - files that can't be read at all, or whose columns don't match the schema
+//! - write-ahead logs that can't be decoded
+//! - transactions that were committed but not fully applied to their tables
+//! - rows with null or duplicate keys
+//! - identifiers kept in, or given a short ID by, a table other than the one
+//!   they belong in, which would stop them from being found
+//! - rows referring to rows in other tables that don't exist, such as devices
+//!   of users that were never created or short IDs that were never assigned
+//! - temporary files left behind by writes, compactions, snapshots or restores
+//!   that were interrupted
+//!
+//! Pending write-ahead log records are taken into account, so a table is
+//! checked as the server would see it after replaying its log.
+//!
+//! With `--repair`, everything that can be fixed without guessing is fixed:
+//! transactions and logs are replayed, temporary files are removed, missing
+//! tables are created, tables are conformed to their schema, and rows with bad
+//! keys or dangling references are dropped. Unreadable files and events are
+//! never touched, since the only way to get them back is to restore a snapshot.
+//!
+//! The server must not be running while the data directory is checked, since
+//! the checker reads tables without locking them. `--repair` refuses to run
+//! while a server is using the data directory (see `data_lock`).
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use polars::prelude::*;
+use tracing::{info, warn};
+
+use crate::{
+    config::{Storage, PROGRAM_CONFIG},
+    data_lock,
+    managers::{
+        dataframes::write_dataframe_atomic,
+        encryption, events, interner,
+        wal::{self, Mutation, WriteAheadLog},
+    },
+    schema::{
+        self, AccessTokenRow, DeviceRow, EventRow, IdentifierRow, ProfileRow,
+        RoomRow, RoomStateRow, Table, UserRow, ACCESS_TOKENS, DEVICES, EVENTS,
+        IDENTIFIERS, IDENTIFIER_TABLES, PROFILES, ROOMS, ROOM_STATE, TABLES,
+        USERS,
+    },
+};
+
+/// Rows in one table that must match a row in another
+struct Reference {
+    /// The table holding the referring rows
+    from: &'static Table,
+    /// The columns of `from` holding the reference
+    columns: &'static [&'static str],
+    /// The table that must hold the referenced row
+    to: &'static Table,
+    /// The columns of `to` the reference is compared against
+    to_columns: &'static [&'static str],
+}
+
+/// Every reference between tables, in the order they are checked.
+///
+/// Devices are checked before access tokens, so repairing a device of a
+/// missing user also drops the tokens issued to it.
+static REFERENCES: &[Reference] = &[
+    Reference {
+        from: &DEVICES,
+        columns: &[DeviceRow::USER_ID],
+        to: &USERS,
+        to_columns: &[UserRow::USER_ID],
+    },
+    Reference {
+        from: &ACCESS_TOKENS,
+        columns: &[AccessTokenRow::USER_ID, AccessTokenRow::DEVICE_ID],
+        to: &DEVICES,
+        to_columns: &[DeviceRow::USER_ID, DeviceRow::DEVICE_ID],
+    },
+    Reference {
+        from: &PROFILES,
+        columns: &[ProfileRow::USER_ID],
+        to: &USERS,
+        to_columns: &[UserRow::USER_ID],
+    },
+    Reference {
+        from: &ROOM_STATE,
+        columns: &[RoomStateRow::ROOM_ID],
+        to: &ROOMS,
+        to_columns: &[RoomRow::ROOM_ID],
+    },
+    Reference {
+        from: &ROOM_STATE,
+        columns: &[RoomStateRow::EVENT_ID],
+        to: &EVENTS,
+        to_columns: &[EventRow::EVENT_ID],
+    },
+    Reference {
+        from: &EVENTS,
+        columns: &[EventRow::ROOM_ID],
+        to: &ROOMS,
+        to_columns: &[RoomRow::ROOM_ID],
+    },
+];
+
+/// The state of a check of the data directory
+struct Checker {
+    /// Whether problems should be repaired where possible
+    repair: bool,
+    /// How many problems were found and not repaired
+    unresolved: usize,
+    /// The contents of every table that could be read, by name. Only the
+    /// interned columns of events are loaded.
+    tables: HashMap<&'static str, DataFrame>,
+    /// Tables changed by a repair that need to be written back
+    changed: Vec<&'static Table>,
+}
+
+impl Checker {
+    /// Record a problem that was not repaired
+    fn found(&mut self, problem: &str) {
+        warn!("{problem}");
+        self.unresolved += 1;
+    }
+
+    /// Record a problem that was repaired
+    fn repaired(problem: &str, repair: &str) {
+        info!("{problem}: {repair}");
+    }
+
+    /// Replace the contents of a table after repairing it
+    fn replace(&mut self, table: &'static Table, df: DataFrame) {
+        self.tables.insert(table.name, df);
+        if !self.changed.iter().any(|changed| changed.name == table.name) {
+            self.changed.push(table);
+        }
+    }
+
+    /// Look for temporary files left behind by interrupted operations
+    fn check_leftovers(&mut self, data_path: &Path) -> PolarsResult<()> {
+        let mut leftovers = Vec::new();
+        for entry in fs::read_dir(data_path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                leftovers.push(path);
+            }
+        }
+        match fs::read_dir(data_path.join(events::STAGING_DIR)) {
+            Ok(entries) => {
+                for entry in entries {
+                    leftovers.push(entry?.path());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        leftovers.extend(wal::uncommitted_transactions(data_path)?);
+        let mut restoring = data_path.as_os_str().to_owned();
+        restoring.push(".restoring");
+        leftovers.push(PathBuf::from(restoring));
+        match fs::read_dir(&PROGRAM_CONFIG.backup_path) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry?.path();
+                    if path
+                        .extension()
+                        .is_some_and(|extension| extension == "partial")
+                    {
+                        leftovers.push(path);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        for path in leftovers.into_iter().filter(|path| path.exists()) {
+            let problem = format!("Leftover temporary file {}", path.display());
+            if !self.repair {
+                self.found(&problem);
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+            Self::repaired(&problem, "removed");
+        }
+        Ok(())
+    }
+
+    /// Check that every write-ahead log can be decoded, replaying them along
+    /// with any committed transactions if repairing
+    fn check_logs(&mut self, data_path: &Path) -> PolarsResult<()> {
+        for path in wal::committed_transactions(data_path)? {
+            let problem = format!(
+                "Transaction {} was committed but not fully applied",
+                path.display()
+            );
+            if self.repair {
+                Self::repaired(&problem, "applied");
+            } else {
+                self.found(&problem);
+            }
+        }
+        if self.repair {
+            wal::recover_transactions(data_path, &TABLES)?;
+        }
+        let mut readable = Vec::new();
+        for table in TABLES.iter() {
+            let path = table.path_in(data_path);
+            match pending(&path) {
+                // A log can only be replayed into a table that can be read,
+                // which is checked separately
+                Ok(_) if read_table(&path).is_ok() => readable.push(*table),
+                Ok(_) => {}
+                Err(e) => self.found(&format!(
+                    "The write-ahead log of table {} can't be read: {e}",
+                    table.name
+                )),
+            }
+        }
+        if self.repair {
+            wal::replay(data_path, &readable)?;
+        }
+        Ok(())
+    }
+
+    /// Load a table, checking its schema and keys
+    fn check_table(
+        &mut self,
+        data_path: &Path,
+        table: &'static Table,
+    ) -> PolarsResult<()> {
+        let path = table.path_in(data_path);
+        if !path.exists() {
+            let problem = format!("Table {} is missing", table.name);
+            if self.repair {
+                self.replace(table, table.empty()?);
+                Self::repaired(&problem, "created an empty table");
+            } else {
+                self.found(&problem);
+            }
+            return Ok(());
+        }
+        let mut df = match read_table(&path) {
+            Ok(df) => df,
+            Err(e) => {
+                self.found(&format!(
+                    "Table {} can't be read: {e}. Restore it from a snapshot.",
+                    table.name
+                ));
+                return Ok(());
+            }
+        };
+        // The log was replayed already if repairing, and its problems have
+        // been reported if it can't be read
+        if let Ok(mutations) = pending(&path) {
+            let mut frame = df.lazy();
+            for mutation in &mutations {
+                frame = mutation.apply_to(frame, table)?;
+            }
+            df = frame.collect()?;
+        }
+        let mut changed = false;
+
+        let mismatched = schema_mismatches(&df.schema(), &table.schema());
+        if !mismatched.is_empty() {
+            let problem = format!(
+                "Table {} does not match its schema: {}",
+                table.name,
+                mismatched.join(", ")
+            );
+            if self.repair {
+                match table.conform(&df).collect() {
+                    Ok(conformed) => {
+                        df = conformed;
+                        changed = true;
+                        Self::repaired(&problem, "converted to the schema");
+                    }
+                    Err(e) => self.found(&format!(
+                        "{problem}. It can't be converted: {e}"
+                    )),
+                }
+            } else {
+                self.found(&problem);
+            }
+        }
+
+        changed |= self.check_keys(table, &mut df)?;
+
+        if changed {
+            self.replace(table, df);
+        } else {
+            self.tables.insert(table.name, df);
+        }
+        Ok(())
+    }
+
+    /// Check that every row of a table has a unique key, returning whether
+    /// `df` was repaired
+    fn check_keys(
+        &mut self,
+        table: &Table,
+        df: &mut DataFrame,
+    ) -> PolarsResult<bool> {
+        if !table.key.iter().all(|name| df.column(name).is_ok()) {
+            // The missing columns have been reported already
+            return Ok(false);
+        }
+        let mut changed = false;
+        let keyed = df.clone().lazy().filter(not_null(table.key)).collect()?;
+        let null_keys = df.height() - keyed.height();
+        if null_keys > 0 {
+            let problem = format!(
+                "Table {} has {null_keys} rows without a key",
+                table.name
+            );
+            if self.repair {
+                *df = keyed;
+                changed = true;
+                Self::repaired(&problem, "dropped them");
+            } else {
+                self.found(&problem);
+            }
+        }
+
+        let key: Vec<String> =
+            table.key.iter().map(|&name| name.to_owned()).collect();
+        let unique = df
+            .clone()
+            .lazy()
+            .unique_stable(Some(key), UniqueKeepStrategy::Last)
+            .collect()?;
+        let duplicates = df.height() - unique.height();
+        if duplicates > 0 {
+            let problem = format!(
+                "Table {} has {duplicates} rows with duplicate keys",
+                table.name
+            );
+            if self.repair {
+                *df = unique;
+                changed = true;
+                Self::repaired(&problem, "kept the last of each");
+            } else {
+                self.found(&problem);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Check that every identifier is in the table it belongs in, and was
+    /// given its short ID by that table. The tables are then combined under
+    /// the name of `IDENTIFIERS` for the references to them.
+    fn check_identifiers(&mut self) -> PolarsResult<()> {
+        let mut identifiers = vec![IDENTIFIERS.empty()?.lazy()];
+        for table in IDENTIFIER_TABLES.iter() {
+            let Some(df) = self.tables.get(table.name) else {
+                continue;
+            };
+            if !schema_mismatches(&df.schema(), &table.schema()).is_empty() {
+                // This has been reported already
+                continue;
+            }
+            let belongs_here = |(identifier, short_id): (&str, u64)| {
+                interner::table_of(identifier).name == table.name
+                    && interner::table_of_id(short_id).name == table.name
+            };
+            let misplaced = df
+                .column(IdentifierRow::IDENTIFIER)?
+                .str()?
+                .into_iter()
+                .zip(df.column(IdentifierRow::SHORT_ID)?.u64()?)
+                .filter(|(identifier, short_id)| {
+                    identifier
+                        .zip(*short_id)
+                        .is_some_and(|row| !belongs_here(row))
+                })
+                .count();
+            identifiers.push(df.clone().lazy());
+            if misplaced > 0 {
+                self.found(&format!(
+                    "Table {} holds {misplaced} identifiers that belong in \
+                     other tables. Restore it from a snapshot.",
+                    table.name
+                ));
+            }
+        }
+        let identifiers =
+            concat(identifiers, UnionArgs::default())?.collect()?;
+        self.tables.insert(IDENTIFIERS.name, identifiers);
+        Ok(())
+    }
+
+    /// Check every fragment in the event store, loading the IDs of every
+    /// event for the references to and from them
+    fn check_events(&mut self, data_path: &Path) -> PolarsResult<()> {
+        let events_dir = data_path.join(events::EVENTS_DIR);
+        let mut ids = Vec::new();
+        for room_dir in events::room_dirs(data_path)? {
+            for fragment in events::room_fragments(&room_dir)? {
+                let df = match read_table(&fragment) {
+                    Ok(df) => df,
+                    Err(e) => {
+                        self.found(&format!(
+                            "Event fragment {} can't be read: {e}. Restore it \
+                             from a snapshot.",
+                            fragment.display()
+                        ));
+                        continue;
+                    }
+                };
+                let mismatched =
+                    schema_mismatches(&df.schema(), &EVENTS.schema());
+                if !mismatched.is_empty() {
+                    self.found(&format!(
+                        "Event fragment {} does not match the schema: {}",
+                        fragment.display(),
+                        mismatched.join(", ")
+                    ));
+                    continue;
+                }
+                let df = df
+                    .lazy()
+                    .select(
+                        EVENTS
+                            .interned
+                            .iter()
+                            .map(|name| col(name))
+                            .collect::<Vec<_>>(),
+                    )
+                    .collect()?;
+                let misplaced = df
+                    .column(EventRow::ROOM_ID)?
+                    .u64()?
+                    .into_iter()
+                    .filter(|room| {
+                        room.is_none_or(|room| {
+                            events::room_dir(&events_dir, room) != room_dir
+                        })
+                    })
+                    .count();
+                if misplaced > 0 {
+                    self.found(&format!(
+                        "Event fragment {} holds {misplaced} events of other \
+                         rooms",
+                        fragment.display()
+                    ));
+                }
+                ids.push(df.lazy());
+            }
+        }
+        let ids = if ids.is_empty() {
+            EVENTS.empty()?
+        } else {
+            concat(ids, UnionArgs::default())?.collect()?
+        };
+        self.tables.insert(EVENTS.name, ids);
+        Ok(())
+    }
+
+    /// Check that every row referring to another table has a match there
+    fn check_reference(&mut self, reference: &Reference) -> PolarsResult<()> {
+        let (Some(from), Some(to)) = (
+            self.tables.get(reference.from.name),
+            self.tables.get(reference.to.name),
+        ) else {
+            // Whichever table is missing has been reported already
+            return Ok(());
+        };
+        let as_strings = |columns: &[&str]| -> Vec<Expr> {
+            columns
+                .iter()
+                .map(|name| col(name).cast(DataType::String))
+                .collect()
+        };
+        let left = as_strings(reference.columns);
+        let right = as_strings(reference.to_columns);
+        // Rows with a null reference don't refer to anything
+        let referring = not_null(reference.columns);
+        let dangling = from
+            .clone()
+            .lazy()
+            .filter(referring.clone())
+            .join(
+                to.clone().lazy(),
+                &left,
+                &right,
+                JoinArgs::new(JoinType::Anti),
+            )
+            .collect()?
+            .height();
+        if dangling == 0 {
+            return Ok(());
+        }
+        let problem = format!(
+            "{dangling} rows of {} refer to rows of {} that don't exist",
+            reference.from.name, reference.to.name
+        );
+        // Events are history, so they are never dropped
+        if !self.repair || reference.from.name == EVENTS.name {
+            self.found(&problem);
+            return Ok(());
+        }
+        let kept = from.clone().lazy().join(
+            to.clone().lazy(),
+            &left,
+            &right,
+            JoinArgs::new(JoinType::Semi),
+        );
+        let unreferring = from.clone().lazy().filter(referring.not());
+        let kept =
+            concat([kept, unreferring], UnionArgs::default())?.collect()?;
+        self.replace(reference.from, kept);
+        Self::repaired(&problem, "dropped them");
+        Ok(())
+    }
+}
+
+/// Every pending record in the write-ahead log of the table at `table_path`,
+/// without creating the log if it doesn't exist
+fn pending(table_path: &Path) -> PolarsResult<Vec<Mutation>> {
+    match fs::metadata(wal::wal_path(table_path)) {
+        Ok(metadata) if metadata.len() > 0 => {
+            WriteAheadLog::open(table_path)?.read()
+        }
+        Ok(_) => Ok(Vec::new()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Matches rows where none of `columns` are null
+fn not_null(columns: &[&str]) -> Expr {
+    columns
+        .iter()
+        .map(|name| col(name).is_not_null())
+        .reduce(Expr::and)
+        .unwrap_or_else(|| lit(true))
+}
+
+/// Read every row of a parquet file
+fn read_table(path: &Path) -> PolarsResult<DataFrame> {
+    encryption::read_parquet(path)
+}
+
+/// Describe every way `actual` differs from `expected`
+fn schema_mismatches(actual: &Schema, expected: &Schema) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    for (name, dtype) in expected.iter() {
+        match actual.get(name) {
+            None => mismatches.push(format!("column {name} is missing")),
+            Some(found) if found != dtype => mismatches
+                .push(format!("column {name} is {found} instead of {dtype}")),
+            Some(_) => {}
+        }
+    }
+    for name in actual.iter_names() {
+        if !expected.contains(name) {
+            mismatches.push(format!("unexpected column {name}"));
+        }
+    }
+    mismatches
+}
+
+/// Check the data directory at `data_path`, repairing what can be repaired if
+/// `repair` is set. This must only be called while the server is not running,
+/// which is enforced when repairing.
+///
+/// Returns how many problems were found and not repaired.
+///
+/// # Errors
+///
+/// This function will return an error if the data directory can't be read,
+/// is at a schema version this version of cubby can't check, or if writing a
+/// repair fails. It also returns an error if repairing while a server is using
+/// the data directory.
+pub(crate) fn check(data_path: &Path, repair: bool) -> PolarsResult<usize> {
+    if !matches!(PROGRAM_CONFIG.storage, Storage::Parquet) {
+        return Err(PolarsError::ComputeError(
+            "Only parquet storage can be checked".into(),
+        ));
+    }
+    // Categorical columns of different tables are compared when checking
+    // references
+    polars::enable_string_cache();
+    // Held until every repair has been written
+    let _lock = if repair {
+        Some(data_lock::lock(data_path)?)
+    } else {
+        None
+    };
+    let current = schema::current_version();
+    match schema::read_version(data_path)? {
+        None => {
+            return Err(PolarsError::ComputeError(
+                format!(
+                    "{} is not an initialized data directory",
+                    data_path.display()
+                )
+                .into(),
+            ))
+        }
+        Some(version) if version != current => {
+            return Err(PolarsError::ComputeError(
+                format!(
+                    "The data directory is at schema version {version}, but \
+                     this version of cubby can only check version {current}. \
+                     Start the server once to migrate it first."
+                )
+                .into(),
+            ))
+        }
+        Some(_) => {}
+    }
+
+    let mut checker = Checker {
+        repair,
+        unresolved: 0,
+        tables: HashMap::new(),
+        changed: Vec::new(),
+    };
+    checker.check_leftovers(data_path)?;
+    checker.check_logs(data_path)?;
+    for table in TABLES.iter() {
+        checker.check_table(data_path, table)?;
+    }
+    checker.check_identifiers()?;
+    checker.check_events(data_path)?;
+    // Rows whose short IDs can't be resolved are checked first, so repairing
+    // them also drops the rows referring to them
+    for table in TABLES.iter().copied().chain([&EVENTS]) {
+        for column in table.interned {
+            checker.check_reference(&Reference {
+                from: table,
+                columns: std::slice::from_ref(column),
+                to: &IDENTIFIERS,
+                to_columns: &[IdentifierRow::SHORT_ID],
+            })?;
+        }
+    }
+    for reference in REFERENCES {
+        checker.check_reference(reference)?;
+    }
+    for table in std::mem::take(&mut checker.changed) {
+        if let Some(df) = checker.tables.get_mut(table.name) {
+            write_dataframe_atomic(&table.path_in(data_path), df)?;
+        }
+    }
+
+    if checker.unresolved == 0 {
+        info!("No problems found in {}", data_path.display());
+    } else {
+        warn!(
+            "Found {} problems in {}{}",
+            checker.unresolved,
+            data_path.display(),
+            if repair {
+                " that could not be repaired"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(checker.unresolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::{check, read_table};
+    use crate::{
+        data_lock,
+        managers::{dataframes::write_dataframe_atomic, events, interner, wal},
+        schema::{
+            self, AccessTokenRow, DeviceRow, IdentifierRow, Table, UserRow,
+            ACCESS_TOKENS, DEVICES, IDENTIFIER_TABLES, LOGIN_FAILURES, USERS,
+        },
+    };
+
+    /// Replace the contents of `table` in `data_path` with `rows`
+    fn write(data_path: &Path, table: &Table, rows: &DataFrame) {
+        let mut rows = table.conform(rows).collect().expect("Invalid rows");
+        write_dataframe_atomic(&table.path_in(data_path), &mut rows)
+            .expect("Failed to write table");
+    }
+
+    /// The number of rows in `table` in `data_path`
+    fn height(data_path: &Path, table: &Table) -> usize {
+        read_table(&table.path_in(data_path))
+            .expect("Failed to read table")
+            .height()
+    }
+
+    /// Initialize a data directory holding alice and her device, along with
+    /// one of each problem `check` looks for. Returns how many problems
+    /// can't be repaired.
+    fn damaged_data_directory(data_path: &Path) -> usize {
+        schema::initialize(data_path).expect("Failed to initialize");
+
+        let alice = "@alice:localhost";
+        let identifiers = interner::assign(
+            &interner::table_of(alice).empty().expect("Invalid table"),
+            &df!(IdentifierRow::IDENTIFIER => [alice]).expect("Invalid frame"),
+        )
+        .expect("Failed to assign a short ID");
+        let alice_id = identifiers
+            .column(IdentifierRow::SHORT_ID)
+            .and_then(|column| column.u64().cloned())
+            .expect("Invalid short IDs")
+            .get(0)
+            .expect("Alice has no short ID");
+        write(data_path, interner::table_of(alice), &identifiers);
+        // An identifier in a table it doesn't belong in can't be found
+        let elsewhere = IDENTIFIER_TABLES
+            .iter()
+            .find(|table| {
+                table.name != interner::table_of("@bob:localhost").name
+            })
+            .expect("Every table holds bob");
+        write(
+            data_path,
+            elsewhere,
+            &df!(
+                IdentifierRow::SHORT_ID => [alice_id + 1],
+                IdentifierRow::IDENTIFIER => ["@bob:localhost"],
+            )
+            .expect("Invalid frame"),
+        );
+
+        // Alice twice, and a user without a key
+        write(
+            data_path,
+            &USERS,
+            &df!(
+                UserRow::USER_ID => [Some(alice_id), Some(alice_id), None],
+                UserRow::USERNAME => ["alice", "alice", "nobody"],
+                UserRow::IS_GUEST => [false, false, false],
+                UserRow::IS_DEACTIVATED => [false, false, false],
+                UserRow::CREATED_TS => [0_u64, 1, 2],
+            )
+            .expect("Invalid frame"),
+        );
+        // A device of a user that was never interned, and a token of a device
+        // that doesn't exist
+        write(
+            data_path,
+            &DEVICES,
+            &df!(
+                DeviceRow::USER_ID => [alice_id, 12345],
+                DeviceRow::DEVICE_ID => ["PHONE", "GHOST"],
+                DeviceRow::CREATED_TS => [0_u64, 0],
+            )
+            .expect("Invalid frame"),
+        );
+        write(
+            data_path,
+            &ACCESS_TOKENS,
+            &df!(
+                AccessTokenRow::TOKEN => ["phone", "gone"],
+                AccessTokenRow::USER_ID => [alice_id, alice_id],
+                AccessTokenRow::DEVICE_ID => ["PHONE", "LAPTOP"],
+                AccessTokenRow::CREATED_TS => [0_u64, 0],
+            )
+            .expect("Invalid frame"),
+        );
+
+        // An intact record that can't be decoded
+        let payload = [0xFF, 0xFF];
+        let mut record = 2_u32.to_le_bytes().to_vec();
+        record.extend(crc32fast::hash(&payload).to_le_bytes());
+        record.extend(payload);
+        fs::write(wal::wal_path(&LOGIN_FAILURES.path_in(data_path)), record)
+            .expect("Failed to write log");
+
+        fs::write(data_path.join("users.parquet.tmp"), b"partial")
+            .expect("Failed to write leftover");
+        let staging = data_path.join(events::STAGING_DIR);
+        fs::create_dir_all(&staging).expect("Failed to create staging");
+        fs::write(staging.join("fragment.parquet"), b"partial")
+            .expect("Failed to write leftover");
+
+        // The log and the misplaced identifier
+        2
+    }
+
+    #[test]
+    fn damage_is_reported_and_repaired() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let unrepairable = damaged_data_directory(dir.path());
+
+        // Two leftovers, a null and a duplicate key, and the device and token
+        // refer to an unknown short ID, a missing user and a missing device
+        assert_eq!(
+            check(dir.path(), false).expect("Failed to check"),
+            unrepairable + 7
+        );
+        assert_eq!(height(dir.path(), &USERS), 3);
+        assert!(dir.path().join("users.parquet.tmp").exists());
+
+        assert_eq!(
+            check(dir.path(), true).expect("Failed to repair"),
+            unrepairable
+        );
+        assert_eq!(height(dir.path(), &USERS), 1);
+        assert_eq!(height(dir.path(), &DEVICES), 1);
+        assert_eq!(height(dir.path(), &ACCESS_TOKENS), 1);
+        assert!(!dir.path().join("users.parquet.tmp").exists());
+        assert!(!dir
+            .path()
+            .join(events::STAGING_DIR)
+            .join("fragment.parquet")
+            .exists());
+        assert_eq!(
+            check(dir.path(), false).expect("Failed to check"),
+            unrepairable
+        );
+    }
+
+    #[test]
+    fn repairing_refuses_a_data_directory_in_use() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        schema::initialize(dir.path()).expect("Failed to initialize");
+        let lock = data_lock::lock(dir.path()).expect("Failed to lock");
+
+        let e = check(dir.path(), true).expect_err("Repaired while in use");
+        assert!(e.to_string().contains("in use"), "{e}");
+        assert_eq!(check(dir.path(), false).expect("Failed to check"), 0);
+
+        drop(lock);
+        assert_eq!(check(dir.path(), true).expect("Failed to repair"), 0);
+    }
+}
@@ -0,0 +1,93 @@
+//! Command line interface
+//!
+//! Running `cubby` with no arguments starts the homeserver. Maintenance
+//! commands, which must be run while the homeserver is stopped, are given as
+//! arguments.
+
+use std::{ffi::OsString, path::PathBuf};
+
+use crate::analytics::Format;
+
+/// Describes every command the program accepts
+pub(crate) const USAGE: &str = "\
+Usage:
+  cubby                     Run the homeserver
+  cubby check [--repair]    Look for damage in the data directory, and
+                            repair what can be repaired
+  cubby restore <snapshot>  Replace the data directory with a snapshot
+  cubby sql [--format <json|csv|ipc>] <query>
+                            Run a read-only SQL query against the tables and
+                            write the results to stdout
+  cubby import-synapse <database>
+                            Fill a new data directory from the SQLite
+                            database of a stopped Synapse server";
+
+/// What the program has been asked to do
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Run the homeserver
+    Serve,
+    /// Check the data directory for damage, repairing it if `repair` is set
+    Check {
+        /// Whether to repair what can be repaired
+        repair: bool,
+    },
+    /// Replace the data directory with the snapshot at the given path
+    Restore(PathBuf),
+    /// Run an analytics query and write the results to stdout
+    Sql {
+        /// The SQL query to run
+        query: String,
+        /// The format to write the results in
+        format: Format,
+    },
+    /// Import the database of a Synapse server at the given path into a new
+    /// data directory
+    ImportSynapse(PathBuf),
+}
+
+/// Parse the command line arguments, not including the program name
+///
+/// # Errors
+///
+/// This function will return a message describing the problem if the
+/// arguments don't form a valid command.
+pub(crate) fn parse<I>(args: I) -> Result<Command, String>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let args: Vec<OsString> = args.into_iter().collect();
+    match args.as_slice() {
+        [] => Ok(Command::Serve),
+        [command] if command == "check" => Ok(Command::Check {
+            repair: false,
+        }),
+        [command, flag] if command == "check" && flag == "--repair" => {
+            Ok(Command::Check {
+                repair: true,
+            })
+        }
+        [command, snapshot] if command == "restore" => {
+            Ok(Command::Restore(PathBuf::from(snapshot)))
+        }
+        [command, query] if command == "sql" => Ok(Command::Sql {
+            query: query.to_string_lossy().into_owned(),
+            format: Format::Json,
+        }),
+        [command, flag, format, query]
+            if command == "sql" && flag == "--format" =>
+        {
+            Ok(Command::Sql {
+                query: query.to_string_lossy().into_owned(),
+                format: format.to_string_lossy().parse()?,
+            })
+        }
+        [command, database] if command == "import-synapse" => {
+            Ok(Command::ImportSynapse(PathBuf::from(database)))
+        }
+        [command, ..] => Err(format!(
+            "Unknown command or wrong number of arguments: {}",
+            command.to_string_lossy()
+        )),
+    }
+}
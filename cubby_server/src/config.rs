@@ -9,6 +9,8 @@ use figment::{
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+use crate::uiaa::Stage;
+
 /// The single source of truth for global homeserver configuration
 pub(crate) static PROGRAM_CONFIG: Lazy<Config> = Lazy::new(|| {
     Figment::new()
@@ -20,6 +22,29 @@ pub(crate) static PROGRAM_CONFIG: Lazy<Config> = Lazy::new(|| {
         .expect("Failed to load configuration from environment")
 });
 
+/// Where the homeserver keeps its tables
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Storage {
+    /// Parquet files in `data_path`
+    Parquet,
+    /// In memory only. Everything is lost when the server stops, so this is
+    /// only useful for testing.
+    Memory,
+}
+
+/// An application service, which can log in as the users it manages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Appservice {
+    /// A unique name for the appservice, used in logs
+    pub(crate) id: String,
+    /// The token the appservice sends its requests with
+    pub(crate) as_token: String,
+    /// Regular expressions matching the user IDs of the users the appservice
+    /// manages
+    pub(crate) users: Vec<String>,
+}
+
 /// Represents an instance of the global program configuration
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Config {
@@ -27,6 +52,11 @@ pub(crate) struct Config {
     ///
     /// Defaults to `false`
     pub(crate) _enable_federation: bool,
+    /// The name of the server, which is the part of every local user ID after
+    /// the colon. This can't be changed once users have registered.
+    ///
+    /// Defaults to `"localhost"`
+    pub(crate) server_name: String,
     /// What port the server should listen on.
     ///
     /// Defaults to `3000`
@@ -37,10 +67,28 @@ pub(crate) struct Config {
     /// the server shuts down. This is probably undesirable for your use
     /// case. You should change this directory.
     pub(crate) data_path: PathBuf,
-    /// Where to store media that gets uploaded to the server.
+    /// Where to keep the homeserver's tables, either `"parquet"` or
+    /// `"memory"`.
+    ///
+    /// Defaults to `"parquet"`. Anything stored in memory is lost when the
+    /// server stops.
+    pub(crate) storage: Storage,
+    /// Where to store media that gets uploaded to the server. It is sealed
+    /// like the data directory if `encryption_key_file` is set.
+    ///
+    /// This defaults to a `media` directory inside the default temporary
+    /// `data_path`. If you change `data_path` you should change this too.
+    pub(crate) media_path: PathBuf,
+    /// Where to write snapshots of the data directory.
     ///
-    /// This is optional and will default to `data_path/media/` if unset.
-    pub(crate) _media_path: PathBuf,
+    /// This defaults to a directory beside the default temporary `data_path`.
+    /// If you change `data_path` you should change this too.
+    pub(crate) backup_path: PathBuf,
+    /// How often, in seconds, to take a snapshot of the data directory. Set
+    /// this to 0 to only take snapshots when the server receives SIGUSR1.
+    ///
+    /// Defaults to 0.
+    pub(crate) snapshot_interval: u64,
     /// How long generated device ids should be.
     ///
     /// You probably don't need to change this. Defaults to 16.
@@ -54,6 +102,51 @@ pub(crate) struct Config {
     ///
     /// It defaults to false, obviously.
     pub(crate) allow_registration: bool,
+    /// Usernames nobody can register, on top of those that aren't valid
+    /// user IDs.
+    ///
+    /// Defaults to `["admin", "root"]`
+    pub(crate) reserved_usernames: Vec<String>,
+    /// Regular expressions matching usernames nobody can register, such as
+    /// `"^_irc_"` for the users of an IRC bridge. A pattern matches anywhere
+    /// in the username unless it is anchored, so `"_irc_"` would also deny
+    /// `"alice_irc_"`.
+    ///
+    /// Defaults to none.
+    pub(crate) username_deny_patterns: Vec<String>,
+    /// The flows of user-interactive authentication stages a client can
+    /// complete to register. Completing every stage of any one flow is
+    /// enough. Stages are given by their types: `"m.login.dummy"`,
+    /// `"m.login.registration_token"` or `"m.login.terms"`. An empty list
+    /// lets clients register without authenticating at all.
+    ///
+    /// Defaults to `[["m.login.dummy"]]`, which asks for nothing but still
+    /// gives clients a session, as some expect one.
+    pub(crate) registration_flows: Vec<Vec<Stage>>,
+    /// The flows of stages a user has to complete to be issued a token for
+    /// logging in on another device with `m.login.token`. An empty list
+    /// issues tokens to anyone logged in, without asking for anything more.
+    ///
+    /// Defaults to `[["m.login.password"]]`
+    pub(crate) login_token_flows: Vec<Vec<Stage>>,
+    /// The tokens accepted by the `m.login.registration_token` stage.
+    ///
+    /// Defaults to none.
+    pub(crate) registration_tokens: Vec<String>,
+    /// Where the terms of service agreed to in the `m.login.terms` stage can
+    /// be read.
+    ///
+    /// Defaults to unset, which shows clients no terms.
+    pub(crate) terms_url: Option<String>,
+    /// The version of the terms of service at `terms_url`.
+    ///
+    /// Defaults to `"1.0"`
+    pub(crate) terms_version: String,
+    /// How many seconds a client has to complete user-interactive
+    /// authentication once it has started.
+    ///
+    /// Defaults to 600.
+    pub(crate) uiaa_session_lifetime: u64,
     /// The log level for `tracing_subscriber`
     ///
     /// 0: Errors only
@@ -62,6 +155,75 @@ pub(crate) struct Config {
     /// 3: Debug
     /// >=4: Trace
     pub(crate) log_level: u8,
+    /// How many seconds a request may wait for access to a table before it
+    /// gives up and fails.
+    ///
+    /// Defaults to 30.
+    pub(crate) lock_timeout: u64,
+    /// How many megabytes of memory may be used to keep frequently read
+    /// tables in memory. Set this to 0 to always read tables from disk.
+    ///
+    /// Defaults to 64.
+    pub(crate) cache_budget: usize,
+    /// The names of the tables kept in memory between reads, such as
+    /// `["users", "devices"]`, replacing the tables cached by default. Set
+    /// this to an empty list to never cache tables.
+    ///
+    /// Defaults to unset, which caches the small tables read by most
+    /// requests.
+    pub(crate) cached_tables: Option<Vec<String>>,
+    /// How often, in seconds, the fragments room timelines are stored in are
+    /// merged into larger files.
+    ///
+    /// Defaults to 300.
+    pub(crate) compaction_interval: u64,
+    /// A file holding the keys used to encrypt the data directory. See
+    /// `managers::encryption` for its format and how to rotate keys.
+    ///
+    /// Defaults to unset, which stores everything unencrypted.
+    pub(crate) encryption_key_file: Option<PathBuf>,
+    /// Whether to refuse anything in the data directory that isn't sealed
+    /// from startup on, rather than only once every file has been resealed.
+    /// Turn this on once the server has sealed everything at least once.
+    ///
+    /// Defaults to false.
+    pub(crate) require_sealed_data: bool,
+    /// How many times in a row logging in to an account with a password may
+    /// fail before the account is locked. Set this to 0 to never lock
+    /// accounts.
+    ///
+    /// Defaults to 5.
+    pub(crate) login_failure_limit: u64,
+    /// How many seconds an account stays locked once logging in to it has
+    /// failed too many times. Failures further apart than this aren't counted
+    /// together.
+    ///
+    /// Defaults to 300.
+    pub(crate) login_lockout: u64,
+    /// How many seconds a token issued for `m.login.token` can be used for.
+    ///
+    /// Defaults to 120.
+    pub(crate) login_token_lifetime: u64,
+    /// The application services allowed to log in as their users, as a list
+    /// of tables with an `id`, an `as_token` and a list of `users` patterns.
+    ///
+    /// Defaults to none.
+    pub(crate) appservices: Vec<Appservice>,
+    /// The bearer token required by the admin endpoints, such as the
+    /// analytics endpoint at `/admin/v1/sql`.
+    ///
+    /// Defaults to unset, which turns the admin endpoints off.
+    pub(crate) admin_token: Option<String>,
+    /// The most rows an analytics query may return. Anything past this is
+    /// cut off.
+    ///
+    /// Defaults to 10000.
+    pub(crate) analytics_row_limit: usize,
+    /// How many seconds an analytics query may run for before it is
+    /// cancelled. Every table stays locked against writes while a query runs.
+    ///
+    /// Defaults to 10.
+    pub(crate) analytics_timeout: u64,
 }
 
 impl Default for Config {
@@ -69,27 +231,79 @@ impl Default for Config {
         let temp_dir = tempdir::TempDir::new("cubby")
             .expect("Failed to create temporary directory")
             .into_path();
-        let mut media_temp_dir = temp_dir.clone();
-        media_temp_dir.push("/media");
+        let mut backup_dir = temp_dir.clone().into_os_string();
+        backup_dir.push(".backups");
+        let backup_dir = PathBuf::from(backup_dir);
+        let media_temp_dir = temp_dir.join("media");
         #[cfg(debug_assertions)]
         return Self {
             _enable_federation: false,
+            server_name: "localhost".to_owned(),
             port: 3000,
             data_path: temp_dir,
-            _media_path: media_temp_dir,
+            storage: Storage::Parquet,
+            media_path: media_temp_dir,
+            backup_path: backup_dir,
+            snapshot_interval: 0,
             device_id_length: 16,
             allow_registration: false,
+            reserved_usernames: vec!["admin".to_owned(), "root".to_owned()],
+            username_deny_patterns: Vec::new(),
+            registration_flows: vec![vec![Stage::Dummy]],
+            login_token_flows: vec![vec![Stage::Password]],
+            registration_tokens: Vec::new(),
+            terms_url: None,
+            terms_version: "1.0".to_owned(),
+            uiaa_session_lifetime: 600,
+            login_failure_limit: 5,
+            login_lockout: 300,
+            login_token_lifetime: 120,
+            appservices: Vec::new(),
             log_level: 4,
+            lock_timeout: 30,
+            cache_budget: 64,
+            cached_tables: None,
+            compaction_interval: 300,
+            encryption_key_file: None,
+            require_sealed_data: false,
+            admin_token: None,
+            analytics_row_limit: 10_000,
+            analytics_timeout: 10,
         };
         #[cfg(not(debug_assertions))]
         return Self {
             _enable_federation: false,
+            server_name: "localhost".to_owned(),
             port: 3000,
             data_path: temp_dir,
-            _media_path: media_temp_dir,
+            storage: Storage::Parquet,
+            media_path: media_temp_dir,
+            backup_path: backup_dir,
+            snapshot_interval: 0,
             device_id_length: 16,
             allow_registration: false,
+            reserved_usernames: vec!["admin".to_owned(), "root".to_owned()],
+            username_deny_patterns: Vec::new(),
+            registration_flows: vec![vec![Stage::Dummy]],
+            login_token_flows: vec![vec![Stage::Password]],
+            registration_tokens: Vec::new(),
+            terms_url: None,
+            terms_version: "1.0".to_owned(),
+            uiaa_session_lifetime: 600,
+            login_failure_limit: 5,
+            login_lockout: 300,
+            login_token_lifetime: 120,
+            appservices: Vec::new(),
             log_level: 2,
+            lock_timeout: 30,
+            cache_budget: 64,
+            cached_tables: None,
+            compaction_interval: 300,
+            encryption_key_file: None,
+            require_sealed_data: false,
+            admin_token: None,
+            analytics_row_limit: 10_000,
+            analytics_timeout: 10,
         };
     }
 }
@@ -0,0 +1,73 @@
+//! Keeping offline commands away from a running server
+//!
+//! The server holds an exclusive lock on `{data_path}/cubby.lock` for as long
+//! as it runs. Commands that change the data directory behind the server's
+//! back, such as `cubby restore`, `cubby check --repair`, `cubby sql` and
+//! `cubby import-synapse`, take the same lock first and refuse to run if they
+//! can't get it.
+//!
+//! The lock is an advisory lock on the open file rather than the file's
+//! existence, so the operating system releases it when the process exits and
+//! a server that crashed never leaves a stale lock behind.
+
+use std::{
+    fs::{self, File, TryLockError},
+    path::Path,
+};
+
+use polars::prelude::*;
+
+/// The name of the lock file inside the data directory
+const LOCK_FILE: &str = "cubby.lock";
+
+/// An exclusive lock on a data directory, held until this is dropped
+#[derive(Debug)]
+pub(crate) struct DataLock {
+    /// The open lock file, which the lock is held through
+    _file: File,
+}
+
+/// Lock the data directory at `data_path`, creating it if it doesn't exist
+///
+/// # Errors
+///
+/// This function will return an error if another process, such as a running
+/// server, already holds the lock, or if the lock file can't be opened.
+pub(crate) fn lock(data_path: &Path) -> PolarsResult<DataLock> {
+    fs::create_dir_all(data_path)?;
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(data_path.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(DataLock {
+            _file: file,
+        }),
+        Err(TryLockError::WouldBlock) => Err(PolarsError::ComputeError(
+            format!(
+                "{} is in use by another cubby process, such as a running \
+                 server. Stop it first.",
+                data_path.display()
+            )
+            .into(),
+        )),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::lock;
+
+    #[test]
+    fn only_one_process_holds_the_lock() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let held = lock(dir.path()).expect("Failed to lock");
+        assert!(lock(dir.path()).is_err());
+        drop(held);
+        assert!(lock(dir.path()).is_ok());
+    }
+}
@@ -1,12 +1,28 @@
 #![doc = include_str!("../../README.md")]
 
+mod analytics;
+mod appservices;
+mod auth;
+mod check;
+mod cli;
 mod config;
+mod data_lock;
 mod managers;
+mod schema;
+mod snapshot;
+mod synapse;
+mod uiaa;
+mod username;
 
 mod api;
-// mod utils;
 
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
 use config::PROGRAM_CONFIG;
 #[cfg(all(not(target_env = "msvc"), feature = "jemalloc"))]
@@ -19,12 +35,38 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 use axum::{
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
+use cubby_lib::SharedTokenValidator;
+use managers::storage::StorageAccess;
+use tracing::{error, info, warn};
 use tracing_subscriber::filter::LevelFilter;
 
+/// Resolves once the process is asked to stop with SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(
+            tokio::signal::unix::SignalKind::terminate(),
+        )
+        .expect("Failed to listen for SIGTERM")
+        .recv()
+        .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = interrupt => {},
+        () = terminate => {},
+    }
+    info!("Shutting down, waiting for in-flight requests to finish");
+}
+
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     // Initialize logging
     tracing_subscriber::fmt::fmt()
         .with_max_level(match PROGRAM_CONFIG.log_level {
@@ -35,7 +77,140 @@ async fn main() {
             _ => LevelFilter::TRACE,
         })
         .init();
-    // utils::setup_dataframes();
+    let command = match cli::parse(std::env::args_os().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}\n\n{}", cli::USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    match command {
+        cli::Command::Serve => serve().await,
+        cli::Command::Check {
+            repair,
+        } => match check::check(&PROGRAM_CONFIG.data_path, repair) {
+            Ok(0) => ExitCode::SUCCESS,
+            Ok(_) => ExitCode::FAILURE,
+            Err(e) => {
+                error!("Failed to check the data directory: {e}");
+                ExitCode::FAILURE
+            }
+        },
+        cli::Command::Restore(snapshot) => {
+            match snapshot::restore(&snapshot, &PROGRAM_CONFIG.data_path) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    error!("Failed to restore snapshot: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        cli::Command::Sql {
+            query,
+            format,
+        } => sql(&query, format).await,
+        cli::Command::ImportSynapse(database) => {
+            match synapse::import(&database).await {
+                Ok(_) => ExitCode::SUCCESS,
+                Err(e) => {
+                    error!("Failed to import the Synapse database: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+    }
+}
+
+/// Run an analytics query against the data directory and write the results
+/// to stdout
+async fn sql(query: &str, format: analytics::Format) -> ExitCode {
+    polars::enable_string_cache();
+    // Initializing storage replays logs and migrates tables, which must not
+    // happen behind a running server's back
+    let _data_lock = match data_lock::lock(&PROGRAM_CONFIG.data_path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let storage = managers::storage::from_config();
+    if let Err(e) = storage.initialize() {
+        error!("Failed to initialize storage: {e}");
+        return ExitCode::FAILURE;
+    }
+    let file_manager = cubby_lib::FileManager::new().with_storage(storage);
+    let result = analytics::query(
+        &file_manager,
+        query,
+        PROGRAM_CONFIG.analytics_row_limit,
+    )
+    .await
+    .and_then(|mut result| {
+        if result.truncated {
+            warn!(
+                "Only the first {} rows are shown",
+                PROGRAM_CONFIG.analytics_row_limit
+            );
+        }
+        analytics::encode(&mut result.rows, format)
+    });
+    match result {
+        Ok(body) => match std::io::stdout().write_all(&body) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("Failed to write the results: {e}");
+                ExitCode::FAILURE
+            }
+        },
+        Err(e) => {
+            error!("Failed to run the query: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Run the homeserver until it is asked to shut down
+async fn serve() -> ExitCode {
+    // Categorical columns can only be compared and joined across frames if
+    // they share a string cache
+    polars::enable_string_cache();
+    // Keep `cubby restore` and `cubby check --repair` from changing the data
+    // directory while it is in use
+    let _data_lock = match data_lock::lock(&PROGRAM_CONFIG.data_path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Make sure every table exists and is at the current schema version
+    let storage = managers::storage::from_config();
+    if let Err(e) = storage.initialize() {
+        error!("Failed to initialize storage: {e}");
+        return ExitCode::FAILURE;
+    }
+    // A username policy that can't be applied would refuse every
+    // registration, a broken appservice could never log in, and a misspelled
+    // table would silently never be cached
+    if let Err(e) = username::check_policy()
+        .and_then(|()| appservices::check_config())
+        .and_then(|()| schema::check_cached_tables())
+    {
+        error!("{e}");
+        return ExitCode::FAILURE;
+    }
+    let file_manager = cubby_lib::FileManager::new()
+        .with_timeout(Duration::from_secs(PROGRAM_CONFIG.lock_timeout))
+        .with_cache_budget(PROGRAM_CONFIG.cache_budget.saturating_mul(1 << 20))
+        .with_storage(storage);
+    // Merge the small files new timeline events are written to in the
+    // background
+    tokio::spawn(managers::events::compactor(file_manager.clone()));
+    // Take snapshots of the data directory when asked to
+    tokio::spawn(snapshot::snapshotter(file_manager.clone()));
+    // Reseal anything not encrypted with the current key in the background
+    tokio::spawn(managers::encryption::rotator(file_manager.clone()));
     // Create basic app
     let app = Router::new()
         .route(
@@ -46,7 +221,21 @@ async fn main() {
             "/client/v3/register/available",
             get(api::client::accounts::get_username_availability::endpoint),
         )
-        .with_state(cubby_lib::FileManager::new());
+        .route(
+            "/client/v3/login",
+            get(api::client::session::get_login_types::endpoint)
+                .post(api::client::session::login::endpoint),
+        )
+        .route(
+            "/client/v1/login/get_token",
+            post(api::client::session::get_login_token::endpoint),
+        )
+        .route("/admin/v1/sql", post(api::admin::sql::endpoint))
+        // Lets handlers find out who sent a request
+        .layer(Extension::<SharedTokenValidator>(Arc::new(
+            auth::AccessTokens(file_manager.clone()),
+        )))
+        .with_state(file_manager.clone());
     // Create listener
     let socket_addr =
         SocketAddr::new(IpAddr::from([0, 0, 0, 0]), PROGRAM_CONFIG.port);
@@ -54,5 +243,24 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind(socket_addr)
         .await
         .expect("Failed to start listener");
-    axum::serve(listener, app).await.expect("Failed to serve axum app");
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .expect("Failed to serve axum app");
+    // Every handler has finished, but tables may still be being written back
+    // to disk. Those writes hold their locks until they are done.
+    info!("Waiting for pending writes to finish");
+    if let Err(e) = file_manager.shutdown().await {
+        error!("Failed to wait for pending writes: {e}");
+        return ExitCode::FAILURE;
+    }
+    if managers::dataframes::writeback_failed() {
+        error!(
+            "Some changes could not be written back to disk. They will be \
+             recovered from the write-ahead log on the next start."
+        );
+        return ExitCode::FAILURE;
+    }
+    info!("Shutdown complete");
+    ExitCode::SUCCESS
 }
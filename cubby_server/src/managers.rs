@@ -1,3 +1,11 @@
 //! Managers that will be loaded into Axum's managed state
 
 pub(crate) mod dataframes;
+pub(crate) mod encryption;
+pub(crate) mod events;
+pub(crate) mod index;
+pub(crate) mod interner;
+pub(crate) mod media;
+pub(crate) mod storage;
+pub(crate) mod transaction;
+pub(crate) mod wal;
@@ -1,159 +1,852 @@
-//! Manages the dataframes used by the program
+//! Locked access to the tables of the homeserver
 //!
-//! This module honestly sucks and should be remade entirely in the future.
+//! Every read and write of a table goes through `ParquetManager`, which locks
+//! the table with the `FileManager` before touching the storage backend.
+//! Readers get a `SharedLazyFrame` or the rows of a `get_by_key` lookup, and
+//! hold a shared lock until they are done. Writers get a `ManagedLazyFrame`,
+//! which holds an exclusive lock, records every change in the table's journal
+//! before applying it, and writes the new contents back when it is dropped.
+//!
+//! The journal is only cleared once a write-back has succeeded. If one fails,
+//! the changes it held have still been acknowledged, so every read applies
+//! whatever is left in the journal on top of the stored table until the next
+//! writer or a restart writes it back. Cached tables are only ever cached
+//! without pending changes.
+//!
+//! This module also holds the helpers used to atomically replace parquet
+//! files.
 
-use std::path::PathBuf;
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use crossbeam_channel::{unbounded, Sender};
-use cubby_lib::file_manager::{FileLock, FileManager, Message, Receive};
+use cubby_lib::file_manager::{
+    FileLock, FileManager, FileManagerError, LockMode, Message, Receive,
+};
 use polars::prelude::*;
-use tracing::{instrument, trace};
-
-/// A message requesting that the file manager return a `LazyFrame` for the
-/// given path
-pub(crate) struct GetLazyFrame<P>(P)
-where
-    P: Into<PathBuf>;
-impl<P> Message for GetLazyFrame<P>
-where
-    P: Into<PathBuf>,
-{
-    type Response = Result<LazyFrame, PolarsError>;
-}
-impl<P> Receive<GetLazyFrame<P>> for FileManager
-where
-    P: Into<PathBuf>,
-{
+use tracing::{error, instrument, trace};
+
+use crate::{
+    managers::{
+        encryption,
+        index::KeyValue,
+        storage::{Journal, StorageAccess, StorageBackend},
+        wal::Mutation,
+    },
+    schema::Table,
+};
+
+/// A message requesting that the file manager return a `SharedLazyFrame` for
+/// the given table
+pub(crate) struct GetLazyFrame(&'static Table);
+
+impl Message for GetLazyFrame {
+    type Response = PolarsResult<SharedLazyFrame>;
+}
+
+impl Receive<GetLazyFrame> for FileManager {
     async fn handle(
         &self,
-        message: GetLazyFrame<P>,
-    ) -> <GetLazyFrame<P> as Message>::Response {
-        let path = message.0.into();
-        LazyFrame::scan_parquet(path, ScanArgsParquet::default())
+        message: GetLazyFrame,
+    ) -> <GetLazyFrame as Message>::Response {
+        let table = message.0;
+        let lock =
+            self.lock_shared(table.path()).await.map_err(|e| lock_error(&e))?;
+        SharedLazyFrame::new(self, table, lock)
+    }
+}
+
+/// A message requesting that the file manager return a `SharedLazyFrame` for
+/// each of the given tables, all locked at once
+pub(crate) struct GetLazyFrames(Vec<&'static Table>);
+
+impl Message for GetLazyFrames {
+    type Response = PolarsResult<Vec<SharedLazyFrame>>;
+}
+
+impl Receive<GetLazyFrames> for FileManager {
+    async fn handle(
+        &self,
+        message: GetLazyFrames,
+    ) -> <GetLazyFrames as Message>::Response {
+        let tables = message.0;
+        let mut locks = self
+            .lock_many(
+                tables
+                    .iter()
+                    .map(|table| (table.path(), LockMode::Shared))
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .map_err(|e| lock_error(&e))?;
+        // Locks are returned sorted by path rather than in the order the
+        // tables were given in
+        tables
+            .iter()
+            .map(|table| {
+                let path = table.path();
+                let position = locks
+                    .iter()
+                    .position(|lock| lock.get_path() == path)
+                    .ok_or_else(|| {
+                        PolarsError::ComputeError(
+                            format!(
+                                "Table {} was given more than once",
+                                table.name
+                            )
+                            .into(),
+                        )
+                    })?;
+                SharedLazyFrame::new(self, table, locks.swap_remove(position))
+            })
+            .collect()
     }
 }
 
 /// A message requesting that the file manager return a `ManagedLazyFrame` for
-/// the given path
-pub(crate) struct GetManagedLazyFrame<P>(P)
-where
-    P: Into<PathBuf>;
-
-impl<P> Message for GetManagedLazyFrame<P>
-where
-    P: Into<PathBuf>,
-{
-    type Response = ManagedLazyFrame;
-}
-
-impl<P> Receive<GetManagedLazyFrame<P>> for FileManager
-where
-    P: Into<PathBuf>,
-{
+/// the given table
+pub(crate) struct GetManagedLazyFrame(&'static Table);
+
+impl Message for GetManagedLazyFrame {
+    type Response = PolarsResult<ManagedLazyFrame>;
+}
+
+impl Receive<GetManagedLazyFrame> for FileManager {
     async fn handle(
         &self,
-        message: GetManagedLazyFrame<P>,
-    ) -> ManagedLazyFrame {
-        let path = message.0.into();
-        let lock = self.lock(path).await;
-        ManagedLazyFrame::new(lock)
+        message: GetManagedLazyFrame,
+    ) -> <GetManagedLazyFrame as Message>::Response {
+        let table = message.0;
+        let lock = self
+            .lock_exclusive(table.path())
+            .await
+            .map_err(|e| lock_error(&e))?;
+        ManagedLazyFrame::new(self, table, lock)
     }
 }
 
-/// A wrapper around a given `LazyFrame`.
+/// A message requesting that the file manager return every row of a table with
+/// a given value in one of its columns
+pub(crate) struct GetByKey {
+    /// The table to search
+    table: &'static Table,
+    /// The column to search, ideally the first key column or an indexed one
+    column: &'static str,
+    /// The value rows must have in `column`
+    value: KeyValue,
+}
+
+impl Message for GetByKey {
+    type Response = PolarsResult<DataFrame>;
+}
+
+impl Receive<GetByKey> for FileManager {
+    async fn handle(
+        &self,
+        message: GetByKey,
+    ) -> <GetByKey as Message>::Response {
+        let GetByKey {
+            table,
+            column,
+            value,
+        } = message;
+        let lock =
+            self.lock_shared(table.path()).await.map_err(|e| lock_error(&e))?;
+        self.check_poisoned(table)?;
+        let storage = self.storage()?;
+        let cached = table
+            .is_cached()
+            .then(|| self.cached::<DataFrame>(&lock))
+            .flatten();
+        let frame = match cached {
+            Some(df) => df.as_ref().clone().lazy(),
+            None => match storage.indexes().candidates(table, column, &value) {
+                // Pending changes are applied to the candidate rows alone.
+                // Rows they change or add are filtered like any other.
+                Some(ranges) => {
+                    let rows = storage.read_ranges(table, &ranges)?.lazy();
+                    apply_pending(storage, table, rows)?.0
+                }
+                None => load(self, table, &lock)?.0,
+            },
+        };
+        let rows = frame.filter(col(column).eq(value.lit())).collect();
+        drop(lock);
+        rows
+    }
+}
+
+/// Set once any `ManagedLazyFrame` fails to write its changes back to disk
+static WRITEBACK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether any `ManagedLazyFrame` has failed to write its changes back to
+/// disk since the server started.
 ///
-/// This struct has a custom Drop implementation that will send the current
-/// contents back to the `DataframeManager` that created it to write changes to
-/// disk.
-pub(crate) struct ManagedLazyFrame {
+/// Changes that failed to write back are still in the table's write-ahead log
+/// and will be applied on the next startup.
+pub(crate) fn writeback_failed() -> bool {
+    WRITEBACK_FAILED.load(Ordering::SeqCst)
+}
+
+/// Record that changes could not be written back to disk, so that shutdown
+/// reports it
+pub(crate) fn set_writeback_failed() {
+    WRITEBACK_FAILED.store(true, Ordering::SeqCst);
+}
+
+/// Convert a failure to lock a table into a `PolarsError`
+pub(crate) fn lock_error(e: &FileManagerError) -> PolarsError {
+    PolarsError::ComputeError(format!("Failed to lock table: {e}").into())
+}
+
+/// Apply every change still in the journal of `table` to `frame`, returning
+/// the result and whether there were any.
+///
+/// A journal only holds changes after a write-back failed, and those changes
+/// were acknowledged before it did, so no read may leave them out.
+fn apply_pending(
+    storage: &dyn StorageBackend,
+    table: &Table,
+    mut frame: LazyFrame,
+) -> PolarsResult<(LazyFrame, bool)> {
+    let pending = storage.journal(table)?.pending()?;
+    for mutation in &pending {
+        frame = mutation.apply_to(frame, table)?;
+    }
+    Ok((frame, !pending.is_empty()))
+}
+
+/// Load the contents of `table` while holding `lock` on it, with any changes
+/// still in its journal applied. Returns whether there were any.
+///
+/// Tables that are cached (see `Table::is_cached`) are read from the
+/// `FileManager`'s cache if possible, and cached after being read from storage
+/// otherwise, unless their journal holds changes.
+fn load(
+    file_manager: &FileManager,
+    table: &Table,
+    lock: &FileLock,
+) -> PolarsResult<(LazyFrame, bool)> {
+    if table.is_cached() {
+        if let Some(df) = file_manager.cached::<DataFrame>(lock) {
+            return Ok((df.as_ref().clone().lazy(), false));
+        }
+    }
+    let storage = file_manager.storage()?;
+    let (frame, pending) = apply_pending(storage, table, storage.read(table)?)?;
+    if !table.is_cached() || pending {
+        return Ok((frame, pending));
+    }
+    let df = frame.collect()?;
+    let size = df.estimated_size();
+    file_manager.cache(lock, Arc::new(df.clone()), size);
+    Ok((df.lazy(), false))
+}
+
+/// A read-only `LazyFrame` holding a shared lock on the file underneath it.
+///
+/// Because polars scans files lazily, the file has to stay in place until a
+/// query against it has been collected. The lock is held until this struct is
+/// dropped, which keeps writers from replacing the file mid-scan.
+pub(crate) struct SharedLazyFrame {
     /// The internal `LazyFrame`
     frame: LazyFrame,
-    /// The lock on the file underneath this `LazyFrame`. Once dropped, this
-    /// will unlock the file for other threads to access it.
+    /// The shared lock on the file underneath this `LazyFrame`
     _lock: FileLock,
-    /// The transmitter for sending the internal `frame` back to the manager
-    tx: Sender<LazyFrame>,
 }
 
-impl ManagedLazyFrame {
-    /// Create a new `ManagedLazyFrame`
-    pub(crate) fn new(lock: FileLock) -> Self {
-        let (tx, rx) = unbounded::<LazyFrame>();
-        tokio::spawn(async move {
-            let Ok(received) = rx.recv() else {
-                panic!("ManagedLazyFrame channel disconnected before drop");
-            };
-            let _frame = received.collect();
-            // TODO: Merge frame here
-        });
+impl SharedLazyFrame {
+    /// Create a new `SharedLazyFrame`
+    pub(crate) fn new(
+        file_manager: &FileManager,
+        table: &Table,
+        lock: FileLock,
+    ) -> PolarsResult<Self> {
+        file_manager.check_poisoned(table)?;
+        Ok(Self {
+            frame: load(file_manager, table, &lock)?.0,
+            _lock: lock,
+        })
+    }
+
+    /// Wrap a `LazyFrame` that reads the files protected by `lock`
+    pub(crate) fn from_frame(frame: LazyFrame, lock: FileLock) -> Self {
         Self {
-            frame: LazyFrame::scan_parquet(
-                lock.get_path_owned(),
-                ScanArgsParquet::default(),
-            )
-            .expect("Failed to scan parquet file"),
+            frame,
             _lock: lock,
-            tx,
         }
     }
 
-    /// Run a closure taking the internal `LazyFrame` as an argument, replacing
-    /// the internal frame with its result
+    /// Build a query from the internal `LazyFrame` and collect it while the
+    /// file is still locked
+    pub(crate) fn query<F: FnOnce(LazyFrame) -> LazyFrame>(
+        &self,
+        closure: F,
+    ) -> PolarsResult<DataFrame> {
+        closure(self.frame.clone()).collect()
+    }
+
+    /// The internal `LazyFrame`. It may scan the locked file, so it has to be
+    /// collected before this struct is dropped.
+    pub(crate) fn frame(&self) -> LazyFrame {
+        self.frame.clone()
+    }
+}
+
+/// The state a `ManagedLazyFrame` hands to its write-back task on drop
+struct Writeback {
+    /// The lock on the file underneath the `LazyFrame`. The file stays locked
+    /// until the new contents have replaced the old ones on disk.
+    lock: FileLock,
+    /// The journal of every change made through the `ManagedLazyFrame`
+    journal: Box<dyn Journal>,
+    /// The manager whose cache has to be updated with the new contents
+    file_manager: FileManager,
+}
+
+/// A wrapper around a given `LazyFrame`.
+///
+/// Changes are made with `apply`, which records them in the table's journal
+/// before applying them to the internal frame. This struct has a custom Drop
+/// implementation that will collect the current contents and write them back
+/// to storage before clearing the journal and releasing the lock on the
+/// underlying file.
+pub(crate) struct ManagedLazyFrame {
+    /// The internal `LazyFrame`
+    frame: LazyFrame,
+    /// The table this frame holds the contents of
+    table: &'static Table,
+    /// Whether the frame differs from the file on disk
+    dirty: bool,
+    /// Moved into the write-back task on drop
+    writeback: Option<Writeback>,
+}
+
+impl ManagedLazyFrame {
+    /// Create a new `ManagedLazyFrame`
     ///
-    /// This function exists because pretty much all the methods for
-    /// `DataFrame` an`LazyFrame`me have the `fn x(self, …) -> Self` function
-    /// signature pattern, meaning it's basically impossible to use references
-    /// in any productive way and any data processing at endpoints would require
-    /// a large number of clones and a convoluted mess of channels. With this
-    /// approach, we bring the function to the data because we can't bring the
-    /// data to the function.
+    /// If the table's journal still holds changes from an earlier write-back
+    /// that failed, they are applied to the frame and written back with it.
+    pub(crate) fn new(
+        file_manager: &FileManager,
+        table: &'static Table,
+        lock: FileLock,
+    ) -> PolarsResult<Self> {
+        file_manager.check_poisoned(table)?;
+        let journal = file_manager.storage()?.journal(table)?;
+        let (frame, pending) = load(file_manager, table, &lock)?;
+        Ok(Self {
+            frame,
+            table,
+            dirty: pending,
+            writeback: Some(Writeback {
+                lock,
+                journal,
+                file_manager: file_manager.clone(),
+            }),
+        })
+    }
+
+    /// Durably record a change to the table and apply it to the internal
+    /// frame.
+    ///
+    /// Once this returns `Ok` the change has been recorded in the table's
+    /// journal and will survive a crash, so it is safe to acknowledge it to the
+    /// client. The table itself is rewritten when this struct is dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the change could not be written
+    /// to the journal or could not be applied to the frame. In either case the
+    /// frame is left unchanged.
+    pub(crate) fn apply(&mut self, mutation: &Mutation) -> PolarsResult<()> {
+        let frame = mutation.apply_to(self.frame.clone(), self.table)?;
+        let Some(writeback) = self.writeback.as_mut() else {
+            return Err(PolarsError::ComputeError(
+                "ManagedLazyFrame has already been written back".into(),
+            ));
+        };
+        writeback.journal.append(mutation)?;
+        self.frame = frame;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Durably record a change in the table's journal without applying it to
+    /// the internal frame. `apply_recorded` has to be called once every change
+    /// is recorded.
+    ///
+    /// # Errors
     ///
-    /// It is theoretically (and trivially) possible to extract the internal
-    /// `LazyFrame` from this closure and replace it with an empty one.
-    /// Because of this, this pattern should not be depended on as a safety
-    /// feature.
-    // This function currently has an underscore prefix because it is not being
-    // used yet. It will be used in the near future once frames need to start
-    // being modified and have those changed written to disk.
-    pub(crate) fn _apply<F: FnOnce(LazyFrame) -> LazyFrame>(
-        mut self,
+    /// This function will return an error if the change could not be written
+    /// to the journal.
+    pub(crate) fn record(&mut self, mutation: &Mutation) -> PolarsResult<()> {
+        let Some(writeback) = self.writeback.as_mut() else {
+            return Err(PolarsError::ComputeError(
+                "ManagedLazyFrame has already been written back".into(),
+            ));
+        };
+        writeback.journal.append(mutation)
+    }
+
+    /// Replace the internal frame with `frame`, which has to be the current
+    /// one with every change recorded through `record` applied
+    pub(crate) fn apply_recorded(&mut self, frame: LazyFrame) {
+        self.frame = frame;
+        self.dirty = true;
+    }
+
+    /// Release the lock on the table without writing anything back. Changes
+    /// already in its journal are applied by the next writer or on startup.
+    pub(crate) fn discard(mut self) {
+        self.writeback = None;
+    }
+
+    /// Build a query from the internal `LazyFrame`, including every change
+    /// applied so far, and collect it while the file is still locked
+    pub(crate) fn query<F: FnOnce(LazyFrame) -> LazyFrame>(
+        &self,
         closure: F,
-    ) {
-        self.frame = closure(self.frame.clone());
+    ) -> PolarsResult<DataFrame> {
+        closure(self.frame.clone()).collect()
+    }
+
+    /// The internal `LazyFrame`, including every change applied so far. It
+    /// may scan the locked file, so it has to be collected before this struct
+    /// is dropped.
+    pub(crate) fn frame(&self) -> LazyFrame {
+        self.frame.clone()
     }
 }
 
 impl Drop for ManagedLazyFrame {
     #[instrument(level = "trace", skip(self))]
     fn drop(&mut self) {
-        trace!("Attempting to send LazyFrame back to manager during drop");
-        self.tx.send(self.frame.clone()).expect("TODO: panic message");
+        let Some(Writeback {
+            lock,
+            mut journal,
+            file_manager,
+        }) = self.writeback.take()
+        else {
+            return;
+        };
+        if !self.dirty {
+            return;
+        }
+        trace!("Writing LazyFrame back to storage during drop");
+        let frame = std::mem::take(&mut self.frame);
+        let table = self.table;
+        tokio::task::spawn_blocking(move || {
+            // The journal is only cleared once the table has been rewritten.
+            // If the rewrite fails the changes stay in the journal and are
+            // applied by the next writer or on startup.
+            let result = frame.collect().and_then(|mut df| {
+                file_manager.storage()?.write(table, &mut df)?;
+                journal.clear()?;
+                Ok(df)
+            });
+            // The cache is updated before the lock is released, so readers
+            // never see a cached copy that differs from what is stored
+            match result {
+                Ok(df) if table.is_cached() => {
+                    let size = df.estimated_size();
+                    file_manager.cache(&lock, Arc::new(df), size);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    file_manager.uncache(&lock);
+                    set_writeback_failed();
+                    error!(
+                        "Failed to write table {} back to storage: {e}",
+                        table.name
+                    );
+                }
+            }
+            drop(lock);
+        });
     }
 }
 
+/// Returns the path of the temporary file used while rewriting `path`
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temp: OsString = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+/// Collect a `LazyFrame` and atomically replace the parquet file at `path`
+/// with its contents.
+///
+/// The frame is written to a temporary file beside the original, flushed to
+/// disk, and then renamed over the original. Readers will either see the old
+/// file or the new one, never a partially written one. The caller is expected
+/// to hold the `FileLock` for `path` for the duration of this call.
+///
+/// # Errors
+///
+/// This function will return an error if collecting the frame fails or if any
+/// of the filesystem operations fail. In either case the original file is left
+/// untouched.
+pub(crate) fn write_parquet_atomic(
+    path: &Path,
+    frame: LazyFrame,
+) -> PolarsResult<()> {
+    write_dataframe_atomic(path, &mut frame.collect()?)
+}
+
+/// Atomically replace the parquet file at `path` with the contents of `df`.
+///
+/// See `write_parquet_atomic` for details.
+pub(crate) fn write_dataframe_atomic(
+    path: &Path,
+    df: &mut DataFrame,
+) -> PolarsResult<()> {
+    write_row_groups_atomic(path, df, None)
+}
+
+/// Atomically replace the parquet file at `path` with the contents of `df`,
+/// split into row groups of `row_group_size` rows.
+///
+/// See `write_parquet_atomic` for details.
+pub(crate) fn write_row_groups_atomic(
+    path: &Path,
+    df: &mut DataFrame,
+    row_group_size: Option<usize>,
+) -> PolarsResult<()> {
+    let temp_path = temporary_path(path);
+    let result = write_and_rename(&temp_path, path, df, row_group_size);
+    if result.is_err() && temp_path.exists() {
+        if let Err(e) = fs::remove_file(&temp_path) {
+            error!(
+                "Failed to clean up temporary file {}: {e}",
+                temp_path.display()
+            );
+        }
+    }
+    result
+}
+
+/// Write `df` to `temp_path`, flush it, and rename it over `path`
+fn write_and_rename(
+    temp_path: &Path,
+    path: &Path,
+    df: &mut DataFrame,
+    row_group_size: Option<usize>,
+) -> PolarsResult<()> {
+    let mut file = File::create(temp_path)?;
+    encryption::write_parquet(&mut file, df, row_group_size)?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)?;
+    // Make sure the rename itself survives a crash
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Encode `df` as parquet into `writer`.
+///
+/// If `row_group_size` is given, every row group holds exactly that many rows
+/// besides the last one. Otherwise polars picks the size.
+///
+/// # Errors
+///
+/// This function will return an error if encoding or writing fails.
+pub(crate) fn encode_parquet<W: Write>(
+    writer: W,
+    df: &mut DataFrame,
+    row_group_size: Option<usize>,
+) -> PolarsResult<()> {
+    let writer = ParquetWriter::new(writer);
+    match row_group_size {
+        // The writer evens out the size of row groups, so each one is written
+        // as its own batch to keep them at exactly `size` rows
+        Some(size) => {
+            df.as_single_chunk_par();
+            let mut batched = writer.batched(&df.schema())?;
+            if df.height() == 0 {
+                batched.write_batch(df)?;
+            }
+            for offset in (0..df.height()).step_by(size.max(1)) {
+                let offset = i64::try_from(offset).map_err(|e| {
+                    PolarsError::ComputeError(e.to_string().into())
+                })?;
+                batched.write_batch(&df.slice(offset, size))?;
+            }
+            batched.finish()?;
+        }
+        None => {
+            writer.finish(df)?;
+        }
+    }
+    Ok(())
+}
+
 /// Functionality required for managing the parquet files used by the cubby
 /// server
-pub(crate) trait ParquetManager<P> {
-    /// Get an unmanaged `LazyFrame`. If data needs to be mutated in a way that
-    /// is written to persistent storage, `get_managed_lazyframe` should be used
-    /// instead.
-    async fn get_lazyframe(&self, path: P) -> Result<LazyFrame, PolarsError>;
+pub(crate) trait ParquetManager {
+    /// Get a read-only `LazyFrame` that holds a shared lock on the table. If
+    /// data needs to be mutated in a way that is written to persistent
+    /// storage, `get_managed_lazyframe` should be used instead.
+    async fn get_lazyframe(
+        &self,
+        table: &'static Table,
+    ) -> PolarsResult<SharedLazyFrame>;
+    /// Get a read-only `LazyFrame` for each of `tables`, in the same order.
+    /// Every table is locked at once, so the frames are consistent with each
+    /// other and waiting for them can't deadlock with other requests.
+    async fn get_lazyframes(
+        &self,
+        tables: &[&'static Table],
+    ) -> PolarsResult<Vec<SharedLazyFrame>>;
     /// Get a managed `LazyFrame`. When dropped, any changes made to the
     /// internal `LazyFrame` via the `apply()` method will be written to disk.
     /// If data should not be written to disk when the `LazyFrame` is dropped,
     /// `get_lazyframe` should be used instead.
-    async fn get_managed_lazyframe(&self, path: P) -> ManagedLazyFrame;
+    async fn get_managed_lazyframe(
+        &self,
+        table: &'static Table,
+    ) -> PolarsResult<ManagedLazyFrame>;
+    /// Get every row of a table with `value` in `column`.
+    ///
+    /// If `column` is the first column of the table's key or one of its
+    /// indexed columns, only the blocks of the table that can hold `value` are
+    /// read, unless the table is already cached in memory. Any other column
+    /// works, but searches the whole table.
+    async fn get_by_key(
+        &self,
+        table: &'static Table,
+        column: &'static str,
+        value: impl Into<KeyValue>,
+    ) -> PolarsResult<DataFrame>;
 }
 
-impl<P> ParquetManager<P> for FileManager
-where
-    P: Into<PathBuf>,
-{
-    async fn get_lazyframe(&self, path: P) -> Result<LazyFrame, PolarsError> {
-        self.handle(GetLazyFrame(path)).await
+impl ParquetManager for FileManager {
+    async fn get_lazyframe(
+        &self,
+        table: &'static Table,
+    ) -> PolarsResult<SharedLazyFrame> {
+        self.handle(GetLazyFrame(table)).await
+    }
+
+    async fn get_lazyframes(
+        &self,
+        tables: &[&'static Table],
+    ) -> PolarsResult<Vec<SharedLazyFrame>> {
+        self.handle(GetLazyFrames(tables.to_vec())).await
     }
 
-    async fn get_managed_lazyframe(&self, path: P) -> ManagedLazyFrame {
-        self.handle(GetManagedLazyFrame(path)).await
+    async fn get_managed_lazyframe(
+        &self,
+        table: &'static Table,
+    ) -> PolarsResult<ManagedLazyFrame> {
+        self.handle(GetManagedLazyFrame(table)).await
+    }
+
+    async fn get_by_key(
+        &self,
+        table: &'static Table,
+        column: &'static str,
+        value: impl Into<KeyValue>,
+    ) -> PolarsResult<DataFrame> {
+        self.handle(GetByKey {
+            table,
+            column,
+            value: value.into(),
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{Seek, SeekFrom, Write},
+        sync::Arc,
+    };
+
+    use cubby_lib::FileManager;
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::ParquetManager;
+    use crate::{
+        managers::{
+            encryption,
+            index::{Indexes, BLOCK_ROWS},
+            storage::{
+                Commit, Journal, ParquetBackend, StorageAccess, StorageBackend,
+            },
+            wal::Mutation,
+        },
+        schema::{ParquetRow, Table, UserRow, PROFILES, USERS},
+    };
+
+    /// A parquet backend that fails to write any table back, as if the disk
+    /// was full
+    struct FullBackend(ParquetBackend);
+
+    impl StorageBackend for FullBackend {
+        fn initialize(&self) -> PolarsResult<()> {
+            self.0.initialize()
+        }
+
+        fn indexes(&self) -> &Indexes {
+            self.0.indexes()
+        }
+
+        fn read(&self, table: &Table) -> PolarsResult<LazyFrame> {
+            self.0.read(table)
+        }
+
+        fn read_ranges(
+            &self,
+            table: &Table,
+            ranges: &[(usize, usize)],
+        ) -> PolarsResult<DataFrame> {
+            self.0.read_ranges(table, ranges)
+        }
+
+        fn journal(&self, table: &Table) -> PolarsResult<Box<dyn Journal>> {
+            self.0.journal(table)
+        }
+
+        fn write(
+            &self,
+            _table: &Table,
+            _df: &mut DataFrame,
+        ) -> PolarsResult<()> {
+            Err(PolarsError::ComputeError("No space left on device".into()))
+        }
+
+        fn commit(
+            &self,
+            changes: &[(&Table, Mutation)],
+        ) -> PolarsResult<Box<dyn Commit>> {
+            self.0.commit(changes)
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_see_changes_that_failed_to_write_back() {
+        polars::enable_string_cache();
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let backend = ParquetBackend::new(dir.path().to_owned());
+        backend.initialize().expect("Failed to initialize storage");
+        let file_manager =
+            FileManager::new().with_storage(Arc::new(FullBackend(backend)));
+
+        // Users are cached and profiles aren't, so both ways of reading a
+        // table are covered
+        let user = UserRow {
+            user_id: 1,
+            username: "alice".to_owned(),
+            password_hash: None,
+            is_guest: false,
+            is_deactivated: false,
+            created_ts: 0,
+        };
+        let profile = df!("user_id" => [1_u64], "displayname" => ["Alice"])
+            .and_then(|profile| PROFILES.conform(&profile).collect())
+            .expect("Invalid profile");
+        for (table, rows) in [
+            (&USERS, UserRow::to_dataframe(&[user]).expect("Invalid row")),
+            (&PROFILES, profile),
+        ] {
+            file_manager
+                .get_managed_lazyframe(table)
+                .await
+                .expect("Failed to lock table")
+                .apply(&Mutation::Upsert(rows))
+                .expect("Failed to journal change");
+        }
+
+        // Every read waits for the failed write-back to release its lock
+        for table in [&USERS, &PROFILES] {
+            let found = file_manager
+                .get_by_key(table, "user_id", 1_u64)
+                .await
+                .expect("Failed to look up row");
+            assert_eq!(found.height(), 1, "{}", table.name);
+            let all = file_manager
+                .get_lazyframe(table)
+                .await
+                .and_then(|frame| frame.query(|frame| frame))
+                .expect("Failed to read table");
+            assert_eq!(all.height(), 1, "{}", table.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_only_decode_candidate_row_groups() {
+        polars::enable_string_cache();
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let backend = ParquetBackend::new(dir.path().to_owned());
+        backend.initialize().expect("Failed to initialize storage");
+        let ids: Vec<u64> = (0..3 * BLOCK_ROWS)
+            .map(|id| u64::try_from(id).expect("Too many rows"))
+            .collect();
+        let names: Vec<String> =
+            ids.iter().map(|id| format!("user {id}")).collect();
+        let profiles = df!("user_id" => ids, "displayname" => names)
+            .expect("Invalid frame");
+        backend
+            .write(
+                &PROFILES,
+                &mut PROFILES.conform(&profiles).collect().expect("Invalid"),
+            )
+            .expect("Failed to write profiles");
+
+        // Overwrite the first and last row groups, so decoding either fails
+        let path = PROFILES.path_in(dir.path());
+        let metadata = ParquetReader::new(
+            File::open(&path).expect("Failed to open profiles"),
+        )
+        .get_metadata()
+        .expect("Failed to read metadata")
+        .clone();
+        assert_eq!(metadata.row_groups.len(), 3);
+        let mut file = File::options()
+            .write(true)
+            .open(&path)
+            .expect("Failed to open profiles");
+        for row_group in [&metadata.row_groups[0], &metadata.row_groups[2]] {
+            for column in row_group.columns() {
+                let (start, len) = column.byte_range();
+                let len = usize::try_from(len).expect("Column too long");
+                file.seek(SeekFrom::Start(start)).expect("Failed to seek");
+                file.write_all(&vec![0xFF; len]).expect("Failed to write");
+            }
+        }
+        drop(file);
+        assert!(encryption::read_parquet(&path).is_err());
+
+        let file_manager = FileManager::new().with_storage(Arc::new(backend));
+        let id = BLOCK_ROWS + 5;
+        let found = file_manager
+            .get_by_key(
+                &PROFILES,
+                "user_id",
+                u64::try_from(id).expect("Too many rows"),
+            )
+            .await
+            .expect("Failed to look up profile");
+        assert_eq!(found.height(), 1);
+        assert_eq!(
+            found
+                .column("displayname")
+                .and_then(|column| column.str().cloned())
+                .expect("Invalid profile")
+                .get(0),
+            Some(format!("user {id}").as_str())
+        );
     }
 }
@@ -0,0 +1,797 @@
+//! Encryption at rest for the data directory
+//!
+//! If `PROGRAM_CONFIG.encryption_key_file` is set, every table, write-ahead
+//! log record and event fragment is sealed with XChaCha20-Poly1305 before it
+//! is written to disk, and opened again when it is read. Files are read and
+//! written through the functions here rather than by polars directly, so the
+//! rest of the server never sees ciphertext.
+//!
+//! The key file holds one key per line as `{id} {64 hex digits}`. Blank lines
+//! and lines starting with `#` are ignored. The first key is the current one,
+//! used for everything written from now on. Any later keys are only used to
+//! read files that were written before the current key was added.
+//!
+//! Each sealed file or record is laid out as:
+//!
+//! | Bytes | Contents                                      |
+//! |-------|-----------------------------------------------|
+//! | 8     | `CUBBYENC`                                    |
+//! | 1     | Format version                                |
+//! | 1     | Length of the key id                          |
+//! | n     | Key id                                        |
+//! | 24    | Nonce                                         |
+//! | m     | Ciphertext and authentication tag             |
+//!
+//! Everything before the ciphertext is authenticated along with it. Parquet
+//! files start with `PAR1`, so sealed and plaintext files can't be confused.
+//! Plaintext written before encryption was turned on is still read until
+//! `rotator` has sealed everything. From then on until the server stops,
+//! anything that isn't sealed must have been planted and is refused. Setting
+//! `PROGRAM_CONFIG.require_sealed_data` refuses it from startup instead.
+//!
+//! To rotate keys, add a new key to the top of the key file and restart the
+//! server. `rotator` re-encrypts every file that isn't sealed with the current
+//! key in the background, including files written before encryption was
+//! turned on, and logs once it is done. Older keys must stay in the key file
+//! until then, and for as long as any snapshot sealed with them may still be
+//! restored.
+//!
+//! Sealed tables can't be scanned lazily, so they are read into memory whole.
+//! `get_by_key` still only decodes the row groups it needs from them, but it
+//! saves no reading.
+//!
+//! Media is stored outside the data directory, in `PROGRAM_CONFIG.media_path`
+//! (see `managers::media`). It is written with `write_sealed` and read with
+//! `read_sealed`, so it is sealed the same way, and `rotator` reseals it along
+//! with everything else.
+
+use std::{
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use cubby_lib::file_manager::{FileManager, FileManagerError};
+use once_cell::sync::Lazy;
+use polars::prelude::*;
+use rand::Rng;
+use tracing::{debug, error, info};
+
+use crate::{
+    config::PROGRAM_CONFIG,
+    managers::{dataframes::encode_parquet, events, media, wal},
+    schema::TABLES,
+};
+
+/// The first bytes of every sealed file or record
+const MAGIC: &[u8; 8] = b"CUBBYENC";
+
+/// The version of the sealed layout. Bump this when it changes.
+const FORMAT_VERSION: u8 = 1;
+
+/// The length of an XChaCha20-Poly1305 key
+const KEY_LEN: usize = 32;
+
+/// The length of an XChaCha20-Poly1305 nonce
+const NONCE_LEN: usize = 24;
+
+/// Set once `rotator` has sealed everything in the data directory with the
+/// current key, after which unsealed data is refused
+static ALL_SEALED: AtomicBool = AtomicBool::new(false);
+
+/// The keys loaded from `PROGRAM_CONFIG.encryption_key_file`, or the reason
+/// they couldn't be
+static KEYRING: Lazy<Result<Option<Keyring>, String>> = Lazy::new(|| {
+    PROGRAM_CONFIG.encryption_key_file.as_deref().map(Keyring::load).transpose()
+});
+
+/// A single key from the key file
+struct Key {
+    /// The name of the key, recorded in everything sealed with it
+    id: String,
+    /// The cipher using the key
+    cipher: XChaCha20Poly1305,
+}
+
+/// Every key in the key file
+pub(crate) struct Keyring {
+    /// The keys, current key first
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    /// Load the keys from the key file at `path`
+    fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            format!("Failed to read key file {}: {e}", path.display())
+        })?;
+        let mut keys = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                format!(
+                    "Invalid key on line {} of {}: {reason}",
+                    number + 1,
+                    path.display()
+                )
+            };
+            let Some((id, hex)) = line.split_once(char::is_whitespace) else {
+                return Err(invalid("expected `{id} {key}`"));
+            };
+            if id.len() > usize::from(u8::MAX) {
+                return Err(invalid("the id is longer than 255 bytes"));
+            }
+            if keys.iter().any(|key: &Key| key.id == id) {
+                return Err(invalid("the id is used by an earlier key"));
+            }
+            let bytes = decode_key(hex.trim())
+                .ok_or_else(|| invalid("the key must be 64 hex digits"))?;
+            let cipher = XChaCha20Poly1305::new_from_slice(&bytes)
+                .map_err(|e| invalid(&e.to_string()))?;
+            keys.push(Key {
+                id: id.to_owned(),
+                cipher,
+            });
+        }
+        if keys.is_empty() {
+            return Err(format!("Key file {} holds no keys", path.display()));
+        }
+        Ok(Self {
+            keys,
+        })
+    }
+
+    /// The key everything is sealed with
+    fn current(&self) -> &Key {
+        &self.keys[0]
+    }
+
+    /// Seal `plaintext` with the current key
+    fn seal(&self, plaintext: &[u8]) -> PolarsResult<Vec<u8>> {
+        let key = self.current();
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let mut sealed = Vec::with_capacity(
+            MAGIC.len() + 2 + key.id.len() + NONCE_LEN + plaintext.len() + 16,
+        );
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(FORMAT_VERSION);
+        sealed.push(
+            u8::try_from(key.id.len())
+                .map_err(|e| encryption_error(&e.to_string()))?,
+        );
+        sealed.extend_from_slice(key.id.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        let ciphertext = key
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &sealed,
+                },
+            )
+            .map_err(|e| encryption_error(&e.to_string()))?;
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Open `sealed` with whichever key it was sealed with
+    fn open(&self, sealed: &[u8]) -> PolarsResult<Vec<u8>> {
+        let header = Header::parse(sealed)?;
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == header.key_id)
+            .ok_or_else(|| {
+                encryption_error(&format!(
+                    "data is sealed with key {}, which is not in the key file",
+                    header.key_id
+                ))
+            })?;
+        let (aad, ciphertext) = sealed.split_at(header.len);
+        key.cipher
+            .decrypt(
+                XNonce::from_slice(header.nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|e| {
+                encryption_error(&format!(
+                    "data sealed with key {} is corrupt or was tampered with \
+                     ({e})",
+                    header.key_id
+                ))
+            })
+    }
+}
+
+/// The header of sealed data
+struct Header<'a> {
+    /// The id of the key the data is sealed with
+    key_id: &'a str,
+    /// The nonce the data is sealed with
+    nonce: &'a [u8],
+    /// The length of the header, where the ciphertext starts
+    len: usize,
+}
+
+impl<'a> Header<'a> {
+    /// Parse the header of `sealed`
+    fn parse(sealed: &'a [u8]) -> PolarsResult<Self> {
+        let truncated = || encryption_error("sealed data is truncated");
+        let rest = sealed
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| encryption_error("data is not sealed"))?;
+        let [version, id_len, rest @ ..] = rest else {
+            return Err(truncated());
+        };
+        if *version != FORMAT_VERSION {
+            return Err(encryption_error(&format!(
+                "unsupported format version {version}"
+            )));
+        }
+        let (key_id, rest) = rest
+            .split_at_checked(usize::from(*id_len))
+            .ok_or_else(truncated)?;
+        let key_id = std::str::from_utf8(key_id)
+            .map_err(|e| encryption_error(&e.to_string()))?;
+        let (nonce, rest) =
+            rest.split_at_checked(NONCE_LEN).ok_or_else(truncated)?;
+        Ok(Self {
+            key_id,
+            nonce,
+            len: sealed.len() - rest.len(),
+        })
+    }
+}
+
+/// Build the error returned when sealing or opening fails
+fn encryption_error(reason: &str) -> PolarsError {
+    PolarsError::ComputeError(format!("Encryption error: {reason}").into())
+}
+
+/// Decode a key written as hex digits
+fn decode_key(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != KEY_LEN * 2 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The keys data is sealed with, if encryption is turned on
+///
+/// # Errors
+///
+/// This function will return an error if the key file can't be read or
+/// holds an invalid key.
+pub(crate) fn keyring() -> PolarsResult<Option<&'static Keyring>> {
+    match &*KEYRING {
+        Ok(keyring) => Ok(keyring.as_ref()),
+        Err(e) => Err(PolarsError::ComputeError(e.clone().into())),
+    }
+}
+
+/// Whether `data` is sealed
+fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Seal `data` with the current key if encryption is turned on, or return it
+/// unchanged otherwise
+///
+/// # Errors
+///
+/// This function will return an error if the key file is invalid.
+pub(crate) fn seal(data: Vec<u8>) -> PolarsResult<Vec<u8>> {
+    seal_with(keyring()?, data)
+}
+
+/// Seal `data` with `keyring` if there is one, or return it unchanged
+/// otherwise, as `seal` does
+fn seal_with(
+    keyring: Option<&Keyring>,
+    data: Vec<u8>,
+) -> PolarsResult<Vec<u8>> {
+    match keyring {
+        Some(keyring) => keyring.seal(&data),
+        None => Ok(data),
+    }
+}
+
+/// Whether unsealed data is refused while encryption is turned on
+fn plaintext_refused() -> bool {
+    PROGRAM_CONFIG.require_sealed_data || ALL_SEALED.load(Ordering::SeqCst)
+}
+
+/// Fail if unsealed data has to be refused, given the keys in use and
+/// whether plaintext is refused while encryption is turned on
+fn check_plaintext(
+    keyring: Option<&Keyring>,
+    refuse_plaintext: bool,
+) -> PolarsResult<()> {
+    if keyring.is_some() && refuse_plaintext {
+        return Err(encryption_error(
+            "data is not sealed, but everything in the data directory should \
+             be, so it may have been tampered with",
+        ));
+    }
+    Ok(())
+}
+
+/// Open `data` if it is sealed, or return it unchanged otherwise
+///
+/// # Errors
+///
+/// This function will return an error if `data` is sealed but encryption is
+/// turned off, its key isn't in the key file, or it fails to authenticate,
+/// or if it isn't sealed but plaintext is refused.
+pub(crate) fn open(data: Vec<u8>) -> PolarsResult<Vec<u8>> {
+    open_with(keyring()?, plaintext_refused(), data)
+}
+
+/// Open `data` with `keyring` if it is sealed, or return it unchanged
+/// otherwise, as `open` does
+fn open_with(
+    keyring: Option<&Keyring>,
+    refuse_plaintext: bool,
+    data: Vec<u8>,
+) -> PolarsResult<Vec<u8>> {
+    if !is_sealed(&data) {
+        check_plaintext(keyring, refuse_plaintext)?;
+        return Ok(data);
+    }
+    keyring
+        .ok_or_else(|| {
+            encryption_error(
+                "data is sealed but no encryption_key_file is configured",
+            )
+        })?
+        .open(&data)
+}
+
+/// Whether `data` has to be resealed to be sealed with the current key. This
+/// is never the case while encryption is turned off.
+///
+/// # Errors
+///
+/// This function will return an error if the key file is invalid or `data`
+/// has a corrupt header.
+pub(crate) fn needs_rotation(data: &[u8]) -> PolarsResult<bool> {
+    let Some(keyring) = keyring()? else {
+        return Ok(false);
+    };
+    if !is_sealed(data) {
+        return Ok(true);
+    }
+    Ok(Header::parse(data)?.key_id != keyring.current().id)
+}
+
+/// Read every row of the parquet file at `path`, opening it if it is sealed
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be read or opened,
+/// or isn't valid parquet.
+pub(crate) fn read_parquet(path: &Path) -> PolarsResult<DataFrame> {
+    let data = open(fs::read(path)?)?;
+    ParquetReader::new(Cursor::new(data)).finish()
+}
+
+/// Whether the file at `path` is sealed, judging by its first bytes
+fn is_sealed_file(path: &Path) -> PolarsResult<bool> {
+    let mut magic = [0; MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(is_sealed(&magic)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Scan the parquet file at `path`.
+///
+/// Plaintext files are scanned lazily. Sealed files have to be read into
+/// memory whole first.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be read or opened.
+pub(crate) fn scan_parquet(path: &Path) -> PolarsResult<LazyFrame> {
+    if is_sealed_file(path)? || keyring()?.is_some() {
+        Ok(read_parquet(path)?.lazy())
+    } else {
+        LazyFrame::scan_parquet(path, ScanArgsParquet::default())
+    }
+}
+
+/// Read the rows of the parquet file at `path` in each of `ranges`, given as
+/// `(offset, len)` pairs, in order.
+///
+/// Only the row groups overlapping a range are decoded. A sealed file is
+/// authenticated as a whole, so it still has to be read and opened whole
+/// first.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be read or opened,
+/// or a row group in a range isn't valid parquet.
+pub(crate) fn read_parquet_ranges(
+    path: &Path,
+    ranges: &[(usize, usize)],
+) -> PolarsResult<Vec<DataFrame>> {
+    if is_sealed_file(path)? {
+        let data = open(fs::read(path)?)?;
+        ranges
+            .iter()
+            .map(|range| {
+                ParquetReader::new(Cursor::new(data.as_slice()))
+                    .with_slice(Some(*range))
+                    .finish()
+            })
+            .collect()
+    } else {
+        check_plaintext(keyring()?, plaintext_refused())?;
+        ranges
+            .iter()
+            .map(|range| {
+                ParquetReader::new(File::open(path)?)
+                    .with_slice(Some(*range))
+                    .finish()
+            })
+            .collect()
+    }
+}
+
+/// Write `df` to `file` as parquet, sealing it if encryption is turned on.
+///
+/// See `encode_parquet` for how `row_group_size` is used.
+///
+/// # Errors
+///
+/// This function will return an error if encoding, sealing or writing fails.
+pub(crate) fn write_parquet(
+    file: &mut File,
+    df: &mut DataFrame,
+    row_group_size: Option<usize>,
+) -> PolarsResult<()> {
+    match keyring()? {
+        Some(keyring) => {
+            let mut plaintext = Vec::new();
+            encode_parquet(&mut plaintext, df, row_group_size)?;
+            file.write_all(&keyring.seal(&plaintext)?)?;
+            Ok(())
+        }
+        None => encode_parquet(file, df, row_group_size),
+    }
+}
+
+/// Atomically replace the file at `path` with `data`, writing it to
+/// `temp_path` first
+///
+/// # Errors
+///
+/// This function will return an error if any of the filesystem operations
+/// fail, in which case `path` is left untouched.
+pub(crate) fn replace_file(
+    path: &Path,
+    temp_path: &Path,
+    data: &[u8],
+) -> PolarsResult<()> {
+    let result = (|| {
+        let mut file = File::create(temp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(temp_path, path)?;
+        if let Some(parent) = path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    })();
+    if result.is_err() && temp_path.exists() {
+        if let Err(e) = fs::remove_file(temp_path) {
+            error!(
+                "Failed to clean up temporary file {}: {e}",
+                temp_path.display()
+            );
+        }
+    }
+    result
+}
+
+/// Atomically replace the file at `path` with `data`, sealed if encryption is
+/// turned on, writing it to `temp_path` first
+///
+/// # Errors
+///
+/// This function will return an error if the key file is invalid, or if any
+/// of the filesystem operations fail, in which case `path` is left untouched.
+pub(crate) fn write_sealed(
+    path: &Path,
+    temp_path: &Path,
+    data: Vec<u8>,
+) -> PolarsResult<()> {
+    write_sealed_with(keyring()?, path, temp_path, data)
+}
+
+/// Replace the file at `path` with `data` sealed with `keyring`, as
+/// `write_sealed` does
+fn write_sealed_with(
+    keyring: Option<&Keyring>,
+    path: &Path,
+    temp_path: &Path,
+    data: Vec<u8>,
+) -> PolarsResult<()> {
+    replace_file(path, temp_path, &seal_with(keyring, data)?)
+}
+
+/// Read the file at `path`, opening it if it is sealed
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be read or opened.
+pub(crate) fn read_sealed(path: &Path) -> PolarsResult<Vec<u8>> {
+    open(fs::read(path)?)
+}
+
+/// Reseal the file at `path` with the current key if it isn't already,
+/// writing it to `temp_path` first.
+///
+/// Returns whether the file was resealed.
+fn reseal_file(path: &Path, temp_path: &Path) -> PolarsResult<bool> {
+    let data = fs::read(path)?;
+    if !needs_rotation(&data)? {
+        return Ok(false);
+    }
+    let data = seal(open(data)?)?;
+    replace_file(path, temp_path, &data)?;
+    Ok(true)
+}
+
+/// Reseal a table and its write-ahead log.
+///
+/// The caller is expected to hold an exclusive lock on the table.
+fn reseal_table(table_path: &Path) -> PolarsResult<usize> {
+    let mut temp_path = table_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let resealed =
+        usize::from(reseal_file(table_path, &PathBuf::from(temp_path))?);
+    Ok(resealed + usize::from(wal::reseal(table_path)?))
+}
+
+/// Reseal every fragment in a room directory.
+///
+/// The caller is expected to hold an exclusive lock on `room_dir`.
+fn reseal_room(data_path: &Path, room_dir: &Path) -> PolarsResult<usize> {
+    let staging_dir = data_path.join(events::STAGING_DIR);
+    fs::create_dir_all(&staging_dir)?;
+    let mut resealed = 0;
+    for fragment in events::room_fragments(room_dir)? {
+        // Fragments are staged outside the event store, so scans never see a
+        // partially written one
+        let temp_path = staging_dir.join(events::fragment_name("resealed-"));
+        resealed += usize::from(reseal_file(&fragment, &temp_path)?);
+    }
+    Ok(resealed)
+}
+
+/// Reseal every table, write-ahead log, event fragment and piece of media that
+/// isn't sealed with the current key, one table, room or piece of media at a
+/// time.
+///
+/// This runs once at startup when encryption is turned on, and only stops
+/// early if the file manager shuts down. Once everything is sealed, unsealed
+/// data is refused until the server stops.
+pub(crate) async fn rotator(file_manager: FileManager) {
+    let Ok(Some(keyring)) = keyring() else {
+        return;
+    };
+    let data_path = PROGRAM_CONFIG.data_path.clone();
+    let rooms = match events::room_dirs(&data_path) {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            error!("Failed to list rooms in the event store: {e}");
+            return;
+        }
+    };
+    let media = match media::media_files(&PROGRAM_CONFIG.media_path) {
+        Ok(media) => media,
+        Err(e) => {
+            error!("Failed to list stored media: {e}");
+            return;
+        }
+    };
+    let paths: Vec<PathBuf> = TABLES
+        .iter()
+        .map(|table| table.path())
+        .chain(rooms.iter().cloned())
+        .chain(media.iter().cloned())
+        .collect();
+    let mut resealed = 0;
+    let mut failed = false;
+    for path in paths {
+        let lock = match file_manager.lock_exclusive(path.clone()).await {
+            Ok(lock) => lock,
+            Err(FileManagerError::Stopped) => {
+                info!("Stopping key rotation");
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to lock {} for key rotation: {e}",
+                    path.display()
+                );
+                failed = true;
+                continue;
+            }
+        };
+        let is_room = rooms.contains(&path);
+        let is_media = media.contains(&path);
+        let data_path = data_path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let result = if is_room {
+                reseal_room(&data_path, lock.get_path())
+            } else if is_media {
+                let path = lock.get_path();
+                reseal_file(path, &media::temp_path(path)).map(usize::from)
+            } else {
+                reseal_table(lock.get_path())
+            };
+            drop(lock);
+            result
+        })
+        .await;
+        match result {
+            Ok(Ok(count)) => {
+                debug!("Resealed {count} files in {}", path.display());
+                resealed += count;
+            }
+            Ok(Err(e)) => {
+                error!("Failed to reseal {}: {e}", path.display());
+                failed = true;
+            }
+            Err(e) => {
+                error!("Resealing {} panicked: {e}", path.display());
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        error!(
+            "Some files could not be resealed with key {}. Keep every key in \
+             the key file and restart to try again.",
+            keyring.current().id
+        );
+    } else {
+        if resealed > 0 {
+            info!(
+                "Resealed {resealed} files. Everything in the data directory \
+                 is now sealed with key {}.",
+                keyring.current().id
+            );
+        }
+        ALL_SEALED.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use super::{open_with, write_sealed_with, Header, Keyring, MAGIC};
+
+    /// A keyring holding a key for each `(id, byte)` in `keys`, current key
+    /// first, where every byte of the key is `byte`
+    fn keyring(dir: &TempDir, keys: &[(&str, u8)]) -> Keyring {
+        let contents: Vec<String> = keys
+            .iter()
+            .map(|(id, byte)| {
+                format!("{id} {}", format!("{byte:02x}").repeat(32))
+            })
+            .collect();
+        let path = dir.path().join(contents.join("-").replace(' ', "_"));
+        fs::write(&path, contents.join("\n")).expect("Failed to write keys");
+        Keyring::load(&path).expect("Invalid key file")
+    }
+
+    #[test]
+    fn sealed_data_round_trips() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let keyring = keyring(&dir, &[("a", 1)]);
+        let sealed = keyring.seal(b"secret").expect("Failed to seal");
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert_eq!(keyring.open(&sealed).expect("Failed to open"), b"secret");
+        assert_eq!(
+            open_with(Some(&keyring), true, sealed).expect("Failed to open"),
+            b"secret"
+        );
+    }
+
+    #[test]
+    fn data_sealed_with_another_key_is_refused() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let sealed =
+            keyring(&dir, &[("a", 1)]).seal(b"secret").expect("Failed to seal");
+        // A different key under the same id fails to authenticate
+        assert!(keyring(&dir, &[("a", 2)]).open(&sealed).is_err());
+        assert!(keyring(&dir, &[("b", 1)]).open(&sealed).is_err());
+        assert!(open_with(None, false, sealed.clone()).is_err());
+
+        let mut tampered = sealed;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(keyring(&dir, &[("a", 1)]).open(&tampered).is_err());
+    }
+
+    #[test]
+    fn rotated_keys_still_open_older_data() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let old = keyring(&dir, &[("old", 1)]);
+        let rotated = keyring(&dir, &[("new", 2), ("old", 1)]);
+        let sealed = old.seal(b"secret").expect("Failed to seal");
+        let opened = rotated.open(&sealed).expect("Failed to open");
+        assert_eq!(opened, b"secret");
+
+        let resealed = rotated.seal(&opened).expect("Failed to reseal");
+        assert_eq!(
+            Header::parse(&resealed).expect("Invalid header").key_id,
+            "new"
+        );
+        assert_eq!(rotated.open(&resealed).expect("Failed to open"), b"secret");
+        assert!(old.open(&resealed).is_err());
+    }
+
+    #[test]
+    fn plaintext_is_refused_once_everything_is_sealed() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let keyring = keyring(&dir, &[("a", 1)]);
+        let plaintext = b"PAR1".to_vec();
+        assert!(open_with(Some(&keyring), false, plaintext.clone()).is_ok());
+        assert!(open_with(Some(&keyring), true, plaintext.clone()).is_err());
+        // Nothing is sealed while encryption is turned off
+        assert!(open_with(None, true, plaintext).is_ok());
+    }
+
+    #[test]
+    fn files_are_written_sealed() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let keyring = keyring(&dir, &[("a", 1)]);
+        let path = dir.path().join("media");
+        let temp_path = dir.path().join("media.tmp");
+
+        write_sealed_with(
+            Some(&keyring),
+            &path,
+            &temp_path,
+            b"secret".to_vec(),
+        )
+        .expect("Failed to write");
+        let sealed = fs::read(&path).expect("Failed to read");
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert!(!temp_path.exists());
+        assert_eq!(
+            open_with(Some(&keyring), true, sealed).expect("Failed to open"),
+            b"secret"
+        );
+
+        write_sealed_with(None, &path, &temp_path, b"public".to_vec())
+            .expect("Failed to write");
+        assert_eq!(fs::read(&path).expect("Failed to read"), b"public");
+    }
+}
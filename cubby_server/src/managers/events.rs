@@ -0,0 +1,548 @@
+//! Append-only storage for timeline events
+//!
+//! Room timelines grow forever, so events can't be kept in a single table that
+//! is rewritten on every change. Instead every batch of new events is written
+//! as small parquet fragments, partitioned by room and by the day they were
+//! sent:
+//!
+//! `{data_path}/events/room={short room id}/bucket={day}/{fragment}.parquet`
+//!
+//! Events are stored with their identifiers interned (see
+//! `managers::interner`), so rooms are named by their short ID. Reads only
+//! scan the buckets of a single room that can hold the events asked for, so
+//! reading recent events never opens the files of older days.
+//!
+//! A background compactor periodically merges the fragments of each day into
+//! a single file with larger row groups, dropping duplicate events on the way.
+//!
+//! Each room directory is locked through the `FileManager`. Appending and
+//! reading take shared locks, since new fragments never change existing ones.
+//! Compacting takes an exclusive lock, since it deletes the fragments it
+//! merged.
+//!
+//! Events are always stored on disk, regardless of `PROGRAM_CONFIG.storage`.
+//! If encryption is turned on, fragments are sealed and read into memory
+//! rather than scanned (see `managers::encryption`).
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use cubby_lib::file_manager::{
+    FileManager, FileManagerError, LockMode, Message, Receive,
+};
+use polars::prelude::*;
+use tracing::{debug, error, info};
+
+use crate::{
+    config::PROGRAM_CONFIG,
+    managers::{
+        dataframes::{lock_error, SharedLazyFrame},
+        encryption,
+        interner::Interner,
+    },
+    schema::{EVENTS, SHORT_ID},
+};
+
+/// The directory in `data_path` holding the event store
+pub(crate) const EVENTS_DIR: &str = "events";
+
+/// The directory in `data_path` fragments are written to before being moved
+/// into place, so scans never see a partially written file
+pub(crate) const STAGING_DIR: &str = "events.staging";
+
+/// How many milliseconds of events each bucket holds
+pub(crate) const BUCKET_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// How many fragments a bucket needs before it is compacted
+const COMPACTION_THRESHOLD: usize = 8;
+
+/// How many rows each row group in a compacted file holds
+const COMPACTED_ROW_GROUP_SIZE: usize = 64 * 1024;
+
+/// Keeps fragment names unique when several are written in the same instant
+static FRAGMENT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A message requesting that the file manager append events to the store
+pub(crate) struct AppendEvents(DataFrame);
+
+impl Message for AppendEvents {
+    type Response = PolarsResult<()>;
+}
+
+impl Receive<AppendEvents> for FileManager {
+    async fn handle(
+        &self,
+        message: AppendEvents,
+    ) -> <AppendEvents as Message>::Response {
+        let events = message.0;
+        let data_path = PROGRAM_CONFIG.data_path.clone();
+        let events_dir = data_path.join(EVENTS_DIR);
+        let rooms = events.column("room_id")?.cast(&SHORT_ID)?;
+        let locks = self
+            .lock_many(
+                rooms
+                    .u64()?
+                    .into_iter()
+                    .flatten()
+                    .map(|room| (room_dir(&events_dir, room), LockMode::Shared))
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .map_err(|e| lock_error(&e))?;
+        tokio::task::spawn_blocking(move || {
+            let result = write_events(&data_path, &events);
+            drop(locks);
+            result
+        })
+        .await
+        .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?
+    }
+}
+
+/// A message requesting that the file manager return the events of a room
+pub(crate) struct GetRoomEvents {
+    /// The short ID of the room to read events from
+    room: u64,
+    /// Only read events sent at or after this time, in milliseconds since the
+    /// unix epoch
+    since: Option<u64>,
+}
+
+impl Message for GetRoomEvents {
+    type Response = PolarsResult<SharedLazyFrame>;
+}
+
+impl Receive<GetRoomEvents> for FileManager {
+    async fn handle(
+        &self,
+        message: GetRoomEvents,
+    ) -> <GetRoomEvents as Message>::Response {
+        let dir =
+            room_dir(&PROGRAM_CONFIG.data_path.join(EVENTS_DIR), message.room);
+        let lock =
+            self.lock_shared(dir.clone()).await.map_err(|e| lock_error(&e))?;
+        let frame = scan_room(&dir, message.since)?;
+        Ok(SharedLazyFrame::from_frame(frame, lock))
+    }
+}
+
+/// The directory holding every fragment of the room with the short ID `room`
+pub(crate) fn room_dir(events_dir: &Path, room: u64) -> PathBuf {
+    events_dir.join(format!("room={room}"))
+}
+
+/// A new, unique fragment file name
+pub(crate) fn fragment_name(prefix: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let count = FRAGMENT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{prefix}{nanos}-{count}.parquet")
+}
+
+/// Every parquet file directly inside `dir`
+fn parquet_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "parquet") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Every directory directly inside `dir`
+pub(crate) fn subdirectories(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// The directory of every room in the event store in `data_path`
+pub(crate) fn room_dirs(data_path: &Path) -> io::Result<Vec<PathBuf>> {
+    match subdirectories(&data_path.join(EVENTS_DIR)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Every fragment in a room directory, across all of its buckets
+pub(crate) fn room_fragments(room_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut fragments = Vec::new();
+    for bucket_dir in subdirectories(room_dir)? {
+        fragments.extend(parquet_files(&bucket_dir)?);
+    }
+    Ok(fragments)
+}
+
+/// Write `df` into `bucket_dir` as a new fragment named `name`.
+///
+/// The fragment is written to the staging directory and flushed before being
+/// moved into place, so it either appears whole or not at all.
+pub(crate) fn write_fragment(
+    data_path: &Path,
+    bucket_dir: &Path,
+    name: &str,
+    df: &mut DataFrame,
+    row_group_size: Option<usize>,
+) -> PolarsResult<()> {
+    let staging_dir = data_path.join(STAGING_DIR);
+    fs::create_dir_all(&staging_dir)?;
+    fs::create_dir_all(bucket_dir)?;
+    let temp_path = staging_dir.join(name);
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        encryption::write_parquet(&mut file, df, row_group_size)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, bucket_dir.join(name))?;
+        File::open(bucket_dir)?.sync_all()?;
+        Ok(())
+    })();
+    if result.is_err() && temp_path.exists() {
+        if let Err(e) = fs::remove_file(&temp_path) {
+            error!(
+                "Failed to clean up temporary file {}: {e}",
+                temp_path.display()
+            );
+        }
+    }
+    result
+}
+
+/// Write events into the store in `data_path` as new fragments, one for
+/// every room and bucket they fall into. The events must already be interned.
+///
+/// The caller is expected to hold a lock on the directory of every room the
+/// events belong to.
+pub(crate) fn write_events(
+    data_path: &Path,
+    events: &DataFrame,
+) -> PolarsResult<()> {
+    let mut events = EVENTS.conform(events).collect()?;
+    let buckets = events
+        .column("origin_server_ts")?
+        .u64()?
+        .apply_values(|ts| ts / BUCKET_MS)
+        .into_series()
+        .with_name("bucket");
+    events.with_column(buckets)?;
+    let partitions = events
+        .clone()
+        .lazy()
+        .select([col("room_id"), col("bucket")])
+        .unique(None, UniqueKeepStrategy::Any)
+        .collect()?;
+    let events_dir = data_path.join(EVENTS_DIR);
+    for (room, bucket) in partitions
+        .column("room_id")?
+        .u64()?
+        .into_iter()
+        .zip(partitions.column("bucket")?.u64()?)
+    {
+        let (Some(room), Some(bucket)) = (room, bucket) else {
+            return Err(PolarsError::ComputeError(
+                "Every event needs a room ID and a timestamp".into(),
+            ));
+        };
+        let mut fragment = events
+            .clone()
+            .lazy()
+            .filter(
+                col("room_id").eq(lit(room)).and(col("bucket").eq(lit(bucket))),
+            )
+            .select(event_columns())
+            .sort(["origin_server_ts"], SortMultipleOptions::default())
+            .collect()?;
+        let bucket_dir =
+            room_dir(&events_dir, room).join(format!("bucket={bucket}"));
+        write_fragment(
+            data_path,
+            &bucket_dir,
+            &fragment_name(""),
+            &mut fragment,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+/// Selects the columns of an event, dropping any hive partition columns
+fn event_columns() -> Vec<Expr> {
+    EVENTS.columns.iter().map(|(name, _)| col(name)).collect()
+}
+
+/// Scan every event in a room directory, optionally only those sent at or
+/// after `since`
+fn scan_room(room_dir: &Path, since: Option<u64>) -> PolarsResult<LazyFrame> {
+    let fragments = if room_dir.exists() {
+        bucket_fragments(room_dir, since)?
+    } else {
+        Vec::new()
+    };
+    if fragments.is_empty() {
+        return Ok(EVENTS.empty()?.lazy());
+    }
+    let mut frame = if encryption::keyring()?.is_some() {
+        // Polars can't scan sealed fragments, so they are read into memory
+        let frames = fragments
+            .iter()
+            .map(|path| {
+                Ok(encryption::read_parquet(path)?
+                    .lazy()
+                    .select(event_columns()))
+            })
+            .collect::<PolarsResult<Vec<_>>>()?;
+        concat(frames, UnionArgs::default())?
+    } else {
+        LazyFrame::scan_parquet_files(
+            fragments.into(),
+            ScanArgsParquet::default(),
+        )?
+    };
+    if let Some(since) = since {
+        frame = frame.filter(
+            col("origin_server_ts").gt_eq(lit(since).cast(DataType::UInt64)),
+        );
+    }
+    // A crash during compaction can leave an event in both the compacted
+    // file and its original fragment
+    Ok(frame
+        .select(event_columns())
+        .unique(Some(vec!["event_id".to_owned()]), UniqueKeepStrategy::First)
+        .sort(["origin_server_ts"], SortMultipleOptions::default()))
+}
+
+/// Every fragment in the buckets of a room directory that can hold events
+/// sent at or after `since`.
+///
+/// Buckets are picked by the `bucket` partition in their directory name, so
+/// the fragments of every other bucket are never opened. Polars would read
+/// the footer of every file to infer a schema if it were given the whole room
+/// directory to scan.
+fn bucket_fragments(
+    room_dir: &Path,
+    since: Option<u64>,
+) -> io::Result<Vec<PathBuf>> {
+    let first_bucket = since.map_or(0, |since| since / BUCKET_MS);
+    let mut fragments = Vec::new();
+    for bucket_dir in subdirectories(room_dir)? {
+        let bucket = bucket_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("bucket="))
+            .and_then(|bucket| bucket.parse::<u64>().ok());
+        if bucket.is_some_and(|bucket| bucket < first_bucket) {
+            continue;
+        }
+        fragments.extend(parquet_files(&bucket_dir)?);
+    }
+    Ok(fragments)
+}
+
+/// Merge the fragments of every bucket in a room that has enough of them.
+///
+/// The caller is expected to hold an exclusive lock on `room_dir`.
+fn compact_room(data_path: &Path, room_dir: &Path) -> PolarsResult<()> {
+    for bucket_dir in subdirectories(room_dir)? {
+        let fragments = parquet_files(&bucket_dir)?;
+        if fragments.len() < COMPACTION_THRESHOLD {
+            continue;
+        }
+        let frames = fragments
+            .iter()
+            .map(|path| Ok(encryption::read_parquet(path)?.lazy()))
+            .collect::<PolarsResult<Vec<_>>>()?;
+        let mut merged = concat(frames, UnionArgs::default())?
+            .select(event_columns())
+            .unique(
+                Some(vec!["event_id".to_owned()]),
+                UniqueKeepStrategy::First,
+            )
+            .sort(["origin_server_ts"], SortMultipleOptions::default())
+            .collect()?;
+        write_fragment(
+            data_path,
+            &bucket_dir,
+            &fragment_name("compacted-"),
+            &mut merged,
+            Some(COMPACTED_ROW_GROUP_SIZE),
+        )?;
+        for fragment in &fragments {
+            fs::remove_file(fragment)?;
+        }
+        File::open(&bucket_dir)?.sync_all()?;
+        debug!(
+            "Compacted {} fragments in {}",
+            fragments.len(),
+            bucket_dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Whether any bucket in a room has enough fragments to be compacted
+fn needs_compaction(room_dir: &Path) -> io::Result<bool> {
+    for bucket_dir in subdirectories(room_dir)? {
+        if parquet_files(&bucket_dir)?.len() >= COMPACTION_THRESHOLD {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Compact every room in the event store every
+/// `PROGRAM_CONFIG.compaction_interval` seconds, until the file manager shuts
+/// down
+pub(crate) async fn compactor(file_manager: FileManager) {
+    let data_path = PROGRAM_CONFIG.data_path.clone();
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        PROGRAM_CONFIG.compaction_interval,
+    ));
+    loop {
+        interval.tick().await;
+        let rooms = match room_dirs(&data_path) {
+            Ok(rooms) => rooms,
+            Err(e) => {
+                error!("Failed to list rooms in the event store: {e}");
+                continue;
+            }
+        };
+        for room in rooms {
+            match needs_compaction(&room) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    error!("Failed to inspect {}: {e}", room.display());
+                    continue;
+                }
+            }
+            let lock = match file_manager.lock_exclusive(room.clone()).await {
+                Ok(lock) => lock,
+                Err(FileManagerError::Stopped) => {
+                    info!("Stopping event compaction");
+                    return;
+                }
+                Err(e) => {
+                    debug!("Skipping compaction of {}: {e}", room.display());
+                    continue;
+                }
+            };
+            let data_path = data_path.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let result = compact_room(&data_path, lock.get_path());
+                drop(lock);
+                result
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("Failed to compact {}: {e}", room.display());
+                }
+                Err(e) => {
+                    error!("Compaction of {} panicked: {e}", room.display());
+                }
+            }
+        }
+    }
+}
+
+/// Functionality for storing and reading timeline events
+pub(crate) trait EventStore {
+    /// Append events to the store. Events may belong to any number of rooms,
+    /// and are interned before being written.
+    async fn append_events(&self, events: DataFrame) -> PolarsResult<()>;
+    /// Get a read-only `LazyFrame` of the events in the room with the short
+    /// ID `room`, sorted by when they were sent. If `since` is given, only
+    /// events sent at or after it are included.
+    // Timelines are only read by the room endpoints, which don't exist yet
+    #[allow(dead_code)]
+    async fn room_events(
+        &self,
+        room: u64,
+        since: Option<u64>,
+    ) -> PolarsResult<SharedLazyFrame>;
+}
+
+impl EventStore for FileManager {
+    async fn append_events(&self, events: DataFrame) -> PolarsResult<()> {
+        let events = self.intern_rows(&EVENTS, events).await?;
+        self.handle(AppendEvents(events)).await
+    }
+
+    async fn room_events(
+        &self,
+        room: u64,
+        since: Option<u64>,
+    ) -> PolarsResult<SharedLazyFrame> {
+        self.handle(GetRoomEvents {
+            room,
+            since,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::{
+        room_dir, room_fragments, scan_room, write_events, BUCKET_MS,
+        EVENTS_DIR,
+    };
+    use crate::schema::EVENTS;
+
+    #[test]
+    fn reads_skip_buckets_before_since() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let events = df!(
+            "event_id" => [1_u64, 2],
+            "room_id" => [7_u64, 7],
+            "sender" => [3_u64, 3],
+            "event_type" => ["m.room.message", "m.room.message"],
+            "state_key" => [None::<&str>, None],
+            "origin_server_ts" => [BUCKET_MS, 5 * BUCKET_MS],
+            "content" => ["{}", "{}"],
+        )
+        .and_then(|events| EVENTS.conform(&events).collect())
+        .expect("Invalid events");
+        write_events(dir.path(), &events).expect("Failed to write events");
+        let room = room_dir(&dir.path().join(EVENTS_DIR), 7);
+
+        // Corrupt the older bucket, so reading it at all fails
+        for fragment in room_fragments(&room).expect("Failed to list room") {
+            if fragment.parent() == Some(&room.join("bucket=1")) {
+                fs::write(&fragment, "corrupt")
+                    .expect("Failed to corrupt fragment");
+            }
+        }
+        let recent = scan_room(&room, Some(5 * BUCKET_MS))
+            .and_then(LazyFrame::collect)
+            .expect("Failed to read recent events");
+        assert_eq!(
+            recent
+                .column("event_id")
+                .and_then(|column| column.u64().cloned())
+                .expect("Missing event IDs")
+                .into_no_null_iter()
+                .collect::<Vec<_>>(),
+            [2]
+        );
+        assert!(scan_room(&room, None).and_then(LazyFrame::collect).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! In-memory indexes for point lookups
+//!
+//! Tables are stored sorted by their key, in row groups of `BLOCK_ROWS` rows.
+//! For every table, the index records the first and last value of the first
+//! key column in each of those blocks, so a binary search finds the only
+//! blocks that can hold a given key. Columns listed in `Table::indexed` also
+//! get a sorted list of every value they hold and the blocks it appears in.
+//!
+//! `ParquetManager::get_by_key` reads just the candidate blocks of a table that
+//! isn't already in the cache through `StorageBackend::read_ranges`. The
+//! parquet backend only decodes the row groups overlapping those blocks (see
+//! `encryption::read_parquet_ranges`).
+//!
+//! Every storage backend keeps its own `Indexes`. They are built from every
+//! table at startup and replaced whenever a table is written through the
+//! backend, while the exclusive lock on the table is still held. They are never
+//! stored on disk, so a table changed by a migration or `cubby check --repair`
+//! can't have a stale index.
+
+use std::{
+    collections::HashMap,
+    sync::{PoisonError, RwLock},
+};
+
+use polars::prelude::*;
+
+use crate::schema::Table;
+
+/// The number of rows in each indexed block, which is also the size of the
+/// row groups tables are written with
+pub(crate) const BLOCK_ROWS: usize = 4096;
+
+/// A value of an indexed column
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum KeyValue {
+    /// A value of an unsigned integer column, such as a short ID
+    Id(u64),
+    /// A value of any other column, as a string
+    Str(String),
+}
+
+impl KeyValue {
+    /// A literal expression holding this value
+    pub(crate) fn lit(&self) -> Expr {
+        match self {
+            KeyValue::Id(value) => lit(*value),
+            KeyValue::Str(value) => lit(value.clone()),
+        }
+    }
+}
+
+impl From<u64> for KeyValue {
+    fn from(value: u64) -> Self {
+        KeyValue::Id(value)
+    }
+}
+
+impl From<String> for KeyValue {
+    fn from(value: String) -> Self {
+        KeyValue::Str(value)
+    }
+}
+
+impl From<&str> for KeyValue {
+    fn from(value: &str) -> Self {
+        KeyValue::Str(value.to_owned())
+    }
+}
+
+/// The index of a single table
+#[derive(Debug, Default)]
+struct TableIndex {
+    /// The first and last value of the first key column in every block
+    blocks: Vec<(Option<KeyValue>, Option<KeyValue>)>,
+    /// For each column in `Table::indexed`, every distinct pair of a value
+    /// and a block it appears in, sorted
+    secondary: HashMap<&'static str, Vec<(Option<KeyValue>, usize)>>,
+}
+
+/// Every value of `column`, in the form stored in the index.
+///
+/// Unsigned integers keep their order. Everything else is compared as a
+/// string, so the first key column of a table has to be one or the other for
+/// the table's sort order to match the index.
+fn key_values(column: &Series) -> PolarsResult<Vec<Option<KeyValue>>> {
+    if column.dtype().is_unsigned_integer() {
+        let column = column.cast(&DataType::UInt64)?;
+        Ok(column.u64()?.into_iter().map(|v| v.map(KeyValue::Id)).collect())
+    } else {
+        let column = column.cast(&DataType::String)?;
+        Ok(column
+            .str()?
+            .into_iter()
+            .map(|v| v.map(|v| KeyValue::Str(v.to_owned())))
+            .collect())
+    }
+}
+
+/// Sort `df` by the key of `table`, the order its rows have to be stored in
+/// for the index to apply
+///
+/// # Errors
+///
+/// This function will return an error if `df` is missing a key column.
+pub(crate) fn sort(table: &Table, df: &mut DataFrame) -> PolarsResult<()> {
+    *df = std::mem::take(df)
+        .lazy()
+        .sort(table.key, SortMultipleOptions::default())
+        .collect()?;
+    Ok(())
+}
+
+/// Whether the rows of `df` are sorted by the first key column of `table`.
+///
+/// `df` only needs to hold the key columns of the table.
+///
+/// # Errors
+///
+/// This function will return an error if `df` is missing a key column.
+pub(crate) fn is_sorted(table: &Table, df: &DataFrame) -> PolarsResult<bool> {
+    let Some(key) = table.key.first() else {
+        return Ok(true);
+    };
+    let values = key_values(df.column(key)?)?;
+    Ok(values.windows(2).all(|pair| pair[0] <= pair[1]))
+}
+
+/// The index of every table of a storage backend
+#[derive(Debug, Default)]
+pub(crate) struct Indexes {
+    /// The index of every table, by name
+    tables: RwLock<HashMap<&'static str, TableIndex>>,
+}
+
+impl Indexes {
+    /// Replace the index of `table` with one built from `df`, its contents in
+    /// the order they are stored.
+    ///
+    /// `df` only needs to hold the first key column and the indexed columns
+    /// of the table.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `df` is missing one of those
+    /// columns.
+    pub(crate) fn update(
+        &self,
+        table: &Table,
+        df: &DataFrame,
+    ) -> PolarsResult<()> {
+        let mut index = TableIndex::default();
+        if let Some(key) = table.key.first() {
+            index.blocks = key_values(df.column(key)?)?
+                .chunks(BLOCK_ROWS)
+                .map(|block| (block[0].clone(), block[block.len() - 1].clone()))
+                .collect();
+        }
+        for column in table.indexed {
+            let mut entries: Vec<(Option<KeyValue>, usize)> =
+                key_values(df.column(column)?)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(row, value)| (value, row / BLOCK_ROWS))
+                    .collect();
+            entries.sort_unstable();
+            entries.dedup();
+            index.secondary.insert(column, entries);
+        }
+        self.tables
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(table.name, index);
+        Ok(())
+    }
+
+    /// The ranges of rows of `table` that can hold `value` in `column`, as
+    /// `(offset, len)` pairs.
+    ///
+    /// Returns `None` if `column` is neither the first key column nor an
+    /// indexed column of the table, in which case every row has to be
+    /// searched.
+    pub(crate) fn candidates(
+        &self,
+        table: &Table,
+        column: &str,
+        value: &KeyValue,
+    ) -> Option<Vec<(usize, usize)>> {
+        let indexes =
+            self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        let index = indexes.get(table.name)?;
+        let value = Some(value.clone());
+        let blocks: Vec<usize> = if table.key.first() == Some(&column) {
+            let start = index.blocks.partition_point(|(_, last)| *last < value);
+            let end =
+                index.blocks.partition_point(|(first, _)| *first <= value);
+            (start..end).collect()
+        } else {
+            let entries = index.secondary.get(column)?;
+            let start = entries.partition_point(|(v, _)| *v < value);
+            entries[start..]
+                .iter()
+                .take_while(|(v, _)| *v == value)
+                .map(|(_, block)| *block)
+                .collect()
+        };
+        Some(ranges(&blocks))
+    }
+}
+
+/// Merge runs of consecutive blocks into `(offset, len)` ranges of rows
+fn ranges(blocks: &[usize]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for &block in blocks {
+        match runs.last_mut() {
+            Some((first, count)) if *first + *count == block => *count += 1,
+            _ => runs.push((block, 1)),
+        }
+    }
+    runs.into_iter()
+        .map(|(first, count)| (first * BLOCK_ROWS, count * BLOCK_ROWS))
+        .collect()
+}
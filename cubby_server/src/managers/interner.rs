@@ -0,0 +1,616 @@
+//! Interning of Matrix identifiers into short IDs
+//!
+//! User IDs, room IDs and event IDs are long strings that would otherwise be
+//! repeated in every row of every table referring to them. Instead, every
+//! identifier is stored once alongside a `u64` short ID, and every other table
+//! stores the short ID. Besides saving space on disk and in memory, this makes
+//! joins between tables compare integers rather than strings.
+//!
+//! Identifiers are spread across the tables in `schema::IDENTIFIER_TABLES` by
+//! a hash of the identifier, so interning one only locks and rewrites the
+//! table it belongs in. Each table hands out the short IDs congruent to its
+//! position modulo `IDENTIFIER_PARTITIONS`, so a short ID also says which
+//! table to find it in. Both columns are indexed, and a handful of
+//! identifiers or short IDs are looked up with `get_by_key` rather than by
+//! reading whole tables.
+//!
+//! Short IDs are handed out in increasing order within each table and never
+//! reused or changed, so they can be stored anywhere without ever having to
+//! be rewritten.
+//!
+//! Rows are interned with `Interner::intern_rows` before being written to a
+//! table, and the identifiers are joined back in with `Interner::resolve_ids`
+//! before being returned to clients. Handlers that create something under a
+//! new identifier inside a transaction intern it with `intern_in` instead,
+//! which needs the table from `table_of` to be part of the transaction, so it
+//! is only interned if the transaction commits.
+
+use std::{collections::BTreeMap, iter};
+
+use cubby_lib::file_manager::{FileManager, Message, Receive};
+use polars::prelude::*;
+
+use crate::{
+    managers::{
+        dataframes::ParquetManager, transaction::Transaction, wal::Mutation,
+    },
+    schema::{
+        IdentifierRow, Table, IDENTIFIERS, IDENTIFIER_PARTITIONS,
+        IDENTIFIER_TABLES, SHORT_ID,
+    },
+};
+
+/// The most identifiers or short IDs of a single table that are looked up one
+/// at a time with `get_by_key`. Any more are looked up by reading the table.
+const POINT_LOOKUPS: usize = 16;
+
+/// A message requesting that the file manager intern the identifiers in rows
+/// about to be written to a table
+pub(crate) struct InternRows {
+    /// The table the rows will be written to
+    table: &'static Table,
+    /// The rows, holding identifiers in the interned columns of `table`
+    rows: DataFrame,
+}
+
+impl Message for InternRows {
+    type Response = PolarsResult<DataFrame>;
+}
+
+impl Receive<InternRows> for FileManager {
+    async fn handle(
+        &self,
+        message: InternRows,
+    ) -> <InternRows as Message>::Response {
+        let InternRows {
+            table,
+            rows,
+        } = message;
+        // Columns that already hold short IDs are left alone
+        let columns: Vec<&str> = table
+            .interned
+            .iter()
+            .copied()
+            .filter(|name| {
+                rows.column(name)
+                    .is_ok_and(|column| column.dtype() != &SHORT_ID)
+            })
+            .collect();
+        let wanted = distinct_identifiers(&rows, &columns)?;
+        if wanted.height() == 0 {
+            return Ok(rows);
+        }
+        let mut mappings = Vec::new();
+        let identifiers = wanted
+            .column(IdentifierRow::IDENTIFIER)?
+            .str()?
+            .into_iter()
+            .flatten()
+            .map(str::to_owned);
+        for (table, identifiers) in
+            by_table(identifiers, |identifier| table_of(identifier))
+        {
+            mappings.push(intern_in_table(self, table, identifiers).await?);
+        }
+        let mapping = concat(
+            mappings.into_iter().map(IntoLazy::lazy).collect::<Vec<_>>(),
+            UnionArgs::default(),
+        )?;
+        to_short_ids(rows.lazy(), &mapping, &columns).collect()
+    }
+}
+
+/// Intern `identifiers`, which all belong in `table`, returning the rows of
+/// `table` holding them
+async fn intern_in_table(
+    file_manager: &FileManager,
+    table: &'static Table,
+    identifiers: Vec<String>,
+) -> PolarsResult<DataFrame> {
+    let wanted = df!(IdentifierRow::IDENTIFIER => &identifiers)?;
+    // Most identifiers have been seen before, which only needs a shared
+    // lock to find out
+    let known = if identifiers.len() <= POINT_LOOKUPS {
+        let mut found = Vec::with_capacity(identifiers.len());
+        for identifier in identifiers {
+            found.push(
+                file_manager
+                    .get_by_key(table, IdentifierRow::IDENTIFIER, identifier)
+                    .await?
+                    .lazy(),
+            );
+        }
+        concat(found, UnionArgs::default())?.collect()?
+    } else {
+        file_manager
+            .get_lazyframe(table)
+            .await?
+            .query(|frame| semi_join(frame, wanted.clone().lazy()))?
+    };
+    if known.height() == wanted.height() {
+        return Ok(known);
+    }
+    let mut managed = file_manager.get_managed_lazyframe(table).await?;
+    let existing = managed.query(|frame| frame)?;
+    let new = assign(&existing, &wanted)?;
+    if new.height() > 0 {
+        managed.apply(&Mutation::Upsert(new.clone()))?;
+    }
+    semi_join(
+        concat([existing.lazy(), new.lazy()], UnionArgs::default())?,
+        wanted.lazy(),
+    )
+    .collect()
+}
+
+/// The rows of `frame` whose identifier is in `wanted`
+fn semi_join(frame: LazyFrame, wanted: LazyFrame) -> LazyFrame {
+    frame.join(
+        wanted,
+        [col(IdentifierRow::IDENTIFIER)],
+        [col(IdentifierRow::IDENTIFIER)],
+        JoinArgs::new(JoinType::Semi),
+    )
+}
+
+/// `values` grouped by the table `table` says each of them belongs in
+fn by_table<T>(
+    values: impl IntoIterator<Item = T>,
+    table: impl Fn(&T) -> &'static Table,
+) -> Vec<(&'static Table, Vec<T>)> {
+    let mut tables: BTreeMap<&str, (&Table, Vec<T>)> = BTreeMap::new();
+    for value in values {
+        let table = table(&value);
+        tables
+            .entry(table.name)
+            .or_insert_with(|| (table, Vec::new()))
+            .1
+            .push(value);
+    }
+    tables.into_values().collect()
+}
+
+/// The table in `IDENTIFIER_TABLES` at `n` modulo `IDENTIFIER_PARTITIONS`
+fn partition(n: u64) -> &'static Table {
+    // The remainder is below IDENTIFIER_PARTITIONS, so it always fits
+    let index = usize::try_from(n % IDENTIFIER_PARTITIONS).unwrap_or_default();
+    &IDENTIFIER_TABLES[index]
+}
+
+/// The position of the table `identifier` is interned in, which is also the
+/// remainder of its short ID modulo `IDENTIFIER_PARTITIONS`
+fn partition_of(identifier: &str) -> u64 {
+    u64::from(crc32fast::hash(identifier.as_bytes())) % IDENTIFIER_PARTITIONS
+}
+
+/// The table `identifier` is interned in
+pub(crate) fn table_of(identifier: &str) -> &'static Table {
+    partition(partition_of(identifier))
+}
+
+/// The table the short ID `short_id` was handed out by
+pub(crate) fn table_of_id(short_id: u64) -> &'static Table {
+    partition(short_id)
+}
+
+/// A message requesting the short ID of a single identifier, without
+/// interning it if it hasn't been seen before
+pub(crate) struct LookupIdentifier(String);
+
+impl Message for LookupIdentifier {
+    type Response = PolarsResult<Option<u64>>;
+}
+
+impl Receive<LookupIdentifier> for FileManager {
+    async fn handle(
+        &self,
+        message: LookupIdentifier,
+    ) -> <LookupIdentifier as Message>::Response {
+        let table = table_of(&message.0);
+        let found = self
+            .get_by_key(table, IdentifierRow::IDENTIFIER, message.0)
+            .await?;
+        first_short_id(&found)
+    }
+}
+
+/// A message requesting that the file manager replace short IDs with the
+/// identifiers they stand for
+pub(crate) struct ResolveIds {
+    /// The rows holding short IDs
+    rows: DataFrame,
+    /// The columns of `rows` holding short IDs
+    columns: &'static [&'static str],
+}
+
+impl Message for ResolveIds {
+    type Response = PolarsResult<DataFrame>;
+}
+
+impl Receive<ResolveIds> for FileManager {
+    async fn handle(
+        &self,
+        message: ResolveIds,
+    ) -> <ResolveIds as Message>::Response {
+        let ResolveIds {
+            rows,
+            columns,
+        } = message;
+        let short_ids = distinct_short_ids(&rows, columns)?;
+        let mut mappings = vec![IDENTIFIERS.empty()?.lazy()];
+        for (table, short_ids) in
+            by_table(short_ids, |short_id| table_of_id(*short_id))
+        {
+            if short_ids.len() <= POINT_LOOKUPS {
+                for short_id in short_ids {
+                    let found = self
+                        .get_by_key(table, IdentifierRow::SHORT_ID, short_id)
+                        .await?;
+                    mappings.push(found.lazy());
+                }
+            } else {
+                let wanted = df!(IdentifierRow::SHORT_ID => short_ids)?.lazy();
+                let found =
+                    self.get_lazyframe(table).await?.query(|frame| {
+                        frame.join(
+                            wanted,
+                            [col(IdentifierRow::SHORT_ID)],
+                            [col(IdentifierRow::SHORT_ID)],
+                            JoinArgs::new(JoinType::Semi),
+                        )
+                    })?;
+                mappings.push(found.lazy());
+            }
+        }
+        let mapping = concat(mappings, UnionArgs::default())?;
+        resolve(rows.lazy(), &mapping, columns).collect()
+    }
+}
+
+/// Every distinct, non-null short ID in `columns` of `rows`
+fn distinct_short_ids(
+    rows: &DataFrame,
+    columns: &[&str],
+) -> PolarsResult<Vec<u64>> {
+    let mut short_ids = Vec::new();
+    for name in columns {
+        short_ids.extend(rows.column(name)?.u64()?.into_iter().flatten());
+    }
+    short_ids.sort_unstable();
+    short_ids.dedup();
+    Ok(short_ids)
+}
+
+/// The first short ID in `rows`, if there is one
+fn first_short_id(rows: &DataFrame) -> PolarsResult<Option<u64>> {
+    let short_id = rows
+        .column(IdentifierRow::SHORT_ID)?
+        .u64()?
+        .into_iter()
+        .next()
+        .flatten();
+    Ok(short_id)
+}
+
+/// Get the short ID of `identifier` as seen by `transaction`, which has to
+/// include `table_of(identifier)`, if it has one
+///
+/// # Errors
+///
+/// This function will return an error if the table of `identifier` isn't part
+/// of the transaction or could not be queried.
+pub(crate) fn lookup_in(
+    transaction: &Transaction,
+    identifier: &str,
+) -> PolarsResult<Option<u64>> {
+    let found = transaction.query(table_of(identifier), |frame| {
+        frame.filter(
+            col(IdentifierRow::IDENTIFIER).eq(lit(identifier.to_owned())),
+        )
+    })?;
+    first_short_id(&found)
+}
+
+/// Get the short ID of `identifier` as seen by `transaction`, which has to
+/// include `table_of(identifier)`. If it hasn't been seen before, a new short
+/// ID is staged in the transaction, and only kept if it commits.
+///
+/// # Errors
+///
+/// This function will return an error if the table of `identifier` isn't part
+/// of the transaction or the new short ID could not be staged.
+pub(crate) fn intern_in(
+    transaction: &mut Transaction,
+    identifier: &str,
+) -> PolarsResult<u64> {
+    if let Some(short_id) = lookup_in(transaction, identifier)? {
+        return Ok(short_id);
+    }
+    let table = table_of(identifier);
+    let existing = transaction.query(table, |frame| frame)?;
+    let new =
+        assign(&existing, &df!(IdentifierRow::IDENTIFIER => [identifier])?)?;
+    let short_id = first_short_id(&new)?.ok_or_else(|| {
+        PolarsError::ComputeError(
+            format!("{identifier} could not be interned").into(),
+        )
+    })?;
+    transaction.stage(table, Mutation::Upsert(new))?;
+    Ok(short_id)
+}
+
+/// Every distinct, non-null value in `columns` of `rows`, as strings in a
+/// single `identifier` column
+pub(crate) fn distinct_identifiers(
+    rows: &DataFrame,
+    columns: &[&str],
+) -> PolarsResult<DataFrame> {
+    if columns.is_empty() {
+        return DataFrame::new(vec![Series::new_empty(
+            IdentifierRow::IDENTIFIER,
+            &DataType::String,
+        )]);
+    }
+    let frames: Vec<LazyFrame> = columns
+        .iter()
+        .map(|name| {
+            rows.clone().lazy().select([col(name)
+                .cast(DataType::String)
+                .alias(IdentifierRow::IDENTIFIER)])
+        })
+        .collect();
+    concat(frames, UnionArgs::default())?
+        .drop_nulls(None)
+        .unique(None, UniqueKeepStrategy::Any)
+        .collect()
+}
+
+/// Assign short IDs to every identifier in `wanted` that isn't in `existing`,
+/// the current contents of the table every identifier in `wanted` belongs in.
+///
+/// Returns the new rows of that table. Short IDs continue from the largest
+/// one in `existing`, skipping those handed out by the other tables.
+pub(crate) fn assign(
+    existing: &DataFrame,
+    wanted: &DataFrame,
+) -> PolarsResult<DataFrame> {
+    let mut new = wanted
+        .clone()
+        .lazy()
+        .select([col(IdentifierRow::IDENTIFIER)])
+        .join(
+            existing.clone().lazy(),
+            [col(IdentifierRow::IDENTIFIER)],
+            [col(IdentifierRow::IDENTIFIER)],
+            JoinArgs::new(JoinType::Anti),
+        )
+        .sort([IdentifierRow::IDENTIFIER], SortMultipleOptions::default())
+        .collect()?;
+    let next = match existing.column(IdentifierRow::SHORT_ID)?.u64()?.max() {
+        Some(max) => max + IDENTIFIER_PARTITIONS,
+        None => new
+            .column(IdentifierRow::IDENTIFIER)?
+            .str()?
+            .get(0)
+            .map_or(0, partition_of),
+    };
+    let short_ids = UInt64Chunked::from_iter_values(
+        IdentifierRow::SHORT_ID,
+        iter::successors(Some(next), |short_id| {
+            Some(short_id + IDENTIFIER_PARTITIONS)
+        })
+        .take(new.height()),
+    );
+    new.insert_column(0, short_ids.into_series())?;
+    Ok(new)
+}
+
+/// Replace the identifiers in `columns` of `frame` with their short IDs from
+/// `identifiers`, a frame with the columns of the `identifiers` table.
+///
+/// Identifiers without a short ID in `identifiers` become null.
+pub(crate) fn to_short_ids(
+    frame: LazyFrame,
+    identifiers: &LazyFrame,
+    columns: &[&str],
+) -> LazyFrame {
+    columns.iter().fold(frame, |frame, name| {
+        frame
+            .with_column(col(name).cast(DataType::String))
+            .join(
+                identifiers.clone(),
+                [col(name)],
+                [col(IdentifierRow::IDENTIFIER)],
+                JoinArgs::new(JoinType::Left),
+            )
+            .with_column(col(IdentifierRow::SHORT_ID).alias(name))
+            .drop([IdentifierRow::SHORT_ID])
+    })
+}
+
+/// Replace the short IDs in `columns` of `frame` with the identifiers they
+/// stand for in `identifiers`, a frame with the columns of the `identifiers`
+/// table.
+///
+/// Short IDs that aren't in `identifiers` become null.
+pub(crate) fn resolve(
+    frame: LazyFrame,
+    identifiers: &LazyFrame,
+    columns: &[&str],
+) -> LazyFrame {
+    columns.iter().fold(frame, |frame, name| {
+        frame
+            .join(
+                identifiers.clone(),
+                [col(name)],
+                [col(IdentifierRow::SHORT_ID)],
+                JoinArgs::new(JoinType::Left),
+            )
+            .with_column(col(IdentifierRow::IDENTIFIER).alias(name))
+            .drop([IdentifierRow::IDENTIFIER])
+    })
+}
+
+/// Functionality for converting between identifiers and short IDs
+pub(crate) trait Interner {
+    /// Replace the identifiers in the interned columns of `rows` with their
+    /// short IDs, so they can be written to `table`. Identifiers that haven't
+    /// been seen before are given new short IDs.
+    async fn intern_rows(
+        &self,
+        table: &'static Table,
+        rows: DataFrame,
+    ) -> PolarsResult<DataFrame>;
+    /// Get the short ID of an identifier, if it has one
+    async fn lookup(&self, identifier: &str) -> PolarsResult<Option<u64>>;
+    /// Replace the short IDs in `columns` of `rows` with the identifiers they
+    /// stand for, so they can be returned to clients
+    async fn resolve_ids(
+        &self,
+        rows: DataFrame,
+        columns: &'static [&'static str],
+    ) -> PolarsResult<DataFrame>;
+}
+
+impl Interner for FileManager {
+    async fn intern_rows(
+        &self,
+        table: &'static Table,
+        rows: DataFrame,
+    ) -> PolarsResult<DataFrame> {
+        self.handle(InternRows {
+            table,
+            rows,
+        })
+        .await
+    }
+
+    async fn lookup(&self, identifier: &str) -> PolarsResult<Option<u64>> {
+        self.handle(LookupIdentifier(identifier.to_owned())).await
+    }
+
+    async fn resolve_ids(
+        &self,
+        rows: DataFrame,
+        columns: &'static [&'static str],
+    ) -> PolarsResult<DataFrame> {
+        self.handle(ResolveIds {
+            rows,
+            columns,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use polars::prelude::*;
+
+    use super::{intern_in, lookup_in, table_of, table_of_id, Interner};
+    use crate::{
+        managers::{
+            dataframes::ParquetManager, storage::memory_file_manager,
+            transaction::Transactions,
+        },
+        schema::{RoomStateRow, IDENTIFIER_PARTITIONS, ROOM_STATE},
+    };
+
+    #[tokio::test]
+    async fn identifiers_are_only_interned_if_the_transaction_commits() {
+        let file_manager = memory_file_manager();
+
+        let mut transaction = file_manager
+            .transaction(&[table_of("@alice:example.org")])
+            .await
+            .expect("Failed to begin transaction");
+        let short_id = intern_in(&mut transaction, "@alice:example.org")
+            .expect("Failed to intern alice");
+        assert_eq!(
+            lookup_in(&transaction, "@alice:example.org")
+                .expect("Failed to look alice up"),
+            Some(short_id)
+        );
+        drop(transaction);
+        assert_eq!(
+            file_manager
+                .lookup("@alice:example.org")
+                .await
+                .expect("Failed to look alice up"),
+            None
+        );
+
+        let mut transaction = file_manager
+            .transaction(&[table_of("@alice:example.org")])
+            .await
+            .expect("Failed to begin transaction");
+        let short_id = intern_in(&mut transaction, "@alice:example.org")
+            .expect("Failed to intern alice");
+        transaction.commit().expect("Failed to commit");
+        assert_eq!(
+            file_manager
+                .lookup("@alice:example.org")
+                .await
+                .expect("Failed to look alice up"),
+            Some(short_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn identifiers_round_trip_through_their_tables() {
+        let file_manager = memory_file_manager();
+        // Enough identifiers for some tables to be read whole rather than
+        // searched one identifier at a time
+        let events: Vec<String> = (0..IDENTIFIER_PARTITIONS * 20)
+            .map(|n| format!("$event{n}"))
+            .collect();
+        let rows = df!(
+            RoomStateRow::ROOM_ID => vec!["!room:example.org"; events.len()],
+            RoomStateRow::EVENT_ID => &events,
+        )
+        .expect("Invalid frame");
+
+        let interned = file_manager
+            .intern_rows(&ROOM_STATE, rows.clone())
+            .await
+            .expect("Failed to intern rows");
+        let short_ids: Vec<u64> = interned
+            .column(RoomStateRow::EVENT_ID)
+            .expect("Missing event IDs")
+            .u64()
+            .expect("Event IDs weren't interned")
+            .into_no_null_iter()
+            .collect();
+        for (event, short_id) in events.iter().zip(&short_ids) {
+            assert_eq!(table_of_id(*short_id).name, table_of(event).name);
+        }
+        let again = file_manager
+            .intern_rows(&ROOM_STATE, rows.clone())
+            .await
+            .expect("Failed to intern rows again");
+        assert!(again.equals(&interned));
+        let identifiers = file_manager
+            .get_lazyframe(table_of(&events[0]))
+            .await
+            .expect("Failed to read identifiers")
+            .query(|frame| frame)
+            .expect("Failed to read identifiers");
+        assert!(identifiers.height() < events.len());
+
+        for rows in [interned.clone(), interned.head(Some(3))] {
+            let resolved = file_manager
+                .resolve_ids(
+                    rows.clone(),
+                    &[RoomStateRow::ROOM_ID, RoomStateRow::EVENT_ID],
+                )
+                .await
+                .expect("Failed to resolve rows");
+            let expected = df!(
+                RoomStateRow::ROOM_ID =>
+                    vec!["!room:example.org"; rows.height()],
+                RoomStateRow::EVENT_ID => &events[..rows.height()],
+            )
+            .expect("Invalid frame");
+            assert!(resolved.equals(&expected));
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Storage for uploaded media
+//!
+//! Every piece of media is kept as a single file in
+//! `PROGRAM_CONFIG.media_path`, named by its media ID. Files are written and
+//! read through `managers::encryption`, so media is sealed at rest whenever
+//! encryption is turned on, and resealed along with everything else when the
+//! key is rotated.
+//!
+//! Each file is locked through the `FileManager`. Reading takes a shared lock
+//! and writing an exclusive one. Files are written beside their final path
+//! and moved into place, so a read never sees a partially written file.
+//!
+//! Media is always stored on disk, regardless of `PROGRAM_CONFIG.storage`.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use cubby_lib::file_manager::{FileManager, Message, Receive};
+use polars::prelude::*;
+
+use crate::{
+    config::PROGRAM_CONFIG,
+    managers::{dataframes::lock_error, encryption},
+};
+
+/// A message requesting that the file manager store a piece of media
+pub(crate) struct PutMedia {
+    /// The media ID to store it under, replacing anything already stored
+    /// under it
+    media_id: String,
+    /// The contents of the media
+    content: Vec<u8>,
+}
+
+impl Message for PutMedia {
+    type Response = PolarsResult<()>;
+}
+
+impl Receive<PutMedia> for FileManager {
+    async fn handle(
+        &self,
+        message: PutMedia,
+    ) -> <PutMedia as Message>::Response {
+        let PutMedia {
+            media_id,
+            content,
+        } = message;
+        let media_dir = PROGRAM_CONFIG.media_path.clone();
+        let path = media_file(&media_dir, &media_id)?;
+        let lock =
+            self.lock_exclusive(path).await.map_err(|e| lock_error(&e))?;
+        tokio::task::spawn_blocking(move || {
+            let result = write_media(&media_dir, lock.get_path(), content);
+            drop(lock);
+            result
+        })
+        .await
+        .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?
+    }
+}
+
+/// A message requesting the contents of a piece of media, if it is stored
+pub(crate) struct GetMedia(String);
+
+impl Message for GetMedia {
+    type Response = PolarsResult<Option<Vec<u8>>>;
+}
+
+impl Receive<GetMedia> for FileManager {
+    async fn handle(
+        &self,
+        message: GetMedia,
+    ) -> <GetMedia as Message>::Response {
+        let path = media_file(&PROGRAM_CONFIG.media_path, &message.0)?;
+        let lock = self.lock_shared(path).await.map_err(|e| lock_error(&e))?;
+        tokio::task::spawn_blocking(move || {
+            let result = read_media(lock.get_path());
+            drop(lock);
+            result
+        })
+        .await
+        .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?
+    }
+}
+
+/// The file `media_id` is stored in inside `media_dir`.
+///
+/// Media IDs may only hold ASCII letters, digits, `-` and `_`, so they can't
+/// name a file anywhere else.
+///
+/// # Errors
+///
+/// This function will return an error if `media_id` is empty or holds any
+/// other characters.
+pub(crate) fn media_file(
+    media_dir: &Path,
+    media_id: &str,
+) -> PolarsResult<PathBuf> {
+    let valid = !media_id.is_empty()
+        && media_id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_".contains(&byte));
+    if !valid {
+        return Err(PolarsError::ComputeError(
+            format!("Invalid media ID {media_id:?}").into(),
+        ));
+    }
+    Ok(media_dir.join(media_id))
+}
+
+/// The temporary file media is written to before being moved to `path`.
+///
+/// Media IDs can't hold a `.`, so this never names another piece of media.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+/// Every file holding media in `media_dir`, leaving out temporary files
+///
+/// # Errors
+///
+/// This function will return an error if `media_dir` exists but can't be
+/// listed.
+pub(crate) fn media_files(media_dir: &Path) -> PolarsResult<Vec<PathBuf>> {
+    let entries = match fs::read_dir(media_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_none() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Write `content` to the file at `path` inside `media_dir`, sealing it if
+/// encryption is turned on
+fn write_media(
+    media_dir: &Path,
+    path: &Path,
+    content: Vec<u8>,
+) -> PolarsResult<()> {
+    fs::create_dir_all(media_dir)?;
+    encryption::write_sealed(path, &temp_path(path), content)
+}
+
+/// Read the media stored at `path`, if there is any
+fn read_media(path: &Path) -> PolarsResult<Option<Vec<u8>>> {
+    match encryption::read_sealed(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(PolarsError::IO {
+            error,
+            ..
+        }) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Functionality for storing and reading uploaded media
+// Media is only stored and served by the media repository endpoints, which
+// don't exist yet
+#[allow(dead_code)]
+pub(crate) trait MediaStore {
+    /// Store `content` under `media_id`, replacing anything already stored
+    /// under it
+    async fn put_media(
+        &self,
+        media_id: &str,
+        content: Vec<u8>,
+    ) -> PolarsResult<()>;
+    /// Get the contents of the media stored under `media_id`, if there is any
+    async fn get_media(&self, media_id: &str) -> PolarsResult<Option<Vec<u8>>>;
+}
+
+impl MediaStore for FileManager {
+    async fn put_media(
+        &self,
+        media_id: &str,
+        content: Vec<u8>,
+    ) -> PolarsResult<()> {
+        self.handle(PutMedia {
+            media_id: media_id.to_owned(),
+            content,
+        })
+        .await
+    }
+
+    async fn get_media(&self, media_id: &str) -> PolarsResult<Option<Vec<u8>>> {
+        self.handle(GetMedia(media_id.to_owned())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{media_file, media_files, MediaStore};
+    use crate::{
+        config::PROGRAM_CONFIG, managers::storage::memory_file_manager,
+    };
+
+    #[tokio::test]
+    async fn media_round_trips() {
+        let file_manager = memory_file_manager();
+        assert_eq!(
+            file_manager
+                .get_media("round_trip")
+                .await
+                .expect("Failed to read media"),
+            None
+        );
+
+        for content in [b"first".to_vec(), b"second".to_vec()] {
+            file_manager
+                .put_media("round_trip", content.clone())
+                .await
+                .expect("Failed to store media");
+            assert_eq!(
+                file_manager
+                    .get_media("round_trip")
+                    .await
+                    .expect("Failed to read media"),
+                Some(content)
+            );
+        }
+        let path = media_file(&PROGRAM_CONFIG.media_path, "round_trip")
+            .expect("Invalid media ID");
+        assert_eq!(fs::read(&path).expect("Failed to read file"), b"second");
+        assert!(media_files(&PROGRAM_CONFIG.media_path)
+            .expect("Failed to list media")
+            .contains(&path));
+    }
+
+    #[tokio::test]
+    async fn media_ids_cannot_name_other_files() {
+        let file_manager = memory_file_manager();
+        for media_id in ["", "..", "../users.parquet", "a/b", "a.tmp"] {
+            assert!(media_file(&PROGRAM_CONFIG.media_path, media_id).is_err());
+            assert!(file_manager
+                .put_media(media_id, b"planted".to_vec())
+                .await
+                .is_err());
+            assert!(file_manager.get_media(media_id).await.is_err());
+        }
+    }
+}
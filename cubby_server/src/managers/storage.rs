@@ -0,0 +1,432 @@
+//! Storage backends for tables
+//!
+//! Endpoints never touch table storage directly. They go through
+//! `ParquetManager`, which locks tables with the `FileManager` and reads and
+//! writes them through the backend attached to it with
+//! `StorageAccess::with_storage`. The server attaches the backend selected by
+//! `PROGRAM_CONFIG.storage`:
+//!
+//! - `ParquetBackend` keeps every table in a parquet file in a data directory,
+//!   with a write-ahead log beside each one. Both are sealed if encryption is
+//!   turned on (see `managers::encryption`).
+//! - `MemoryBackend` keeps every table in memory. Nothing survives a restart,
+//!   which makes it useful for running handlers in tests without touching the
+//!   filesystem.
+//!
+//! Tables are still locked by path with either backend, so handlers behave the
+//! same way regardless of where their tables live. Both backends keep tables
+//! sorted by key and update their `Indexes` (see `managers::index`) on every
+//! write.
+//!
+//! Transactions over several tables (see `managers::transaction`) are
+//! committed through `StorageBackend::commit` before any of their changes
+//! reach the journals of their tables.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use cubby_lib::FileManager;
+use polars::prelude::*;
+use tracing::info;
+
+use crate::{
+    config::{Storage, PROGRAM_CONFIG},
+    managers::{
+        dataframes::write_row_groups_atomic,
+        encryption,
+        index::{self, Indexes},
+        wal::{Mutation, TransactionLog, WriteAheadLog},
+    },
+    schema::{self, Table, TABLES},
+};
+
+/// The storage backend selected in the configuration. It still has to be
+/// initialized.
+pub(crate) fn from_config() -> Arc<dyn StorageBackend> {
+    match PROGRAM_CONFIG.storage {
+        Storage::Parquet => {
+            Arc::new(ParquetBackend::new(PROGRAM_CONFIG.data_path.clone()))
+        }
+        Storage::Memory => Arc::new(MemoryBackend::default()),
+    }
+}
+
+/// A `FileManager` storing tables in a fresh `MemoryBackend`, for tests
+#[cfg(test)]
+pub(crate) fn memory_file_manager() -> FileManager {
+    // Categorical columns written by different tests have to share a cache
+    polars::enable_string_cache();
+    let backend = MemoryBackend::default();
+    backend.initialize().expect("Failed to initialize memory storage");
+    FileManager::new().with_storage(Arc::new(backend))
+}
+
+/// The tables of a backend that are poisoned until it is initialized again
+#[derive(Debug, Default)]
+struct Poisoned {
+    /// The names of the poisoned tables
+    tables: Mutex<HashSet<&'static str>>,
+}
+
+/// Access to the storage backend attached to a `FileManager`
+pub(crate) trait StorageAccess {
+    /// Attach the backend tables locked through this `FileManager` are stored
+    /// in. It must already be initialized.
+    #[must_use]
+    fn with_storage(self, backend: Arc<dyn StorageBackend>) -> Self;
+
+    /// The backend tables locked through this `FileManager` are stored in
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no backend has been attached.
+    fn storage(&self) -> PolarsResult<&dyn StorageBackend>;
+
+    /// Refuse every further read or write of `table` until the server
+    /// restarts, because its journal is missing changes that only recovery
+    /// at startup can restore
+    fn poison(&self, table: &'static Table);
+
+    /// Fail if `table` has been poisoned
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `table` has been poisoned.
+    fn check_poisoned(&self, table: &Table) -> PolarsResult<()>;
+}
+
+impl StorageAccess for FileManager {
+    fn with_storage(self, backend: Arc<dyn StorageBackend>) -> Self {
+        self.with_extension(backend).with_extension(Poisoned::default())
+    }
+
+    fn storage(&self) -> PolarsResult<&dyn StorageBackend> {
+        self.extension::<Arc<dyn StorageBackend>>()
+            .map(AsRef::as_ref)
+            .ok_or_else(|| {
+                PolarsError::ComputeError(
+                    "No storage backend is attached to the file manager".into(),
+                )
+            })
+    }
+
+    fn poison(&self, table: &'static Table) {
+        if let Some(poisoned) = self.extension::<Poisoned>() {
+            poisoned
+                .tables
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(table.name);
+        }
+    }
+
+    fn check_poisoned(&self, table: &Table) -> PolarsResult<()> {
+        let poisoned = self.extension::<Poisoned>().is_some_and(|poisoned| {
+            poisoned
+                .tables
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .contains(table.name)
+        });
+        if poisoned {
+            return Err(PolarsError::ComputeError(
+                format!(
+                    "Table {} is missing changes from a transaction that \
+                     could not be completed. Restart the server to recover \
+                     them.",
+                    table.name
+                )
+                .into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Somewhere tables can be read from and written to.
+///
+/// Callers must hold a lock on `table.path()` from the `FileManager` while
+/// calling any of these functions for a table: a shared lock for reading, and
+/// an exclusive one for anything else.
+pub(crate) trait StorageBackend: Send + Sync {
+    /// Prepare the backend for use, making sure every table exists and is at
+    /// the current schema version. This must run before any lock is issued.
+    fn initialize(&self) -> PolarsResult<()>;
+
+    /// The indexes of every table, kept up to date by `write`
+    fn indexes(&self) -> &Indexes;
+
+    /// Read the contents of a table
+    fn read(&self, table: &Table) -> PolarsResult<LazyFrame>;
+
+    /// Read only the rows of a table in `ranges`, given as `(offset, len)`
+    /// pairs such as those from `Indexes::candidates`, without reading any
+    /// more of the table than the backend has to
+    fn read_ranges(
+        &self,
+        table: &Table,
+        ranges: &[(usize, usize)],
+    ) -> PolarsResult<DataFrame>;
+
+    /// Open the journal recording changes to a table until they are written
+    fn journal(&self, table: &Table) -> PolarsResult<Box<dyn Journal>>;
+
+    /// Replace the contents of a table, sorting them by key and updating the
+    /// table's index
+    fn write(&self, table: &Table, df: &mut DataFrame) -> PolarsResult<()>;
+
+    /// Durably record every change a transaction makes, committing it. The
+    /// changes are appended to the journals of their tables afterwards, so
+    /// once this returns `Ok` they must survive anything the backend promises
+    /// to survive even if that never happens.
+    fn commit(
+        &self,
+        changes: &[(&Table, Mutation)],
+    ) -> PolarsResult<Box<dyn Commit>>;
+}
+
+/// A committed transaction whose changes are being appended to the journals
+/// of their tables
+pub(crate) trait Commit: Send {
+    /// Forget the transaction, once every change is in its table's journal
+    fn finish(self: Box<Self>) -> PolarsResult<()>;
+}
+
+/// A record of changes made to a table that have not been written yet
+pub(crate) trait Journal: Send {
+    /// Every change recorded since the journal was last cleared
+    fn pending(&self) -> PolarsResult<Vec<Mutation>>;
+
+    /// Record a change. Once this returns `Ok` the change must survive
+    /// anything the backend promises to survive.
+    fn append(&mut self, mutation: &Mutation) -> PolarsResult<()>;
+
+    /// Forget every recorded change, once they have all been written
+    fn clear(&mut self) -> PolarsResult<()>;
+}
+
+/// Stack the parts of a table read by `StorageBackend::read_ranges` into one
+/// `DataFrame`
+fn stack(table: &Table, parts: Vec<DataFrame>) -> PolarsResult<DataFrame> {
+    let mut parts = parts.into_iter();
+    let Some(mut rows) = parts.next() else {
+        return table.empty();
+    };
+    for part in parts {
+        rows.vstack_mut(&part)?;
+    }
+    Ok(rows)
+}
+
+/// Tables stored as parquet files in a data directory
+pub(crate) struct ParquetBackend {
+    /// The directory every table is stored in
+    data_path: PathBuf,
+    /// The indexes of every table
+    indexes: Indexes,
+}
+
+impl ParquetBackend {
+    /// A backend storing tables in `data_path`
+    pub(crate) fn new(data_path: PathBuf) -> Self {
+        Self {
+            data_path,
+            indexes: Indexes::default(),
+        }
+    }
+
+    /// Build the index of every table, sorting tables that were written
+    /// unsorted by older versions, migrations or `cubby check --repair`
+    fn build_indexes(&self) -> PolarsResult<()> {
+        for table in TABLES.iter() {
+            let columns: Vec<Expr> = table
+                .key
+                .iter()
+                .chain(table.indexed)
+                .map(|name| col(name))
+                .collect();
+            let indexed = self.read(table)?.select(columns).collect()?;
+            if index::is_sorted(table, &indexed)? {
+                self.indexes.update(table, &indexed)?;
+            } else {
+                info!("Sorting table {} so it can be indexed", table.name);
+                self.write(table, &mut self.read(table)?.collect()?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for ParquetBackend {
+    fn initialize(&self) -> PolarsResult<()> {
+        // Fail before touching anything if the key file is invalid
+        encryption::keyring()?;
+        schema::initialize(&self.data_path)?;
+        self.build_indexes()
+    }
+
+    fn indexes(&self) -> &Indexes {
+        &self.indexes
+    }
+
+    fn read(&self, table: &Table) -> PolarsResult<LazyFrame> {
+        encryption::scan_parquet(&table.path_in(&self.data_path))
+    }
+
+    fn read_ranges(
+        &self,
+        table: &Table,
+        ranges: &[(usize, usize)],
+    ) -> PolarsResult<DataFrame> {
+        let path = table.path_in(&self.data_path);
+        stack(table, encryption::read_parquet_ranges(&path, ranges)?)
+    }
+
+    fn journal(&self, table: &Table) -> PolarsResult<Box<dyn Journal>> {
+        Ok(Box::new(WriteAheadLog::open(&table.path_in(&self.data_path))?))
+    }
+
+    fn write(&self, table: &Table, df: &mut DataFrame) -> PolarsResult<()> {
+        index::sort(table, df)?;
+        write_row_groups_atomic(
+            &table.path_in(&self.data_path),
+            df,
+            Some(index::BLOCK_ROWS),
+        )?;
+        self.indexes.update(table, df)
+    }
+
+    fn commit(
+        &self,
+        changes: &[(&Table, Mutation)],
+    ) -> PolarsResult<Box<dyn Commit>> {
+        Ok(Box::new(TransactionLog::commit(&self.data_path, changes)?))
+    }
+}
+
+impl Commit for TransactionLog {
+    fn finish(self: Box<Self>) -> PolarsResult<()> {
+        TransactionLog::finish(*self)
+    }
+}
+
+impl Journal for WriteAheadLog {
+    fn pending(&self) -> PolarsResult<Vec<Mutation>> {
+        self.read()
+    }
+
+    fn append(&mut self, mutation: &Mutation) -> PolarsResult<()> {
+        WriteAheadLog::append(self, mutation)
+    }
+
+    fn clear(&mut self) -> PolarsResult<()> {
+        self.truncate()
+    }
+}
+
+/// Tables stored in memory, lost when the server stops
+#[derive(Default)]
+pub(crate) struct MemoryBackend {
+    /// The contents of every table, by name
+    tables: Mutex<HashMap<&'static str, DataFrame>>,
+    /// The indexes of every table
+    indexes: Indexes,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn initialize(&self) -> PolarsResult<()> {
+        let mut tables =
+            self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+        for table in TABLES.iter() {
+            if !tables.contains_key(table.name) {
+                let empty = table.empty()?;
+                self.indexes.update(table, &empty)?;
+                tables.insert(table.name, empty);
+            }
+        }
+        Ok(())
+    }
+
+    fn indexes(&self) -> &Indexes {
+        &self.indexes
+    }
+
+    fn read(&self, table: &Table) -> PolarsResult<LazyFrame> {
+        let tables = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+        let df = tables.get(table.name).ok_or_else(|| {
+            PolarsError::ComputeError(
+                format!("Table {} does not exist", table.name).into(),
+            )
+        })?;
+        Ok(df.clone().lazy())
+    }
+
+    fn read_ranges(
+        &self,
+        table: &Table,
+        ranges: &[(usize, usize)],
+    ) -> PolarsResult<DataFrame> {
+        let df = self.read(table)?.collect()?;
+        let slices = ranges
+            .iter()
+            .map(|(offset, len)| {
+                let offset = i64::try_from(*offset).map_err(|e| {
+                    PolarsError::ComputeError(e.to_string().into())
+                })?;
+                Ok(df.slice(offset, *len))
+            })
+            .collect::<PolarsResult<Vec<_>>>()?;
+        stack(table, slices)
+    }
+
+    fn journal(&self, _table: &Table) -> PolarsResult<Box<dyn Journal>> {
+        Ok(Box::new(MemoryJournal))
+    }
+
+    fn write(&self, table: &Table, df: &mut DataFrame) -> PolarsResult<()> {
+        index::sort(table, df)?;
+        self.indexes.update(table, df)?;
+        self.tables
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(table.name, df.clone());
+        Ok(())
+    }
+
+    fn commit(
+        &self,
+        _changes: &[(&Table, Mutation)],
+    ) -> PolarsResult<Box<dyn Commit>> {
+        Ok(Box::new(MemoryJournal))
+    }
+}
+
+/// The journal of a `MemoryBackend` table, and the record of a committed
+/// `MemoryBackend` transaction.
+///
+/// Writing to memory can't fail and nothing survives a crash anyway, so there
+/// is nothing to record.
+struct MemoryJournal;
+
+impl Journal for MemoryJournal {
+    fn pending(&self) -> PolarsResult<Vec<Mutation>> {
+        Ok(Vec::new())
+    }
+
+    fn append(&mut self, _mutation: &Mutation) -> PolarsResult<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> PolarsResult<()> {
+        Ok(())
+    }
+}
+
+impl Commit for MemoryJournal {
+    fn finish(self: Box<Self>) -> PolarsResult<()> {
+        Ok(())
+    }
+}
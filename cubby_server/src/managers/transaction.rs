@@ -0,0 +1,408 @@
+//! Transactions over several tables
+//!
+//! Each `ManagedLazyFrame` journals and writes back its own table, so a
+//! handler that changes several tables through them can crash with only some
+//! of its changes made. A `Transaction` instead locks every table it needs
+//! exclusively up front and stages changes in memory, where `query` can see
+//! them but nothing else can.
+//!
+//! Nothing is recorded until `Transaction::commit`, which hands every staged
+//! change to `StorageBackend::commit` in one go. For parquet storage, that
+//! writes a single transaction log to `{data_path}/transactions` and renames
+//! it into place, which is the instant the transaction commits. Only then are
+//! the changes appended to the write-ahead log of each table and written back
+//! as usual, and the transaction log removed. If the server stops in between,
+//! the transaction log is replayed into the tables on the next startup.
+//!
+//! A transaction that is dropped without being committed, or fails before its
+//! log is written, changes nothing. One that fails after its log is written is
+//! only completed on the next startup, so its tables are poisoned and refuse
+//! every request until then. Otherwise changes made to them in the meantime
+//! would be overwritten when the log is replayed.
+
+use cubby_lib::file_manager::{FileManager, LockMode, Message, Receive};
+use polars::prelude::*;
+use tracing::error;
+
+use crate::{
+    managers::{
+        dataframes::{lock_error, set_writeback_failed, ManagedLazyFrame},
+        storage::StorageAccess,
+        wal::Mutation,
+    },
+    schema::Table,
+};
+
+/// A message requesting that the file manager begin a transaction over the
+/// given tables
+pub(crate) struct BeginTransaction(Vec<&'static Table>);
+
+impl Message for BeginTransaction {
+    type Response = PolarsResult<Transaction>;
+}
+
+impl Receive<BeginTransaction> for FileManager {
+    async fn handle(
+        &self,
+        message: BeginTransaction,
+    ) -> <BeginTransaction as Message>::Response {
+        let mut tables = message.0;
+        tables.sort_by_key(|table| table.name);
+        tables.dedup_by_key(|table| table.name);
+        let mut locks = self
+            .lock_many(
+                tables
+                    .iter()
+                    .map(|table| (table.path(), LockMode::Exclusive))
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .map_err(|e| lock_error(&e))?;
+        let mut members = Vec::with_capacity(tables.len());
+        for table in tables {
+            let path = table.path();
+            let position = locks
+                .iter()
+                .position(|lock| lock.get_path() == path)
+                .ok_or_else(|| {
+                    PolarsError::ComputeError(
+                        format!(
+                            "No lock was acquired for table {}",
+                            table.name
+                        )
+                        .into(),
+                    )
+                })?;
+            let frame = ManagedLazyFrame::new(
+                self,
+                table,
+                locks.swap_remove(position),
+            )?;
+            members.push(Member {
+                view: frame.frame(),
+                table,
+                frame,
+            });
+        }
+        Ok(Transaction {
+            file_manager: self.clone(),
+            members,
+            staged: Vec::new(),
+        })
+    }
+}
+
+/// A table taking part in a transaction
+struct Member {
+    /// The table
+    table: &'static Table,
+    /// The contents of the table as committed, holding the exclusive lock on
+    /// it
+    frame: ManagedLazyFrame,
+    /// The contents of the table including every staged change
+    view: LazyFrame,
+}
+
+/// A set of changes to several tables that are made all together or not at
+/// all.
+///
+/// Every table in the transaction stays locked exclusively until it is
+/// committed or dropped. Dropping it without committing discards every staged
+/// change.
+pub(crate) struct Transaction {
+    /// The manager the tables were locked through
+    file_manager: FileManager,
+    /// Every table in the transaction
+    members: Vec<Member>,
+    /// Every staged change, in order, with the index of its table in `members`
+    staged: Vec<(usize, Mutation)>,
+}
+
+impl Transaction {
+    /// The index in `members` of `table`
+    fn member(&self, table: &Table) -> PolarsResult<usize> {
+        self.members
+            .iter()
+            .position(|member| member.table.name == table.name)
+            .ok_or_else(|| {
+                PolarsError::ComputeError(
+                    format!(
+                        "Table {} is not part of the transaction",
+                        table.name
+                    )
+                    .into(),
+                )
+            })
+    }
+
+    /// Stage a change to `table`. It is visible to `query` straight away, but
+    /// isn't recorded anywhere until the transaction is committed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `table` isn't part of the
+    /// transaction or the change could not be applied to it. In either case
+    /// nothing is staged.
+    pub(crate) fn stage(
+        &mut self,
+        table: &Table,
+        mutation: Mutation,
+    ) -> PolarsResult<()> {
+        let index = self.member(table)?;
+        let member = &mut self.members[index];
+        member.view = mutation.apply_to(member.view.clone(), member.table)?;
+        self.staged.push((index, mutation));
+        Ok(())
+    }
+
+    /// Build a query from the contents of `table`, including every change
+    /// staged so far, and collect it while the table is still locked
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `table` isn't part of the
+    /// transaction or the query fails.
+    pub(crate) fn query<F: FnOnce(LazyFrame) -> LazyFrame>(
+        &self,
+        table: &Table,
+        closure: F,
+    ) -> PolarsResult<DataFrame> {
+        closure(self.members[self.member(table)?].view.clone()).collect()
+    }
+
+    /// Commit every staged change, then release the tables once they have
+    /// been written back.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the transaction could not be
+    /// committed, in which case nothing has changed. It will also return an
+    /// error if the transaction was committed but its changes could not all
+    /// be appended to the journals of their tables. Those are applied on the
+    /// next startup, and every table in the transaction is poisoned until
+    /// then.
+    pub(crate) fn commit(mut self) -> PolarsResult<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+        let changes: Vec<(&Table, Mutation)> = self
+            .staged
+            .iter()
+            .map(|(index, mutation)| {
+                (self.members[*index].table, mutation.clone())
+            })
+            .collect();
+        let record = self.file_manager.storage()?.commit(&changes)?;
+        // Every change is journaled before any frame is touched, so a failure
+        // part way through leaves nothing to be written back
+        for (index, mutation) in &self.staged {
+            if let Err(e) = self.members[*index].frame.record(mutation) {
+                return Err(self.abandon(e));
+            }
+        }
+        for (index, member) in self.members.iter_mut().enumerate() {
+            if self.staged.iter().any(|(staged, _)| *staged == index) {
+                member.frame.apply_recorded(member.view.clone());
+            }
+        }
+        if let Err(e) = record.finish() {
+            return Err(self.abandon(e));
+        }
+        Ok(())
+    }
+
+    /// Give up on a committed transaction whose changes could not all be
+    /// journaled or whose log could not be removed, returning `e`.
+    ///
+    /// Its log is replayed into the journals of its tables on the next
+    /// startup, after any change made to them in the meantime, so every table
+    /// in it is poisoned until then. Nothing staged is written back.
+    fn abandon(self, e: PolarsError) -> PolarsError {
+        set_writeback_failed();
+        error!(
+            "Committed transaction could not be completed: {e}. Its tables \
+             are unavailable until it is recovered on the next startup."
+        );
+        for member in self.members {
+            self.file_manager.poison(member.table);
+            member.frame.discard();
+        }
+        e
+    }
+}
+
+/// Functionality for changing several tables at once
+pub(crate) trait Transactions {
+    /// Begin a transaction over `tables`, locking every one of them
+    /// exclusively until it is committed or dropped
+    async fn transaction(
+        &self,
+        tables: &[&'static Table],
+    ) -> PolarsResult<Transaction>;
+}
+
+impl Transactions for FileManager {
+    async fn transaction(
+        &self,
+        tables: &[&'static Table],
+    ) -> PolarsResult<Transaction> {
+        self.handle(BeginTransaction(tables.to_vec())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cubby_lib::FileManager;
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::Transactions;
+    use crate::{
+        managers::{
+            dataframes::ParquetManager,
+            index::Indexes,
+            storage::{
+                Commit, Journal, ParquetBackend, StorageAccess, StorageBackend,
+            },
+            wal::Mutation,
+        },
+        schema::{DeviceRow, ParquetRow, Table, UserRow, DEVICES, USERS},
+    };
+
+    /// A parquet backend whose journal for one table fails every change, as
+    /// if the server was killed while journaling a transaction
+    struct KilledBackend {
+        /// The backend everything else is passed on to
+        inner: ParquetBackend,
+        /// The name of the table whose journal fails
+        table: &'static str,
+    }
+
+    /// A journal that fails every change
+    struct KilledJournal(Box<dyn Journal>);
+
+    impl Journal for KilledJournal {
+        fn pending(&self) -> PolarsResult<Vec<Mutation>> {
+            self.0.pending()
+        }
+
+        fn append(&mut self, _mutation: &Mutation) -> PolarsResult<()> {
+            Err(PolarsError::ComputeError("Killed".into()))
+        }
+
+        fn clear(&mut self) -> PolarsResult<()> {
+            self.0.clear()
+        }
+    }
+
+    impl StorageBackend for KilledBackend {
+        fn initialize(&self) -> PolarsResult<()> {
+            self.inner.initialize()
+        }
+
+        fn indexes(&self) -> &Indexes {
+            self.inner.indexes()
+        }
+
+        fn read(&self, table: &Table) -> PolarsResult<LazyFrame> {
+            self.inner.read(table)
+        }
+
+        fn read_ranges(
+            &self,
+            table: &Table,
+            ranges: &[(usize, usize)],
+        ) -> PolarsResult<DataFrame> {
+            self.inner.read_ranges(table, ranges)
+        }
+
+        fn journal(&self, table: &Table) -> PolarsResult<Box<dyn Journal>> {
+            let journal = self.inner.journal(table)?;
+            if table.name == self.table {
+                Ok(Box::new(KilledJournal(journal)))
+            } else {
+                Ok(journal)
+            }
+        }
+
+        fn write(&self, table: &Table, df: &mut DataFrame) -> PolarsResult<()> {
+            self.inner.write(table, df)
+        }
+
+        fn commit(
+            &self,
+            changes: &[(&Table, Mutation)],
+        ) -> PolarsResult<Box<dyn Commit>> {
+            self.inner.commit(changes)
+        }
+    }
+
+    #[tokio::test]
+    async fn commits_killed_between_tables_are_completed_on_startup() {
+        polars::enable_string_cache();
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let backend = ParquetBackend::new(dir.path().to_owned());
+        backend.initialize().expect("Failed to initialize storage");
+        let file_manager =
+            FileManager::new().with_storage(Arc::new(KilledBackend {
+                inner: backend,
+                table: DEVICES.name,
+            }));
+
+        // Changes are journaled in the order they were staged, so the change
+        // to users is journaled before journaling the one to devices fails
+        let mut transaction = file_manager
+            .transaction(&[&USERS, &DEVICES])
+            .await
+            .expect("Failed to begin transaction");
+        let user = UserRow {
+            user_id: 1,
+            username: "alice".to_owned(),
+            password_hash: None,
+            is_guest: false,
+            is_deactivated: false,
+            created_ts: 0,
+        };
+        let device = DeviceRow {
+            user_id: 1,
+            device_id: "DEVICE".to_owned(),
+            display_name: None,
+            created_ts: 0,
+        };
+        transaction
+            .stage(
+                &USERS,
+                Mutation::Upsert(
+                    UserRow::to_dataframe(&[user]).expect("Invalid row"),
+                ),
+            )
+            .expect("Failed to stage user");
+        transaction
+            .stage(
+                &DEVICES,
+                Mutation::Upsert(
+                    DeviceRow::to_dataframe(&[device]).expect("Invalid row"),
+                ),
+            )
+            .expect("Failed to stage device");
+        assert!(transaction.commit().is_err());
+
+        // Neither table may change before the transaction is completed, or
+        // completing it would undo the change
+        assert!(file_manager.get_managed_lazyframe(&USERS).await.is_err());
+        assert!(file_manager.get_lazyframe(&DEVICES).await.is_err());
+        file_manager.shutdown().await.expect("Failed to shut down");
+
+        let backend = ParquetBackend::new(dir.path().to_owned());
+        backend.initialize().expect("Failed to recover");
+        for table in [&USERS, &DEVICES] {
+            let rows = backend
+                .read(table)
+                .and_then(LazyFrame::collect)
+                .expect("Failed to read table");
+            assert_eq!(rows.height(), 1, "{}", table.name);
+        }
+    }
+}
@@ -0,0 +1,664 @@
+//! Write-ahead log for parquet tables
+//!
+//! Parquet files can't be modified in place, so every change to a table means
+//! rewriting the whole file. To avoid losing changes if the server crashes
+//! before that rewrite finishes, each change is first appended to a log file
+//! beside the table (`{table}.parquet.wal`) and flushed to disk. Once the
+//! table has been rewritten the log is truncated.
+//!
+//! Every `Mutation` is idempotent, so replaying a log that was already partly
+//! or fully applied before a crash is harmless.
+//!
+//! Each record on disk is laid out as:
+//!
+//! | Bytes | Contents                                   |
+//! |-------|--------------------------------------------|
+//! | 4     | Payload length (`u32`, little endian)      |
+//! | 4     | CRC32 checksum of the payload (`u32`, LE)  |
+//! | n     | Payload                                    |
+//!
+//! A record that is cut short or fails its checksum marks the end of the log.
+//! If encryption is turned on, every payload is sealed on its own before being
+//! written (see `managers::encryption`).
+//!
+//! Transactions changing several tables at once are committed by writing all
+//! of their changes to a single `TransactionLog` in
+//! `{data_path}/transactions`, using the same record layout with the name of
+//! the table at the start of each payload. Its changes are then appended to
+//! the log of each table and the transaction log is removed. If the server
+//! stops in between, `recover_transactions` finishes the job on startup.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use polars::prelude::*;
+use tracing::{info, warn};
+
+use crate::{
+    managers::{dataframes::write_parquet_atomic, encryption},
+    schema::Table,
+};
+
+/// The version of the payload encoding. Bump this when the layout changes.
+const RECORD_VERSION: u8 = 1;
+
+/// Payload tag for `Mutation::Upsert`
+const TAG_UPSERT: u8 = 0;
+
+/// Payload tag for `Mutation::Delete`
+const TAG_DELETE: u8 = 1;
+
+/// The directory in `data_path` holding the logs of committed transactions
+pub(crate) const TRANSACTIONS_DIR: &str = "transactions";
+
+/// Keeps transaction log names unique when several commit in the same instant
+static TRANSACTION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A logical change to a single table
+#[derive(Debug, Clone)]
+pub(crate) enum Mutation {
+    /// Insert rows into the table, replacing any existing rows with the same
+    /// key. Columns missing from the provided rows are filled with nulls.
+    Upsert(DataFrame),
+    /// Remove every row where `column` is equal to `value`
+    Delete {
+        /// The column to compare against
+        column: String,
+        /// The value rows must have to be removed
+        value: String,
+    },
+}
+
+impl Mutation {
+    /// Apply this mutation to a `LazyFrame` holding the contents of `table`
+    pub(crate) fn apply_to(
+        &self,
+        frame: LazyFrame,
+        table: &Table,
+    ) -> PolarsResult<LazyFrame> {
+        match self {
+            Mutation::Upsert(rows) => {
+                let rows = table.conform(rows);
+                let key: Vec<Expr> =
+                    table.key.iter().map(|name| col(name)).collect();
+                let kept = frame.join(
+                    rows.clone(),
+                    &key,
+                    &key,
+                    JoinArgs::new(JoinType::Anti),
+                );
+                concat([kept, rows], UnionArgs::default())
+            }
+            Mutation::Delete {
+                column,
+                value,
+            } => {
+                // Values are logged as strings, so they have to be parsed to
+                // be compared against numeric columns such as short IDs
+                let value =
+                    match table.columns.iter().find(|(name, _)| name == column)
+                    {
+                        Some((_, dtype)) if dtype.is_numeric() => {
+                            lit(value.clone()).cast(dtype.clone())
+                        }
+                        _ => lit(value.clone()),
+                    };
+                Ok(frame.filter(col(column).neq_missing(value)))
+            }
+        }
+    }
+
+    /// Serialize this mutation into a WAL payload
+    fn encode(&self) -> PolarsResult<Vec<u8>> {
+        let mut payload = vec![RECORD_VERSION];
+        match self {
+            Mutation::Upsert(rows) => {
+                payload.push(TAG_UPSERT);
+                IpcWriter::new(&mut payload).finish(&mut rows.clone())?;
+            }
+            Mutation::Delete {
+                column,
+                value,
+            } => {
+                payload.push(TAG_DELETE);
+                encode_str(&mut payload, column)?;
+                encode_str(&mut payload, value)?;
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Deserialize a mutation from a WAL payload
+    fn decode(payload: &[u8]) -> PolarsResult<Self> {
+        let [version, tag, body @ ..] = payload else {
+            return Err(corrupt("record is too short"));
+        };
+        if *version != RECORD_VERSION {
+            return Err(corrupt(&format!(
+                "unsupported record version {version}"
+            )));
+        }
+        match *tag {
+            TAG_UPSERT => Ok(Mutation::Upsert(
+                IpcReader::new(Cursor::new(body)).finish()?,
+            )),
+            TAG_DELETE => {
+                let mut cursor = Cursor::new(body);
+                Ok(Mutation::Delete {
+                    column: decode_str(&mut cursor)?,
+                    value: decode_str(&mut cursor)?,
+                })
+            }
+            tag => Err(corrupt(&format!("unknown record tag {tag}"))),
+        }
+    }
+}
+
+/// Build the error returned for an undecodable record
+fn corrupt(reason: &str) -> PolarsError {
+    PolarsError::ComputeError(format!("Corrupt WAL record: {reason}").into())
+}
+
+/// Append a length prefixed string to `buf`
+fn encode_str(buf: &mut Vec<u8>, s: &str) -> PolarsResult<()> {
+    let len = u32::try_from(s.len())
+        .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+/// Read a length prefixed string from `cursor`
+fn decode_str(cursor: &mut Cursor<&[u8]>) -> PolarsResult<String> {
+    let mut len = [0; 4];
+    cursor.read_exact(&mut len)?;
+    let len = usize::try_from(u32::from_le_bytes(len))
+        .map_err(|e| corrupt(&e.to_string()))?;
+    let mut buf = vec![0; len];
+    cursor.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| corrupt(&e.to_string()))
+}
+
+/// Returns the path of the WAL for the table stored at `table_path`
+pub(crate) fn wal_path(table_path: &Path) -> PathBuf {
+    let mut path: OsString = table_path.as_os_str().to_owned();
+    path.push(".wal");
+    PathBuf::from(path)
+}
+
+/// Frame a payload as a record, prefixed with its length and checksum
+fn record(payload: &[u8]) -> PolarsResult<Vec<u8>> {
+    let len = u32::try_from(payload.len())
+        .map_err(|e| PolarsError::ComputeError(e.to_string().into()))?;
+    let mut record = Vec::with_capacity(payload.len() + 8);
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    Ok(record)
+}
+
+/// Read the payload of every intact record in the log at `path`
+fn read_payloads(path: &Path) -> PolarsResult<Vec<Vec<u8>>> {
+    let bytes = fs::read(path)?;
+    let mut payloads = Vec::new();
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        let Some((header, body)) = rest.split_first_chunk::<8>() else {
+            warn!("Ignoring torn record at end of {}", path.display());
+            break;
+        };
+        let len = usize::try_from(u32::from_le_bytes([
+            header[0], header[1], header[2], header[3],
+        ]))
+        .map_err(|e| corrupt(&e.to_string()))?;
+        let checksum =
+            u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let Some((payload, next)) = body.split_at_checked(len) else {
+            warn!("Ignoring torn record at end of {}", path.display());
+            break;
+        };
+        if crc32fast::hash(payload) != checksum {
+            warn!(
+                "Ignoring record with a bad checksum at end of {}",
+                path.display()
+            );
+            break;
+        }
+        payloads.push(payload.to_vec());
+        rest = next;
+    }
+    Ok(payloads)
+}
+
+/// Reseal every record in the log for the table at `table_path` with the
+/// current encryption key, if any of them aren't already.
+///
+/// Returns whether the log was rewritten. The caller is expected to hold an
+/// exclusive lock on the table.
+pub(crate) fn reseal(table_path: &Path) -> PolarsResult<bool> {
+    let path = wal_path(table_path);
+    if !path.exists() {
+        return Ok(false);
+    }
+    let payloads = read_payloads(&path)?;
+    let mut stale = false;
+    for payload in &payloads {
+        stale |= encryption::needs_rotation(payload)?;
+    }
+    if !stale {
+        return Ok(false);
+    }
+    let mut log = Vec::new();
+    for payload in payloads {
+        log.extend(record(&encryption::seal(encryption::open(payload)?)?)?);
+    }
+    let mut temp_path: OsString = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    encryption::replace_file(&path, &PathBuf::from(temp_path), &log)?;
+    Ok(true)
+}
+
+/// An open write-ahead log for a single table.
+///
+/// Only the holder of the table's `FileLock` may append to or truncate its
+/// log.
+#[derive(Debug)]
+pub(crate) struct WriteAheadLog {
+    /// The log file, opened for appending
+    file: File,
+    /// The path of the log file
+    path: PathBuf,
+}
+
+impl WriteAheadLog {
+    /// Open the log for the table at `table_path`, creating it if needed
+    pub(crate) fn open(table_path: &Path) -> PolarsResult<Self> {
+        let path = wal_path(table_path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            file,
+            path,
+        })
+    }
+
+    /// Read every intact record in the log
+    pub(crate) fn read(&self) -> PolarsResult<Vec<Mutation>> {
+        read_payloads(&self.path)?
+            .into_iter()
+            .map(|payload| Mutation::decode(&encryption::open(payload)?))
+            .collect()
+    }
+
+    /// Durably append a mutation to the log.
+    ///
+    /// Once this returns `Ok` the mutation will survive a crash. If writing
+    /// fails, the log is cut back to its previous length so a partial record
+    /// can't hide records appended after it.
+    pub(crate) fn append(&mut self, mutation: &Mutation) -> PolarsResult<()> {
+        let record = record(&encryption::seal(mutation.encode()?)?)?;
+        let previous_len = self.file.metadata()?.len();
+        let result =
+            self.file.write_all(&record).and_then(|()| self.file.sync_data());
+        if let Err(e) = result {
+            self.file.set_len(previous_len)?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Discard every record in the log. This must only be called once the
+    /// table has been rewritten with every record applied.
+    pub(crate) fn truncate(&mut self) -> PolarsResult<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Apply and checkpoint any outstanding log records for every table in
+/// `data_path`.
+///
+/// This must run before the server starts handing out locks.
+pub(crate) fn replay(data_path: &Path, tables: &[&Table]) -> PolarsResult<()> {
+    for table in tables {
+        let table_path = table.path_in(data_path);
+        match fs::metadata(wal_path(&table_path)) {
+            Ok(metadata) if metadata.len() > 0 => {}
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+        let mut wal = WriteAheadLog::open(&table_path)?;
+        let mutations = wal.read()?;
+        info!(
+            "Replaying {} logged changes to table {}",
+            mutations.len(),
+            table.name
+        );
+        let mut frame = encryption::scan_parquet(&table_path)?;
+        for mutation in &mutations {
+            frame = mutation.apply_to(frame, table)?;
+        }
+        write_parquet_atomic(&table_path, frame)?;
+        wal.truncate()?;
+    }
+    Ok(())
+}
+
+/// The log of a committed transaction, holding every change it makes to every
+/// table.
+///
+/// Writing the log is what commits a transaction. Its tables must stay locked,
+/// or be poisoned if it can't be completed (see `managers::transaction`), until
+/// the log is removed, so that its changes are always the last ones made to
+/// them and adding them again during recovery is harmless.
+#[derive(Debug)]
+pub(crate) struct TransactionLog {
+    /// The path of the log file
+    path: PathBuf,
+}
+
+impl TransactionLog {
+    /// Durably write the log of a transaction making `changes` in
+    /// `data_path`, committing it.
+    ///
+    /// The log is written under a temporary name and only renamed into place
+    /// once it is complete, so a transaction either commits whole or not at
+    /// all.
+    pub(crate) fn commit(
+        data_path: &Path,
+        changes: &[(&Table, Mutation)],
+    ) -> PolarsResult<Self> {
+        let dir = data_path.join(TRANSACTIONS_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut log = Vec::new();
+        for (table, mutation) in changes {
+            let mut payload = Vec::new();
+            encode_str(&mut payload, table.name)?;
+            payload.extend(mutation.encode()?);
+            log.extend(record(&encryption::seal(payload)?)?);
+        }
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        let count = TRANSACTION_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{nanos}-{count}.txn"));
+        let temp_path = dir.join(format!("{nanos}-{count}.txn.tmp"));
+        encryption::replace_file(&path, &temp_path, &log)?;
+        Ok(Self {
+            path,
+        })
+    }
+
+    /// Remove the log. This must only be called once every change in it has
+    /// been appended to the log of its table.
+    pub(crate) fn finish(self) -> PolarsResult<()> {
+        fs::remove_file(&self.path)?;
+        if let Some(parent) = self.path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Every file in the transactions directory of `data_path` with the given
+/// extension, sorted by name
+fn transaction_files(
+    data_path: &Path,
+    extension: &str,
+) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    match fs::read_dir(data_path.join(TRANSACTIONS_DIR)) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_some_and(|found| found == extension) {
+                    files.push(path);
+                }
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The log of every committed transaction in `data_path` that may not have
+/// been fully appended to the logs of its tables yet, oldest first
+pub(crate) fn committed_transactions(
+    data_path: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    transaction_files(data_path, "txn")
+}
+
+/// The temporary files of transactions in `data_path` that never committed
+pub(crate) fn uncommitted_transactions(
+    data_path: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    transaction_files(data_path, "tmp")
+}
+
+/// Append the changes of every committed transaction in `data_path` to the
+/// logs of their tables, then remove the transaction logs along with any left
+/// behind by transactions that never committed.
+///
+/// This must run before the logs of `tables` are replayed.
+pub(crate) fn recover_transactions(
+    data_path: &Path,
+    tables: &[&Table],
+) -> PolarsResult<()> {
+    for path in committed_transactions(data_path)? {
+        let payloads = read_payloads(&path)?;
+        info!(
+            "Completing transaction {} with {} changes",
+            path.display(),
+            payloads.len()
+        );
+        for payload in payloads {
+            let payload = encryption::open(payload)?;
+            let mut cursor = Cursor::new(payload.as_slice());
+            let name = decode_str(&mut cursor)?;
+            let table = tables
+                .iter()
+                .find(|table| table.name == name)
+                .ok_or_else(|| corrupt(&format!("unknown table {name}")))?;
+            let start = usize::try_from(cursor.position())
+                .map_err(|e| corrupt(&e.to_string()))?;
+            let mutation = Mutation::decode(&payload[start..])?;
+            WriteAheadLog::open(&table.path_in(data_path))?
+                .append(&mutation)?;
+        }
+        fs::remove_file(&path)?;
+    }
+    for path in uncommitted_transactions(data_path)? {
+        info!("Discarding uncommitted transaction {}", path.display());
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+    };
+
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::{
+        record, recover_transactions, replay, wal_path, Mutation,
+        TransactionLog, WriteAheadLog, TRANSACTIONS_DIR,
+    };
+    use crate::{
+        managers::dataframes::write_parquet_atomic,
+        schema::{DeviceRow, ParquetRow, UserRow, DEVICES, USERS},
+    };
+
+    /// An upsert of the user with the short ID `user_id`
+    fn upsert_user(user_id: u64) -> Mutation {
+        Mutation::Upsert(
+            UserRow::to_dataframe(&[UserRow {
+                user_id,
+                username: format!("user{user_id}"),
+                password_hash: None,
+                is_guest: false,
+                is_deactivated: false,
+                created_ts: 0,
+            }])
+            .expect("Invalid row"),
+        )
+    }
+
+    /// The short IDs of every user in the users table in `dir`
+    fn stored_users(dir: &TempDir) -> Vec<u64> {
+        let users = LazyFrame::scan_parquet(
+            USERS.path_in(dir.path()),
+            ScanArgsParquet::default(),
+        )
+        .and_then(|frame| {
+            frame
+                .sort([UserRow::USER_ID], SortMultipleOptions::default())
+                .collect()
+        })
+        .expect("Failed to read users");
+        users
+            .column(UserRow::USER_ID)
+            .and_then(|column| column.u64().cloned())
+            .expect("Invalid users")
+            .into_no_null_iter()
+            .collect()
+    }
+
+    /// Create an empty users table in `dir`
+    fn create_users(dir: &TempDir) {
+        write_parquet_atomic(
+            &USERS.path_in(dir.path()),
+            USERS.empty().expect("Invalid table").lazy(),
+        )
+        .expect("Failed to create users");
+    }
+
+    #[test]
+    fn torn_and_corrupt_records_end_the_log() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let table_path = USERS.path_in(dir.path());
+        let mut wal =
+            WriteAheadLog::open(&table_path).expect("Failed to open log");
+        wal.append(&upsert_user(1)).expect("Failed to append");
+        wal.append(&upsert_user(2)).expect("Failed to append");
+
+        // A record cut short by a crash halfway through writing it
+        let torn = record(b"a payload that never finished").expect("Too long");
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(wal_path(&table_path))
+            .expect("Failed to open log");
+        file.write_all(&torn[..torn.len() - 4]).expect("Failed to write");
+        assert_eq!(wal.read().expect("Failed to read log").len(), 2);
+
+        // A record whose payload doesn't match its checksum hides every
+        // record after it, even intact ones
+        let mut log = fs::read(wal_path(&table_path)).expect("No log");
+        log.truncate(log.len() - (torn.len() - 4));
+        let mut corrupt = record(b"payload").expect("Too long");
+        corrupt[4] ^= 0xFF;
+        log.extend(corrupt);
+        log.extend(record(b"payload").expect("Too long"));
+        fs::write(wal_path(&table_path), log).expect("Failed to write log");
+        assert_eq!(wal.read().expect("Failed to read log").len(), 2);
+    }
+
+    #[test]
+    fn replays_can_be_repeated_and_keep_the_log_until_written() {
+        polars::enable_string_cache();
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        create_users(&dir);
+        let table_path = USERS.path_in(dir.path());
+        let mutations = [
+            upsert_user(1),
+            upsert_user(2),
+            Mutation::Delete {
+                column: UserRow::USER_ID.to_owned(),
+                value: "1".to_owned(),
+            },
+        ];
+        let mut wal =
+            WriteAheadLog::open(&table_path).expect("Failed to open log");
+        for mutation in &mutations {
+            wal.append(mutation).expect("Failed to append");
+        }
+
+        // The table can't be rewritten while its temporary file is blocked
+        let blocker = dir.path().join("users.parquet.tmp");
+        fs::create_dir(&blocker).expect("Failed to block table");
+        assert!(replay(dir.path(), &[&USERS]).is_err());
+        assert_eq!(stored_users(&dir), Vec::<u64>::new());
+        assert_eq!(wal.read().expect("Failed to read log").len(), 3);
+        fs::remove_dir(&blocker).expect("Failed to unblock table");
+
+        replay(dir.path(), &[&USERS]).expect("Failed to replay");
+        assert_eq!(stored_users(&dir), [2]);
+        assert!(wal.read().expect("Failed to read log").is_empty());
+
+        // A crash after the table was rewritten but before the log was
+        // truncated replays the same changes again
+        for mutation in &mutations {
+            wal.append(mutation).expect("Failed to append");
+        }
+        replay(dir.path(), &[&USERS]).expect("Failed to replay");
+        assert_eq!(stored_users(&dir), [2]);
+    }
+
+    #[test]
+    fn only_committed_transactions_are_recovered() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let device = DeviceRow {
+            user_id: 1,
+            device_id: "PHONE".to_owned(),
+            display_name: None,
+            created_ts: 0,
+        };
+        TransactionLog::commit(
+            dir.path(),
+            &[
+                (&USERS, upsert_user(1)),
+                (
+                    &DEVICES,
+                    Mutation::Upsert(
+                        DeviceRow::to_dataframe(&[device])
+                            .expect("Invalid row"),
+                    ),
+                ),
+            ],
+        )
+        .expect("Failed to commit");
+        // A transaction interrupted while its log was being written
+        let transactions = dir.path().join(TRANSACTIONS_DIR);
+        fs::write(transactions.join("0-0.txn.tmp"), b"half a log")
+            .expect("Failed to write log");
+
+        recover_transactions(dir.path(), &[&USERS, &DEVICES])
+            .expect("Failed to recover");
+        for table in [&USERS, &DEVICES] {
+            let logged = WriteAheadLog::open(&table.path_in(dir.path()))
+                .and_then(|wal| wal.read())
+                .expect("Failed to read log");
+            assert_eq!(logged.len(), 1, "{}", table.name);
+        }
+        assert_eq!(
+            fs::read_dir(&transactions)
+                .expect("Failed to list transactions")
+                .count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,750 @@
+//! Schema registry for the parquet tables backing the homeserver
+//!
+//! Every table the server reads from or writes to is declared here along with
+//! its columns and which of them should be stored as categoricals or as
+//! interned short IDs (see `managers::interner`). On startup
+//! `initialize` makes sure every table exists in the data directory and runs
+//! any migrations needed to bring an older data directory up to the
+//! current schema version.
+
+mod migrations;
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+pub(crate) use cubby_lib::ParquetRow;
+use cubby_macros::ParquetRow;
+use once_cell::sync::Lazy;
+use polars::prelude::*;
+use tracing::{info, warn};
+
+use crate::{
+    config::PROGRAM_CONFIG,
+    managers::{dataframes::write_parquet_atomic, wal},
+};
+
+/// The name of the file recording the schema version of the data directory
+pub(crate) const VERSION_FILE: &str = "schema_version";
+
+/// Shorthand for the categorical datatype used for low cardinality string
+/// columns
+const CATEGORICAL: DataType =
+    DataType::Categorical(None, CategoricalOrdering::Physical);
+
+/// The datatype of columns holding the short ID of an interned identifier
+pub(crate) const SHORT_ID: DataType = DataType::UInt64;
+
+/// A single parquet table used by the homeserver
+#[derive(Debug)]
+pub(crate) struct Table {
+    /// The name of the table. The file on disk is `{name}.parquet`.
+    pub(crate) name: &'static str,
+    /// The columns of the table and their datatypes.
+    ///
+    /// String columns listed here as `Categorical` are stored with a
+    /// categorical datatype for string interning. Tables with a row struct
+    /// take these from its `ParquetRow::COLUMNS`.
+    pub(crate) columns: &'static [(&'static str, DataType)],
+    /// The columns that together uniquely identify a row
+    pub(crate) key: &'static [&'static str],
+    /// The columns holding the short IDs of interned Matrix identifiers
+    /// rather than the identifiers themselves.
+    ///
+    /// Rows written to the table must already have these columns interned.
+    /// `conform` casts columns to their datatypes, and an identifier cast to
+    /// a short ID is silently turned into a null.
+    pub(crate) interned: &'static [&'static str],
+    /// Columns besides the key that point lookups can search without reading
+    /// the whole table (see `managers::index`)
+    pub(crate) indexed: &'static [&'static str],
+    /// The columns holding secrets, which analytics queries see as nulls (see
+    /// `analytics`)
+    pub(crate) redacted: &'static [&'static str],
+    /// Whether the contents of the table should be kept in memory between
+    /// reads unless `PROGRAM_CONFIG.cached_tables` says otherwise. Only small
+    /// tables that are read often should set this.
+    pub(crate) cached: bool,
+}
+
+impl Table {
+    /// The file name of the table on disk
+    pub(crate) fn file_name(&self) -> String {
+        format!("{}.parquet", self.name)
+    }
+
+    /// The path of the table inside the configured data directory
+    pub(crate) fn path(&self) -> PathBuf {
+        self.path_in(&PROGRAM_CONFIG.data_path)
+    }
+
+    /// The path of the table inside an arbitrary data directory
+    pub(crate) fn path_in(&self, data_path: &Path) -> PathBuf {
+        data_path.join(self.file_name())
+    }
+
+    /// Whether the contents of the table are kept in memory between reads
+    pub(crate) fn is_cached(&self) -> bool {
+        match &PROGRAM_CONFIG.cached_tables {
+            Some(names) => names.iter().any(|name| name == self.name),
+            None => self.cached,
+        }
+    }
+
+    /// The polars `Schema` of the table
+    pub(crate) fn schema(&self) -> Schema {
+        self.columns
+            .iter()
+            .map(|(name, dtype)| Field::new(name, dtype.clone()))
+            .collect()
+    }
+
+    /// Select the columns of this table from `rows`, casting them to their
+    /// datatypes and filling any columns `rows` is missing with nulls
+    pub(crate) fn conform(&self, rows: &DataFrame) -> LazyFrame {
+        let present = rows.get_column_names();
+        rows.clone().lazy().select(
+            self.columns
+                .iter()
+                .map(|(name, dtype)| {
+                    if present.contains(name) {
+                        col(name).cast(dtype.clone())
+                    } else {
+                        lit(NULL).cast(dtype.clone()).alias(name)
+                    }
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// An empty `DataFrame` with the schema of this table
+    pub(crate) fn empty(&self) -> PolarsResult<DataFrame> {
+        DataFrame::new(
+            self.schema()
+                .iter()
+                .map(|(name, dtype)| Series::new_empty(name, dtype))
+                .collect(),
+        )
+    }
+}
+
+/// The number of tables identifiers are spread across
+pub(crate) const IDENTIFIER_PARTITIONS: u64 = 64;
+
+/// Every interned Matrix identifier and the short ID standing in for it in
+/// every other table.
+///
+/// Identifiers are spread across the tables in `IDENTIFIER_TABLES` (see
+/// `managers::interner`), so interning one only rewrites the table holding
+/// it, and this table is not part of `TABLES`. Its columns describe every one
+/// of them.
+pub(crate) static IDENTIFIERS: Table = Table {
+    name: "identifiers",
+    columns: IdentifierRow::COLUMNS,
+    key: &["identifier"],
+    interned: &[],
+    indexed: &["short_id"],
+    redacted: &[],
+    cached: false,
+};
+
+/// A row of `IDENTIFIERS`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct IdentifierRow {
+    /// The short ID standing in for the identifier
+    pub(crate) short_id: u64,
+    /// The identifier itself
+    pub(crate) identifier: String,
+}
+
+/// The tables holding the identifiers of `IDENTIFIERS`, named
+/// `identifiers_00` to `identifiers_63`
+pub(crate) static IDENTIFIER_TABLES: Lazy<Vec<Table>> = Lazy::new(|| {
+    (0..IDENTIFIER_PARTITIONS)
+        .map(|partition| Table {
+            name: Box::leak(
+                format!("{}_{partition:02}", IDENTIFIERS.name).into_boxed_str(),
+            ),
+            ..IDENTIFIERS
+        })
+        .collect()
+});
+
+/// Local user accounts
+pub(crate) static USERS: Table = Table {
+    name: "users",
+    columns: UserRow::COLUMNS,
+    key: &["user_id"],
+    interned: &["user_id"],
+    indexed: &["username"],
+    redacted: &["password_hash"],
+    cached: true,
+};
+
+/// A row of `USERS`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct UserRow {
+    /// The short ID of the full user ID
+    pub(crate) user_id: u64,
+    /// The localpart of the user ID
+    #[parquet(categorical)]
+    pub(crate) username: String,
+    /// The hash of the user's password, if they have one
+    pub(crate) password_hash: Option<String>,
+    /// Whether the account is a guest account
+    pub(crate) is_guest: bool,
+    /// Whether the account has been deactivated
+    pub(crate) is_deactivated: bool,
+    /// When the account was created, in milliseconds since the unix epoch
+    pub(crate) created_ts: u64,
+}
+
+/// Devices belonging to local users
+pub(crate) static DEVICES: Table = Table {
+    name: "devices",
+    columns: DeviceRow::COLUMNS,
+    key: &["user_id", "device_id"],
+    interned: &["user_id"],
+    indexed: &[],
+    redacted: &[],
+    cached: true,
+};
+
+/// A row of `DEVICES`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct DeviceRow {
+    /// The short ID of the user the device belongs to
+    pub(crate) user_id: u64,
+    /// The ID of the device, unique among the user's devices
+    pub(crate) device_id: String,
+    /// The name of the device shown to the user, if it has one
+    pub(crate) display_name: Option<String>,
+    /// When the device was created, in milliseconds since the unix epoch
+    pub(crate) created_ts: u64,
+}
+
+/// Access tokens issued to devices
+pub(crate) static ACCESS_TOKENS: Table = Table {
+    name: "access_tokens",
+    columns: AccessTokenRow::COLUMNS,
+    key: &["token"],
+    interned: &["user_id"],
+    indexed: &[],
+    redacted: &["token"],
+    cached: true,
+};
+
+/// A row of `ACCESS_TOKENS`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct AccessTokenRow {
+    /// The access token itself
+    pub(crate) token: String,
+    /// The short ID of the user the token was issued to
+    pub(crate) user_id: u64,
+    /// The device the token was issued to
+    pub(crate) device_id: String,
+    /// When the token was issued, in milliseconds since the unix epoch
+    pub(crate) created_ts: u64,
+}
+
+/// Global profile information for local users
+pub(crate) static PROFILES: Table = Table {
+    name: "profiles",
+    columns: ProfileRow::COLUMNS,
+    key: &["user_id"],
+    interned: &["user_id"],
+    indexed: &[],
+    redacted: &[],
+    cached: false,
+};
+
+/// A row of `PROFILES`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct ProfileRow {
+    /// The short ID of the user the profile belongs to
+    pub(crate) user_id: u64,
+    /// The display name of the user, if they have set one
+    pub(crate) displayname: Option<String>,
+    /// The `mxc://` URI of the user's avatar, if they have set one
+    pub(crate) avatar_url: Option<String>,
+}
+
+/// Rooms known to the server
+pub(crate) static ROOMS: Table = Table {
+    name: "rooms",
+    columns: RoomRow::COLUMNS,
+    key: &["room_id"],
+    interned: &["room_id", "creator"],
+    indexed: &[],
+    redacted: &[],
+    cached: false,
+};
+
+/// A row of `ROOMS`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct RoomRow {
+    /// The short ID of the room ID
+    pub(crate) room_id: u64,
+    /// The version of the room
+    #[parquet(categorical)]
+    pub(crate) room_version: String,
+    /// The short ID of the user who created the room
+    pub(crate) creator: u64,
+    /// When the room was created, in milliseconds since the unix epoch
+    pub(crate) created_ts: u64,
+}
+
+/// Persistent data units for every room.
+///
+/// These are kept in the partitioned event store in `managers::events` rather
+/// than in a single table file, so this table is not part of `TABLES`. Its
+/// columns describe every event in the store.
+pub(crate) static EVENTS: Table = Table {
+    name: "events",
+    columns: EventRow::COLUMNS,
+    key: &["event_id"],
+    interned: &["event_id", "room_id", "sender"],
+    indexed: &[],
+    redacted: &[],
+    cached: false,
+};
+
+/// A row of `EVENTS`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct EventRow {
+    /// The short ID of the event ID
+    pub(crate) event_id: u64,
+    /// The short ID of the room the event was sent in
+    pub(crate) room_id: u64,
+    /// The short ID of the user who sent the event
+    pub(crate) sender: u64,
+    /// The type of the event, such as `m.room.message`
+    #[parquet(categorical)]
+    pub(crate) event_type: String,
+    /// The state key of the event, if it is a state event
+    pub(crate) state_key: Option<String>,
+    /// When the event was sent, in milliseconds since the unix epoch
+    pub(crate) origin_server_ts: u64,
+    /// The content of the event, as JSON
+    pub(crate) content: String,
+}
+
+/// The current state of every room
+pub(crate) static ROOM_STATE: Table = Table {
+    name: "room_state",
+    columns: RoomStateRow::COLUMNS,
+    key: &["room_id", "event_type", "state_key"],
+    interned: &["room_id", "event_id"],
+    indexed: &[],
+    redacted: &[],
+    cached: true,
+};
+
+/// A row of `ROOM_STATE`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct RoomStateRow {
+    /// The short ID of the room
+    pub(crate) room_id: u64,
+    /// The type of the state event
+    #[parquet(categorical)]
+    pub(crate) event_type: String,
+    /// The state key of the state event, which is empty for most types
+    pub(crate) state_key: String,
+    /// The short ID of the event currently holding this piece of state
+    pub(crate) event_id: u64,
+}
+
+/// User-interactive authentication sessions that are still in progress (see
+/// `uiaa`)
+pub(crate) static UIAA_SESSIONS: Table = Table {
+    name: "uiaa_sessions",
+    columns: UiaaSessionRow::COLUMNS,
+    key: &["session"],
+    interned: &["user_id"],
+    indexed: &[],
+    redacted: &["session"],
+    cached: true,
+};
+
+/// A row of `UIAA_SESSIONS`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct UiaaSessionRow {
+    /// The session ID handed to the client
+    pub(crate) session: String,
+    /// The endpoint the session authenticates a request to
+    #[parquet(categorical)]
+    pub(crate) endpoint: String,
+    /// A hash of the request the session authenticates, which can't change
+    /// while the session is in progress
+    pub(crate) request_hash: String,
+    /// The short ID of the user who started the session, if they were logged
+    /// in
+    pub(crate) user_id: Option<u64>,
+    /// The types of the stages completed so far, separated by commas
+    pub(crate) completed: String,
+    /// When the session was started, in milliseconds since the unix epoch
+    pub(crate) created_ts: u64,
+}
+
+/// Single-use tokens that log a user in through `m.login.token`
+pub(crate) static LOGIN_TOKENS: Table = Table {
+    name: "login_tokens",
+    columns: LoginTokenRow::COLUMNS,
+    key: &["token"],
+    interned: &["user_id"],
+    indexed: &[],
+    redacted: &["token"],
+    cached: true,
+};
+
+/// A row of `LOGIN_TOKENS`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct LoginTokenRow {
+    /// The login token itself
+    pub(crate) token: String,
+    /// The short ID of the user the token logs in
+    pub(crate) user_id: u64,
+    /// When the token stops working, in milliseconds since the unix epoch
+    pub(crate) expires_ts: u64,
+}
+
+/// Failed attempts to log in to local accounts, used to lock accounts against
+/// guessing passwords
+pub(crate) static LOGIN_FAILURES: Table = Table {
+    name: "login_failures",
+    columns: LoginFailureRow::COLUMNS,
+    key: &["user_id"],
+    interned: &["user_id"],
+    indexed: &[],
+    redacted: &[],
+    cached: true,
+};
+
+/// A row of `LOGIN_FAILURES`
+#[derive(Debug, ParquetRow)]
+pub(crate) struct LoginFailureRow {
+    /// The short ID of the user someone failed to log in as
+    pub(crate) user_id: u64,
+    /// How many attempts in a row have failed since the last successful one
+    pub(crate) failures: u64,
+    /// When the last attempt failed, in milliseconds since the unix epoch
+    pub(crate) last_failure_ts: u64,
+}
+
+/// Every table used by the homeserver
+pub(crate) static TABLES: Lazy<Vec<&'static Table>> = Lazy::new(|| {
+    IDENTIFIER_TABLES
+        .iter()
+        .chain([
+            &USERS,
+            &DEVICES,
+            &ACCESS_TOKENS,
+            &PROFILES,
+            &ROOMS,
+            &ROOM_STATE,
+            &UIAA_SESSIONS,
+            &LOGIN_TOKENS,
+            &LOGIN_FAILURES,
+        ])
+        .collect()
+});
+
+/// The first of `names` that isn't the name of a table, if any
+fn unknown_table<'a>(
+    names: impl IntoIterator<Item = &'a String>,
+) -> Option<&'a String> {
+    names
+        .into_iter()
+        .find(|name| !TABLES.iter().any(|table| table.name == name.as_str()))
+}
+
+/// Check that every table named in `PROGRAM_CONFIG.cached_tables` exists
+///
+/// # Errors
+///
+/// This function will return an error naming the first one that doesn't.
+pub(crate) fn check_cached_tables() -> Result<(), String> {
+    match unknown_table(PROGRAM_CONFIG.cached_tables.iter().flatten()) {
+        Some(name) => Err(format!("Unknown table {name} in cached_tables")),
+        None => Ok(()),
+    }
+}
+
+/// The schema version this build of cubby expects the data directory to be at
+pub(crate) fn current_version() -> u32 {
+    migrations::MIGRATIONS.last().map_or(1, |m| m.version)
+}
+
+/// Read the schema version recorded in `data_path`.
+///
+/// Returns `None` if no version has been recorded yet, which means the data
+/// directory has never been initialized.
+pub(crate) fn read_version(data_path: &Path) -> PolarsResult<Option<u32>> {
+    match fs::read_to_string(data_path.join(VERSION_FILE)) {
+        Ok(contents) => contents.trim().parse().map(Some).map_err(|e| {
+            PolarsError::ComputeError(
+                format!("Invalid schema version file: {e}").into(),
+            )
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record `version` as the schema version of `data_path`
+fn write_version(data_path: &Path, version: u32) -> PolarsResult<()> {
+    let temp_path = data_path.join(format!("{VERSION_FILE}.tmp"));
+    fs::write(&temp_path, version.to_string())?;
+    fs::File::open(&temp_path)?.sync_all()?;
+    fs::rename(temp_path, data_path.join(VERSION_FILE))?;
+    Ok(())
+}
+
+/// Whether `data_path` holds any tables. Every version of cubby has stored
+/// tables as parquet files directly inside the data directory.
+fn has_tables(data_path: &Path) -> PolarsResult<bool> {
+    for entry in fs::read_dir(data_path)? {
+        let path = entry?.path();
+        if path.is_file()
+            && path.extension().is_some_and(|extension| extension == "parquet")
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Create any tables that do not exist yet in `data_path`
+fn create_missing_tables(data_path: &Path) -> PolarsResult<()> {
+    for table in TABLES.iter() {
+        let path = table.path_in(data_path);
+        if path.exists() {
+            continue;
+        }
+        info!("Creating table {}", table.name);
+        write_parquet_atomic(&path, table.empty()?.lazy())?;
+    }
+    Ok(())
+}
+
+/// Prepare the data directory at `data_path` for use.
+///
+/// A fresh data directory has every table created at the current schema
+/// version. One holding tables but no recorded version was written before
+/// schema versioning, when tables had no fixed columns, and is refused rather
+/// than guessed at. An existing one has every migration newer than its recorded
+/// version run in order, recording the new version after each one so an
+/// interrupted upgrade picks up where it left off.
+///
+/// # Errors
+///
+/// This function will return an error if the data directory was written by a
+/// newer version of cubby or by one from before schema versioning, or if
+/// creating a table or running a migration
+/// fails.
+pub(crate) fn initialize(data_path: &Path) -> PolarsResult<()> {
+    fs::create_dir_all(data_path)?;
+    let current = current_version();
+    match read_version(data_path)? {
+        None if has_tables(data_path)? => {
+            return Err(PolarsError::ComputeError(
+                format!(
+                    "{} holds tables but no schema version, so it was written \
+                     by a version of cubby from before schema versioning and \
+                     can't be upgraded. Move its tables aside to start with \
+                     an empty data directory.",
+                    data_path.display()
+                )
+                .into(),
+            ));
+        }
+        None => {
+            info!("Initializing new data directory at {}", data_path.display());
+            create_missing_tables(data_path)?;
+            write_version(data_path, current)?;
+        }
+        Some(version) if version > current => {
+            return Err(PolarsError::ComputeError(
+                format!(
+                    "The data directory is at schema version {version}, but \
+                     this version of cubby only supports up to {current}"
+                )
+                .into(),
+            ));
+        }
+        Some(version) => {
+            // Committed transactions are moved into the logs of their tables
+            // first, so they are replayed like any other change
+            let tables = migrations::tables_at(version);
+            wal::recover_transactions(data_path, tables)?;
+            // Logged changes were made against the tables as they were at
+            // the version on disk, so they are replayed with those
+            // definitions before anything is migrated
+            wal::replay(data_path, tables)?;
+            for migration in
+                migrations::MIGRATIONS.iter().filter(|m| m.version > version)
+            {
+                warn!(
+                    "Migrating data directory to schema version {}: {}",
+                    migration.version, migration.description
+                );
+                (migration.run)(data_path)?;
+                write_version(data_path, migration.version)?;
+            }
+            create_missing_tables(data_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::{
+        current_version, initialize, read_version, unknown_table, ParquetRow,
+        UserRow, TABLES, USERS,
+    };
+    use crate::managers::dataframes::write_dataframe_atomic;
+
+    #[test]
+    fn fresh_data_directories_are_created_at_the_current_version() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        initialize(dir.path()).expect("Failed to initialize");
+        assert_eq!(
+            read_version(dir.path()).expect("Failed to read version"),
+            Some(current_version())
+        );
+        for table in TABLES.iter() {
+            assert!(table.path_in(dir.path()).exists(), "{}", table.name);
+        }
+    }
+
+    #[test]
+    fn rows_round_trip_through_their_tables() {
+        let rows = [
+            UserRow {
+                user_id: 1,
+                username: "alice".to_owned(),
+                password_hash: None,
+                is_guest: false,
+                is_deactivated: true,
+                created_ts: 2,
+            },
+            UserRow {
+                user_id: 3,
+                username: "bob".to_owned(),
+                password_hash: Some("hash".to_owned()),
+                is_guest: true,
+                is_deactivated: false,
+                created_ts: 4,
+            },
+        ];
+        let df = UserRow::to_dataframe(&rows).expect("Failed to convert rows");
+        assert_eq!(df.schema(), USERS.schema());
+        let conformed =
+            USERS.conform(&df).collect().expect("Failed to conform rows");
+        let read =
+            UserRow::from_dataframe(&conformed).expect("Failed to read rows");
+        assert_eq!(format!("{read:?}"), format!("{rows:?}"));
+        assert_eq!(UserRow::USERNAME, "username");
+    }
+
+    /// A row exercising every `#[parquet(...)]` attribute
+    #[derive(Debug, ParquetRow)]
+    struct AttributeRow {
+        /// A column that may hold nulls
+        nullable: Option<u64>,
+        /// A categorical column
+        #[parquet(categorical)]
+        kind: String,
+        /// A column that may be missing from a frame
+        #[parquet(optional)]
+        added_later: Option<String>,
+        /// A column named after a keyword
+        #[parquet(rename = "type")]
+        r#type: u64,
+    }
+
+    #[test]
+    fn derived_rows_follow_their_attributes() {
+        assert_eq!(
+            AttributeRow::schema(),
+            Schema::from_iter([
+                Field::new("nullable", DataType::UInt64),
+                Field::new(
+                    "kind",
+                    DataType::Categorical(None, CategoricalOrdering::Physical)
+                ),
+                Field::new("added_later", DataType::String),
+                Field::new("type", DataType::UInt64),
+            ])
+        );
+        assert_eq!(AttributeRow::TYPE, "type");
+
+        // The optional column is missing, and the others are converted
+        let df = df!(
+            "nullable" => [Some(1_u32), None],
+            "kind" => ["a", "b"],
+            "type" => [2_u32, 3],
+        )
+        .expect("Invalid frame");
+        let rows =
+            AttributeRow::from_dataframe(&df).expect("Failed to read rows");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].nullable, Some(1));
+        assert_eq!(rows[1].nullable, None);
+        assert_eq!(rows[1].kind, "b");
+        assert!(rows.iter().all(|row| row.added_later.is_none()));
+        assert_eq!(rows[1].r#type, 3);
+        let written =
+            AttributeRow::to_dataframe(&rows).expect("Failed to write rows");
+        assert_eq!(written.schema(), AttributeRow::schema());
+
+        let df = df!(
+            "nullable" => [1_u64],
+            "kind" => [None::<&str>],
+            "type" => [2_u64],
+        )
+        .expect("Invalid frame");
+        let e = AttributeRow::from_dataframe(&df)
+            .expect_err("Read a null into a field that isn't an Option");
+        assert!(e.to_string().contains("Column kind holds a null"), "{e}");
+        let df =
+            df!("nullable" => [1_u64], "kind" => ["a"]).expect("Invalid frame");
+        assert!(AttributeRow::from_dataframe(&df).is_err());
+    }
+
+    #[test]
+    fn cached_tables_must_exist() {
+        let names = ["users".to_owned(), "devices".to_owned()];
+        assert_eq!(unknown_table(&names), None);
+        let names = ["users".to_owned(), "user".to_owned()];
+        assert_eq!(unknown_table(&names), Some(&names[1]));
+    }
+
+    #[test]
+    fn unversioned_data_directories_with_tables_are_refused() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let users = USERS.path_in(dir.path());
+        write_dataframe_atomic(
+            &users,
+            &mut df!("username" => ["cubby"]).expect("Invalid frame"),
+        )
+        .expect("Failed to write users");
+        let before = fs::read(&users).expect("Failed to read users");
+
+        assert!(initialize(dir.path()).is_err());
+        assert_eq!(read_version(dir.path()).expect("Unreadable"), None);
+        assert_eq!(fs::read(&users).expect("Failed to read users"), before);
+        assert_eq!(
+            fs::read_dir(dir.path()).expect("Failed to list").count(),
+            1
+        );
+    }
+}
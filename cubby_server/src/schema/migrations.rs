@@ -0,0 +1,41 @@
+//! Ordered migrations between schema versions
+//!
+//! Each migration upgrades a data directory from the previous version to its
+//! own `version`. Migrations must be listed in ascending order and must never
+//! be edited once released, since a deployment may already have run them.
+//! New tables do not need a migration; they are created automatically once
+//! every migration has run.
+//!
+//! Migrations only use the frozen table definitions of the versions they
+//! upgrade from and to, kept in a module per version, never the current ones
+//! in `schema`. Changing a table's columns therefore needs a new migration
+//! and a frozen copy of the tables as they were before it, which
+//! `tables_at` has to return for the older version.
+
+use std::path::Path;
+
+use polars::prelude::*;
+
+use crate::schema::{Table, TABLES};
+
+/// A single step in upgrading a data directory
+pub(super) struct Migration {
+    /// The schema version the data directory is at after this migration
+    pub(super) version: u32,
+    /// A short human readable summary of what the migration changes
+    pub(super) description: &'static str,
+    /// The migration itself. It is given the path of the data directory.
+    pub(super) run: fn(&Path) -> PolarsResult<()>,
+}
+
+/// Every migration, in the order they must be run.
+///
+/// Version 1 is the initial schema and has no migration. No other version has
+/// been released yet.
+pub(super) static MIGRATIONS: &[Migration] = &[];
+
+/// The tables of a data directory at schema `version`, as they were defined
+/// at that version. `version` must not be newer than the current version.
+pub(super) fn tables_at(_version: u32) -> &'static [&'static Table] {
+    &TABLES
+}
@@ -0,0 +1,596 @@
+//! Online snapshots of the data directory and offline restores
+//!
+//! A snapshot takes shared locks on every table and every room in the event
+//! store through the `FileManager`, so no table is being rewritten and no
+//! fragment is being compacted while it is taken. Tables and fragments are
+//! never modified in place, only replaced or deleted, so they are hard linked
+//! into the snapshot, which takes next to no time or space. Write-ahead logs
+//! are appended to in place, so those are copied instead. The locks are
+//! released as soon as every file has been captured.
+//!
+//! Every snapshot is written to `{backup_path}/snapshot-{unix ms}` along with a
+//! manifest listing the size and checksum of every file. It is first written
+//! under a `.partial` name and only renamed once complete, so a snapshot that
+//! exists is always whole.
+//!
+//! Snapshots are taken every `PROGRAM_CONFIG.snapshot_interval` seconds if it
+//! is set, and whenever the server receives SIGUSR1.
+//!
+//! `cubby restore <snapshot>` validates a snapshot against its manifest and
+//! swaps it in as the data directory. It refuses to run while a server is
+//! using the data directory (see `data_lock`). The previous data directory is
+//! kept beside the new one rather than deleted.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use cubby_lib::file_manager::{FileManager, LockMode};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    config::{Storage, PROGRAM_CONFIG},
+    data_lock,
+    managers::{dataframes::lock_error, events, wal},
+    schema::{self, TABLES, VERSION_FILE},
+};
+
+/// The name of the manifest file inside every snapshot
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes the contents of a snapshot
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// The schema version of the data directory the snapshot was taken from
+    schema_version: u32,
+    /// When the snapshot was taken, in milliseconds since the unix epoch
+    created_ms: u64,
+    /// Every file in the snapshot, besides the manifest itself
+    files: Vec<ManifestEntry>,
+}
+
+/// A single file in a snapshot
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    /// The path of the file, relative to the root of the snapshot
+    path: PathBuf,
+    /// The size of the file in bytes
+    size: u64,
+    /// The CRC32 checksum of the contents of the file
+    crc32: u32,
+}
+
+/// Build the error returned when a snapshot can't be taken or restored
+fn snapshot_error(reason: &str) -> PolarsError {
+    PolarsError::ComputeError(format!("Snapshot error: {reason}").into())
+}
+
+/// The current time in milliseconds since the unix epoch
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| {
+        u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+    })
+}
+
+/// `path` with `suffix` appended to its final component
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Flush the entries of a directory to disk
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Compute the size and checksum of a file
+fn checksum(path: &Path) -> io::Result<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += u64::try_from(read).unwrap_or(u64::MAX);
+    }
+    Ok((size, hasher.finalize()))
+}
+
+/// Every file that makes up the data directory, relative to `data_path`,
+/// along with whether it is modified in place and so has to be copied rather
+/// than linked.
+///
+/// Only the fragments of `rooms` are included. The caller is expected to hold
+/// a lock on every table and on each of `rooms`, so rooms created since they
+/// were locked are left out rather than captured unlocked.
+fn data_files(
+    data_path: &Path,
+    rooms: &[PathBuf],
+) -> PolarsResult<Vec<(PathBuf, bool)>> {
+    let mut files = vec![(PathBuf::from(VERSION_FILE), false)];
+    for table in TABLES.iter() {
+        files.push((PathBuf::from(table.file_name()), false));
+        let log = wal::wal_path(&table.path_in(data_path));
+        if log.metadata().is_ok_and(|metadata| metadata.len() > 0) {
+            files.push((relative_to(&log, data_path)?, true));
+        }
+    }
+    // Transaction logs are never changed once written, so they can be linked
+    for transaction in wal::committed_transactions(data_path)? {
+        files.push((relative_to(&transaction, data_path)?, false));
+    }
+    for room in rooms {
+        for fragment in events::room_fragments(room)? {
+            files.push((relative_to(&fragment, data_path)?, false));
+        }
+    }
+    Ok(files)
+}
+
+/// `path` relative to `base`
+fn relative_to(path: &Path, base: &Path) -> PolarsResult<PathBuf> {
+    path.strip_prefix(base).map(Path::to_path_buf).map_err(|_not_prefix| {
+        snapshot_error(&format!(
+            "{} is outside the data directory",
+            path.display()
+        ))
+    })
+}
+
+/// Hard link or copy every table and the fragments of every room in `rooms`
+/// into `target`
+fn capture(
+    data_path: &Path,
+    rooms: &[PathBuf],
+    target: &Path,
+) -> PolarsResult<Vec<PathBuf>> {
+    let files = data_files(data_path, rooms)?;
+    for (file, copy) in &files {
+        let source = data_path.join(file);
+        let destination = target.join(file);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Hard links can't cross filesystems, so fall back to copying
+        if *copy || fs::hard_link(&source, &destination).is_err() {
+            fs::copy(&source, &destination)?;
+        }
+    }
+    Ok(files.into_iter().map(|(file, _)| file).collect())
+}
+
+/// Write the manifest for the files captured in `target`
+fn write_manifest(
+    target: &Path,
+    files: Vec<PathBuf>,
+    created_ms: u64,
+) -> PolarsResult<()> {
+    let schema_version = schema::read_version(target)?
+        .ok_or_else(|| snapshot_error("the data directory has no version"))?;
+    let mut entries = Vec::with_capacity(files.len());
+    for path in files {
+        let (size, crc32) = checksum(&target.join(&path))?;
+        entries.push(ManifestEntry {
+            path,
+            size,
+            crc32,
+        });
+    }
+    let manifest = Manifest {
+        schema_version,
+        created_ms,
+        files: entries,
+    };
+    let json = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| snapshot_error(&e.to_string()))?;
+    let manifest_path = target.join(MANIFEST_FILE);
+    fs::write(&manifest_path, json)?;
+    File::open(&manifest_path)?.sync_all()?;
+    Ok(())
+}
+
+/// Take a snapshot of the data directory while the server is running.
+///
+/// Returns the path of the new snapshot.
+///
+/// # Errors
+///
+/// This function will return an error if the server does not store its
+/// tables on disk, if the locks can't be acquired, or if writing the snapshot
+/// fails. A failed snapshot leaves at most a `.partial` directory behind.
+pub(crate) async fn snapshot(
+    file_manager: &FileManager,
+) -> PolarsResult<PathBuf> {
+    if !matches!(PROGRAM_CONFIG.storage, Storage::Parquet) {
+        return Err(snapshot_error("only parquet storage can be snapshotted"));
+    }
+    let data_path = PROGRAM_CONFIG.data_path.clone();
+    let created_ms = now_ms();
+    let target =
+        PROGRAM_CONFIG.backup_path.join(format!("snapshot-{created_ms}"));
+    let partial = with_suffix(&target, ".partial");
+    let rooms = events::room_dirs(&data_path)?;
+    let locks = file_manager
+        .lock_many(
+            TABLES
+                .iter()
+                .map(|table| table.path())
+                .chain(rooms.iter().cloned())
+                .map(|path| (path, LockMode::Shared)),
+        )
+        .await
+        .map_err(|e| lock_error(&e))?;
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&partial)?;
+        let files = capture(&data_path, &rooms, &partial);
+        // Everything captured is immutable from here on, so the locks don't
+        // need to be held while it is checksummed
+        drop(locks);
+        write_manifest(&partial, files?, created_ms)?;
+        sync_dir(&partial)?;
+        fs::rename(&partial, &target)?;
+        sync_dir(&PROGRAM_CONFIG.backup_path)?;
+        Ok(target)
+    })
+    .await
+    .map_err(|e| snapshot_error(&e.to_string()))?
+}
+
+/// Take a snapshot whenever one is requested, either by
+/// `PROGRAM_CONFIG.snapshot_interval` elapsing or by SIGUSR1
+pub(crate) async fn snapshotter(file_manager: FileManager) {
+    let period = Duration::from_secs(PROGRAM_CONFIG.snapshot_interval);
+    let mut interval = (!period.is_zero()).then(|| {
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+    #[cfg(unix)]
+    let mut signal = match tokio::signal::unix::signal(
+        tokio::signal::unix::SignalKind::user_defined1(),
+    ) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            error!("Failed to listen for SIGUSR1: {e}");
+            None
+        }
+    };
+    loop {
+        let elapsed = async {
+            match interval.as_mut() {
+                Some(interval) => {
+                    interval.tick().await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(unix)]
+        let requested = async {
+            match signal.as_mut() {
+                Some(signal) => {
+                    signal.recv().await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let requested = std::future::pending::<()>();
+        tokio::select! {
+            () = elapsed => {},
+            () = requested => info!("Snapshot requested"),
+        }
+        match snapshot(&file_manager).await {
+            Ok(path) => info!("Wrote snapshot to {}", path.display()),
+            Err(e) => error!("Failed to take a snapshot: {e}"),
+        }
+    }
+}
+
+/// Read the manifest of a snapshot and check every file against it
+fn validate(snapshot: &Path) -> PolarsResult<Manifest> {
+    let json = fs::read(snapshot.join(MANIFEST_FILE))?;
+    let manifest: Manifest = serde_json::from_slice(&json)
+        .map_err(|e| snapshot_error(&format!("invalid manifest: {e}")))?;
+    let current = schema::current_version();
+    if manifest.schema_version > current {
+        return Err(snapshot_error(&format!(
+            "the snapshot is at schema version {}, but this version of cubby \
+             only supports up to {current}",
+            manifest.schema_version
+        )));
+    }
+    for entry in &manifest.files {
+        // A manifest must not be able to point outside the snapshot
+        if !entry
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(snapshot_error(&format!(
+                "invalid path {} in manifest",
+                entry.path.display()
+            )));
+        }
+        let (size, crc32) = checksum(&snapshot.join(&entry.path))?;
+        if size != entry.size || crc32 != entry.crc32 {
+            return Err(snapshot_error(&format!(
+                "{} does not match the manifest",
+                entry.path.display()
+            )));
+        }
+    }
+    Ok(manifest)
+}
+
+/// Replace the data directory at `data_path` with the contents of a snapshot.
+///
+/// The snapshot is validated and copied next to the data directory before
+/// anything is replaced, so a failure partway through leaves the data
+/// directory untouched. The old data directory is renamed rather than
+/// deleted.
+///
+/// # Errors
+///
+/// This function will return an error if a server is using the data
+/// directory, if the snapshot is invalid or was taken by a newer version of
+/// cubby, or if copying or swapping it in fails.
+pub(crate) fn restore(snapshot: &Path, data_path: &Path) -> PolarsResult<()> {
+    // Held until the old data directory has been moved aside
+    let _lock = if data_path.exists() {
+        Some(data_lock::lock(data_path)?)
+    } else {
+        None
+    };
+    let manifest = validate(snapshot)?;
+    let staging = with_suffix(data_path, ".restoring");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    for entry in &manifest.files {
+        let destination = staging.join(&entry.path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(snapshot.join(&entry.path), &destination)?;
+        File::open(&destination)?.sync_all()?;
+    }
+    sync_dir(&staging)?;
+    let replaced = with_suffix(data_path, &format!(".replaced-{}", now_ms()));
+    if data_path.exists() {
+        fs::rename(data_path, &replaced)?;
+    }
+    fs::rename(&staging, data_path)?;
+    if let Some(parent) = data_path.parent() {
+        sync_dir(parent)?;
+    }
+    info!(
+        "Restored snapshot {} taken at {} ms",
+        snapshot.display(),
+        manifest.created_ms
+    );
+    if replaced.exists() {
+        info!(
+            "The previous data directory was moved to {}",
+            replaced.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use polars::prelude::*;
+    use tempdir::TempDir;
+
+    use super::{
+        capture, data_files, restore, with_suffix, write_manifest, Manifest,
+        ManifestEntry, MANIFEST_FILE,
+    };
+    use crate::{
+        data_lock,
+        managers::{dataframes::write_dataframe_atomic, events},
+        schema::{self, UserRow, USERS},
+    };
+
+    /// Initialize a data directory in `dir` with a user and an event fragment,
+    /// and take a snapshot of it. Returns the paths of the data directory and
+    /// the snapshot.
+    fn snapshot_of_data_directory(dir: &Path) -> (PathBuf, PathBuf) {
+        let data_path = dir.join("data");
+        let snapshot = dir.join("snapshot");
+        schema::initialize(&data_path).expect("Failed to initialize");
+        write_users(&data_path, "alice");
+        let room = events::room_dir(&data_path.join(events::EVENTS_DIR), 1);
+        let bucket = room.join("bucket=0");
+        fs::create_dir_all(&bucket).expect("Failed to create bucket");
+        fs::write(bucket.join("fragment.parquet"), b"events")
+            .expect("Failed to write fragment");
+
+        fs::create_dir_all(&snapshot).expect("Failed to create snapshot");
+        let files = capture(&data_path, &[room], &snapshot)
+            .expect("Failed to capture files");
+        write_manifest(&snapshot, files, 1).expect("Failed to write manifest");
+        (data_path, snapshot)
+    }
+
+    /// Replace the users in `data_path` with a single user named `username`
+    fn write_users(data_path: &Path, username: &str) {
+        let mut users = USERS
+            .conform(
+                &df!(UserRow::USERNAME => [username]).expect("Invalid frame"),
+            )
+            .collect()
+            .expect("Invalid users");
+        // Written to a new file, so it doesn't change any snapshot it is
+        // linked into
+        write_dataframe_atomic(&USERS.path_in(data_path), &mut users)
+            .expect("Failed to write users");
+    }
+
+    /// The usernames in `data_path`
+    fn usernames(data_path: &Path) -> Vec<String> {
+        ParquetReader::new(
+            fs::File::open(USERS.path_in(data_path))
+                .expect("Failed to open users"),
+        )
+        .finish()
+        .expect("Failed to read users")
+        .column(UserRow::USERNAME)
+        .and_then(|column| column.cast(&DataType::String))
+        .expect("Invalid usernames")
+        .str()
+        .expect("Invalid usernames")
+        .into_no_null_iter()
+        .map(str::to_owned)
+        .collect()
+    }
+
+    /// Add `entry` to the manifest of `snapshot`
+    fn add_to_manifest(snapshot: &Path, entry: ManifestEntry) {
+        let path = snapshot.join(MANIFEST_FILE);
+        let mut manifest: Manifest = serde_json::from_slice(
+            &fs::read(&path).expect("Failed to read manifest"),
+        )
+        .expect("Invalid manifest");
+        manifest.files.push(entry);
+        fs::write(
+            &path,
+            serde_json::to_vec(&manifest).expect("Failed to encode manifest"),
+        )
+        .expect("Failed to write manifest");
+    }
+
+    /// How many data directories a restore has moved aside next to
+    /// `data_path`
+    fn replaced_data_directories(data_path: &Path) -> usize {
+        fs::read_dir(data_path.parent().expect("No parent directory"))
+            .expect("Failed to list directory")
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .expect("Failed to read entry")
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("data.replaced-")
+            })
+            .count()
+    }
+
+    #[test]
+    fn snapshots_restore_the_data_directory_they_were_taken_of() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let (data_path, snapshot) = snapshot_of_data_directory(dir.path());
+        write_users(&data_path, "bob");
+        assert_eq!(usernames(&data_path), ["bob"]);
+
+        restore(&snapshot, &data_path).expect("Failed to restore");
+        assert_eq!(usernames(&data_path), ["alice"]);
+        assert_eq!(
+            fs::read(data_path.join("events/room=1/bucket=0/fragment.parquet"))
+                .expect("Failed to read fragment"),
+            b"events"
+        );
+        assert_eq!(
+            schema::read_version(&data_path).expect("Failed to read version"),
+            Some(schema::current_version())
+        );
+        // The data directory that was replaced is kept
+        assert_eq!(replaced_data_directories(&data_path), 1);
+        assert!(!with_suffix(&data_path, ".restoring").exists());
+    }
+
+    #[test]
+    fn corrupted_snapshots_are_refused() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let (data_path, snapshot) = snapshot_of_data_directory(dir.path());
+        write_users(&data_path, "bob");
+        // Replaced rather than changed in place, since it is linked into the
+        // data directory
+        let fragment = snapshot.join("events/room=1/bucket=0/fragment.parquet");
+        fs::remove_file(&fragment).expect("Failed to remove fragment");
+        fs::write(&fragment, b"evenTs").expect("Failed to corrupt fragment");
+
+        let e = restore(&snapshot, &data_path)
+            .expect_err("Restored a corrupted snapshot");
+        assert!(e.to_string().contains("does not match"), "{e}");
+        assert_eq!(usernames(&data_path), ["bob"]);
+        assert_eq!(replaced_data_directories(&data_path), 0);
+        assert!(!with_suffix(&data_path, ".restoring").exists());
+    }
+
+    #[test]
+    fn manifests_cannot_point_outside_the_snapshot() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let outside = dir.path().join("outside");
+        fs::write(&outside, b"planted").expect("Failed to write file");
+
+        for path in [PathBuf::from("../../outside"), outside] {
+            let dir =
+                TempDir::new("cubby").expect("Failed to create directory");
+            let (data_path, snapshot) = snapshot_of_data_directory(dir.path());
+            add_to_manifest(
+                &snapshot,
+                ManifestEntry {
+                    path,
+                    size: 7,
+                    crc32: crc32fast::hash(b"planted"),
+                },
+            );
+
+            let e = restore(&snapshot, &data_path)
+                .expect_err("Restored a file outside the snapshot");
+            assert!(e.to_string().contains("invalid path"), "{e}");
+            assert_eq!(usernames(&data_path), ["alice"]);
+            assert_eq!(replaced_data_directories(&data_path), 0);
+        }
+    }
+
+    #[test]
+    fn only_locked_rooms_are_captured() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        schema::initialize(dir.path()).expect("Failed to initialize");
+        let events_dir = dir.path().join(events::EVENTS_DIR);
+        let rooms = [1, 2].map(|room| events::room_dir(&events_dir, room));
+        for room in &rooms {
+            let bucket = room.join("bucket=0");
+            fs::create_dir_all(&bucket).expect("Failed to create bucket");
+            fs::write(bucket.join("fragment.parquet"), b"")
+                .expect("Failed to write fragment");
+        }
+
+        let files: Vec<PathBuf> = data_files(dir.path(), &rooms[..1])
+            .expect("Failed to list files")
+            .into_iter()
+            .map(|(file, _)| file)
+            .collect();
+        assert!(files.contains(&PathBuf::from(
+            "events/room=1/bucket=0/fragment.parquet"
+        )));
+        assert!(!files.iter().any(|file| file.starts_with("events/room=2")));
+    }
+
+    #[test]
+    fn restoring_refuses_a_data_directory_in_use() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        schema::initialize(dir.path()).expect("Failed to initialize");
+        let _lock = data_lock::lock(dir.path()).expect("Failed to lock");
+        let e = restore(&PathBuf::from("missing-snapshot"), dir.path())
+            .expect_err("Restored into a locked data directory");
+        assert!(e.to_string().contains("in use"), "{e}");
+        assert!(schema::read_version(dir.path()).is_ok_and(|v| v.is_some()));
+    }
+}
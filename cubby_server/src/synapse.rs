@@ -0,0 +1,617 @@
+//! Importing accounts and rooms from a Synapse database
+//!
+//! `cubby import-synapse <database>` reads the database file of a stopped
+//! Synapse homeserver and fills a new cubby data directory with its users
+//! (along with their password hashes), devices, access tokens, profiles,
+//! rooms, current room state and event history. Identifiers are interned on
+//! the way in, exactly as if the server had written the rows itself.
+//!
+//! Each source table is read with a query that also gives the reason a row
+//! can't be converted, if there is one. Rows referring to rows that were not
+//! imported, such as the devices of users with invalid IDs, are dropped
+//! afterwards. Every skipped row is reported by reason, and nothing about it
+//! is guessed.
+//!
+//! Password hashes are copied as they are. Synapse hashes passwords with
+//! bcrypt, which cubby checks passwords against until each user next logs in
+//! and their password is hashed again (see `auth`). Hashes made with a
+//! `password_config.pepper` can't be checked, so the passwords of a server
+//! configured with one have to be reset. Media, receipts, account data, push
+//! rules, refresh tokens and federation state are not imported.
+//!
+//! The database must be from a recent Synapse whose server name is the
+//! configured `server_name`, and is only ever opened read-only. The data
+//! directory must not have been initialized yet, so imported rows never have to
+//! be merged with existing ones. If an import fails part of the way through,
+//! remove the data directory before trying again.
+
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cubby_lib::FileManager;
+use polars::prelude::*;
+use rusqlite::{types::Value, Connection, OpenFlags, Params};
+use tracing::{info, warn};
+
+use crate::{
+    config::{Storage, PROGRAM_CONFIG},
+    data_lock,
+    managers::{
+        dataframes::{writeback_failed, ParquetManager},
+        events::EventStore,
+        interner::Interner,
+        storage::{self, StorageAccess},
+        wal::Mutation,
+    },
+    schema::{
+        self, Table, ACCESS_TOKENS, DEVICES, PROFILES, ROOMS, ROOM_STATE, USERS,
+    },
+};
+
+/// The column every source query uses for the reason a row can't be
+/// converted, or null if it can
+const SKIP_REASON: &str = "skip_reason";
+
+/// Local user accounts. Synapse stores creation times in seconds.
+const USERS_QUERY: &str = "
+    SELECT name AS user_id,
+           substr(name, 2, instr(name, ':') - 2) AS username,
+           password_hash,
+           is_guest,
+           deactivated AS is_deactivated,
+           creation_ts * 1000 AS created_ts,
+           CASE WHEN name NOT LIKE '@_%:_%' THEN 'invalid user ID' END
+               AS skip_reason
+    FROM users";
+
+/// Devices. Synapse keeps cross-signing keys as hidden devices, which are
+/// never shown to clients.
+const DEVICES_QUERY: &str = "
+    SELECT user_id,
+           device_id,
+           display_name,
+           CASE WHEN hidden THEN 'hidden devices used internally by Synapse'
+           END AS skip_reason
+    FROM devices";
+
+/// Access tokens, given the current time in milliseconds since the unix epoch
+const ACCESS_TOKENS_QUERY: &str = "
+    SELECT token,
+           user_id,
+           device_id,
+           CASE WHEN device_id IS NULL THEN 'not issued to a device'
+                WHEN valid_until_ms < ?1 THEN 'expired'
+           END AS skip_reason
+    FROM access_tokens";
+
+/// Profiles, given the server name. Synapse stores the localpart of the user
+/// ID rather than the whole ID.
+const PROFILES_QUERY: &str = "
+    SELECT '@' || user_id || ':' || ?1 AS user_id,
+           displayname,
+           avatar_url,
+           NULL AS skip_reason
+    FROM profiles";
+
+/// Rooms. Rooms created before room versions existed have no version
+/// recorded, and were created at version 1.
+const ROOMS_QUERY: &str = "
+    SELECT r.room_id,
+           coalesce(r.room_version, '1') AS room_version,
+           r.creator,
+           (SELECT min(e.origin_server_ts) FROM events e
+            WHERE e.room_id = r.room_id AND e.type = 'm.room.create')
+               AS created_ts,
+           NULL AS skip_reason
+    FROM rooms r";
+
+/// Events in the order Synapse received them. Only their content is kept
+/// from the full JSON of each event.
+const EVENTS_QUERY: &str = "
+    SELECT e.event_id,
+           e.room_id,
+           e.sender,
+           e.type AS event_type,
+           e.state_key,
+           e.origin_server_ts,
+           json_extract(j.json, '$.content') AS content,
+           CASE WHEN e.outlier THEN 'outliers, which are not part of a \
+                            timeline'
+                WHEN e.event_id IN (SELECT event_id FROM rejections)
+                    THEN 'rejected'
+                WHEN j.json IS NULL THEN 'missing their JSON'
+           END AS skip_reason
+    FROM events e LEFT JOIN event_json j ON j.event_id = e.event_id
+    ORDER BY e.stream_ordering";
+
+/// The current state of every room
+const ROOM_STATE_QUERY: &str = "
+    SELECT room_id,
+           type AS event_type,
+           state_key,
+           event_id,
+           NULL AS skip_reason
+    FROM current_state_events";
+
+/// Convert a failure to read the Synapse database into a `PolarsError`
+fn sqlite_error(e: &rusqlite::Error) -> PolarsError {
+    PolarsError::ComputeError(
+        format!("Failed to read the Synapse database: {e}").into(),
+    )
+}
+
+/// Convert the values of a column read from the database into a `Series`.
+///
+/// Column types aren't enforced by the database, so a column is only read as
+/// integers if every value in it is one. Anything else is read as strings.
+fn series(name: &str, values: &[Value]) -> Series {
+    let integers =
+        values.iter().all(|v| matches!(v, Value::Null | Value::Integer(_)))
+            && values.iter().any(|v| matches!(v, Value::Integer(_)));
+    if integers {
+        let values: Vec<Option<i64>> = values
+            .iter()
+            .map(|v| match v {
+                Value::Integer(i) => Some(*i),
+                _ => None,
+            })
+            .collect();
+        return Series::new(name, values);
+    }
+    let values: Vec<Option<String>> = values
+        .iter()
+        .map(|v| match v {
+            Value::Null => None,
+            Value::Integer(i) => Some(i.to_string()),
+            Value::Real(f) => Some(f.to_string()),
+            Value::Text(s) => Some(s.clone()),
+            Value::Blob(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        })
+        .collect();
+    Series::new(name, values)
+}
+
+/// Run `sql` against the Synapse database and collect every row it returns
+fn read<P: Params>(
+    connection: &Connection,
+    sql: &str,
+    params: P,
+) -> PolarsResult<DataFrame> {
+    let mut statement =
+        connection.prepare(sql).map_err(|e| sqlite_error(&e))?;
+    let names: Vec<String> =
+        statement.column_names().into_iter().map(str::to_owned).collect();
+    let mut columns: Vec<Vec<Value>> = vec![Vec::new(); names.len()];
+    let mut rows = statement.query(params).map_err(|e| sqlite_error(&e))?;
+    while let Some(row) = rows.next().map_err(|e| sqlite_error(&e))? {
+        for (index, column) in columns.iter_mut().enumerate() {
+            column.push(row.get(index).map_err(|e| sqlite_error(&e))?);
+        }
+    }
+    DataFrame::new(
+        names
+            .iter()
+            .zip(&columns)
+            .map(|(name, values)| series(name, values))
+            .collect(),
+    )
+}
+
+/// Tallies the rows that could not be imported
+#[derive(Default)]
+struct Report {
+    /// How many rows were skipped in total
+    skipped: usize,
+}
+
+impl Report {
+    /// Record that `count` rows of `what` were skipped for `reason`
+    fn skip(&mut self, count: usize, what: &str, reason: &str) {
+        if count > 0 {
+            warn!("Skipped {count} {what}: {reason}");
+            self.skipped += count;
+        }
+    }
+
+    /// Drop every row of `rows` with a skip reason, reporting them, and
+    /// remove the skip reason column
+    fn convertible(
+        &mut self,
+        rows: DataFrame,
+        what: &str,
+    ) -> PolarsResult<DataFrame> {
+        let reasons = rows
+            .clone()
+            .lazy()
+            .filter(col(SKIP_REASON).is_not_null())
+            .group_by([col(SKIP_REASON)])
+            .agg([len().alias("count")])
+            .collect()?;
+        for (reason, count) in reasons
+            .column(SKIP_REASON)?
+            .cast(&DataType::String)?
+            .str()?
+            .into_iter()
+            .zip(reasons.column("count")?.cast(&DataType::UInt64)?.u64()?)
+        {
+            self.skip(
+                usize::try_from(count.unwrap_or(0)).unwrap_or(usize::MAX),
+                what,
+                reason.unwrap_or_default(),
+            );
+        }
+        rows.lazy()
+            .filter(col(SKIP_REASON).is_null())
+            .drop([SKIP_REASON])
+            .collect()
+    }
+
+    /// Drop every row of `rows` whose `columns` don't match a row of `parent`
+    /// in `parent_columns`, reporting them
+    fn referenced(
+        &mut self,
+        rows: &DataFrame,
+        columns: &[&str],
+        parent: &DataFrame,
+        parent_columns: &[&str],
+        what: &str,
+        reason: &str,
+    ) -> PolarsResult<DataFrame> {
+        let join = |join_type| {
+            rows.clone().lazy().join(
+                parent.clone().lazy(),
+                columns.iter().map(|name| col(name)).collect::<Vec<_>>(),
+                parent_columns.iter().map(|name| col(name)).collect::<Vec<_>>(),
+                JoinArgs::new(join_type),
+            )
+        };
+        let kept = join(JoinType::Semi).collect()?;
+        self.skip(rows.height() - kept.height(), what, reason);
+        Ok(kept)
+    }
+}
+
+/// Read and convert every table of the Synapse database at `database`, which
+/// must belong to the server `server_name`, returning the rows to write to
+/// each cubby table and the events in the order they should be appended
+fn convert(
+    database: &Path,
+    server_name: &str,
+    report: &mut Report,
+) -> PolarsResult<(Vec<(&'static Table, DataFrame)>, DataFrame)> {
+    let connection =
+        Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| sqlite_error(&e))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let now = i64::try_from(now).unwrap_or(i64::MAX);
+
+    let users =
+        report.convertible(read(&connection, USERS_QUERY, [])?, "users")?;
+    let devices =
+        report.convertible(read(&connection, DEVICES_QUERY, [])?, "devices")?;
+    let devices = report.referenced(
+        &devices,
+        &["user_id"],
+        &users,
+        &["user_id"],
+        "devices",
+        "belong to users that were not imported",
+    )?;
+    let access_tokens = report.convertible(
+        read(&connection, ACCESS_TOKENS_QUERY, [now])?,
+        "access tokens",
+    )?;
+    let access_tokens = report.referenced(
+        &access_tokens,
+        &["user_id", "device_id"],
+        &devices,
+        &["user_id", "device_id"],
+        "access tokens",
+        "issued to devices that were not imported",
+    )?;
+    // Synapse only stores local users, so they all have its server name.
+    // Importing them under another name would give them IDs no other server
+    // knows them by.
+    if let Some(foreign) =
+        users.column("user_id")?.str()?.into_iter().flatten().find(|user_id| {
+            user_id.split_once(':').map(|(_, name)| name) != Some(server_name)
+        })
+    {
+        return Err(PolarsError::ComputeError(
+            format!(
+                "The database holds the user {foreign}, so it doesn't belong \
+                 to {server_name}. Set server_name to the name of the Synapse \
+                 server."
+            )
+            .into(),
+        ));
+    }
+    let profiles = report.convertible(
+        read(&connection, PROFILES_QUERY, [server_name])?,
+        "profiles",
+    )?;
+    let profiles = report.referenced(
+        &profiles,
+        &["user_id"],
+        &users,
+        &["user_id"],
+        "profiles",
+        "belong to users that were not imported",
+    )?;
+    let rooms =
+        report.convertible(read(&connection, ROOMS_QUERY, [])?, "rooms")?;
+    let events =
+        report.convertible(read(&connection, EVENTS_QUERY, [])?, "events")?;
+    let events = report.referenced(
+        &events,
+        &["room_id"],
+        &rooms,
+        &["room_id"],
+        "events",
+        "in rooms that were not imported",
+    )?;
+    let room_state = report.convertible(
+        read(&connection, ROOM_STATE_QUERY, [])?,
+        "room state entries",
+    )?;
+    let room_state = report.referenced(
+        &room_state,
+        &["event_id"],
+        &events,
+        &["event_id"],
+        "room state entries",
+        "set by events that were not imported",
+    )?;
+
+    Ok((
+        vec![
+            (&USERS, users),
+            (&DEVICES, devices),
+            (&ACCESS_TOKENS, access_tokens),
+            (&PROFILES, profiles),
+            (&ROOMS, rooms),
+            (&ROOM_STATE, room_state),
+        ],
+        events,
+    ))
+}
+
+/// Import the Synapse database at `database` into the configured data
+/// directory, which must not have been initialized yet. Returns how many rows
+/// were skipped because they could not be converted.
+///
+/// # Errors
+///
+/// This function will return an error if a server is using the data
+/// directory or it has already been initialized, if the database can't be read,
+/// isn't from a recent Synapse or belongs to a server with another name, or if
+/// writing a table fails.
+pub(crate) async fn import(database: &Path) -> PolarsResult<usize> {
+    if !matches!(PROGRAM_CONFIG.storage, Storage::Parquet) {
+        return Err(PolarsError::ComputeError(
+            "Only parquet storage can be imported into".into(),
+        ));
+    }
+    let data_path = PROGRAM_CONFIG.data_path.as_path();
+    // Held until every imported row has been written, so a server can't
+    // start on the data directory half way through
+    let _lock = data_lock::lock(data_path)?;
+    if schema::read_version(data_path)?.is_some() {
+        return Err(PolarsError::ComputeError(
+            format!(
+                "{} has already been initialized. Import into a new data \
+                 directory instead.",
+                data_path.display()
+            )
+            .into(),
+        ));
+    }
+    // Categorical columns can only be compared and joined across frames if
+    // they share a string cache
+    polars::enable_string_cache();
+    let mut report = Report::default();
+    let (tables, events) =
+        convert(database, &PROGRAM_CONFIG.server_name, &mut report)?;
+
+    let storage = storage::from_config();
+    storage.initialize()?;
+    let file_manager = FileManager::new().with_storage(storage);
+    for (table, rows) in tables {
+        info!("Importing {} rows into table {}", rows.height(), table.name);
+        let rows = file_manager.intern_rows(table, rows).await?;
+        file_manager
+            .get_managed_lazyframe(table)
+            .await?
+            .apply(&Mutation::Upsert(rows))?;
+    }
+    info!("Importing {} events", events.height());
+    file_manager.append_events(events).await?;
+    file_manager.shutdown().await.map_err(|e| {
+        PolarsError::ComputeError(
+            format!("Failed to wait for pending writes: {e}").into(),
+        )
+    })?;
+    if writeback_failed() {
+        return Err(PolarsError::ComputeError(
+            "Some tables could not be written".into(),
+        ));
+    }
+    info!(
+        "Imported {} into {}, skipping {} rows",
+        database.display(),
+        data_path.display(),
+        report.skipped
+    );
+    Ok(report.skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use tempdir::TempDir;
+
+    use super::{convert, Report};
+
+    /// The parts of the Synapse schema that are imported, with a row for each
+    /// way of being imported or skipped
+    const FIXTURE: &str = "
+        CREATE TABLE users (name TEXT, password_hash TEXT, is_guest INTEGER,
+                            deactivated INTEGER, creation_ts INTEGER);
+        INSERT INTO users VALUES
+            ('@alice:example.org', '$2b$04$hash', 0, 0, 1),
+            ('@bob:example.org', NULL, 1, 0, 2),
+            ('carol', NULL, 0, 0, 3);
+
+        CREATE TABLE devices (user_id TEXT, device_id TEXT,
+                              display_name TEXT, hidden INTEGER);
+        INSERT INTO devices VALUES
+            ('@alice:example.org', 'PHONE', 'Phone', 0),
+            ('@alice:example.org', 'MASTERKEY', NULL, 1),
+            ('carol', 'LAPTOP', NULL, 0);
+
+        CREATE TABLE access_tokens (token TEXT, user_id TEXT, device_id TEXT,
+                                    valid_until_ms INTEGER);
+        INSERT INTO access_tokens VALUES
+            ('valid', '@alice:example.org', 'PHONE', NULL),
+            ('deviceless', '@alice:example.org', NULL, NULL),
+            ('expired', '@alice:example.org', 'PHONE', 1),
+            ('hidden', '@alice:example.org', 'MASTERKEY', NULL);
+
+        CREATE TABLE profiles (user_id TEXT, displayname TEXT,
+                               avatar_url TEXT);
+        INSERT INTO profiles VALUES
+            ('alice', 'Alice', NULL),
+            ('ghost', 'Ghost', NULL);
+
+        CREATE TABLE rooms (room_id TEXT, room_version TEXT, creator TEXT);
+        INSERT INTO rooms VALUES
+            ('!room:example.org', NULL, '@alice:example.org');
+
+        CREATE TABLE events (event_id TEXT, room_id TEXT, sender TEXT,
+                             type TEXT, state_key TEXT,
+                             origin_server_ts INTEGER, outlier INTEGER,
+                             stream_ordering INTEGER);
+        INSERT INTO events VALUES
+            ('$message', '!room:example.org', '@alice:example.org',
+             'm.room.message', NULL, 20, 0, 2),
+            ('$create', '!room:example.org', '@alice:example.org',
+             'm.room.create', '', 10, 0, 1),
+            ('$outlier', '!room:example.org', '@bob:example.org',
+             'm.room.member', '@bob:example.org', 30, 1, 3),
+            ('$rejected', '!room:example.org', '@bob:example.org',
+             'm.room.name', '', 40, 0, 4),
+            ('$jsonless', '!room:example.org', '@bob:example.org',
+             'm.room.message', NULL, 50, 0, 5),
+            ('$elsewhere', '!gone:example.org', '@bob:example.org',
+             'm.room.message', NULL, 60, 0, 6);
+
+        CREATE TABLE rejections (event_id TEXT);
+        INSERT INTO rejections VALUES ('$rejected');
+
+        CREATE TABLE event_json (event_id TEXT, json TEXT);
+        INSERT INTO event_json VALUES
+            ('$create', '{\"content\": {\"creator\": \
+                           \"@alice:example.org\"}}'),
+            ('$message', '{\"content\": {\"body\": \"hi\"}}'),
+            ('$outlier', '{\"content\": {}}'),
+            ('$rejected', '{\"content\": {}}'),
+            ('$elsewhere', '{\"content\": {}}');
+
+        CREATE TABLE current_state_events (room_id TEXT, type TEXT,
+                                           state_key TEXT, event_id TEXT);
+        INSERT INTO current_state_events VALUES
+            ('!room:example.org', 'm.room.create', '', '$create'),
+            ('!room:example.org', 'm.room.name', '', '$rejected');
+    ";
+
+    #[test]
+    fn synapse_databases_are_converted() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let database = dir.path().join("homeserver.db");
+        Connection::open(&database)
+            .expect("Failed to create database")
+            .execute_batch(FIXTURE)
+            .expect("Failed to fill database");
+
+        let mut report = Report::default();
+        let (tables, events) = convert(&database, "example.org", &mut report)
+            .expect("Failed to convert");
+        // One user, two devices, three access tokens, one profile, four
+        // events and one room state entry
+        assert_eq!(report.skipped, 12);
+
+        let column = |table: &str, column: &str| -> Vec<Option<String>> {
+            let (_, rows) = tables
+                .iter()
+                .find(|(t, _)| t.name == table)
+                .expect("Missing table");
+            rows.column(column)
+                .expect("Missing column")
+                .cast(&polars::prelude::DataType::String)
+                .expect("Failed to cast")
+                .str()
+                .expect("Not a string column")
+                .into_iter()
+                .map(|value| value.map(str::to_owned))
+                .collect()
+        };
+        let some = |values: &[&str]| -> Vec<Option<String>> {
+            values.iter().map(|value| Some((*value).to_owned())).collect()
+        };
+        assert_eq!(
+            column("users", "user_id"),
+            some(&["@alice:example.org", "@bob:example.org"])
+        );
+        assert_eq!(column("users", "username"), some(&["alice", "bob"]));
+        assert_eq!(
+            column("users", "password_hash"),
+            vec![Some("$2b$04$hash".to_owned()), None]
+        );
+        assert_eq!(column("users", "created_ts"), some(&["1000", "2000"]));
+        assert_eq!(column("devices", "device_id"), some(&["PHONE"]));
+        assert_eq!(column("access_tokens", "token"), some(&["valid"]));
+        assert_eq!(
+            column("profiles", "user_id"),
+            some(&["@alice:example.org"])
+        );
+        assert_eq!(column("rooms", "room_version"), some(&["1"]));
+        assert_eq!(column("rooms", "created_ts"), some(&["10"]));
+        assert_eq!(column("room_state", "event_id"), some(&["$create"]));
+
+        let event_ids: Vec<_> = events
+            .column("event_id")
+            .expect("Missing column")
+            .str()
+            .expect("Not a string column")
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(event_ids, ["$create", "$message"]);
+        let content: Vec<_> = events
+            .column("content")
+            .expect("Missing column")
+            .str()
+            .expect("Not a string column")
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(content[1], r#"{"body":"hi"}"#);
+    }
+
+    #[test]
+    fn databases_of_other_servers_are_refused() {
+        let dir = TempDir::new("cubby").expect("Failed to create directory");
+        let database = dir.path().join("homeserver.db");
+        Connection::open(&database)
+            .expect("Failed to create database")
+            .execute_batch(FIXTURE)
+            .expect("Failed to fill database");
+
+        let mut report = Report::default();
+        assert!(convert(&database, "example.com", &mut report).is_err());
+    }
+}
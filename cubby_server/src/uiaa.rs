@@ -0,0 +1,561 @@
+//! User-interactive authentication
+//!
+//! Some endpoints, like registration, make clients complete one or more
+//! authentication stages before the request is carried out. The stages each
+//! endpoint accepts are configured as a list of flows, and completing every
+//! stage of any one flow is enough.
+//!
+//! A request without an `auth` dict gets a 401 response listing the flows and
+//! a new session ID. Each following request repeats the session ID and
+//! completes one more stage. The stages completed so far are kept in the
+//! `uiaa_sessions` table, so a session survives restarts. Once a flow is
+//! complete the request goes ahead. Sessions that aren't completed within
+//! `PROGRAM_CONFIG.uiaa_session_lifetime` seconds expire.
+//!
+//! A session records a hash of the request it was started for, and refuses
+//! to authenticate a different one, so a client can't switch to another
+//! username after completing the stages for the first.
+//!
+//! Handlers call `authenticate` with the `auth` dict of their request, and
+//! return the `Rejection` it gives them as their response. Once they have
+//! carried out the request, they call `Authenticated::finish` to remove the
+//! session. A request that fails leaves the session in place, so the client
+//! can retry it.
+//!
+//! [Spec](https://spec.matrix.org/latest/client-server-api/#user-interactive-authentication-api)
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::StatusCode;
+use cubby_lib::{CubbyResponder, FileManager};
+use polars::prelude::*;
+use ruma::{
+    api::client::uiaa::{AuthData, UserIdentifier},
+    UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{
+    auth,
+    config::PROGRAM_CONFIG,
+    managers::{dataframes::ParquetManager, interner::Interner, wal::Mutation},
+    schema::{ParquetRow, UiaaSessionRow, UserRow, UIAA_SESSIONS, USERS},
+};
+
+/// A single stage of authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Stage {
+    /// Always succeeds, for flows that only need a session
+    #[serde(rename = "m.login.dummy")]
+    Dummy,
+    /// The user enters their current password
+    #[serde(rename = "m.login.password")]
+    Password,
+    /// The client gives one of `PROGRAM_CONFIG.registration_tokens`. Only
+    /// registration accepts this.
+    #[serde(rename = "m.login.registration_token")]
+    RegistrationToken,
+    /// The user agrees to the terms of service at `PROGRAM_CONFIG.terms_url`
+    #[serde(rename = "m.login.terms")]
+    Terms,
+}
+
+impl Stage {
+    /// The type of the stage, as it appears in flows and `auth` dicts
+    fn as_str(self) -> &'static str {
+        match self {
+            Stage::Dummy => "m.login.dummy",
+            Stage::Password => "m.login.password",
+            Stage::RegistrationToken => "m.login.registration_token",
+            Stage::Terms => "m.login.terms",
+        }
+    }
+
+    /// The stage with the type `kind`, if it is one cubby supports
+    fn from_type(kind: &str) -> Option<Self> {
+        [Stage::Dummy, Stage::Password, Stage::RegistrationToken, Stage::Terms]
+            .into_iter()
+            .find(|stage| stage.as_str() == kind)
+    }
+}
+
+/// An endpoint that requires user-interactive authentication
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Endpoint {
+    /// `POST /register`
+    Register,
+    /// `POST /login/get_token`
+    GetLoginToken,
+}
+
+impl Endpoint {
+    /// The name sessions for this endpoint are recorded under
+    fn name(self) -> &'static str {
+        match self {
+            Endpoint::Register => "register",
+            Endpoint::GetLoginToken => "get_login_token",
+        }
+    }
+
+    /// The flows the endpoint accepts
+    fn flows(self) -> &'static [Vec<Stage>] {
+        match self {
+            Endpoint::Register => &PROGRAM_CONFIG.registration_flows,
+            Endpoint::GetLoginToken => &PROGRAM_CONFIG.login_token_flows,
+        }
+    }
+}
+
+/// Proof that a request has been authenticated, holding the session that was
+/// completed for it, if there was one
+#[must_use = "the session has to be removed once the request succeeds"]
+#[derive(Debug)]
+pub(crate) struct Authenticated {
+    /// The ID of the completed session
+    session: Option<String>,
+}
+
+impl Authenticated {
+    /// Remove the completed session, once the request it authenticated has
+    /// been carried out. A session that can't be removed expires as usual.
+    pub(crate) async fn finish(self, file_manager: &FileManager) {
+        let Some(session) = self.session else {
+            return;
+        };
+        if let Err(e) = remove(file_manager, &session).await {
+            error!("Failed to remove the completed session {session}: {e}");
+        }
+    }
+}
+
+/// The response to send instead of carrying out a request that hasn't been
+/// authenticated yet
+#[derive(Debug)]
+pub(crate) struct Rejection {
+    /// The status code of the response
+    status: StatusCode,
+    /// The body of the response
+    body: Value,
+}
+
+impl Rejection {
+    /// Respond to the request with this rejection
+    pub(crate) fn respond<T, E>(self) -> CubbyResponder<T, E> {
+        CubbyResponder::OneOff(self.status, self.body)
+    }
+
+    /// A plain Matrix error that doesn't continue the session
+    fn error(status: StatusCode, errcode: &str, message: &str) -> Self {
+        Self {
+            status,
+            body: json!({
+                "errcode": errcode,
+                "error": message,
+            }),
+        }
+    }
+
+    /// The 401 response telling the client which stages it can complete
+    /// next, along with the error of the stage it just failed, if any
+    fn challenge(
+        flows: &[Vec<Stage>],
+        session: &UiaaSessionRow,
+        failure: Option<(&str, &str)>,
+    ) -> Self {
+        let mut body = json!({
+            "flows": flows
+                .iter()
+                .map(|stages| json!({ "stages": stages }))
+                .collect::<Vec<_>>(),
+            "params": params(flows),
+            "completed": completed(session),
+            "session": session.session,
+        });
+        if let Some((errcode, message)) = failure {
+            body["errcode"] = errcode.into();
+            body["error"] = message.into();
+        }
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            body,
+        }
+    }
+}
+
+/// The parameters clients need to complete the stages in `flows`
+fn params(flows: &[Vec<Stage>]) -> Value {
+    let mut params = json!({});
+    if flows.iter().flatten().any(|stage| *stage == Stage::Terms) {
+        let policies = PROGRAM_CONFIG.terms_url.as_ref().map_or_else(
+            || json!({}),
+            |url| {
+                json!({
+                    "terms_of_service": {
+                        "version": PROGRAM_CONFIG.terms_version,
+                        "en": {
+                            "name": "Terms of Service",
+                            "url": url,
+                        },
+                    },
+                })
+            },
+        );
+        params[Stage::Terms.as_str()] = json!({ "policies": policies });
+    }
+    params
+}
+
+/// The stages `session` has completed
+fn completed(session: &UiaaSessionRow) -> Vec<Stage> {
+    session.completed.split(',').filter_map(Stage::from_type).collect()
+}
+
+/// A hash of `request`, the parts of a request a session authenticates
+fn request_hash(request: &[&str]) -> String {
+    /// The digits used for each nibble
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hasher = Sha256::new();
+    for part in request {
+        // Each part is prefixed with its length, so moving characters from
+        // one part to the next changes the hash
+        hasher.update(
+            u64::try_from(part.len()).unwrap_or(u64::MAX).to_le_bytes(),
+        );
+        hasher.update(part.as_bytes());
+    }
+    let digest = hasher.finalize();
+    let mut hash = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        hash.push(char::from(DIGITS[usize::from(byte >> 4)]));
+        hash.push(char::from(DIGITS[usize::from(byte & 0xF)]));
+    }
+    hash
+}
+
+/// The current time, in milliseconds since the unix epoch
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| {
+        u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+    })
+}
+
+/// Whether `session` was started too long ago to be continued at `now`
+fn expired(session: &UiaaSessionRow, now: u64) -> bool {
+    let lifetime = PROGRAM_CONFIG.uiaa_session_lifetime.saturating_mul(1000);
+    session.created_ts.saturating_add(lifetime) < now
+}
+
+/// Find a session by its ID
+async fn load(
+    file_manager: &FileManager,
+    session: &str,
+) -> PolarsResult<Option<UiaaSessionRow>> {
+    let rows = file_manager
+        .get_by_key(&UIAA_SESSIONS, UiaaSessionRow::SESSION, session)
+        .await?;
+    Ok(UiaaSessionRow::from_dataframe(&rows)?.into_iter().next())
+}
+
+/// Record the progress of `session`, removing any sessions that have expired
+/// by `now` while the table is locked
+async fn save(
+    file_manager: &FileManager,
+    session: &UiaaSessionRow,
+    now: u64,
+) -> PolarsResult<()> {
+    let mut sessions =
+        file_manager.get_managed_lazyframe(&UIAA_SESSIONS).await?;
+    let lifetime = PROGRAM_CONFIG.uiaa_session_lifetime.saturating_mul(1000);
+    let expired = sessions.query(|frame| {
+        frame
+            .filter(
+                col(UiaaSessionRow::CREATED_TS)
+                    .lt(lit(now.saturating_sub(lifetime))),
+            )
+            .select([col(UiaaSessionRow::SESSION)])
+    })?;
+    for id in
+        expired.column(UiaaSessionRow::SESSION)?.str()?.into_iter().flatten()
+    {
+        sessions.apply(&Mutation::Delete {
+            column: UiaaSessionRow::SESSION.to_owned(),
+            value: id.to_owned(),
+        })?;
+    }
+    sessions.apply(&Mutation::Upsert(UiaaSessionRow::to_dataframe(
+        std::slice::from_ref(session),
+    )?))
+}
+
+/// Remove a session once the request it authenticated has been carried out
+async fn remove(file_manager: &FileManager, session: &str) -> PolarsResult<()> {
+    file_manager.get_managed_lazyframe(&UIAA_SESSIONS).await?.apply(
+        &Mutation::Delete {
+            column: UiaaSessionRow::SESSION.to_owned(),
+            value: session.to_owned(),
+        },
+    )
+}
+
+/// Check the password given in a `m.login.password` stage against the one
+/// of `user`, the user making the request
+async fn check_password(
+    file_manager: &FileManager,
+    auth: &AuthData,
+    user: Option<(&UserId, u64)>,
+) -> PolarsResult<bool> {
+    let (AuthData::Password(password), Some((user, short_id))) = (auth, user)
+    else {
+        return Ok(false);
+    };
+    // The identifier has to name the user making the request, not just any
+    // user whose password the client knows
+    let UserIdentifier::UserIdOrLocalpart(identifier) = &password.identifier
+    else {
+        return Ok(false);
+    };
+    if identifier != user.as_str() && identifier != user.localpart() {
+        return Ok(false);
+    }
+    let rows =
+        file_manager.get_by_key(&USERS, UserRow::USER_ID, short_id).await?;
+    let Some(hash) = UserRow::from_dataframe(&rows)?
+        .into_iter()
+        .next()
+        .and_then(|row| row.password_hash)
+    else {
+        return Ok(false);
+    };
+    auth::verify_password(password.password.clone(), hash).await
+}
+
+/// Check the token given in a `m.login.registration_token` stage
+fn check_registration_token(endpoint: Endpoint, auth: &AuthData) -> bool {
+    let (Endpoint::Register, AuthData::RegistrationToken(given)) =
+        (endpoint, auth)
+    else {
+        return false;
+    };
+    PROGRAM_CONFIG.registration_tokens.iter().any(|token| {
+        auth::constant_time_eq(token.as_bytes(), given.token.as_bytes())
+    })
+}
+
+/// Check the stage completed by `auth`, returning the error to send if it
+/// failed
+async fn check(
+    file_manager: &FileManager,
+    endpoint: Endpoint,
+    stage: Stage,
+    auth: &AuthData,
+    user: Option<(&UserId, u64)>,
+) -> PolarsResult<Option<(&'static str, &'static str)>> {
+    let failure = match stage {
+        Stage::Dummy | Stage::Terms => None,
+        Stage::Password => (!check_password(file_manager, auth, user).await?)
+            .then_some("Invalid password"),
+        Stage::RegistrationToken => (!check_registration_token(endpoint, auth))
+            .then_some("Invalid registration token"),
+    };
+    Ok(failure.map(|message| ("M_FORBIDDEN", message)))
+}
+
+/// Advance the session named in `auth` by the stage it completes
+async fn advance(
+    file_manager: &FileManager,
+    endpoint: Endpoint,
+    flows: &[Vec<Stage>],
+    request: &[&str],
+    auth: Option<&AuthData>,
+    user: Option<&UserId>,
+) -> PolarsResult<Result<Authenticated, Rejection>> {
+    let now = now_ms();
+    let request_hash = request_hash(request);
+    let user = match user {
+        Some(user) => {
+            file_manager.lookup(user.as_str()).await?.map(|id| (user, id))
+        }
+        None => None,
+    };
+    let user_id = user.map(|(_, id)| id);
+    let resumed = auth.and_then(AuthData::session).is_some();
+    let mut session = match auth.and_then(AuthData::session) {
+        Some(id) => match load(file_manager, id).await? {
+            // A session can only be continued by whoever started it, for the
+            // endpoint it was started for
+            Some(session)
+                if session.endpoint == endpoint.name()
+                    && session.user_id == user_id
+                    && !expired(&session, now) =>
+            {
+                session
+            }
+            _ => {
+                return Ok(Err(Rejection::error(
+                    StatusCode::BAD_REQUEST,
+                    "M_UNKNOWN",
+                    "Unknown or expired session",
+                )))
+            }
+        },
+        None => UiaaSessionRow {
+            session: auth::generate_session_id(),
+            endpoint: endpoint.name().to_owned(),
+            request_hash: request_hash.clone(),
+            user_id,
+            completed: String::new(),
+            created_ts: now,
+        },
+    };
+    if session.request_hash != request_hash {
+        return Ok(Err(Rejection::error(
+            StatusCode::FORBIDDEN,
+            "M_FORBIDDEN",
+            "The request has changed since the session was started",
+        )));
+    }
+    let Some(auth) = auth else {
+        save(file_manager, &session, now).await?;
+        return Ok(Err(Rejection::challenge(flows, &session, None)));
+    };
+
+    let stage = auth
+        .auth_type()
+        .and_then(|kind| Stage::from_type(kind.as_str()))
+        .filter(|stage| flows.iter().flatten().any(|s| s == stage));
+    let failure = match stage {
+        Some(stage) => check(file_manager, endpoint, stage, auth, user).await?,
+        None => Some(("M_UNRECOGNIZED", "This stage isn't accepted here")),
+    };
+    if let Some(failure) = failure {
+        save(file_manager, &session, now).await?;
+        return Ok(Err(Rejection::challenge(flows, &session, Some(failure))));
+    }
+
+    let mut stages = completed(&session);
+    if let Some(stage) = stage.filter(|stage| !stages.contains(stage)) {
+        stages.push(stage);
+    }
+    if flows.iter().any(|flow| flow.iter().all(|stage| stages.contains(stage)))
+    {
+        return Ok(Ok(Authenticated {
+            session: resumed.then_some(session.session),
+        }));
+    }
+    session.completed =
+        stages.iter().map(|stage| stage.as_str()).collect::<Vec<_>>().join(",");
+    save(file_manager, &session, now).await?;
+    Ok(Err(Rejection::challenge(flows, &session, None)))
+}
+
+/// Authenticate a request to `endpoint` made by `user`, if they are logged
+/// in, with the `auth` dict of the request. `request` holds the parts of the
+/// request that have to stay the same for the whole session, leaving out
+/// secrets like passwords.
+///
+/// Returns `Ok` once the client has completed a flow, and the request can go
+/// ahead. Otherwise returns the response to send instead, which is usually a
+/// 401 telling the client what to do next.
+pub(crate) async fn authenticate(
+    file_manager: &FileManager,
+    endpoint: Endpoint,
+    request: &[&str],
+    auth: Option<&AuthData>,
+    user: Option<&UserId>,
+) -> Result<Authenticated, Rejection> {
+    let flows = endpoint.flows();
+    if flows.is_empty() {
+        return Ok(Authenticated {
+            session: None,
+        });
+    }
+    match advance(file_manager, endpoint, flows, request, auth, user).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!(
+                "Failed to record user-interactive authentication: {e}. Run \
+                 `cubby check` to look for damage in the data directory."
+            );
+            Err(Rejection::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "M_UNKNOWN",
+                "Authentication could not be recorded",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::api::client::uiaa::{AuthData, Dummy};
+
+    use super::{authenticate, load, Endpoint};
+    use crate::managers::storage::memory_file_manager;
+
+    /// An `m.login.dummy` stage continuing `session`
+    fn dummy(session: &str) -> AuthData {
+        let mut dummy = Dummy::new();
+        dummy.session = Some(session.to_owned());
+        AuthData::Dummy(dummy)
+    }
+
+    #[tokio::test]
+    async fn sessions_outlive_failed_requests_but_not_changed_ones() {
+        let file_manager = memory_file_manager();
+        let rejection = authenticate(
+            &file_manager,
+            Endpoint::Register,
+            &["alice"],
+            None,
+            None,
+        )
+        .await
+        .expect_err("Authenticated without a session");
+        let session = rejection.body["session"]
+            .as_str()
+            .expect("No session was started")
+            .to_owned();
+
+        // Someone else's username can't be registered with the session
+        let rejection = authenticate(
+            &file_manager,
+            Endpoint::Register,
+            &["mallory"],
+            Some(&dummy(&session)),
+            None,
+        )
+        .await
+        .expect_err("Authenticated a different request");
+        assert_eq!(rejection.body["errcode"], "M_FORBIDDEN");
+
+        // The session is kept until the request has been carried out, so a
+        // request that fails can be retried
+        let authenticated = authenticate(
+            &file_manager,
+            Endpoint::Register,
+            &["alice"],
+            Some(&dummy(&session)),
+            None,
+        )
+        .await
+        .expect("Failed to authenticate");
+        drop(authenticated);
+        let authenticated = authenticate(
+            &file_manager,
+            Endpoint::Register,
+            &["alice"],
+            Some(&dummy(&session)),
+            None,
+        )
+        .await
+        .expect("Failed to authenticate again");
+        authenticated.finish(&file_manager).await;
+        assert!(load(&file_manager, &session)
+            .await
+            .expect("Failed to load session")
+            .is_none());
+    }
+}
@@ -0,0 +1,142 @@
+//! Which usernames can be registered
+//!
+//! A username is the localpart of a user ID. New ones have to follow the
+//! current user ID grammar, which only allows lowercase letters, digits and
+//! `._=-/+`, and the whole user ID can be at most 255 bytes long. On top of
+//! that, the server refuses names listed in
+//! `PROGRAM_CONFIG.reserved_usernames` or matching any of
+//! `PROGRAM_CONFIG.username_deny_patterns`, such as the prefixes claimed by
+//! bridges. Reserved names are compared ignoring case. Deny patterns are
+//! searched for anywhere in the username like any regular expression, so a
+//! pattern only matches a prefix or a whole name if it is anchored with `^`
+//! or `^...$`.
+//!
+//! Usernames in the namespace of an appservice (see `appservices`) are
+//! refused as well, with their own error.
+//!
+//! `/register` and `/register/available` both check usernames here, so a name
+//! reported as available can always be registered unless someone takes it
+//! first.
+//!
+//! [Spec](https://spec.matrix.org/latest/appendices/#user-identifiers)
+
+use once_cell::sync::Lazy;
+use regex::RegexSet;
+use ruma::UserId;
+
+use crate::{appservices, config::PROGRAM_CONFIG};
+
+/// The longest a user ID can be, in bytes
+const MAX_USER_ID_LENGTH: usize = 255;
+
+/// The compiled `PROGRAM_CONFIG.username_deny_patterns`
+static DENY_PATTERNS: Lazy<Result<RegexSet, String>> = Lazy::new(|| {
+    RegexSet::new(&PROGRAM_CONFIG.username_deny_patterns)
+        .map_err(|e| format!("Invalid username deny pattern: {e}"))
+});
+
+/// Check that the configured username policy can be applied
+///
+/// # Errors
+///
+/// This function will return an error if one of the deny patterns isn't a
+/// valid regular expression.
+pub(crate) fn check_policy() -> Result<(), String> {
+    DENY_PATTERNS.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+/// Whether `localpart` follows the user ID grammar, and makes a short enough
+/// user ID on this server
+pub(crate) fn is_valid(localpart: &str) -> bool {
+    let length =
+        "@:".len() + localpart.len() + PROGRAM_CONFIG.server_name.len();
+    !localpart.is_empty()
+        && length <= MAX_USER_ID_LENGTH
+        && localpart.bytes().all(|byte| {
+            byte.is_ascii_lowercase()
+                || byte.is_ascii_digit()
+                || b"._=-/+".contains(&byte)
+        })
+}
+
+/// Whether `localpart` can be registered as a username on this server
+pub(crate) fn is_allowed(localpart: &str) -> bool {
+    // An invalid policy refuses everything rather than nothing. The server
+    // doesn't start with one, so this can't happen in practice.
+    let Ok(deny_patterns) = &*DENY_PATTERNS else {
+        return false;
+    };
+    is_allowed_by(localpart, &PROGRAM_CONFIG.reserved_usernames, deny_patterns)
+}
+
+/// Whether `localpart` is valid, isn't one of `reserved` and doesn't match
+/// any of `deny_patterns`
+fn is_allowed_by(
+    localpart: &str,
+    reserved: &[String],
+    deny_patterns: &RegexSet,
+) -> bool {
+    is_valid(localpart)
+        && !reserved.iter().any(|name| name.eq_ignore_ascii_case(localpart))
+        && !deny_patterns.is_match(localpart)
+}
+
+/// Whether `localpart` is in the namespace of an appservice, which only the
+/// appservice can use
+pub(crate) fn is_exclusive(localpart: &str) -> bool {
+    UserId::parse(format!("@{localpart}:{}", PROGRAM_CONFIG.server_name))
+        .is_ok_and(|user_id| appservices::is_claimed(&user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::RegexSet;
+
+    use super::{is_allowed, is_allowed_by, is_valid, MAX_USER_ID_LENGTH};
+    use crate::config::PROGRAM_CONFIG;
+
+    #[test]
+    fn user_ids_are_at_most_255_bytes() {
+        let longest =
+            MAX_USER_ID_LENGTH - "@:".len() - PROGRAM_CONFIG.server_name.len();
+        assert!(is_valid(&"a".repeat(longest)));
+        assert!(!is_valid(&"a".repeat(longest + 1)));
+    }
+
+    #[test]
+    fn only_lowercase_letters_digits_and_some_symbols_are_valid() {
+        assert!(is_valid("alice"));
+        assert!(is_valid("a.b_c=d-e/f+1"));
+        for localpart in ["", "Alice", "ALICE", "al ice", "al:ice", "al@ice"] {
+            assert!(!is_valid(localpart), "{localpart:?}");
+        }
+        // Historical user IDs allowed more, but new ones can't use it
+        for localpart in ["alicé", "al\u{0}ice", "al!ice", "al#ice"] {
+            assert!(!is_valid(localpart), "{localpart:?}");
+        }
+    }
+
+    #[test]
+    fn reserved_usernames_are_refused() {
+        assert!(is_allowed("alice"));
+        for reserved in &PROGRAM_CONFIG.reserved_usernames {
+            assert!(!is_allowed(reserved), "{reserved}");
+        }
+        let reserved = ["Admin".to_owned()];
+        let none = RegexSet::empty();
+        assert!(!is_allowed_by("admin", &reserved, &none));
+        assert!(is_allowed_by("administrator", &reserved, &none));
+    }
+
+    #[test]
+    fn deny_patterns_match_anywhere_unless_anchored() {
+        let patterns = RegexSet::new(["^_irc_", "bot", "^spam$"])
+            .expect("Invalid patterns");
+        for denied in ["_irc_alice", "bot", "robot", "bots_r_us", "spam"] {
+            assert!(!is_allowed_by(denied, &[], &patterns), "{denied}");
+        }
+        for allowed in ["alice", "alice_irc_", "spammer", "b_o_t"] {
+            assert!(is_allowed_by(allowed, &[], &patterns), "{allowed}");
+        }
+    }
+}